@@ -1,14 +1,60 @@
-use candid::{CandidType, Deserialize};
+use std::cell::Cell;
+use std::future::Future;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use candid::{CandidType, Deserialize, Nat};
 use ethers_core::types::transaction::eip2718::TypedTransaction;
 use ethers_core::types::{Address, TransactionRequest, U64};
-use ic_canister::{generate_idl, init, query, update, Canister, Idl, PreUpdate};
+use ic_canister::{
+    generate_idl, init, post_upgrade, pre_upgrade, query, update, Canister, Idl, PreUpdate,
+};
 use ic_exports::candid::Principal;
+use ic_exports::ic_cdk::api::call::{msg_cycles_accept128, msg_cycles_available128, notify};
+use ic_exports::ic_cdk::spawn;
+use ic_exports::ic_cdk_timers::set_timer;
 use ic_exports::ic_kit::ic;
+use serde::de::DeserializeOwned;
 
 use crate::error::{Error, Result};
+use crate::ledger::{self, Account, IcrcLedger};
+use crate::state::access::AccessPolicy;
+use crate::state::ecdsa::bch::{BchTransaction, BchWallet};
+use crate::state::ecdsa::btc::{self, BtcAddressType, BtcSignedMessage, BtcWallet};
+use crate::state::ecdsa::cosmos::{CosmosTransaction, CosmosWallet};
 use crate::state::ecdsa::eth::EthWallet;
+use crate::state::ecdsa::filecoin::{
+    FilecoinMessage, FilecoinNetwork, FilecoinSignedMessage, FilecoinWallet,
+};
+use crate::state::ecdsa::ic::{IcRequest, IcSignedRequest, IcWallet};
+use crate::state::ecdsa::stacks::{StacksSignedTransaction, StacksTransaction, StacksWallet};
+use crate::state::ecdsa::sui::SuiWallet;
+use crate::state::ecdsa::tron::{TronSignedTransaction, TronTransaction, TronWallet};
+use crate::state::ecdsa::xrp::{XrpSignedTransaction, XrpTransaction, XrpWallet};
 use crate::state::ecdsa::{CoinType, EcdsaKeyIds, Signer};
-use crate::state::{Settings, State};
+use crate::state::fees::{Due, FeeSchedule, Operation, Payment, UsageStatement};
+use crate::state::idempotency::{fingerprint, Call, Idempotency};
+use crate::state::ledgers::LedgerInfo;
+use crate::state::pauses::{Pause, PauseScope, Pauses};
+use crate::state::proposals::{Action, ApprovalPolicy, Proposal};
+use crate::state::queue::{Callback, SigningJob, SigningRequest, SigningResponse};
+use crate::state::roles::{Role, RoleChange};
+#[cfg(feature = "bench")]
+use crate::state::schema::{self, SignersBench};
+use crate::state::schnorr::nostr::{NostrEvent, NostrWallet};
+use crate::state::schnorr::polkadot::{
+    PolkadotKeyType, PolkadotSignedTransaction, PolkadotSigner, PolkadotTransaction, PolkadotWallet,
+};
+use crate::state::schnorr::solana::{SolanaSignedMessage, SolanaTransaction, SolanaWallet};
+use crate::state::schnorr::ton::{TonSignedMessage, TonTransaction, TonWallet};
+use crate::state::schnorr::{SchnorrAlgorithm, SchnorrSigner};
+use crate::state::{decode, encode, Settings, State, UpgradeSettings};
+
+/// Maximum number of audit log entries or proposals returned by a call.
+const MAX_AUDIT_LOG_PAGE: u64 = 100;
+/// Delay before starting again the signing jobs left waiting.
+const WAITING_JOBS_RETRY: Duration = Duration::from_secs(60);
 
 /// A canister to transfer funds between IC token canisters and EVM canister contracts.
 #[derive(Canister)]
@@ -26,38 +72,148 @@ impl TornadoCanister {
     #[init]
     pub fn init(&mut self, init_data: InitData) {
         let settings = Settings {
+            installer: ic::caller(),
             owner: init_data.owner,
             ecdsa_env: init_data.ecdsa_env,
+            timestamp: ic::time(),
         };
 
         self.state.reset(settings);
     }
 
+    /// The state lives in stable memory, which upgrades keep as is.
+    #[pre_upgrade]
+    pub fn pre_upgrade(&self) {}
+
+    /// Migrates the state of the previous version and applies the settings changed
+    /// by `upgrade_data`. Users, their keys and nonces are kept.
+    #[post_upgrade]
+    pub fn post_upgrade(&mut self, upgrade_data: Option<UpgradeData>) {
+        let upgrade_data = upgrade_data.unwrap_or_default();
+        let settings = UpgradeSettings {
+            installer: ic::caller(),
+            owner: upgrade_data.owner,
+            ecdsa_env: upgrade_data.ecdsa_env,
+            timestamp: ic::time(),
+        };
+
+        self.state
+            .upgrade(settings)
+            .expect("failed to upgrade canister state");
+        if self.state.queue.count() > 0 {
+            schedule_jobs();
+        }
+    }
+
     /// Returns principal of canister owner.
     #[query]
     pub fn get_owner(&self) -> Principal {
         self.state.config.get_owner()
     }
 
-    /// Sets a new principal for canister owner.
+    /// Returns the roles of the caller.
+    #[query]
+    pub fn get_roles(&self) -> Vec<Role> {
+        self.state.roles.get(ic::caller())
+    }
+
+    /// Proposes a sensitive action, approved by the caller. The action is executed
+    /// once the approval threshold is reached, right away while it is 1.
     ///
-    /// This method should be called only by current owner,
-    /// else `Error::NotAuthorised` will be returned.
+    /// This method should be called only by an admin,
+    /// else `Error::NotAuthorized` will be returned.
     #[update]
-    pub fn set_owner(&mut self, owner: Principal) -> Result<()> {
-        self.check_owner(ic::caller())?;
-        self.state.config.set_owner(owner)?;
-        Ok(())
+    pub fn propose(&mut self, action: Action) -> Result<Proposal> {
+        let caller = self.check_role(Role::Admin)?;
+        action.validate()?;
+        let now = ic::time();
+        let policy = self.state.config.get_approval_policy();
+        let proposal = self.state.proposals.create(action, caller, now, policy);
+        Ok(self.state.execute_approved(proposal, caller, now))
+    }
+
+    /// Approves an open proposal, executing its action if the approval threshold
+    /// is reached.
+    ///
+    /// This method should be called only by an admin,
+    /// else `Error::NotAuthorized` will be returned.
+    #[update]
+    pub fn approve_proposal(&mut self, id: u64) -> Result<Proposal> {
+        let caller = self.check_role(Role::Admin)?;
+        let now = ic::time();
+        let proposal = self.state.proposals.approve(id, caller, now)?;
+        Ok(self.state.execute_approved(proposal, caller, now))
+    }
+
+    /// This method should be called only by an auditor,
+    /// else `Error::NotAuthorized` will be returned.
+    #[query]
+    pub fn get_proposal(&self, id: u64) -> Result<Proposal> {
+        self.check_role(Role::Auditor)?;
+        self.state
+            .proposals
+            .get(id)
+            .map(|proposal| proposal.at(ic::time()))
+            .ok_or(Error::ProposalNotFound(id))
+    }
+
+    /// Returns at most `limit` proposals, from the proposal `start`.
+    ///
+    /// This method should be called only by an auditor,
+    /// else `Error::NotAuthorized` will be returned.
+    #[query]
+    pub fn list_proposals(&self, start: u64, limit: u64) -> Result<Vec<Proposal>> {
+        self.check_role(Role::Auditor)?;
+        let limit = limit.min(MAX_AUDIT_LOG_PAGE) as usize;
+        Ok(self.state.proposals.list(start, limit, ic::time()))
+    }
+
+    #[query]
+    pub fn get_approval_policy(&self) -> ApprovalPolicy {
+        self.state.config.get_approval_policy()
     }
 
+    /// Returns the principals holding roles.
+    ///
+    /// This method should be called only by an auditor,
+    /// else `Error::NotAuthorized` will be returned.
+    #[query]
+    pub fn list_roles(&self) -> Result<Vec<(Principal, Vec<Role>)>> {
+        self.check_role(Role::Auditor)?;
+        Ok(self.state.roles.list())
+    }
+
+    /// Returns at most `limit` grants and revocations of roles, from the change `start`.
+    ///
+    /// This method should be called only by an auditor,
+    /// else `Error::NotAuthorized` will be returned.
+    #[query]
+    pub fn get_role_audit_log(&self, start: u64, limit: u64) -> Result<Vec<RoleChange>> {
+        self.check_role(Role::Auditor)?;
+        let limit = limit.min(MAX_AUDIT_LOG_PAGE) as usize;
+        Ok(self.state.roles.audit_log(start, limit))
+    }
+
+    /// Creates the caller's threshold ECDSA key, used by the secp256k1 based chains.
+    ///
+    /// Returns the hex encoded compressed public key.
     #[update]
     pub async fn init_user(&mut self) -> Result<String> {
         let caller = ic::caller();
-        let signer = match self.state.signers.get(ic::caller()) {
+        self.state.access.check(caller, ic::time())?;
+        let signer = match self.state.signers.get(caller) {
             Some(s) => s,
             None => {
+                let _reservation = self.state.reserve_user(caller)?;
                 let ecdsa_env = self.state.config.get_ecdsa_env();
-                let s = Signer::new(ecdsa_env, caller.as_slice().to_vec()).await?;
+                let s = self
+                    .paid(
+                        caller,
+                        Operation::CreateKey,
+                        Signer::new(ecdsa_env, caller.as_slice().to_vec()),
+                    )
+                    .await?;
+                self.state.add_user(caller);
                 self.state.signers.set(caller, s.clone());
                 s
             }
@@ -65,30 +221,716 @@ impl TornadoCanister {
         Ok(hex::encode(signer.public_key()))
     }
 
+    /// Creates the caller's threshold Ed25519 key, used by the Ed25519 based chains.
+    ///
+    /// Returns the hex encoded 32 bytes public key.
+    #[update]
+    pub async fn init_ed25519_user(&mut self) -> Result<String> {
+        let caller = ic::caller();
+        self.state.access.check(caller, ic::time())?;
+        let signer = match self.state.ed25519_signers.get(caller) {
+            Some(s) => s,
+            None => {
+                let _reservation = self.state.reserve_user(caller)?;
+                let ecdsa_env = self.state.config.get_ecdsa_env();
+                let s = self
+                    .paid(
+                        caller,
+                        Operation::CreateKey,
+                        SchnorrSigner::new(
+                            ecdsa_env,
+                            SchnorrAlgorithm::Ed25519,
+                            caller.as_slice().to_vec(),
+                        ),
+                    )
+                    .await?;
+                self.state.add_user(caller);
+                self.state.ed25519_signers.set(caller, s.clone());
+                s
+            }
+        };
+        Ok(hex::encode(signer.public_key()))
+    }
+
+    /// Creates the caller's BIP-340 key, for chains signing with secp256k1 Schnorr signatures.
+    #[update]
+    pub async fn init_bip340_user(&mut self) -> Result<String> {
+        let caller = ic::caller();
+        self.state.access.check(caller, ic::time())?;
+        let signer = match self.state.bip340_signers.get(caller) {
+            Some(s) => s,
+            None => {
+                let _reservation = self.state.reserve_user(caller)?;
+                let ecdsa_env = self.state.config.get_ecdsa_env();
+                let s = self
+                    .paid(
+                        caller,
+                        Operation::CreateKey,
+                        SchnorrSigner::new(
+                            ecdsa_env,
+                            SchnorrAlgorithm::Bip340Secp256k1,
+                            caller.as_slice().to_vec(),
+                        ),
+                    )
+                    .await?;
+                self.state.add_user(caller);
+                self.state.bip340_signers.set(caller, s.clone());
+                s
+            }
+        };
+        Ok(hex::encode(signer.public_key()))
+    }
+
     #[query]
     pub fn get_address(&self, coin_type: CoinType) -> Result<String> {
-        let signer = self
-            .state
-            .signers
-            .get(ic::caller())
-            .ok_or(Error::UserNotInitialized)?;
-
+        let caller = ic::caller();
         match coin_type {
             CoinType::Evm(chain_id) => {
-                let wallet = EthWallet::new(signer, chain_id)?;
+                let wallet = EthWallet::new(self.get_signer(caller)?, chain_id)?;
                 Ok(format!("{:?}", wallet.address()))
             }
-            CoinType::Btc => Err(Error::Internal("not suppported".to_string())),
+            CoinType::Btc => {
+                Ok(BtcWallet::new(self.get_signer(caller)?).address(BtcAddressType::P2wpkh))
+            }
+            CoinType::Bch => Ok(BchWallet::new(self.get_signer(caller)?).address()),
+            CoinType::Cosmos { hrp, chain_id } => {
+                let wallet = CosmosWallet::new(self.get_signer(caller)?, &hrp, chain_id)?;
+                Ok(wallet.address().to_string())
+            }
+            CoinType::Ethermint { hrp, chain_id } => {
+                let wallet =
+                    CosmosWallet::new_ethermint(self.get_signer(caller)?, &hrp, chain_id, None)?;
+                Ok(wallet.address().to_string())
+            }
+            CoinType::Tron => Ok(TronWallet::new(self.get_signer(caller)?)?.address()),
+            CoinType::Xrp => Ok(XrpWallet::new(self.get_signer(caller)?).address()),
+            CoinType::Solana => Ok(SolanaWallet::new(self.get_ed25519_signer(caller)?)?.address()),
+            CoinType::Polkadot { prefix, key_type } => {
+                self.get_polkadot_wallet(caller, key_type)?.address(prefix)
+            }
+            CoinType::Sui => Ok(SuiWallet::new(self.get_signer(caller)?).address()),
+            CoinType::Stacks(network) => {
+                Ok(StacksWallet::new(self.get_signer(caller)?).address(network))
+            }
+            CoinType::Filecoin(network) => {
+                Ok(FilecoinWallet::new(self.get_signer(caller)?)?.address(network))
+            }
+            CoinType::Ton => Ok(TonWallet::new(self.get_ed25519_signer(caller)?)?.address(false)),
+            CoinType::Nostr => NostrWallet::new(self.get_bip340_signer(caller)?)?.npub(),
+            CoinType::InternetComputer => Ok(IcWallet::new(self.get_signer(caller)?)?
+                .principal()
+                .to_text()),
         }
     }
 
+    /// Signs a P2PKH transaction spending outputs of the caller's BCH address.
+    ///
+    /// Returns the hex encoded signed transaction, ready to be broadcast.
     #[update]
-    pub async fn test_transfer_eth(&self) -> Result<String> {
-        let signer = self
-            .state
-            .signers
-            .get(ic::caller())
-            .ok_or(Error::UserNotInitialized)?;
+    pub async fn sign_bch_transaction(
+        &mut self,
+        tx: BchTransaction,
+        idempotency_key: Option<String>,
+    ) -> Result<String> {
+        let caller = ic::caller();
+        let fingerprint = fingerprint("sign_bch_transaction", &tx);
+        idempotent(
+            self.state.idempotency,
+            caller,
+            idempotency_key,
+            fingerprint,
+            async {
+                self.check_signing("sign_bch_transaction", "Bch", None)?;
+                self.sign_bch_transaction_for(caller, tx).await
+            },
+        )
+        .await
+    }
+
+    /// Signs a message proving control of the caller's BTC address of the given type.
+    ///
+    /// P2PKH addresses get a legacy `signmessage` compact signature,
+    /// P2WPKH addresses a BIP-322 simple signature.
+    #[update]
+    pub async fn sign_btc_message(
+        &mut self,
+        address_type: BtcAddressType,
+        message: String,
+        idempotency_key: Option<String>,
+    ) -> Result<BtcSignedMessage> {
+        let caller = ic::caller();
+        let fingerprint = fingerprint("sign_btc_message", &(&address_type, &message));
+        idempotent(
+            self.state.idempotency,
+            caller,
+            idempotency_key,
+            fingerprint,
+            async {
+                self.check_signing("sign_btc_message", "Btc", None)?;
+                self.sign_btc_message_for(caller, address_type, message)
+                    .await
+            },
+        )
+        .await
+    }
+
+    /// Checks a legacy or BIP-322 simple message signature for a P2PKH or P2WPKH address.
+    #[query]
+    pub fn verify_btc_message(
+        &self,
+        address: String,
+        message: String,
+        signature: String,
+    ) -> Result<bool> {
+        btc::verify_message(&address, &message, &signature)
+    }
+
+    /// Signs a Cosmos SDK transaction in `SIGN_MODE_DIRECT` with the caller's key.
+    ///
+    /// Returns the base64 encoded `TxRaw`, as expected by the `/cosmos/tx/v1beta1/txs` endpoint.
+    #[update]
+    pub async fn sign_cosmos_transaction(
+        &mut self,
+        hrp: String,
+        chain_id: String,
+        tx: CosmosTransaction,
+        idempotency_key: Option<String>,
+    ) -> Result<String> {
+        let caller = ic::caller();
+        let fingerprint = fingerprint("sign_cosmos_transaction", &(&hrp, &chain_id, &tx));
+        idempotent(
+            self.state.idempotency,
+            caller,
+            idempotency_key,
+            fingerprint,
+            async {
+                self.check_signing("sign_cosmos_transaction", "Cosmos", Some(chain_id.as_str()))?;
+                self.sign_cosmos_transaction_for(caller, hrp, chain_id, tx)
+                    .await
+            },
+        )
+        .await
+    }
+
+    /// Signs a Cosmos transaction of an Ethermint based chain with the caller's
+    /// `ethsecp256k1` key, the same key that controls the caller's EVM address.
+    ///
+    /// `pub_key_type` overrides the Ethermint public key type url for chains using their
+    /// own, e.g. `/injective.crypto.v1beta1.ethsecp256k1.PubKey`.
+    /// Returns the base64 encoded `TxRaw`.
+    #[update]
+    pub async fn sign_ethermint_transaction(
+        &mut self,
+        hrp: String,
+        chain_id: String,
+        pub_key_type: Option<String>,
+        tx: CosmosTransaction,
+        idempotency_key: Option<String>,
+    ) -> Result<String> {
+        let caller = ic::caller();
+        let fingerprint = fingerprint(
+            "sign_ethermint_transaction",
+            &(&hrp, &chain_id, &pub_key_type, &tx),
+        );
+        idempotent(
+            self.state.idempotency,
+            caller,
+            idempotency_key,
+            fingerprint,
+            async {
+                self.check_signing(
+                    "sign_ethermint_transaction",
+                    "Ethermint",
+                    Some(chain_id.as_str()),
+                )?;
+                self.sign_ethermint_transaction_for(caller, hrp, chain_id, pub_key_type, tx)
+                    .await
+            },
+        )
+        .await
+    }
+
+    /// Signs a TRX transfer or a TRC-20 `transfer` call from the caller's Tron address.
+    #[update]
+    pub async fn sign_tron_transaction(
+        &mut self,
+        tx: TronTransaction,
+        idempotency_key: Option<String>,
+    ) -> Result<TronSignedTransaction> {
+        let caller = ic::caller();
+        let fingerprint = fingerprint("sign_tron_transaction", &tx);
+        idempotent(
+            self.state.idempotency,
+            caller,
+            idempotency_key,
+            fingerprint,
+            async {
+                self.check_signing("sign_tron_transaction", "Tron", None)?;
+                self.sign_tron_transaction_for(caller, tx).await
+            },
+        )
+        .await
+    }
+
+    /// Signs an XRP Ledger `Payment` or `TrustSet` transaction from the caller's account.
+    #[update]
+    pub async fn sign_xrp_transaction(
+        &mut self,
+        tx: XrpTransaction,
+        idempotency_key: Option<String>,
+    ) -> Result<XrpSignedTransaction> {
+        let caller = ic::caller();
+        let fingerprint = fingerprint("sign_xrp_transaction", &tx);
+        idempotent(
+            self.state.idempotency,
+            caller,
+            idempotency_key,
+            fingerprint,
+            async {
+                self.check_signing("sign_xrp_transaction", "Xrp", None)?;
+                self.sign_xrp_transaction_for(caller, tx).await
+            },
+        )
+        .await
+    }
+
+    /// Signs a serialized legacy or v0 Solana message in which the caller is a signer.
+    ///
+    /// The signed transaction is returned too when the caller is the only required signer.
+    #[update]
+    pub async fn sign_solana_message(
+        &mut self,
+        message: Vec<u8>,
+        idempotency_key: Option<String>,
+    ) -> Result<SolanaSignedMessage> {
+        let caller = ic::caller();
+        let fingerprint = fingerprint("sign_solana_message", &message);
+        idempotent(
+            self.state.idempotency,
+            caller,
+            idempotency_key,
+            fingerprint,
+            async {
+                self.check_signing("sign_solana_message", "Solana", None)?;
+                self.sign_solana_message_for(caller, message).await
+            },
+        )
+        .await
+    }
+
+    /// Builds and signs a SOL or SPL token transfer paid by the caller's Solana account.
+    #[update]
+    pub async fn sign_solana_transaction(
+        &mut self,
+        tx: SolanaTransaction,
+        idempotency_key: Option<String>,
+    ) -> Result<SolanaSignedMessage> {
+        let caller = ic::caller();
+        let fingerprint = fingerprint("sign_solana_transaction", &tx);
+        idempotent(
+            self.state.idempotency,
+            caller,
+            idempotency_key,
+            fingerprint,
+            async {
+                self.check_signing("sign_solana_transaction", "Solana", None)?;
+                self.sign_solana_transaction_for(caller, tx).await
+            },
+        )
+        .await
+    }
+
+    /// Signs a Substrate extrinsic with the caller's Ed25519 or secp256k1 key.
+    #[update]
+    pub async fn sign_polkadot_transaction(
+        &mut self,
+        key_type: PolkadotKeyType,
+        tx: PolkadotTransaction,
+        idempotency_key: Option<String>,
+    ) -> Result<PolkadotSignedTransaction> {
+        let caller = ic::caller();
+        let fingerprint = fingerprint("sign_polkadot_transaction", &(&key_type, &tx));
+        idempotent(
+            self.state.idempotency,
+            caller,
+            idempotency_key,
+            fingerprint,
+            async {
+                self.check_signing(
+                    "sign_polkadot_transaction",
+                    "Polkadot",
+                    Some(tx.genesis_hash.as_str()),
+                )?;
+                self.sign_polkadot_transaction_for(caller, key_type, tx)
+                    .await
+            },
+        )
+        .await
+    }
+
+    /// Signs base64 encoded BCS `TransactionData` bytes from the caller's Sui address.
+    ///
+    /// Returns the base64 encoded serialized signature, as expected by `sui_executeTransactionBlock`.
+    #[update]
+    pub async fn sign_sui_transaction(
+        &mut self,
+        tx_bytes: String,
+        idempotency_key: Option<String>,
+    ) -> Result<String> {
+        let caller = ic::caller();
+        let fingerprint = fingerprint("sign_sui_transaction", &tx_bytes);
+        idempotent(
+            self.state.idempotency,
+            caller,
+            idempotency_key,
+            fingerprint,
+            async {
+                self.check_signing("sign_sui_transaction", "Sui", None)?;
+                self.sign_sui_transaction_for(caller, tx_bytes).await
+            },
+        )
+        .await
+    }
+
+    /// Signs a STX transfer or a contract call from the caller's Stacks address.
+    #[update]
+    pub async fn sign_stacks_transaction(
+        &mut self,
+        tx: StacksTransaction,
+        idempotency_key: Option<String>,
+    ) -> Result<StacksSignedTransaction> {
+        let caller = ic::caller();
+        let fingerprint = fingerprint("sign_stacks_transaction", &tx);
+        idempotent(
+            self.state.idempotency,
+            caller,
+            idempotency_key,
+            fingerprint,
+            async {
+                self.check_signing(
+                    "sign_stacks_transaction",
+                    "Stacks",
+                    Some(tx.network.as_str()),
+                )?;
+                self.sign_stacks_transaction_for(caller, tx).await
+            },
+        )
+        .await
+    }
+
+    /// Returns the f410 address of the caller's EVM address, to receive FIL from f1 addresses
+    /// and use it on FEVM.
+    #[query]
+    pub fn get_filecoin_delegated_address(&self, network: FilecoinNetwork) -> Result<String> {
+        FilecoinWallet::new(self.get_signer(ic::caller())?)?.delegated_address(network)
+    }
+
+    /// Signs a Filecoin message from the caller's f1 address.
+    #[update]
+    pub async fn sign_filecoin_message(
+        &mut self,
+        msg: FilecoinMessage,
+        idempotency_key: Option<String>,
+    ) -> Result<FilecoinSignedMessage> {
+        let caller = ic::caller();
+        let fingerprint = fingerprint("sign_filecoin_message", &msg);
+        idempotent(
+            self.state.idempotency,
+            caller,
+            idempotency_key,
+            fingerprint,
+            async {
+                self.check_signing("sign_filecoin_message", "Filecoin", None)?;
+                self.sign_filecoin_message_for(caller, msg).await
+            },
+        )
+        .await
+    }
+
+    /// Signs an external message of the caller's Wallet V4R2 contract, deploying it when
+    /// `seqno` is 0.
+    #[update]
+    pub async fn sign_ton_transaction(
+        &mut self,
+        tx: TonTransaction,
+        idempotency_key: Option<String>,
+    ) -> Result<TonSignedMessage> {
+        let caller = ic::caller();
+        let fingerprint = fingerprint("sign_ton_transaction", &tx);
+        idempotent(
+            self.state.idempotency,
+            caller,
+            idempotency_key,
+            fingerprint,
+            async {
+                self.check_signing("sign_ton_transaction", "Ton", None)?;
+                self.sign_ton_transaction_for(caller, tx).await
+            },
+        )
+        .await
+    }
+
+    /// Signs a NIP-01 event of the caller and returns the signed event as JSON.
+    #[update]
+    pub async fn sign_nostr_event(
+        &mut self,
+        event: NostrEvent,
+        idempotency_key: Option<String>,
+    ) -> Result<String> {
+        let caller = ic::caller();
+        let fingerprint = fingerprint("sign_nostr_event", &event);
+        idempotent(
+            self.state.idempotency,
+            caller,
+            idempotency_key,
+            fingerprint,
+            async {
+                self.check_signing("sign_nostr_event", "Nostr", None)?;
+                self.sign_nostr_event_for(caller, event).await
+            },
+        )
+        .await
+    }
+
+    /// Signs an ingress message sent by the caller's self-authenticating principal,
+    /// so agents outside the IC can act on its behalf.
+    #[update]
+    pub async fn sign_ic_request(
+        &mut self,
+        req: IcRequest,
+        idempotency_key: Option<String>,
+    ) -> Result<IcSignedRequest> {
+        let caller = ic::caller();
+        let fingerprint = fingerprint("sign_ic_request", &req);
+        idempotent(
+            self.state.idempotency,
+            caller,
+            idempotency_key,
+            fingerprint,
+            async {
+                self.check_signing("sign_ic_request", "InternetComputer", None)?;
+                self.sign_ic_request_for(caller, req).await
+            },
+        )
+        .await
+    }
+
+    /// Queues `request` to be signed with the caller's keys and returns the id of its
+    /// job, whose result is read with `get_signing_result`. Timers run the jobs in turns
+    /// of users; the access policy and the pauses are checked on submission, the fee
+    /// is charged when the job runs.
+    ///
+    /// Once the job completes, `callback` is notified with its id and its status; it must
+    /// be a method of the calling canister.
+    /// Like the signing endpoints, a retry with the same `idempotency_key` returns the
+    /// result of the first call instead of submitting again.
+    #[update]
+    pub async fn submit_signing_request(
+        &mut self,
+        request: SigningRequest,
+        callback: Option<Callback>,
+        idempotency_key: Option<String>,
+    ) -> Result<u64> {
+        let caller = ic::caller();
+        let fingerprint = fingerprint("submit_signing_request", &(&request, &callback));
+        let (endpoint, coin_type, chain_id) = request.scope();
+        let canister = &*self;
+        let mut queue = self.state.queue;
+        let submit = async move {
+            canister.check_signing(endpoint, coin_type, chain_id.as_deref())?;
+            queue.submit(caller, request, callback, ic::time())
+        };
+        let id = idempotent(
+            self.state.idempotency,
+            caller,
+            idempotency_key,
+            fingerprint,
+            submit,
+        )
+        .await?;
+        schedule_jobs();
+        Ok(id)
+    }
+
+    /// Returns a signing job of the caller, with its result once completed. Completed
+    /// jobs are removed after `COMPLETED_JOB_TTL`.
+    #[query]
+    pub fn get_signing_result(&self, id: u64) -> Result<SigningJob> {
+        match self.state.queue.get(id) {
+            Some(job) if job.caller == ic::caller() => Ok(job),
+            _ => Err(Error::JobNotFound(id)),
+        }
+    }
+
+    /// Nanoseconds during which the signing endpoints return the stored result of a call
+    /// retried with the same idempotency key.
+    #[query]
+    pub fn get_idempotency_ttl(&self) -> u64 {
+        self.state.idempotency.get_ttl()
+    }
+
+    /// Stops the signing requests of `scope` until resumed or, if given, for `duration`
+    /// nanoseconds. Pausing a paused scope replaces its reason and expiry.
+    ///
+    /// This method should be called only by an operator,
+    /// else `Error::NotAuthorized` will be returned.
+    #[update]
+    pub fn pause(
+        &mut self,
+        scope: PauseScope,
+        reason: String,
+        duration: Option<u64>,
+    ) -> Result<()> {
+        let caller = self.check_role(Role::Operator)?;
+        let now = ic::time();
+        let pause = Pause {
+            reason,
+            paused_by: caller,
+            paused_at: now,
+            expires_at: duration.map(|duration| now.saturating_add(duration)),
+        };
+        self.state.pauses.pause(scope, pause)
+    }
+
+    /// Resumes the signing requests of `scope`, returns false if it wasn't paused.
+    ///
+    /// This method should be called only by an operator,
+    /// else `Error::NotAuthorized` will be returned.
+    #[update]
+    pub fn resume(&mut self, scope: PauseScope) -> Result<bool> {
+        self.check_role(Role::Operator)?;
+        let resumed = self.state.pauses.resume(scope)?.is_some();
+        if resumed && self.state.queue.count() > 0 {
+            schedule_jobs();
+        }
+        Ok(resumed)
+    }
+
+    /// Returns the allowlist, changed by `Action::AllowPrincipals` and
+    /// `Action::DisallowPrincipals` proposals.
+    ///
+    /// This method should be called only by an auditor,
+    /// else `Error::NotAuthorized` will be returned.
+    #[query]
+    pub fn get_allowlist(&self) -> Result<Vec<Principal>> {
+        self.check_role(Role::Auditor)?;
+        Ok(self.state.access.allowlist())
+    }
+
+    /// Returns the limits of the users, changed by `Action::SetAccessPolicy` proposals.
+    #[query]
+    pub fn get_access_policy(&self) -> AccessPolicy {
+        self.state.access.get_policy()
+    }
+
+    /// Returns the active pauses, for clients to show the maintenance status.
+    #[query]
+    pub fn get_pauses(&self) -> Vec<(PauseScope, Pause)> {
+        self.state.pauses.list(ic::time())
+    }
+
+    /// Returns the fees of the operations, in cycles and in the tokens of the fee ledger.
+    #[query]
+    pub fn get_fee_schedule(&self) -> FeeSchedule {
+        self.state.fees.get_schedule()
+    }
+
+    /// Sets the fees charged from now on. The fee ledger must be a supported ledger.
+    ///
+    /// This method should be called only by a fee manager,
+    /// else `Error::NotAuthorized` will be returned.
+    #[update]
+    pub fn set_fee_schedule(&mut self, schedule: FeeSchedule) -> Result<()> {
+        self.check_role(Role::FeeManager)?;
+        if let Some(icrc) = schedule.icrc {
+            self.get_ledger(icrc.ledger)?;
+        }
+        self.state.fees.set_schedule(schedule)
+    }
+
+    /// Adds the cycles attached to the call to the caller's prepaid balance, returns
+    /// the new balance.
+    #[update]
+    pub fn top_up_cycles(&mut self) -> Result<u128> {
+        let caller = ic::caller();
+        if caller == Principal::anonymous() {
+            return Err(Error::AnonymousNotAllowed);
+        }
+        let amount = msg_cycles_accept128(msg_cycles_available128());
+        Ok(self.state.fees.top_up(caller, amount))
+    }
+
+    /// Returns the caller's prepaid balance and at most `limit` of its charges, from
+    /// the charge `start`.
+    #[query]
+    pub fn get_usage_statement(&self, start: u64, limit: u64) -> UsageStatement {
+        let limit = limit.min(MAX_AUDIT_LOG_PAGE) as usize;
+        self.state.fees.statement(ic::caller(), start, limit)
+    }
+
+    /// Returns the usage statement of `user`.
+    ///
+    /// This method should be called only by a fee manager,
+    /// else `Error::NotAuthorized` will be returned.
+    #[query]
+    pub fn get_user_usage_statement(
+        &self,
+        user: Principal,
+        start: u64,
+        limit: u64,
+    ) -> Result<UsageStatement> {
+        self.check_role(Role::FeeManager)?;
+        let limit = limit.min(MAX_AUDIT_LOG_PAGE) as usize;
+        Ok(self.state.fees.statement(user, start, limit))
+    }
+
+    /// Returns the ledgers supported by the canister, added and removed by proposals.
+    /// Removed ledgers keep the deposits, which can be withdrawn once added back.
+    #[query]
+    pub fn get_ledgers(&self) -> Vec<(Principal, LedgerInfo)> {
+        self.state.ledgers.list()
+    }
+
+    /// Returns the account of the canister to which the caller deposits ICRC-1 tokens.
+    #[query]
+    pub fn get_deposit_account(&self) -> Account {
+        ledger::deposit_account(ic::id(), ic::caller())
+    }
+
+    /// Returns the caller's balance on a supported ledger.
+    #[update]
+    pub async fn get_icrc_balance(&self, ledger: Principal) -> Result<Nat> {
+        let icrc = self.get_ledger(ledger)?;
+        ledger::balance(&icrc, ic::id(), ic::caller()).await
+    }
+
+    /// Transfers `amount` of the caller's tokens to `to`, the ledger fee being charged
+    /// on top of it. Returns the index of the transfer block.
+    #[update]
+    pub async fn icrc_withdraw(&self, ledger: Principal, to: Account, amount: Nat) -> Result<Nat> {
+        let icrc = self.get_ledger(ledger)?;
+        ledger::withdraw(&icrc, ic::caller(), to, amount).await
+    }
+
+    #[update]
+    pub async fn test_transfer_eth(&mut self, idempotency_key: Option<String>) -> Result<String> {
+        let caller = ic::caller();
+        let fingerprint = fingerprint("test_transfer_eth", &());
+        idempotent(
+            self.state.idempotency,
+            caller,
+            idempotency_key,
+            fingerprint,
+            async {
+                self.check_signing("test_transfer_eth", "Evm", Some("11155111"))?;
+                self.test_transfer_eth_for(caller).await
+            },
+        )
+        .await
+    }
+
+    async fn test_transfer_eth_for(&mut self, caller: Principal) -> Result<String> {
+        let signer = self.get_signer(caller)?;
 
         let wallet = EthWallet::new(signer, 11155111)?;
 
@@ -113,20 +955,469 @@ impl TornadoCanister {
             chain_id: Some(U64::from(11155111)),
         }
         .into();
-        let signature = wallet.sign_transaction(&tx).await?;
+        let signature = self
+            .paid(
+                caller,
+                Operation::EcdsaSignature,
+                wallet.sign_transaction(&tx),
+            )
+            .await?;
         let bytes = tx.rlp_signed(&signature);
 
         Ok(format!("{}", bytes))
     }
 
-    fn check_owner(&self, principal: Principal) -> Result<()> {
-        let owner = self.state.config.get_owner();
-        if owner == principal || owner == Principal::anonymous() {
-            return Ok(());
+    /// Instructions spent storing and loading `count` signers in the candid and binary layouts.
+    #[cfg(feature = "bench")]
+    #[update]
+    pub fn bench_signers(&self, count: u8) -> SignersBench {
+        schema::bench_signers(count)
+    }
+
+    async fn sign_bch_transaction_for(
+        &mut self,
+        user: Principal,
+        tx: BchTransaction,
+    ) -> Result<String> {
+        let wallet = BchWallet::new(self.get_signer(user)?);
+        // each input is signed on its own
+        let signatures = tx.inputs.len() as u64;
+        let bytes = self
+            .paid_times(
+                user,
+                Operation::EcdsaSignature,
+                signatures,
+                wallet.sign_transaction(&tx),
+            )
+            .await?;
+        Ok(hex::encode(bytes))
+    }
+
+    async fn sign_btc_message_for(
+        &mut self,
+        user: Principal,
+        address_type: BtcAddressType,
+        message: String,
+    ) -> Result<BtcSignedMessage> {
+        let wallet = BtcWallet::new(self.get_signer(user)?);
+        self.paid(
+            user,
+            Operation::EcdsaSignature,
+            wallet.sign_message(address_type, &message),
+        )
+        .await
+    }
+
+    async fn sign_cosmos_transaction_for(
+        &mut self,
+        user: Principal,
+        hrp: String,
+        chain_id: String,
+        tx: CosmosTransaction,
+    ) -> Result<String> {
+        let wallet = CosmosWallet::new(self.get_signer(user)?, &hrp, chain_id)?;
+        let bytes = self
+            .paid(
+                user,
+                Operation::EcdsaSignature,
+                wallet.sign_transaction(&tx),
+            )
+            .await?;
+        Ok(BASE64.encode(bytes))
+    }
+
+    async fn sign_ethermint_transaction_for(
+        &mut self,
+        user: Principal,
+        hrp: String,
+        chain_id: String,
+        pub_key_type: Option<String>,
+        tx: CosmosTransaction,
+    ) -> Result<String> {
+        let wallet =
+            CosmosWallet::new_ethermint(self.get_signer(user)?, &hrp, chain_id, pub_key_type)?;
+        let bytes = self
+            .paid(
+                user,
+                Operation::EcdsaSignature,
+                wallet.sign_transaction(&tx),
+            )
+            .await?;
+        Ok(BASE64.encode(bytes))
+    }
+
+    async fn sign_tron_transaction_for(
+        &mut self,
+        user: Principal,
+        tx: TronTransaction,
+    ) -> Result<TronSignedTransaction> {
+        let wallet = TronWallet::new(self.get_signer(user)?)?;
+        self.paid(
+            user,
+            Operation::EcdsaSignature,
+            wallet.sign_transaction(&tx),
+        )
+        .await
+    }
+
+    async fn sign_xrp_transaction_for(
+        &mut self,
+        user: Principal,
+        tx: XrpTransaction,
+    ) -> Result<XrpSignedTransaction> {
+        let wallet = XrpWallet::new(self.get_signer(user)?);
+        self.paid(
+            user,
+            Operation::EcdsaSignature,
+            wallet.sign_transaction(&tx),
+        )
+        .await
+    }
+
+    async fn sign_solana_message_for(
+        &mut self,
+        user: Principal,
+        message: Vec<u8>,
+    ) -> Result<SolanaSignedMessage> {
+        let wallet = SolanaWallet::new(self.get_ed25519_signer(user)?)?;
+        self.paid(
+            user,
+            Operation::SchnorrSignature,
+            wallet.sign_message(&message),
+        )
+        .await
+    }
+
+    async fn sign_solana_transaction_for(
+        &mut self,
+        user: Principal,
+        tx: SolanaTransaction,
+    ) -> Result<SolanaSignedMessage> {
+        let wallet = SolanaWallet::new(self.get_ed25519_signer(user)?)?;
+        let message = wallet.build_message(&tx)?;
+        self.paid(
+            user,
+            Operation::SchnorrSignature,
+            wallet.sign_message(&message),
+        )
+        .await
+    }
+
+    async fn sign_polkadot_transaction_for(
+        &mut self,
+        user: Principal,
+        key_type: PolkadotKeyType,
+        tx: PolkadotTransaction,
+    ) -> Result<PolkadotSignedTransaction> {
+        let operation = match key_type {
+            PolkadotKeyType::Ed25519 => Operation::SchnorrSignature,
+            PolkadotKeyType::Ecdsa => Operation::EcdsaSignature,
+        };
+        let wallet = self.get_polkadot_wallet(user, key_type)?;
+        self.paid(user, operation, wallet.sign_transaction(&tx))
+            .await
+    }
+
+    async fn sign_sui_transaction_for(
+        &mut self,
+        user: Principal,
+        tx_bytes: String,
+    ) -> Result<String> {
+        let tx_bytes = BASE64
+            .decode(&tx_bytes)
+            .map_err(|_| Error::InvalidTransaction(tx_bytes))?;
+        let wallet = SuiWallet::new(self.get_signer(user)?);
+        let signature = self
+            .paid(
+                user,
+                Operation::EcdsaSignature,
+                wallet.sign_transaction(&tx_bytes),
+            )
+            .await?;
+        Ok(BASE64.encode(signature))
+    }
+
+    async fn sign_stacks_transaction_for(
+        &mut self,
+        user: Principal,
+        tx: StacksTransaction,
+    ) -> Result<StacksSignedTransaction> {
+        let wallet = StacksWallet::new(self.get_signer(user)?);
+        self.paid(
+            user,
+            Operation::EcdsaSignature,
+            wallet.sign_transaction(&tx),
+        )
+        .await
+    }
+
+    async fn sign_filecoin_message_for(
+        &mut self,
+        user: Principal,
+        msg: FilecoinMessage,
+    ) -> Result<FilecoinSignedMessage> {
+        let wallet = FilecoinWallet::new(self.get_signer(user)?)?;
+        self.paid(user, Operation::EcdsaSignature, wallet.sign_message(&msg))
+            .await
+    }
+
+    async fn sign_ton_transaction_for(
+        &mut self,
+        user: Principal,
+        tx: TonTransaction,
+    ) -> Result<TonSignedMessage> {
+        let wallet = TonWallet::new(self.get_ed25519_signer(user)?)?;
+        self.paid(
+            user,
+            Operation::SchnorrSignature,
+            wallet.sign_transaction(&tx),
+        )
+        .await
+    }
+
+    async fn sign_nostr_event_for(&mut self, user: Principal, event: NostrEvent) -> Result<String> {
+        let wallet = NostrWallet::new(self.get_bip340_signer(user)?)?;
+        self.paid(user, Operation::SchnorrSignature, wallet.sign_event(&event))
+            .await
+    }
+
+    async fn sign_ic_request_for(
+        &mut self,
+        user: Principal,
+        req: IcRequest,
+    ) -> Result<IcSignedRequest> {
+        let wallet = IcWallet::new(self.get_signer(user)?)?;
+        self.paid(user, Operation::EcdsaSignature, wallet.sign_request(&req))
+            .await
+    }
+
+    /// Signs `request` with the keys of `user`, as its signing endpoint does.
+    async fn sign(&mut self, user: Principal, request: SigningRequest) -> Result<SigningResponse> {
+        Ok(match request {
+            SigningRequest::BchTransaction(tx) => {
+                SigningResponse::BchTransaction(self.sign_bch_transaction_for(user, tx).await?)
+            }
+            SigningRequest::BtcMessage {
+                address_type,
+                message,
+            } => SigningResponse::BtcMessage(
+                self.sign_btc_message_for(user, address_type, message)
+                    .await?,
+            ),
+            SigningRequest::CosmosTransaction { hrp, chain_id, tx } => {
+                SigningResponse::CosmosTransaction(
+                    self.sign_cosmos_transaction_for(user, hrp, chain_id, tx)
+                        .await?,
+                )
+            }
+            SigningRequest::EthermintTransaction {
+                hrp,
+                chain_id,
+                pub_key_type,
+                tx,
+            } => SigningResponse::EthermintTransaction(
+                self.sign_ethermint_transaction_for(user, hrp, chain_id, pub_key_type, tx)
+                    .await?,
+            ),
+            SigningRequest::TronTransaction(tx) => {
+                SigningResponse::TronTransaction(self.sign_tron_transaction_for(user, tx).await?)
+            }
+            SigningRequest::XrpTransaction(tx) => {
+                SigningResponse::XrpTransaction(self.sign_xrp_transaction_for(user, tx).await?)
+            }
+            SigningRequest::SolanaMessage(message) => {
+                SigningResponse::SolanaMessage(self.sign_solana_message_for(user, message).await?)
+            }
+            SigningRequest::SolanaTransaction(tx) => SigningResponse::SolanaTransaction(
+                self.sign_solana_transaction_for(user, tx).await?,
+            ),
+            SigningRequest::PolkadotTransaction { key_type, tx } => {
+                SigningResponse::PolkadotTransaction(
+                    self.sign_polkadot_transaction_for(user, key_type, tx)
+                        .await?,
+                )
+            }
+            SigningRequest::SuiTransaction(tx_bytes) => SigningResponse::SuiTransaction(
+                self.sign_sui_transaction_for(user, tx_bytes).await?,
+            ),
+            SigningRequest::StacksTransaction(tx) => SigningResponse::StacksTransaction(
+                self.sign_stacks_transaction_for(user, tx).await?,
+            ),
+            SigningRequest::FilecoinMessage(msg) => {
+                SigningResponse::FilecoinMessage(self.sign_filecoin_message_for(user, msg).await?)
+            }
+            SigningRequest::TonTransaction(tx) => {
+                SigningResponse::TonTransaction(self.sign_ton_transaction_for(user, tx).await?)
+            }
+            SigningRequest::NostrEvent(event) => {
+                SigningResponse::NostrEvent(self.sign_nostr_event_for(user, event).await?)
+            }
+            SigningRequest::IcRequest(req) => {
+                SigningResponse::IcRequest(self.sign_ic_request_for(user, req).await?)
+            }
+        })
+    }
+
+    /// Signs the request of a started job and stores its result, then notifies the
+    /// callback of the job and starts the next jobs.
+    async fn run_job(&mut self, job: SigningJob) {
+        // pauses and access policy may have changed since the submission
+        let checked = check_job_pauses(self.state.pauses, &job, ic::time())
+            .and_then(|()| self.state.access.inspect(job.caller));
+        let result = match checked {
+            Ok(()) => self.sign(job.caller, job.request).await,
+            Err(Error::Paused(_)) => {
+                self.state.queue.defer(job.id);
+                schedule_jobs();
+                return;
+            }
+            Err(e) => Err(e),
+        };
+        if let Some(job) = self.state.queue.complete(job.id, result, ic::time()) {
+            if let Some(callback) = &job.callback {
+                // the result stays available to `get_signing_result` if the call fails
+                let _ = notify(
+                    callback.canister,
+                    &callback.method,
+                    (job.id, job.status.clone()),
+                );
+            }
+        }
+        schedule_jobs();
+    }
+
+    fn get_signer(&self, user: Principal) -> Result<Signer> {
+        self.state
+            .signers
+            .get(user)
+            .ok_or(Error::UserNotInitialized)
+    }
+
+    fn get_ed25519_signer(&self, user: Principal) -> Result<SchnorrSigner> {
+        self.state
+            .ed25519_signers
+            .get(user)
+            .ok_or(Error::UserNotInitialized)
+    }
+
+    fn get_bip340_signer(&self, user: Principal) -> Result<SchnorrSigner> {
+        self.state
+            .bip340_signers
+            .get(user)
+            .ok_or(Error::UserNotInitialized)
+    }
+
+    fn get_polkadot_wallet(
+        &self,
+        user: Principal,
+        key_type: PolkadotKeyType,
+    ) -> Result<PolkadotWallet> {
+        let signer = match key_type {
+            PolkadotKeyType::Ed25519 => PolkadotSigner::Ed25519(self.get_ed25519_signer(user)?),
+            PolkadotKeyType::Ecdsa => PolkadotSigner::Ecdsa(self.get_signer(user)?),
+        };
+        PolkadotWallet::new(signer)
+    }
+
+    fn get_ledger(&self, ledger: Principal) -> Result<IcrcLedger> {
+        self.state
+            .ledgers
+            .get(ledger)
+            .map(|_| IcrcLedger {
+                canister_id: ledger,
+            })
+            .ok_or(Error::LedgerNotSupported(ledger))
+    }
+
+    /// Charges `user` the fee of `operation` and runs `op`, giving back the fee if it
+    /// fails.
+    async fn paid<T>(
+        &mut self,
+        user: Principal,
+        operation: Operation,
+        op: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        self.paid_times(user, operation, 1, op).await
+    }
+
+    /// Same as `paid` for an `op` doing `operation` several `times`, charged at once.
+    async fn paid_times<T>(
+        &mut self,
+        user: Principal,
+        operation: Operation,
+        times: u64,
+        op: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let charge = self.pay(user, operation, times).await?;
+        let res = op.await;
+        if let (Err(_), Some(id)) = (&res, charge) {
+            self.refund_charge(user, id).await;
+        }
+        res
+    }
+
+    /// Takes `times` the fee of `operation` from the prepaid balance of `user`, else from
+    /// its allowance on the fee ledger. Returns the id of the charge, if any.
+    async fn pay(
+        &mut self,
+        user: Principal,
+        operation: Operation,
+        times: u64,
+    ) -> Result<Option<u64>> {
+        let (ledger, amount) = match self.state.fees.charge(user, operation, times, ic::time())? {
+            Due::Nothing => return Ok(None),
+            Due::Charged(id) => return Ok(Some(id)),
+            Due::Tokens { ledger, amount } => (ledger, amount),
+        };
+        let block =
+            ledger::collect_fee(&self.get_ledger(ledger)?, ic::id(), user, amount.into()).await?;
+        let payment = Payment::Icrc {
+            ledger,
+            amount: amount.into(),
+            block,
+        };
+        Ok(Some(self.state.fees.record(
+            user,
+            operation,
+            payment,
+            ic::time(),
+        )))
+    }
+
+    /// Gives back the fee of the charge `id` of `user`, tokens being transferred from the
+    /// main account of the canister. The charge stays unrefunded if the transfer fails.
+    async fn refund_charge(&mut self, user: Principal, id: u64) {
+        let Some(Payment::Icrc { ledger, amount, .. }) = self.state.fees.refund(user, id) else {
+            return;
+        };
+        let res = match self.get_ledger(ledger) {
+            Ok(ledger) => ledger::refund_fee(&ledger, user, amount).await,
+            Err(e) => Err(e),
+        };
+        if res.is_err() {
+            self.state.fees.cancel_refund(user, id);
+        }
+    }
+
+    /// Returns the caller if it holds `role`.
+    fn check_role(&self, role: Role) -> Result<Principal> {
+        let caller = ic::caller();
+        if self.state.roles.has(caller, role) {
+            return Ok(caller);
         }
         Err(Error::NotAuthorized)
     }
 
+    /// Fails if signing from `endpoint` with a key of `coin_type` on `chain_id` is paused,
+    /// or if the access policy rejects the caller.
+    fn check_signing(&self, endpoint: &str, coin_type: &str, chain_id: Option<&str>) -> Result<()> {
+        let now = ic::time();
+        self.state
+            .pauses
+            .check(endpoint, coin_type, chain_id, now)?;
+        self.state.access.check(ic::caller(), now)
+    }
+
     /// Returns candid IDL.
     /// This should be the last fn to see previous endpoints in macro.
     pub fn idl() -> Idl {
@@ -134,6 +1425,116 @@ impl TornadoCanister {
     }
 }
 
+/// Rejects the ingress messages of the user endpoints that the access policy would
+/// reject, before the canister pays for their execution. The rate limit spent here
+/// is restored with the rest of the state once the inspection ends.
+///
+/// The signing endpoints are only checked against the allowlist: their rate limits
+/// apply once a call runs, retries of completed calls replaying their response.
+#[cfg(feature = "export-api")]
+#[ic_exports::ic_cdk_macros::inspect_message]
+fn inspect_message() {
+    use ic_exports::ic_cdk::api::call::{accept_message, method_name};
+
+    let method = method_name();
+    let access = crate::state::access::Access::default();
+    let accepted = if method.starts_with("init_") {
+        access.check(ic::caller(), ic::time()).is_ok()
+    } else if method.starts_with("sign_")
+        || method == "submit_signing_request"
+        || method == "test_transfer_eth"
+    {
+        access.inspect(ic::caller()).is_ok()
+    } else {
+        true
+    };
+    if accepted {
+        accept_message();
+    }
+}
+
+/// Runs `op` unless a call of `caller` with the same idempotency `key` and `fingerprint`
+/// already completed, in which case its stored response is returned instead. Checks
+/// such as pauses and rate limits belong to `op`, to apply only when it runs.
+async fn idempotent<T: CandidType + DeserializeOwned>(
+    mut idempotency: Idempotency,
+    caller: Principal,
+    key: Option<String>,
+    fingerprint: [u8; 32],
+    op: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let Some(key) = key else {
+        return op.await;
+    };
+    let attempt = match idempotency.begin(caller, &key, fingerprint, ic::time())? {
+        Call::Replay(response) => return Ok(decode(&response)),
+        Call::Run(attempt) => attempt,
+    };
+    let mut call = IdempotentCall {
+        idempotency,
+        caller,
+        key,
+        attempt,
+        response: None,
+    };
+    let res = op.await;
+    call.response = res.as_ref().ok().map(encode);
+    res
+}
+
+/// Call of `idempotent` running, finished when dropped. A call which traps after an
+/// await is dropped by the cleanup of the trap without a response, which frees its key.
+struct IdempotentCall {
+    idempotency: Idempotency,
+    caller: Principal,
+    key: String,
+    attempt: u64,
+    response: Option<Vec<u8>>,
+}
+
+impl Drop for IdempotentCall {
+    fn drop(&mut self) {
+        self.idempotency
+            .finish(self.caller, &self.key, self.attempt, self.response.take());
+    }
+}
+
+/// Starts the queued signing jobs from a timer, as many as the queue allows.
+fn schedule_jobs() {
+    set_timer(Duration::ZERO, start_jobs);
+}
+
+/// Starts the queued signing jobs which are not paused. While jobs are left waiting,
+/// starts them again after `WAITING_JOBS_RETRY`, once pauses may have expired.
+fn start_jobs() {
+    let mut canister = TornadoCanister::from_principal(ic::id());
+    let pauses = canister.state.pauses;
+    let now = ic::time();
+    while let Some(job) = canister
+        .state
+        .queue
+        .start_next(|job| !matches!(check_job_pauses(pauses, job, now), Err(Error::Paused(_))))
+    {
+        spawn(async move { TornadoCanister::from_principal(ic::id()).run_job(job).await });
+    }
+    if canister.state.queue.has_waiting() && !RETRY_SCHEDULED.with(|retry| retry.replace(true)) {
+        set_timer(WAITING_JOBS_RETRY, || {
+            RETRY_SCHEDULED.with(|retry| retry.set(false));
+            start_jobs();
+        });
+    }
+}
+
+/// Fails if the pause switches stop the request of `job`.
+fn check_job_pauses(pauses: Pauses, job: &SigningJob, timestamp: u64) -> Result<()> {
+    let (endpoint, coin_type, chain_id) = job.request.scope();
+    pauses.check(endpoint, coin_type, chain_id.as_deref(), timestamp)
+}
+
+thread_local! {
+    static RETRY_SCHEDULED: Cell<bool> = Cell::new(false);
+}
+
 /// Minter canister initialization data.
 #[derive(Deserialize, CandidType)]
 pub struct InitData {
@@ -141,3 +1542,131 @@ pub struct InitData {
     pub owner: Principal,
     pub ecdsa_env: EcdsaKeyIds,
 }
+
+/// Minter canister upgrade data, `None` keeps the current value.
+#[derive(Default, Deserialize, CandidType)]
+pub struct UpgradeData {
+    pub owner: Option<Principal>,
+    /// Key of the users created after the upgrade.
+    pub ecdsa_env: Option<EcdsaKeyIds>,
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_canister::Canister;
+    use ic_exports::ic_kit::MockContext;
+
+    use super::*;
+
+    fn user(byte: u8) -> Principal {
+        Principal::from_slice(&[byte; 29])
+    }
+
+    #[test]
+    fn user_addresses_survive_upgrades() {
+        let owner = user(1);
+        let ctx = MockContext::new().with_caller(owner).inject();
+        let mut canister = TornadoCanister::init_instance();
+        canister.init(InitData {
+            owner,
+            ecdsa_env: EcdsaKeyIds::TestKeyLocalDevelopment,
+        });
+
+        // the key `init_user` gets from the management canister, which isn't mocked
+        let public_key =
+            hex::decode("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+                .unwrap();
+        canister.state.signers.set(
+            user(2),
+            Signer::test(EcdsaKeyIds::TestKeyLocalDevelopment, public_key),
+        );
+        ctx.update_caller(user(2));
+        let addresses: Vec<String> = [CoinType::Evm(1), CoinType::Btc, CoinType::Xrp]
+            .into_iter()
+            .map(|coin_type| canister.get_address(coin_type).unwrap())
+            .collect();
+
+        // the users created after the upgrade get the new key
+        ctx.update_caller(owner);
+        canister.post_upgrade(Some(UpgradeData {
+            owner: None,
+            ecdsa_env: Some(EcdsaKeyIds::TestKey1),
+        }));
+        assert_eq!(canister.get_owner(), owner);
+        assert!(matches!(
+            canister.state.config.get_ecdsa_env(),
+            EcdsaKeyIds::TestKey1
+        ));
+
+        ctx.update_caller(user(2));
+        let upgraded: Vec<String> = [CoinType::Evm(1), CoinType::Btc, CoinType::Xrp]
+            .into_iter()
+            .map(|coin_type| canister.get_address(coin_type).unwrap())
+            .collect();
+        assert_eq!(upgraded, addresses);
+        ctx.update_caller(user(3));
+        assert_eq!(
+            canister.get_address(CoinType::Evm(1)),
+            Err(Error::UserNotInitialized)
+        );
+    }
+
+    #[tokio::test]
+    async fn replays_completed_calls_while_paused() {
+        let owner = user(1);
+        MockContext::new().with_caller(owner).inject();
+        let mut canister = TornadoCanister::init_instance();
+        canister.init(InitData {
+            owner,
+            ecdsa_env: EcdsaKeyIds::TestKeyLocalDevelopment,
+        });
+        let fingerprint = fingerprint("sign_nostr_event", &"event");
+        let key = Some("key".to_string());
+
+        let first = idempotent(
+            canister.state.idempotency,
+            owner,
+            key.clone(),
+            fingerprint,
+            async {
+                canister.check_signing("sign_nostr_event", "Nostr", None)?;
+                Ok::<_, Error>("signature".to_string())
+            },
+        )
+        .await;
+        assert_eq!(first, Ok("signature".to_string()));
+
+        canister
+            .state
+            .pauses
+            .pause(
+                PauseScope::Global,
+                Pause {
+                    reason: "incident".to_string(),
+                    paused_by: owner,
+                    paused_at: 0,
+                    expires_at: None,
+                },
+            )
+            .unwrap();
+        // the retry doesn't run, the pause only stops new calls
+        let retry = idempotent(canister.state.idempotency, owner, key, fingerprint, async {
+            canister.check_signing("sign_nostr_event", "Nostr", None)?;
+            Ok::<_, Error>("other signature".to_string())
+        })
+        .await;
+        assert_eq!(retry, Ok("signature".to_string()));
+        let other = idempotent(
+            canister.state.idempotency,
+            owner,
+            Some("other key".to_string()),
+            fingerprint,
+            async {
+                canister.check_signing("sign_nostr_event", "Nostr", None)?;
+                Ok::<_, Error>("other signature".to_string())
+            },
+        )
+        .await;
+        assert!(matches!(other, Err(Error::Paused(_))));
+    }
+}
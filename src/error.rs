@@ -1,10 +1,12 @@
-use candid::{CandidType, Deserialize};
+use candid::{CandidType, Deserialize, Principal};
 use ic_exports::ic_cdk::api::call::RejectionCode;
 use thiserror::Error;
 
+use crate::ledger::{TransferError, TransferFromError};
+
 pub type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Debug, Error, Deserialize, CandidType, Eq, PartialEq)]
+#[derive(Clone, Debug, Error, Deserialize, CandidType, Eq, PartialEq)]
 pub enum Error {
     #[error("internal error: {0}")]
     Internal(String),
@@ -23,6 +25,69 @@ pub enum Error {
 
     #[error("user not init")]
     UserNotInitialized,
+
+    #[error("invalid address: {0}")]
+    InvalidAddress(String),
+
+    #[error("invalid transaction: {0}")]
+    InvalidTransaction(String),
+
+    #[error("invalid signature: {0}")]
+    InvalidSignature(String),
+
+    #[error("ledger not supported: {0}")]
+    LedgerNotSupported(Principal),
+
+    #[error("ledger transfer error: {0:?}")]
+    Transfer(TransferError),
+
+    #[error("the anonymous principal is not allowed")]
+    AnonymousNotAllowed,
+
+    #[error("the last admin can't be revoked")]
+    LastAdmin,
+
+    #[error("proposal not found: {0}")]
+    ProposalNotFound(u64),
+
+    #[error("proposal already closed: {0}")]
+    ProposalClosed(u64),
+
+    #[error("proposal expired: {0}")]
+    ProposalExpired(u64),
+
+    #[error("paused: {0}")]
+    Paused(String),
+
+    #[error("too many requests, retry later")]
+    RateLimited,
+
+    #[error("the maximum number of users is reached")]
+    MaxUsersReached,
+
+    #[error("ledger transfer from error: {0:?}")]
+    TransferFrom(TransferFromError),
+
+    #[error("insufficient cycles balance, the fee is {0}")]
+    InsufficientCycles(u128),
+
+    #[error("signing job not found: {0}")]
+    JobNotFound(u64),
+
+    #[error("the signing queue is full, retry later")]
+    QueueFull,
+
+    #[error("signing job interrupted by an upgrade, it may have been signed and charged")]
+    JobInterrupted,
+
+    #[error("idempotency key already used with other arguments: {0}")]
+    IdempotencyConflict(String),
+
+    #[error("a request with the idempotency key is in progress: {0}")]
+    RequestInProgress(String),
+
+    #[error("a key creation of the user is in progress, retry later")]
+    KeyCreationInProgress,
 }
 
 impl From<(RejectionCode, String)> for Error {
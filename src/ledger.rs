@@ -0,0 +1,412 @@
+use std::future::Future;
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_exports::ic_cdk::api::call::call;
+
+use crate::error::{Error, Result};
+
+pub type Subaccount = Vec<u8>;
+
+/// ICRC-1 account.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, CandidType, Deserialize)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub fee: Option<Nat>,
+    pub created_at_time: Option<u64>,
+    pub memo: Option<Vec<u8>>,
+    pub amount: Nat,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    TemporarilyUnavailable,
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+}
+
+/// ICRC-2 `transfer_from` argument.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+/// Subaccount of the canister holding the tokens of `user`: the length of the principal
+/// followed by its bytes, zero padded to 32 bytes.
+pub fn user_subaccount(user: Principal) -> Subaccount {
+    let bytes = user.as_slice();
+    let mut subaccount = vec![0; 32];
+    subaccount[0] = bytes.len() as u8;
+    subaccount[1..=bytes.len()].copy_from_slice(bytes);
+    subaccount
+}
+
+/// Operations of an ICRC-1 ledger used by the canister.
+pub trait Ledger {
+    fn balance_of(&self, account: Account) -> impl Future<Output = Result<Nat>>;
+
+    /// Returns the index of the transfer block.
+    fn transfer(&self, arg: TransferArg) -> impl Future<Output = Result<Nat>>;
+
+    /// Spends an ICRC-2 allowance, returns the index of the transfer block.
+    fn transfer_from(&self, arg: TransferFromArgs) -> impl Future<Output = Result<Nat>>;
+}
+
+/// ICRC-1 ledger canister.
+pub struct IcrcLedger {
+    pub canister_id: Principal,
+}
+
+impl Ledger for IcrcLedger {
+    async fn balance_of(&self, account: Account) -> Result<Nat> {
+        let (balance,): (Nat,) = call(self.canister_id, "icrc1_balance_of", (account,)).await?;
+        Ok(balance)
+    }
+
+    async fn transfer(&self, arg: TransferArg) -> Result<Nat> {
+        let (res,): (std::result::Result<Nat, TransferError>,) =
+            call(self.canister_id, "icrc1_transfer", (arg,)).await?;
+        res.map_err(Error::Transfer)
+    }
+
+    async fn transfer_from(&self, arg: TransferFromArgs) -> Result<Nat> {
+        let (res,): (std::result::Result<Nat, TransferFromError>,) =
+            call(self.canister_id, "icrc2_transfer_from", (arg,)).await?;
+        res.map_err(Error::TransferFrom)
+    }
+}
+
+/// Account of the canister `custodian` holding the tokens of `user`.
+pub fn deposit_account(custodian: Principal, user: Principal) -> Account {
+    Account {
+        owner: custodian,
+        subaccount: Some(user_subaccount(user)),
+    }
+}
+
+/// Balance of `user` held by the canister `custodian`.
+pub async fn balance<L: Ledger>(ledger: &L, custodian: Principal, user: Principal) -> Result<Nat> {
+    ledger.balance_of(deposit_account(custodian, user)).await
+}
+
+/// Transfers `amount` from the subaccount of `user` to `to`, the ledger fee being
+/// paid on top of it.
+pub async fn withdraw<L: Ledger>(
+    ledger: &L,
+    user: Principal,
+    to: Account,
+    amount: Nat,
+) -> Result<Nat> {
+    ledger
+        .transfer(TransferArg {
+            from_subaccount: Some(user_subaccount(user)),
+            to,
+            fee: None,
+            created_at_time: None,
+            memo: None,
+            amount,
+        })
+        .await
+}
+
+/// Takes `amount` from the main account of `user` to the main account of the canister
+/// `custodian`, out of the allowance given by `user` to the canister.
+pub async fn collect_fee<L: Ledger>(
+    ledger: &L,
+    custodian: Principal,
+    user: Principal,
+    amount: Nat,
+) -> Result<Nat> {
+    ledger
+        .transfer_from(TransferFromArgs {
+            spender_subaccount: None,
+            from: Account {
+                owner: user,
+                subaccount: None,
+            },
+            to: Account {
+                owner: custodian,
+                subaccount: None,
+            },
+            amount,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        })
+        .await
+}
+
+/// Gives back `amount` taken by `collect_fee` to the main account of `user`, the canister
+/// paying the ledger fee.
+pub async fn refund_fee<L: Ledger>(ledger: &L, user: Principal, amount: Nat) -> Result<Nat> {
+    ledger
+        .transfer(TransferArg {
+            from_subaccount: None,
+            to: Account {
+                owner: user,
+                subaccount: None,
+            },
+            fee: None,
+            created_at_time: None,
+            memo: None,
+            amount,
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// Ledger keeping the balances in memory, with a fixed fee.
+    struct InMemoryLedger {
+        owner: Principal,
+        fee: Nat,
+        balances: RefCell<HashMap<Account, Nat>>,
+        /// Allowances given to the owner.
+        allowances: RefCell<HashMap<Account, Nat>>,
+        blocks: RefCell<u64>,
+    }
+
+    impl InMemoryLedger {
+        fn new(owner: Principal, fee: u64) -> Self {
+            Self {
+                owner,
+                fee: fee.into(),
+                balances: Default::default(),
+                allowances: Default::default(),
+                blocks: RefCell::new(0),
+            }
+        }
+
+        fn mint(&self, account: Account, amount: u64) {
+            *self
+                .balances
+                .borrow_mut()
+                .entry(account)
+                .or_insert_with(|| Nat::from(0u64)) += Nat::from(amount);
+        }
+
+        fn approve(&self, account: Account, amount: u64) {
+            self.allowances
+                .borrow_mut()
+                .insert(account, Nat::from(amount));
+        }
+
+        fn move_funds(&self, from: Account, to: Account, amount: Nat) -> Nat {
+            let mut balances = self.balances.borrow_mut();
+            let balance = balances.get(&from).cloned().unwrap_or_else(|| 0u64.into());
+            balances.insert(from, balance - amount.clone() - self.fee.clone());
+            *balances.entry(to).or_insert_with(|| 0u64.into()) += amount;
+            let mut blocks = self.blocks.borrow_mut();
+            *blocks += 1;
+            (*blocks).into()
+        }
+    }
+
+    impl Ledger for InMemoryLedger {
+        async fn balance_of(&self, account: Account) -> Result<Nat> {
+            let balances = self.balances.borrow();
+            Ok(balances
+                .get(&account)
+                .cloned()
+                .unwrap_or_else(|| 0u64.into()))
+        }
+
+        async fn transfer(&self, arg: TransferArg) -> Result<Nat> {
+            if arg.fee.as_ref().is_some_and(|fee| *fee != self.fee) {
+                return Err(Error::Transfer(TransferError::BadFee {
+                    expected_fee: self.fee.clone(),
+                }));
+            }
+            let from = Account {
+                owner: self.owner,
+                subaccount: arg.from_subaccount,
+            };
+            let balance = self.balance_of(from.clone()).await?;
+            let total = arg.amount.clone() + self.fee.clone();
+            if balance < total {
+                return Err(Error::Transfer(TransferError::InsufficientFunds {
+                    balance,
+                }));
+            }
+
+            Ok(self.move_funds(from, arg.to, arg.amount))
+        }
+
+        async fn transfer_from(&self, arg: TransferFromArgs) -> Result<Nat> {
+            let balance = self.balance_of(arg.from.clone()).await?;
+            let allowance = self
+                .allowances
+                .borrow()
+                .get(&arg.from)
+                .cloned()
+                .unwrap_or_else(|| 0u64.into());
+            let total = arg.amount.clone() + self.fee.clone();
+            if allowance < total {
+                return Err(Error::TransferFrom(
+                    TransferFromError::InsufficientAllowance { allowance },
+                ));
+            }
+            if balance < total {
+                return Err(Error::TransferFrom(TransferFromError::InsufficientFunds {
+                    balance,
+                }));
+            }
+
+            self.allowances
+                .borrow_mut()
+                .insert(arg.from.clone(), allowance - total);
+            Ok(self.move_funds(arg.from, arg.to, arg.amount))
+        }
+    }
+
+    fn user(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    #[test]
+    fn derives_user_subaccounts() {
+        let subaccount = user_subaccount(user(1));
+        assert_eq!(subaccount.len(), 32);
+        assert_eq!(subaccount[0], 29);
+        assert_eq!(&subaccount[1..30], &[1; 29]);
+        assert_ne!(subaccount, user_subaccount(user(2)));
+        assert_ne!(
+            user_subaccount(Principal::anonymous()),
+            user_subaccount(Principal::management_canister())
+        );
+    }
+
+    #[tokio::test]
+    async fn withdraws_from_user_subaccount() {
+        let custodian = user(0);
+        let ledger = InMemoryLedger::new(custodian, 10);
+        ledger.mint(deposit_account(custodian, user(1)), 1_000);
+        let to = Account {
+            owner: user(3),
+            subaccount: None,
+        };
+
+        assert_eq!(
+            withdraw(&ledger, user(1), to.clone(), 500u64.into())
+                .await
+                .unwrap(),
+            Nat::from(1u64)
+        );
+        assert_eq!(
+            balance(&ledger, custodian, user(1)).await.unwrap(),
+            Nat::from(490u64)
+        );
+        assert_eq!(
+            ledger.balance_of(to.clone()).await.unwrap(),
+            Nat::from(500u64)
+        );
+
+        // other users can't spend it
+        assert_eq!(
+            withdraw(&ledger, user(2), to, 1u64.into()).await,
+            Err(Error::Transfer(TransferError::InsufficientFunds {
+                balance: 0u64.into()
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn collects_fees_from_allowance() {
+        let custodian = user(0);
+        let ledger = InMemoryLedger::new(custodian, 10);
+        let payer = Account {
+            owner: user(1),
+            subaccount: None,
+        };
+        ledger.mint(payer.clone(), 1_000);
+
+        assert_eq!(
+            collect_fee(&ledger, custodian, user(1), 100u64.into()).await,
+            Err(Error::TransferFrom(
+                TransferFromError::InsufficientAllowance {
+                    allowance: 0u64.into()
+                }
+            ))
+        );
+
+        ledger.approve(payer.clone(), 150);
+        assert_eq!(
+            collect_fee(&ledger, custodian, user(1), 100u64.into())
+                .await
+                .unwrap(),
+            Nat::from(1u64)
+        );
+        assert_eq!(
+            ledger.balance_of(payer.clone()).await.unwrap(),
+            Nat::from(890u64)
+        );
+        assert_eq!(
+            ledger
+                .balance_of(Account {
+                    owner: custodian,
+                    subaccount: None
+                })
+                .await
+                .unwrap(),
+            Nat::from(100u64)
+        );
+        // the allowance left doesn't cover another fee
+        assert_eq!(
+            collect_fee(&ledger, custodian, user(1), 100u64.into()).await,
+            Err(Error::TransferFrom(
+                TransferFromError::InsufficientAllowance {
+                    allowance: 40u64.into()
+                }
+            ))
+        );
+
+        // a refunded fee comes back, the canister paying the ledger fee
+        ledger.mint(
+            Account {
+                owner: custodian,
+                subaccount: None,
+            },
+            10,
+        );
+        assert_eq!(
+            refund_fee(&ledger, user(1), 100u64.into()).await.unwrap(),
+            Nat::from(2u64)
+        );
+        assert_eq!(ledger.balance_of(payer).await.unwrap(), Nat::from(990u64));
+    }
+}
@@ -0,0 +1,346 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::memory_manager::VirtualMemory;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+
+use crate::error::{Error, Result};
+use crate::state::{
+    decode, encode, StorablePrincipal, ACCESS_POLICY_MEMORY_ID, ALLOWLIST_MEMORY_ID, MEMORY_MANAGER,
+};
+
+/// Principals whose buckets are kept, the least recently seen one is dropped beyond it.
+const MAX_TRACKED_PRINCIPALS: usize = 10_000;
+
+/// Token bucket: `capacity` requests in a burst, then one every `refill_interval`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct RateLimit {
+    pub capacity: u64,
+    /// Nanoseconds to regain one request.
+    pub refill_interval: u64,
+}
+
+/// Limits of the users, set by the admins through proposals.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct AccessPolicy {
+    /// Only the allowlisted principals can create keys and sign.
+    pub allowlist_enabled: bool,
+    /// Maximum number of principals with a key of any type.
+    pub max_users: Option<u64>,
+    /// Requests of a principal.
+    pub user_rate: Option<RateLimit>,
+    /// Requests of all the principals.
+    pub global_rate: Option<RateLimit>,
+}
+
+impl Default for AccessPolicy {
+    fn default() -> Self {
+        Self {
+            allowlist_enabled: false,
+            max_users: None,
+            // 20 requests, then one every 3 seconds
+            user_rate: Some(RateLimit {
+                capacity: 20,
+                refill_interval: 3_000_000_000,
+            }),
+            // 500 requests, then 100 per second
+            global_rate: Some(RateLimit {
+                capacity: 500,
+                refill_interval: 10_000_000,
+            }),
+        }
+    }
+}
+
+impl Storable for AccessPolicy {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        encode(&self).into()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(bytes.as_ref())
+    }
+
+    // the cell stores the value whatever its size
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl AccessPolicy {
+    pub fn validate(&self) -> Result<()> {
+        let rates = [self.user_rate, self.global_rate];
+        if rates
+            .iter()
+            .flatten()
+            .any(|rate| rate.capacity == 0 || rate.refill_interval == 0)
+        {
+            return Err(Error::Internal(format!("invalid access policy: {self:?}")));
+        }
+        Ok(())
+    }
+}
+
+/// Who can use the canister and how often.
+///
+/// The allowlist and the policy live in stable memory, the rate limit buckets on the
+/// heap: they start full after an upgrade.
+#[derive(Default, Clone, Copy)]
+pub struct Access {}
+
+impl Access {
+    pub fn reset(&mut self) {
+        ACCESS_POLICY.with(|cell| {
+            cell.borrow_mut()
+                .set(AccessPolicy::default())
+                .expect("failed to update access policy stable memory data")
+        });
+        ALLOWLIST.with(|allowlist| {
+            allowlist.replace(StableBTreeMap::new(
+                MEMORY_MANAGER.with(|m| m.borrow().get(ALLOWLIST_MEMORY_ID)),
+            ))
+        });
+        BUCKETS.with(|buckets| buckets.take());
+    }
+
+    pub fn get_policy(&self) -> AccessPolicy {
+        ACCESS_POLICY.with(|cell| *cell.borrow().get())
+    }
+
+    pub fn set_policy(&mut self, policy: AccessPolicy) -> Result<()> {
+        ACCESS_POLICY
+            .with(|cell| cell.borrow_mut().set(policy))
+            .map_err(|e| Error::StableError(format!("access policy update error is {:?}", e)))?;
+        Ok(())
+    }
+
+    pub fn allow(&mut self, principal: Principal) {
+        ALLOWLIST.with(|allowlist| {
+            allowlist
+                .borrow_mut()
+                .insert(StorablePrincipal(principal), ())
+        });
+    }
+
+    pub fn disallow(&mut self, principal: Principal) {
+        ALLOWLIST.with(|allowlist| allowlist.borrow_mut().remove(&StorablePrincipal(principal)));
+    }
+
+    pub fn allowlist(&self) -> Vec<Principal> {
+        ALLOWLIST.with(|allowlist| allowlist.borrow().iter().map(|(p, _)| p.0).collect())
+    }
+
+    /// Fails if `caller` can't use the canister at all. Anonymous callers are rejected,
+    /// they would all share the same keys.
+    pub fn inspect(&self, caller: Principal) -> Result<()> {
+        if caller == Principal::anonymous() {
+            return Err(Error::AnonymousNotAllowed);
+        }
+        if self.get_policy().allowlist_enabled
+            && !ALLOWLIST
+                .with(|allowlist| allowlist.borrow().contains_key(&StorablePrincipal(caller)))
+        {
+            return Err(Error::NotAuthorized);
+        }
+        Ok(())
+    }
+
+    /// Admits a request of `caller` at `timestamp`, spending a request of its rate
+    /// limit and of the global one.
+    pub fn check(&self, caller: Principal, timestamp: u64) -> Result<()> {
+        self.inspect(caller)?;
+        let policy = self.get_policy();
+        BUCKETS.with(|buckets| buckets.borrow_mut().take(&policy, caller, timestamp))
+    }
+
+    /// Fails if a new user would exceed the maximum, `users` having a key already.
+    pub fn check_new_user(&self, users: u64) -> Result<()> {
+        match self.get_policy().max_users {
+            Some(max_users) if users >= max_users => Err(Error::MaxUsersReached),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    global: Option<Bucket>,
+    users: BTreeMap<Principal, Bucket>,
+    /// Principals of `users` by time of their last request.
+    seen: BTreeSet<(u64, Principal)>,
+}
+
+impl Buckets {
+    /// Spends a request of the global bucket and of the caller's one, if both have any.
+    fn take(&mut self, policy: &AccessPolicy, caller: Principal, timestamp: u64) -> Result<()> {
+        let mut buckets = vec![];
+        if let Some(rate) = policy.global_rate {
+            let global = self
+                .global
+                .get_or_insert_with(|| Bucket::full(rate, timestamp));
+            global.refill(rate, timestamp);
+            buckets.push(global);
+        }
+        if let Some(rate) = policy.user_rate {
+            match self.users.get(&caller) {
+                Some(user) => {
+                    self.seen.remove(&(user.seen_at, caller));
+                }
+                // the oldest bucket is the most likely to be full again
+                None if self.users.len() >= MAX_TRACKED_PRINCIPALS => {
+                    if let Some((_, oldest)) = self.seen.pop_first() {
+                        self.users.remove(&oldest);
+                    }
+                }
+                None => {}
+            }
+            self.seen.insert((timestamp, caller));
+            let user = self
+                .users
+                .entry(caller)
+                .or_insert_with(|| Bucket::full(rate, timestamp));
+            user.seen_at = timestamp;
+            user.refill(rate, timestamp);
+            buckets.push(user);
+        }
+
+        if buckets.iter().any(|bucket| bucket.tokens == 0) {
+            return Err(Error::RateLimited);
+        }
+        buckets.into_iter().for_each(|bucket| bucket.tokens -= 1);
+        Ok(())
+    }
+}
+
+struct Bucket {
+    tokens: u64,
+    updated_at: u64,
+    /// Time of the last request.
+    seen_at: u64,
+}
+
+impl Bucket {
+    fn full(rate: RateLimit, timestamp: u64) -> Self {
+        Self {
+            tokens: rate.capacity,
+            updated_at: timestamp,
+            seen_at: timestamp,
+        }
+    }
+
+    fn refill(&mut self, rate: RateLimit, timestamp: u64) {
+        let refilled = timestamp.saturating_sub(self.updated_at) / rate.refill_interval;
+        self.tokens = rate.capacity.min(self.tokens.saturating_add(refilled));
+        if self.tokens == rate.capacity {
+            self.updated_at = timestamp;
+        } else {
+            self.updated_at += refilled * rate.refill_interval;
+        }
+    }
+}
+
+thread_local! {
+    static ACCESS_POLICY: RefCell<StableCell<AccessPolicy, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(ACCESS_POLICY_MEMORY_ID)), AccessPolicy::default()).expect("access policy initialization failed"));
+    static ALLOWLIST: RefCell<StableBTreeMap<StorablePrincipal, (), VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(ALLOWLIST_MEMORY_ID))));
+    static BUCKETS: RefCell<Buckets> = RefCell::new(Buckets::default());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(byte: u8) -> Principal {
+        Principal::from_slice(&[byte; 29])
+    }
+
+    #[test]
+    fn limits_rates() {
+        let mut access = Access::default();
+        access.reset();
+        access
+            .set_policy(AccessPolicy {
+                user_rate: Some(RateLimit {
+                    capacity: 2,
+                    refill_interval: 10,
+                }),
+                global_rate: Some(RateLimit {
+                    capacity: 3,
+                    refill_interval: 100,
+                }),
+                ..AccessPolicy::default()
+            })
+            .unwrap();
+
+        assert!(access.check(user(1), 0).is_ok());
+        assert!(access.check(user(1), 0).is_ok());
+        assert_eq!(access.check(user(1), 9), Err(Error::RateLimited));
+        assert!(access.check(user(1), 10).is_ok());
+        // the global bucket is empty
+        assert_eq!(access.check(user(2), 10), Err(Error::RateLimited));
+        assert!(access.check(user(2), 100).is_ok());
+
+        assert_eq!(
+            access.check(Principal::anonymous(), 1000),
+            Err(Error::AnonymousNotAllowed)
+        );
+    }
+
+    #[test]
+    fn bounds_tracked_principals() {
+        let mut access = Access::default();
+        access.reset();
+        access
+            .set_policy(AccessPolicy {
+                user_rate: Some(RateLimit {
+                    capacity: 1,
+                    refill_interval: u64::MAX,
+                }),
+                global_rate: None,
+                ..AccessPolicy::default()
+            })
+            .unwrap();
+        let principal = |i: usize| Principal::from_slice(&(i as u32).to_be_bytes());
+        let tracked = || BUCKETS.with(|buckets| buckets.borrow().users.len());
+
+        assert!(access.check(principal(0), 0).is_ok());
+        for i in 1..MAX_TRACKED_PRINCIPALS {
+            assert!(access.check(principal(i), i as u64).is_ok());
+        }
+        assert_eq!(tracked(), MAX_TRACKED_PRINCIPALS);
+
+        // a request keeps a bucket, the least recently seen one is dropped
+        let now = MAX_TRACKED_PRINCIPALS as u64;
+        assert_eq!(access.check(principal(0), now), Err(Error::RateLimited));
+        assert!(access.check(principal(MAX_TRACKED_PRINCIPALS), now).is_ok());
+        assert_eq!(tracked(), MAX_TRACKED_PRINCIPALS);
+        assert_eq!(access.check(principal(0), now), Err(Error::RateLimited));
+        assert!(access.check(principal(1), now).is_ok());
+        assert_eq!(tracked(), MAX_TRACKED_PRINCIPALS);
+    }
+
+    #[test]
+    fn checks_allowlist_and_users() {
+        let mut access = Access::default();
+        access.reset();
+        assert!(access.check(user(1), 0).is_ok());
+        assert!(access.check_new_user(1_000_000).is_ok());
+
+        access
+            .set_policy(AccessPolicy {
+                allowlist_enabled: true,
+                max_users: Some(2),
+                ..AccessPolicy::default()
+            })
+            .unwrap();
+        assert_eq!(access.check(user(1), 0), Err(Error::NotAuthorized));
+        access.allow(user(1));
+        assert!(access.check(user(1), 0).is_ok());
+        assert_eq!(access.allowlist(), vec![user(1)]);
+        access.disallow(user(1));
+        assert_eq!(access.check(user(1), 0), Err(Error::NotAuthorized));
+
+        assert!(access.check_new_user(1).is_ok());
+        assert_eq!(access.check_new_user(2), Err(Error::MaxUsersReached));
+    }
+}
@@ -0,0 +1,92 @@
+//! Building blocks of the binary layouts of the stored records.
+//!
+//! The layouts are versioned by `schema`: changing how a helper writes its bytes changes
+//! every layout using it and needs a new version with its migration.
+
+use candid::Principal;
+
+/// Longest principal, and derivation path of the user keys.
+pub const MAX_PRINCIPAL_LEN: usize = 29;
+
+/// Length byte followed by the principal bytes, zero padded to the longest principal.
+pub const PRINCIPAL_SIZE: usize = 1 + MAX_PRINCIPAL_LEN;
+
+/// Writes `bytes` as a length byte followed by the bytes zero padded to `max_len`.
+///
+/// The encodings compare like the length and then the bytes, as principals do.
+pub fn write_padded(buf: &mut Vec<u8>, bytes: &[u8], max_len: usize) {
+    assert!(
+        bytes.len() <= max_len,
+        "{} bytes don't fit {max_len}",
+        bytes.len()
+    );
+    buf.push(bytes.len() as u8);
+    buf.extend_from_slice(bytes);
+    buf.resize(buf.len() + max_len - bytes.len(), 0);
+}
+
+/// Reads the bytes written by `write_padded` at the start of `bytes`.
+pub fn read_padded(bytes: &[u8]) -> &[u8] {
+    &bytes[1..1 + bytes[0] as usize]
+}
+
+pub fn write_principal(buf: &mut Vec<u8>, principal: &Principal) {
+    write_padded(buf, principal.as_slice(), MAX_PRINCIPAL_LEN);
+}
+
+pub fn read_principal(bytes: &[u8]) -> Principal {
+    Principal::from_slice(read_padded(bytes))
+}
+
+pub fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes[..8].try_into().expect("8 bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(principal: &Principal) -> Vec<u8> {
+        let mut buf = vec![];
+        write_principal(&mut buf, principal);
+        buf
+    }
+
+    #[test]
+    fn encodes_principals() {
+        let principals = [
+            Principal::management_canister(),
+            Principal::anonymous(),
+            Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 1, 1]),
+            Principal::from_slice(&[9; 29]),
+        ];
+        for principal in &principals {
+            let bytes = encode(principal);
+            assert_eq!(bytes.len(), PRINCIPAL_SIZE);
+            assert_eq!(&read_principal(&bytes), principal);
+        }
+        assert_eq!(hex::encode(&encode(&Principal::anonymous())[..3]), "010400");
+    }
+
+    #[test]
+    fn orders_like_principals() {
+        let mut principals = vec![
+            Principal::from_slice(&[2; 29]),
+            Principal::anonymous(),
+            Principal::from_slice(&[1, 255]),
+            Principal::from_slice(&[1; 29]),
+            Principal::from_slice(&[3]),
+            Principal::management_canister(),
+        ];
+        let mut encoded = principals.iter().map(encode).collect::<Vec<_>>();
+        principals.sort();
+        encoded.sort();
+        assert_eq!(
+            encoded
+                .iter()
+                .map(|b| read_principal(b))
+                .collect::<Vec<_>>(),
+            principals
+        );
+    }
+}
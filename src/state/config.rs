@@ -9,7 +9,9 @@ use ic_stable_structures::{DefaultMemoryImpl, StableCell, Storable};
 use super::ecdsa::EcdsaKeyIds;
 use super::Settings;
 use crate::error::{Error, Result};
-use crate::state::{decode, encode, CONFIG_MEMORY_ID, MEMORY_MANAGER};
+use crate::state::codec::{read_principal, read_u64, write_principal, PRINCIPAL_SIZE};
+use crate::state::proposals::ApprovalPolicy;
+use crate::state::{CONFIG_MEMORY_ID, MEMORY_MANAGER};
 
 /// Minter canister configuration.
 #[derive(Default)]
@@ -20,7 +22,8 @@ impl Config {
     pub fn reset(&mut self, settings: Settings) {
         let new_data = ConfigData {
             owner: settings.owner,
-            ..Default::default()
+            ecdsa_env: settings.ecdsa_env,
+            approval_policy: ApprovalPolicy::default(),
         };
         CONFIG_CELL.with(|cell| {
             cell.borrow_mut()
@@ -40,18 +43,39 @@ impl Config {
 
     /// Sets a new principal for canister owner.
     pub fn set_owner(&mut self, owner: Principal) -> Result<()> {
-        let ecdsa_env = self.get_ecdsa_env();
+        self.update(|data| data.owner = owner)
+    }
+
+    /// Sets the key of the users created from now on, existing users keep their key.
+    pub fn set_ecdsa_env(&mut self, ecdsa_env: EcdsaKeyIds) -> Result<()> {
+        self.update(|data| data.ecdsa_env = ecdsa_env)
+    }
+
+    pub fn get_approval_policy(&self) -> ApprovalPolicy {
+        CONFIG_CELL.with(|c| c.borrow().get().approval_policy)
+    }
+
+    pub fn set_approval_policy(&mut self, policy: ApprovalPolicy) -> Result<()> {
+        self.update(|data| data.approval_policy = policy)
+    }
+
+    fn update(&mut self, f: impl FnOnce(&mut ConfigData)) -> Result<()> {
         CONFIG_CELL
-            .with(|cell| cell.borrow_mut().set(ConfigData { owner, ecdsa_env }))
-            .map_err(|e| Error::StableError(format!("set_owner error is {:?}", e)))?;
+            .with(|cell| {
+                let mut data = cell.borrow().get().clone();
+                f(&mut data);
+                cell.borrow_mut().set(data)
+            })
+            .map_err(|e| Error::StableError(format!("config update error is {:?}", e)))?;
         Ok(())
     }
 }
 
-#[derive(Deserialize, CandidType)]
+#[derive(Clone, Deserialize, CandidType)]
 pub struct ConfigData {
     pub owner: Principal,
     pub ecdsa_env: EcdsaKeyIds,
+    pub approval_policy: ApprovalPolicy,
 }
 
 impl Default for ConfigData {
@@ -59,23 +83,37 @@ impl Default for ConfigData {
         Self {
             owner: Principal::anonymous(),
             ecdsa_env: EcdsaKeyIds::TestKeyLocalDevelopment,
+            approval_policy: ApprovalPolicy::default(),
         }
     }
 }
 
+/// Owner followed by the ECDSA key byte, the approval threshold and the proposal
+/// expiry, both big endian.
 impl Storable for ConfigData {
     fn to_bytes(&self) -> Cow<[u8]> {
-        encode(&self).into()
+        let mut bytes = Vec::with_capacity(PRINCIPAL_SIZE + 13);
+        write_principal(&mut bytes, &self.owner);
+        bytes.push(self.ecdsa_env.to_byte());
+        bytes.extend_from_slice(&self.approval_policy.threshold.to_be_bytes());
+        bytes.extend_from_slice(&self.approval_policy.expiry.to_be_bytes());
+        bytes.into()
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        decode(bytes.as_ref())
+        let policy = &bytes[PRINCIPAL_SIZE + 1..];
+        Self {
+            owner: read_principal(&bytes),
+            ecdsa_env: EcdsaKeyIds::from_byte(bytes[PRINCIPAL_SIZE]),
+            approval_policy: ApprovalPolicy {
+                threshold: u32::from_be_bytes(policy[..4].try_into().expect("4 bytes")),
+                expiry: read_u64(&policy[4..]),
+            },
+        }
     }
 
-    const BOUND: Bound = Bound::Bounded {
-        max_size: 29,
-        is_fixed_size: true,
-    };
+    // the cell stores the value whatever its size
+    const BOUND: Bound = Bound::Unbounded;
 }
 
 // If the struct's memory already contains a value, initializes the struct with the decoded value.
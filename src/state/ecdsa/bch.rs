@@ -0,0 +1,574 @@
+use candid::{CandidType, Deserialize};
+
+use crate::error::{Error, Result};
+use crate::state::ecdsa::hash::{hash160, sha256d};
+use crate::state::ecdsa::signature::to_der;
+use crate::state::ecdsa::Signer;
+
+/// CashAddr prefix of Bitcoin Cash mainnet.
+pub const CASHADDR_PREFIX: &str = "bitcoincash";
+
+/// `SIGHASH_ALL | SIGHASH_FORKID`, the only hash type produced by the wallet.
+pub const SIGHASH_ALL_FORKID: u32 = 0x41;
+
+const TX_VERSION: u32 = 2;
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// Input of an unsigned BCH transaction, spending an output of the caller's address.
+#[derive(Clone, CandidType, Deserialize)]
+pub struct BchTxInput {
+    /// Hex encoded id of the transaction being spent, as shown by block explorers.
+    pub txid: String,
+    pub vout: u32,
+    /// Value of the spent output in satoshis, committed to by the signature.
+    pub value: u64,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct BchTxOutput {
+    /// CashAddr of the receiver, with or without the `bitcoincash:` prefix.
+    pub address: String,
+    pub value: u64,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct BchTransaction {
+    pub inputs: Vec<BchTxInput>,
+    pub outputs: Vec<BchTxOutput>,
+    pub lock_time: u32,
+}
+
+pub struct BchWallet {
+    pub signer: Signer,
+    pub pubkey_hash: [u8; 20],
+}
+
+impl BchWallet {
+    pub fn new(signer: Signer) -> Self {
+        let pubkey_hash = hash160(signer.public_key());
+        Self {
+            signer,
+            pubkey_hash,
+        }
+    }
+
+    /// CashAddr of the P2PKH output script controlled by the signer.
+    pub fn address(&self) -> String {
+        cashaddr_encode(CASHADDR_PREFIX, AddressType::P2pkh, &self.pubkey_hash)
+    }
+
+    /// Signs every input of the transaction and returns the serialized signed transaction.
+    pub async fn sign_transaction(&self, tx: &BchTransaction) -> Result<Vec<u8>> {
+        let mut tx = Transaction::try_from(tx)?;
+        let script_code = p2pkh_script(&self.pubkey_hash);
+        let values = tx.values.clone();
+
+        for (index, value) in values.into_iter().enumerate() {
+            let sighash = tx.signature_hash(index, &script_code, value, SIGHASH_ALL_FORKID);
+            let sign = self.signer.sign_hash(sighash).await?;
+            tx.inputs[index].script_sig =
+                p2pkh_script_sig(&to_der(&sign)?, self.signer.public_key());
+        }
+
+        Ok(tx.serialize())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressType {
+    P2pkh,
+    P2sh,
+}
+
+impl AddressType {
+    fn version_byte(self) -> u8 {
+        // type bits shifted by 3, size bits 0 for 160 bit hashes
+        match self {
+            Self::P2pkh => 0,
+            Self::P2sh => 8,
+        }
+    }
+
+    fn script_pubkey(self, hash: &[u8; 20]) -> Vec<u8> {
+        match self {
+            Self::P2pkh => p2pkh_script(hash),
+            Self::P2sh => {
+                let mut script = vec![0xa9, 0x14];
+                script.extend_from_slice(hash);
+                script.push(0x87);
+                script
+            }
+        }
+    }
+}
+
+/// Encodes a 160 bit hash as a CashAddr.
+pub fn cashaddr_encode(prefix: &str, kind: AddressType, hash: &[u8; 20]) -> String {
+    let mut payload = vec![kind.version_byte()];
+    payload.extend_from_slice(hash);
+    let mut data = convert_bits(&payload, 8, 5, true).expect("8 to 5 bits conversion never fails");
+
+    let checksum = cashaddr_polymod(&checksum_input(prefix, &data, &[0; 8]));
+    data.extend((0..8).map(|i| ((checksum >> (5 * (7 - i))) & 0x1f) as u8));
+
+    let encoded: String = data.iter().map(|d| CHARSET[*d as usize] as char).collect();
+    format!("{prefix}:{encoded}")
+}
+
+/// Decodes a mainnet CashAddr into its type and hash, the prefix defaults to `bitcoincash`.
+pub fn cashaddr_decode(address: &str) -> Result<(AddressType, [u8; 20])> {
+    let invalid = || Error::InvalidAddress(address.to_string());
+
+    if address.chars().any(|c| c.is_ascii_lowercase())
+        && address.chars().any(|c| c.is_ascii_uppercase())
+    {
+        return Err(invalid());
+    }
+    let lowercase = address.to_ascii_lowercase();
+    let (prefix, encoded) = lowercase
+        .rsplit_once(':')
+        .unwrap_or((CASHADDR_PREFIX, &lowercase));
+    // testnet and regtest addresses would burn mainnet coins
+    if prefix != CASHADDR_PREFIX {
+        return Err(invalid());
+    }
+
+    let data = encoded
+        .bytes()
+        .map(|c| CHARSET.iter().position(|x| *x == c).map(|p| p as u8))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(invalid)?;
+    if data.len() < 8 || cashaddr_polymod(&checksum_input(prefix, &data, &[])) != 0 {
+        return Err(invalid());
+    }
+
+    let payload = convert_bits(&data[..data.len() - 8], 5, 8, false).ok_or_else(invalid)?;
+    if payload.len() != 21 {
+        return Err(invalid());
+    }
+    let kind = match payload[0] {
+        0 => AddressType::P2pkh,
+        8 => AddressType::P2sh,
+        _ => return Err(invalid()),
+    };
+
+    let mut hash = [0u8; 20];
+    hash.copy_from_slice(&payload[1..]);
+    Ok((kind, hash))
+}
+
+fn checksum_input(prefix: &str, data: &[u8], template: &[u8]) -> Vec<u8> {
+    prefix
+        .bytes()
+        .map(|c| c & 0x1f)
+        .chain(std::iter::once(0))
+        .chain(data.iter().copied())
+        .chain(template.iter().copied())
+        .collect()
+}
+
+fn cashaddr_polymod(values: &[u8]) -> u64 {
+    const GENERATORS: [u64; 5] = [
+        0x98f2bc8e61,
+        0x79b76d99e2,
+        0xf33e5fb3c4,
+        0xae2eabe2a8,
+        0x1e4f43e470,
+    ];
+
+    let mut c: u64 = 1;
+    for d in values {
+        let c0 = c >> 35;
+        c = ((c & 0x07ffffffff) << 5) ^ (*d as u64);
+        for (i, generator) in GENERATORS.iter().enumerate() {
+            if c0 & (1 << i) != 0 {
+                c ^= generator;
+            }
+        }
+    }
+    c ^ 1
+}
+
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let mut acc: u32 = 0;
+    let mut bits: u32 = 0;
+    let max = (1u32 << to) - 1;
+    let mut out = Vec::with_capacity(data.len() * from as usize / to as usize + 1);
+
+    for value in data {
+        acc = (acc << from) | *value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            out.push(((acc >> bits) & max) as u8);
+        }
+    }
+
+    if pad {
+        if bits > 0 {
+            out.push(((acc << (to - bits)) & max) as u8);
+        }
+    } else if bits >= from || ((acc << (to - bits)) & max) != 0 {
+        return None;
+    }
+    Some(out)
+}
+
+/// `OP_DUP OP_HASH160 <hash> OP_EQUALVERIFY OP_CHECKSIG`
+pub fn p2pkh_script(hash: &[u8; 20]) -> Vec<u8> {
+    let mut script = vec![0x76, 0xa9, 0x14];
+    script.extend_from_slice(hash);
+    script.extend_from_slice(&[0x88, 0xac]);
+    script
+}
+
+/// `<DER signature + SIGHASH_ALL_FORKID> <public key>`
+fn p2pkh_script_sig(der: &[u8], public_key: &[u8]) -> Vec<u8> {
+    let mut sig = der.to_vec();
+    sig.push(SIGHASH_ALL_FORKID as u8);
+
+    let mut script_sig = Vec::with_capacity(sig.len() + public_key.len() + 2);
+    push_data(&mut script_sig, &sig);
+    push_data(&mut script_sig, public_key);
+    script_sig
+}
+
+fn push_data(script: &mut Vec<u8>, data: &[u8]) {
+    // signatures and public keys always fit a direct push
+    debug_assert!(data.len() < 0x4c);
+    script.push(data.len() as u8);
+    script.extend_from_slice(data);
+}
+
+pub(crate) fn write_var_int(buf: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0xfc => buf.push(n as u8),
+        0xfd..=0xffff => {
+            buf.push(0xfd);
+            buf.extend_from_slice(&(n as u16).to_le_bytes());
+        }
+        0x10000..=0xffff_ffff => {
+            buf.push(0xfe);
+            buf.extend_from_slice(&(n as u32).to_le_bytes());
+        }
+        _ => {
+            buf.push(0xff);
+            buf.extend_from_slice(&n.to_le_bytes());
+        }
+    }
+}
+
+pub(crate) struct TxIn {
+    /// Previous transaction id in internal (little endian) byte order.
+    pub prev_txid: [u8; 32],
+    pub vout: u32,
+    pub script_sig: Vec<u8>,
+    pub sequence: u32,
+}
+
+pub(crate) struct TxOut {
+    pub value: u64,
+    pub script_pubkey: Vec<u8>,
+}
+
+pub(crate) struct Transaction {
+    pub version: u32,
+    pub inputs: Vec<TxIn>,
+    pub outputs: Vec<TxOut>,
+    pub lock_time: u32,
+    /// Values of the spent outputs, in inputs order.
+    pub values: Vec<u64>,
+}
+
+impl TryFrom<&BchTransaction> for Transaction {
+    type Error = Error;
+
+    fn try_from(tx: &BchTransaction) -> Result<Self> {
+        if tx.inputs.is_empty() || tx.outputs.is_empty() {
+            return Err(Error::InvalidTransaction(
+                "transaction needs at least one input and one output".to_string(),
+            ));
+        }
+
+        // a non final sequence is required for the lock time to be enforced
+        let sequence = if tx.lock_time == 0 {
+            0xffff_ffff
+        } else {
+            0xffff_fffe
+        };
+
+        let inputs = tx
+            .inputs
+            .iter()
+            .map(|input| {
+                let mut prev_txid: [u8; 32] = hex::decode(&input.txid)
+                    .ok()
+                    .and_then(|id| id.try_into().ok())
+                    .ok_or_else(|| Error::InvalidTransaction(format!("txid {}", input.txid)))?;
+                prev_txid.reverse();
+                Ok(TxIn {
+                    prev_txid,
+                    vout: input.vout,
+                    script_sig: vec![],
+                    sequence,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let outputs = tx
+            .outputs
+            .iter()
+            .map(|output| {
+                let (kind, hash) = cashaddr_decode(&output.address)?;
+                Ok(TxOut {
+                    value: output.value,
+                    script_pubkey: kind.script_pubkey(&hash),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            version: TX_VERSION,
+            inputs,
+            outputs,
+            lock_time: tx.lock_time,
+            values: tx.inputs.iter().map(|input| input.value).collect(),
+        })
+    }
+}
+
+impl Transaction {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.version.to_le_bytes());
+
+        write_var_int(&mut buf, self.inputs.len() as u64);
+        for input in &self.inputs {
+            buf.extend_from_slice(&input.prev_txid);
+            buf.extend_from_slice(&input.vout.to_le_bytes());
+            write_var_int(&mut buf, input.script_sig.len() as u64);
+            buf.extend_from_slice(&input.script_sig);
+            buf.extend_from_slice(&input.sequence.to_le_bytes());
+        }
+
+        write_var_int(&mut buf, self.outputs.len() as u64);
+        buf.extend_from_slice(&self.serialize_outputs());
+
+        buf.extend_from_slice(&self.lock_time.to_le_bytes());
+        buf
+    }
+
+    fn serialize_outputs(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for output in &self.outputs {
+            buf.extend_from_slice(&output.value.to_le_bytes());
+            write_var_int(&mut buf, output.script_pubkey.len() as u64);
+            buf.extend_from_slice(&output.script_pubkey);
+        }
+        buf
+    }
+
+    /// BIP-143 style signature hash, which BCH uses for every input with `SIGHASH_FORKID`.
+    ///
+    /// Only the `SIGHASH_ALL` base type is supported.
+    pub fn signature_hash(
+        &self,
+        index: usize,
+        script_code: &[u8],
+        value: u64,
+        sighash_type: u32,
+    ) -> [u8; 32] {
+        let mut prevouts = Vec::with_capacity(self.inputs.len() * 36);
+        let mut sequences = Vec::with_capacity(self.inputs.len() * 4);
+        for input in &self.inputs {
+            prevouts.extend_from_slice(&input.prev_txid);
+            prevouts.extend_from_slice(&input.vout.to_le_bytes());
+            sequences.extend_from_slice(&input.sequence.to_le_bytes());
+        }
+
+        let input = &self.inputs[index];
+        let mut preimage = Vec::new();
+        preimage.extend_from_slice(&self.version.to_le_bytes());
+        preimage.extend_from_slice(&sha256d(prevouts));
+        preimage.extend_from_slice(&sha256d(sequences));
+        preimage.extend_from_slice(&input.prev_txid);
+        preimage.extend_from_slice(&input.vout.to_le_bytes());
+        write_var_int(&mut preimage, script_code.len() as u64);
+        preimage.extend_from_slice(script_code);
+        preimage.extend_from_slice(&value.to_le_bytes());
+        preimage.extend_from_slice(&input.sequence.to_le_bytes());
+        preimage.extend_from_slice(&sha256d(self.serialize_outputs()));
+        preimage.extend_from_slice(&self.lock_time.to_le_bytes());
+        preimage.extend_from_slice(&sighash_type.to_le_bytes());
+
+        sha256d(preimage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(s: &str) -> [u8; 20] {
+        hex::decode(s).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn cashaddr_spec_vectors() {
+        // https://github.com/bitcoincashorg/bitcoincash.org/blob/master/spec/cashaddr.md
+        let pkh = hash("76a04053bda0a88bda5177b86a15c3b29f559873");
+        let address = "bitcoincash:qpm2qsznhks23z7629mms6s4cwef74vcwvy22gdx6a";
+        assert_eq!(
+            cashaddr_encode("bitcoincash", AddressType::P2pkh, &pkh),
+            address
+        );
+        assert_eq!(cashaddr_decode(address).unwrap(), (AddressType::P2pkh, pkh));
+
+        let sh = hash("76a04053bda0a88bda5177b86a15c3b29f559873");
+        let address = "bitcoincash:ppm2qsznhks23z7629mms6s4cwef74vcwvn0h829pq";
+        assert_eq!(
+            cashaddr_encode("bitcoincash", AddressType::P2sh, &sh),
+            address
+        );
+        assert_eq!(cashaddr_decode(address).unwrap(), (AddressType::P2sh, sh));
+
+        let pkh = hash("F5BF48B397DAE70BE82B3CCA4793F8EB2B6CDAC9");
+        let address = "bitcoincash:qr6m7j9njldwwzlg9v7v53unlr4jkmx6eylep8ekg2";
+        assert_eq!(
+            cashaddr_encode("bitcoincash", AddressType::P2pkh, &pkh),
+            address
+        );
+        assert_eq!(
+            cashaddr_decode(&address.to_ascii_uppercase()).unwrap(),
+            (AddressType::P2pkh, pkh)
+        );
+        assert_eq!(
+            cashaddr_decode("qr6m7j9njldwwzlg9v7v53unlr4jkmx6eylep8ekg2").unwrap(),
+            (AddressType::P2pkh, pkh)
+        );
+    }
+
+    #[test]
+    fn cashaddr_rejects_bad_checksum() {
+        assert!(cashaddr_decode("bitcoincash:qpm2qsznhks23z7629mms6s4cwef74vcwvy22gdx6b").is_err());
+        assert!(cashaddr_decode("bchtest:qpm2qsznhks23z7629mms6s4cwef74vcwvy22gdx6a").is_err());
+        assert!(cashaddr_decode("bitcoincash:Qpm2qsznhks23z7629mms6s4cwef74vcwvy22gdx6a").is_err());
+    }
+
+    #[test]
+    fn cashaddr_rejects_other_networks() {
+        let pkh = hash("76a04053bda0a88bda5177b86a15c3b29f559873");
+        for prefix in ["bchtest", "bchreg", "pref"] {
+            let address = cashaddr_encode(prefix, AddressType::P2pkh, &pkh);
+            assert!(cashaddr_decode(&address).is_err(), "{address}");
+        }
+    }
+
+    /// Unsigned transaction of the native P2WPKH example of BIP-143, the digest BCH uses
+    /// for all inputs: https://github.com/bitcoin/bips/blob/master/bip-0143.mediawiki#native-p2wpkh
+    fn bip143_transaction() -> Transaction {
+        let txid = |s: &str| -> [u8; 32] { hex::decode(s).unwrap().try_into().unwrap() };
+        Transaction {
+            version: 1,
+            inputs: vec![
+                TxIn {
+                    prev_txid: txid(
+                        "fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f",
+                    ),
+                    vout: 0,
+                    script_sig: vec![],
+                    sequence: 0xffffffee,
+                },
+                TxIn {
+                    prev_txid: txid(
+                        "ef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a",
+                    ),
+                    vout: 1,
+                    script_sig: vec![],
+                    sequence: 0xffffffff,
+                },
+            ],
+            outputs: vec![
+                TxOut {
+                    value: 112340000,
+                    script_pubkey: p2pkh_script(&hash("8280b37df378db99f66f85c95a783a76ac7a6d59")),
+                },
+                TxOut {
+                    value: 223450000,
+                    script_pubkey: p2pkh_script(&hash("3bde42dbee7e4dbe6a21b2d50ce2f0167faa8159")),
+                },
+            ],
+            lock_time: 0x11,
+            values: vec![625000000, 600000000],
+        }
+    }
+
+    #[test]
+    fn bip143_signature_hash() {
+        let tx = bip143_transaction();
+        assert_eq!(
+            hex::encode(tx.serialize()),
+            "0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac11000000"
+        );
+
+        let script_code = p2pkh_script(&hash("1d0f172a0ecb48aee1be1f2687d2963ae33f71a1"));
+        let sighash = tx.signature_hash(1, &script_code, 600000000, 0x01);
+        assert_eq!(
+            hex::encode(sighash),
+            "c37af31116d1b27caf68aae9e3ac82f1477929014d5b917657d0eb49478cb670"
+        );
+    }
+
+    #[test]
+    fn forkid_signature_hash_and_signed_transaction() {
+        use ethers_core::k256::ecdsa::signature::hazmat::PrehashVerifier;
+        use ethers_core::k256::ecdsa::{Signature, VerifyingKey};
+
+        // second input of the BIP-143 example, key 619c3350...86feb9, with SIGHASH_ALL|FORKID
+        let mut tx = bip143_transaction();
+        let script_code = p2pkh_script(&hash("1d0f172a0ecb48aee1be1f2687d2963ae33f71a1"));
+        let sighash = tx.signature_hash(1, &script_code, 600000000, SIGHASH_ALL_FORKID);
+
+        // the published preimage of the example, whose sighash type becomes 0x41 with a zero
+        // fork id as specified by the replay protected sighash of Bitcoin Cash:
+        // https://github.com/bitcoincashorg/bitcoincash.org/blob/master/spec/replay-protected-sighash.md
+        let preimage = hex::decode(concat!(
+            "0100000096b827c8483d4e9b96712b6713a7b68d6e8003a781feba36c31143470b4efd37",
+            "52b0a642eea2fb7ae638c36f6252b6750293dbe574a806984b8e4d8548339a3b",
+            "ef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a01000000",
+            "1976a9141d0f172a0ecb48aee1be1f2687d2963ae33f71a188ac0046c32300000000ffffffff",
+            "863ef3e1a92afbfdb97f31ad0fc7683ee943e9abcf2501590ff8f6551f47e5e51100000001000000",
+        ))
+        .unwrap();
+        assert_eq!(
+            hex::encode(sha256d(&preimage)),
+            "c37af31116d1b27caf68aae9e3ac82f1477929014d5b917657d0eb49478cb670"
+        );
+        let forkid_preimage = [&preimage[..preimage.len() - 4], &[0x41, 0, 0, 0]].concat();
+        assert_eq!(sighash, sha256d(forkid_preimage));
+        assert_eq!(
+            hex::encode(sighash),
+            "467f411d178762db122a6aced76370a1c8324355bf0796502bf82eeaeda86a35"
+        );
+
+        let public_key =
+            hex::decode("025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee6357")
+                .unwrap();
+        let compact = hex::decode(concat!(
+            "1933278ee7fb5977b57747104245414656375b7c30468f52fb4bee72bcf9d666",
+            "460369936fbb60fec256b062fe4751b05d5f038c2816cab9ccb5a5d37f65a0b1",
+        ))
+        .unwrap();
+        let der = to_der(&compact).unwrap();
+        assert!(VerifyingKey::from_sec1_bytes(&public_key)
+            .unwrap()
+            .verify_prehash(&sighash, &Signature::from_der(&der).unwrap())
+            .is_ok());
+
+        tx.inputs[1].script_sig = p2pkh_script_sig(&der, &public_key);
+        assert_eq!(
+            hex::encode(tx.serialize()),
+            "0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a010000006a47304402201933278ee7fb5977b57747104245414656375b7c30468f52fb4bee72bcf9d6660220460369936fbb60fec256b062fe4751b05d5f038c2816cab9ccb5a5d37f65a0b14121025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee6357ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac11000000"
+        );
+    }
+}
@@ -0,0 +1,413 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bech32::{FromBase32, ToBase32, Variant};
+use candid::{CandidType, Deserialize};
+use ethers_core::k256::ecdsa::signature::hazmat::PrehashVerifier;
+use ethers_core::k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result};
+use crate::state::ecdsa::bch::{p2pkh_script, write_var_int, Transaction, TxIn, TxOut};
+use crate::state::ecdsa::hash::{hash160, sha256, sha256d};
+use crate::state::ecdsa::signature::{recoverable_signature, to_der};
+use crate::state::ecdsa::Signer;
+
+/// Prefix of messages signed with the legacy `signmessage` scheme.
+pub const MESSAGE_MAGIC: &str = "Bitcoin Signed Message:\n";
+
+const BIP322_TAG: &[u8] = b"BIP0322-signed-message";
+const SEGWIT_HRP: &str = "bc";
+const P2PKH_VERSION: u8 = 0x00;
+const SIGHASH_ALL: u32 = 0x01;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum BtcAddressType {
+    /// Legacy `1...` address, signed with the `signmessage` compact format.
+    P2pkh,
+    /// Native segwit `bc1q...` address, signed with the BIP-322 simple format.
+    P2wpkh,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct BtcSignedMessage {
+    pub address: String,
+    /// Base64 encoded signature.
+    pub signature: String,
+}
+
+pub struct BtcWallet {
+    pub signer: Signer,
+    pub pubkey_hash: [u8; 20],
+}
+
+impl BtcWallet {
+    pub fn new(signer: Signer) -> Self {
+        let pubkey_hash = hash160(signer.public_key());
+        Self {
+            signer,
+            pubkey_hash,
+        }
+    }
+
+    pub fn address(&self, address_type: BtcAddressType) -> String {
+        match address_type {
+            BtcAddressType::P2pkh => p2pkh_address(&self.pubkey_hash),
+            BtcAddressType::P2wpkh => p2wpkh_address(&self.pubkey_hash),
+        }
+    }
+
+    /// Signs `message` with the format matching the address type.
+    pub async fn sign_message(
+        &self,
+        address_type: BtcAddressType,
+        message: &str,
+    ) -> Result<BtcSignedMessage> {
+        let signature = match address_type {
+            BtcAddressType::P2pkh => self.sign_message_legacy(message).await?,
+            BtcAddressType::P2wpkh => self.sign_message_bip322(message).await?,
+        };
+        Ok(BtcSignedMessage {
+            address: self.address(address_type),
+            signature: BASE64.encode(signature),
+        })
+    }
+
+    /// 65 bytes compact signature: header byte followed by `r || s`.
+    async fn sign_message_legacy(&self, message: &str) -> Result<Vec<u8>> {
+        let hash = message_hash(message);
+        let sign = self.signer.sign_hash(hash).await?;
+        let (sig, recovery_id) = recoverable_signature(self.signer.public_key(), &hash, &sign)?;
+
+        // 27 + recovery id, plus 4 for a compressed public key
+        let mut compact = vec![31 + recovery_id.to_byte()];
+        compact.extend_from_slice(&sig.to_bytes());
+        Ok(compact)
+    }
+
+    /// BIP-322 simple signature: the serialized witness of the `to_sign` transaction.
+    async fn sign_message_bip322(&self, message: &str) -> Result<Vec<u8>> {
+        let script_pubkey = p2wpkh_script(&self.pubkey_hash);
+        let to_sign = bip322_to_sign(&script_pubkey, message);
+        let sighash = to_sign.signature_hash(0, &p2pkh_script(&self.pubkey_hash), 0, SIGHASH_ALL);
+
+        let sign = self.signer.sign_hash(sighash).await?;
+        let mut sig = to_der(&sign)?;
+        sig.push(SIGHASH_ALL as u8);
+
+        let mut witness = Vec::new();
+        write_var_int(&mut witness, 2);
+        write_var_int(&mut witness, sig.len() as u64);
+        witness.extend_from_slice(&sig);
+        write_var_int(&mut witness, self.signer.public_key().len() as u64);
+        witness.extend_from_slice(self.signer.public_key());
+        Ok(witness)
+    }
+}
+
+/// Base58check encoded P2PKH address.
+pub fn p2pkh_address(hash: &[u8; 20]) -> String {
+    let mut payload = vec![P2PKH_VERSION];
+    payload.extend_from_slice(hash);
+    bs58::encode(payload).with_check().into_string()
+}
+
+/// Bech32 encoded witness v0 P2WPKH address.
+pub fn p2wpkh_address(hash: &[u8; 20]) -> String {
+    let mut data = vec![bech32::u5::try_from_u8(0).expect("witness version fits in 5 bits")];
+    data.extend(hash.to_base32());
+    bech32::encode(SEGWIT_HRP, data, Variant::Bech32).expect("valid bech32 hrp")
+}
+
+fn p2wpkh_script(hash: &[u8; 20]) -> Vec<u8> {
+    let mut script = vec![0x00, 0x14];
+    script.extend_from_slice(hash);
+    script
+}
+
+fn decode_address(address: &str) -> Result<(BtcAddressType, [u8; 20])> {
+    let invalid = || Error::InvalidAddress(address.to_string());
+
+    if let Ok((hrp, data, variant)) = bech32::decode(address) {
+        if hrp != SEGWIT_HRP
+            || variant != Variant::Bech32
+            || data.is_empty()
+            || data[0].to_u8() != 0
+        {
+            return Err(invalid());
+        }
+        let program = Vec::<u8>::from_base32(&data[1..]).map_err(|_| invalid())?;
+        return Ok((
+            BtcAddressType::P2wpkh,
+            program.try_into().map_err(|_| invalid())?,
+        ));
+    }
+
+    let payload = bs58::decode(address)
+        .with_check(Some(P2PKH_VERSION))
+        .into_vec()
+        .map_err(|_| invalid())?;
+    Ok((
+        BtcAddressType::P2pkh,
+        payload[1..].try_into().map_err(|_| invalid())?,
+    ))
+}
+
+/// Digest signed by the legacy `signmessage` scheme.
+pub fn message_hash(message: &str) -> [u8; 32] {
+    let mut buf = Vec::with_capacity(MESSAGE_MAGIC.len() + message.len() + 10);
+    write_var_int(&mut buf, MESSAGE_MAGIC.len() as u64);
+    buf.extend_from_slice(MESSAGE_MAGIC.as_bytes());
+    write_var_int(&mut buf, message.len() as u64);
+    buf.extend_from_slice(message.as_bytes());
+    sha256d(buf)
+}
+
+/// BIP-340 style tagged hash of the message, committed to by the `to_spend` transaction.
+pub fn bip322_message_hash(message: &str) -> [u8; 32] {
+    let tag = sha256(BIP322_TAG);
+    Sha256::new()
+        .chain_update(tag)
+        .chain_update(tag)
+        .chain_update(message.as_bytes())
+        .finalize()
+        .into()
+}
+
+fn bip322_to_spend(script_pubkey: &[u8], message: &str) -> Transaction {
+    // OP_0 PUSH32 <message hash>
+    let mut script_sig = vec![0x00, 0x20];
+    script_sig.extend_from_slice(&bip322_message_hash(message));
+
+    Transaction {
+        version: 0,
+        inputs: vec![TxIn {
+            prev_txid: [0; 32],
+            vout: 0xffff_ffff,
+            script_sig,
+            sequence: 0,
+        }],
+        outputs: vec![TxOut {
+            value: 0,
+            script_pubkey: script_pubkey.to_vec(),
+        }],
+        lock_time: 0,
+        values: vec![],
+    }
+}
+
+fn bip322_to_sign(script_pubkey: &[u8], message: &str) -> Transaction {
+    let to_spend = bip322_to_spend(script_pubkey, message);
+
+    Transaction {
+        version: 0,
+        inputs: vec![TxIn {
+            prev_txid: sha256d(to_spend.serialize()),
+            vout: 0,
+            script_sig: vec![],
+            sequence: 0,
+        }],
+        outputs: vec![TxOut {
+            value: 0,
+            // OP_RETURN
+            script_pubkey: vec![0x6a],
+        }],
+        lock_time: 0,
+        values: vec![0],
+    }
+}
+
+/// Checks a message signature produced by any wallet for a P2PKH or P2WPKH address.
+///
+/// Legacy compact signatures are accepted for both address types, BIP-322 simple
+/// signatures only for P2WPKH addresses.
+pub fn verify_message(address: &str, message: &str, signature: &str) -> Result<bool> {
+    let (address_type, hash) = decode_address(address)?;
+    let signature = BASE64
+        .decode(signature)
+        .map_err(|_| Error::InvalidSignature(signature.to_string()))?;
+
+    if signature.len() == 65 && (27..=42).contains(&signature[0]) {
+        return Ok(verify_legacy(address_type, &hash, message, &signature));
+    }
+
+    match address_type {
+        BtcAddressType::P2wpkh => verify_bip322(&hash, message, &signature),
+        BtcAddressType::P2pkh => Err(Error::InvalidSignature(
+            "BIP-322 signatures are only supported for P2WPKH addresses".to_string(),
+        )),
+    }
+}
+
+fn verify_legacy(
+    address_type: BtcAddressType,
+    hash: &[u8; 20],
+    message: &str,
+    signature: &[u8],
+) -> bool {
+    let header = signature[0] - 27;
+    // headers above 30 are produced for compressed keys, including the segwit
+    // flavoured ones used by Electrum and Trezor
+    let compressed = header >= 4;
+    if address_type == BtcAddressType::P2wpkh && !compressed {
+        return false;
+    }
+
+    let recovered = RecoveryId::from_byte(header & 3).and_then(|id| {
+        let sig = Signature::from_slice(&signature[1..]).ok()?;
+        VerifyingKey::recover_from_prehash(&message_hash(message), &sig, id).ok()
+    });
+
+    match recovered {
+        Some(key) => hash160(key.to_encoded_point(compressed).as_bytes()) == *hash,
+        None => false,
+    }
+}
+
+fn verify_bip322(hash: &[u8; 20], message: &str, witness: &[u8]) -> Result<bool> {
+    let items =
+        parse_witness(witness).ok_or_else(|| Error::InvalidSignature(BASE64.encode(witness)))?;
+    let [sig, public_key] = items.as_slice() else {
+        return Ok(false);
+    };
+    let Some((sighash_type, der)) = sig.split_last() else {
+        return Ok(false);
+    };
+    if *sighash_type as u32 != SIGHASH_ALL || hash160(public_key) != *hash {
+        return Ok(false);
+    }
+
+    let (Ok(key), Ok(sig)) = (
+        VerifyingKey::from_sec1_bytes(public_key),
+        Signature::from_der(der),
+    ) else {
+        return Ok(false);
+    };
+    let sig = sig.normalize_s().unwrap_or(sig);
+
+    let to_sign = bip322_to_sign(&p2wpkh_script(hash), message);
+    let sighash = to_sign.signature_hash(0, &p2pkh_script(hash), 0, SIGHASH_ALL);
+    Ok(key.verify_prehash(&sighash, &sig).is_ok())
+}
+
+fn parse_witness(bytes: &[u8]) -> Option<Vec<&[u8]>> {
+    fn read_len(bytes: &[u8], pos: &mut usize) -> Option<usize> {
+        // witness items and counts of simple signatures always fit a single byte
+        let len = *bytes.get(*pos)?;
+        if len >= 0xfd {
+            return None;
+        }
+        *pos += 1;
+        Some(len as usize)
+    }
+
+    let mut pos = 0;
+    let count = read_len(bytes, &mut pos)?;
+    let mut items = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len = read_len(bytes, &mut pos)?;
+        items.push(bytes.get(pos..pos + len)?);
+        pos += len;
+    }
+    (pos == bytes.len()).then_some(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test vectors from https://github.com/bitcoin/bips/blob/master/bip-0322.mediawiki#test-vectors
+    const BIP322_ADDRESS: &str = "bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l";
+
+    fn txid(tx: &Transaction) -> String {
+        let mut id = sha256d(tx.serialize());
+        id.reverse();
+        hex::encode(id)
+    }
+
+    #[test]
+    fn bip322_message_hashes() {
+        assert_eq!(
+            hex::encode(bip322_message_hash("")),
+            "c90c269c4f8fcbe6880f72a721ddfbf1914268a794cbb21cfafee13770ae19f1"
+        );
+        assert_eq!(
+            hex::encode(bip322_message_hash("Hello World")),
+            "f0eb03b1a75ac6d9847f55c624a99169b5dccba2a31f5b23bea77ba270de0a7a"
+        );
+    }
+
+    #[test]
+    fn bip322_transaction_ids() {
+        let (_, hash) = decode_address(BIP322_ADDRESS).unwrap();
+        let script_pubkey = p2wpkh_script(&hash);
+
+        assert_eq!(
+            txid(&bip322_to_spend(&script_pubkey, "")),
+            "c5680aa69bb8d860bf82d4e9cd3504b55dde018de765a91bb566283c545a99a7"
+        );
+        assert_eq!(
+            txid(&bip322_to_sign(&script_pubkey, "")),
+            "1e9654e951a5ba44c8604c4de6c67fd78a27e81dcadcfe1edf638ba3aaebaed6"
+        );
+        assert_eq!(
+            txid(&bip322_to_spend(&script_pubkey, "Hello World")),
+            "b79d196740ad5217771c1098fc4a4b51e0535c32236c71f1ea4d61a2d603352b"
+        );
+        assert_eq!(
+            txid(&bip322_to_sign(&script_pubkey, "Hello World")),
+            "88737ae86f2077145f93cc4b153ae9a1cb8d56afa511988c149c5c8c9d93bddf"
+        );
+    }
+
+    #[test]
+    fn verifies_bip322_signatures() {
+        let empty = "AkcwRAIgM2gBAQqvZX15ZiysmKmQpDrG83avLIT492QBzLnQIxYCIBaTpOaD20qRlEylyxFSeEA2ba9YOixpX8z46TSDtS40ASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=";
+        let hello = "AkcwRAIgZRfIY3p7/DoVTty6YZbWS71bc5Vct9p9Fia83eRmw2QCICK/ENGfwLtptFluMGs2KsqoNSk89pO7F29zJLUx9a/sASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=";
+
+        assert!(verify_message(BIP322_ADDRESS, "", empty).unwrap());
+        assert!(verify_message(BIP322_ADDRESS, "Hello World", hello).unwrap());
+        assert!(!verify_message(BIP322_ADDRESS, "Hello World", empty).unwrap());
+        assert!(!verify_message(
+            "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq",
+            "Hello World",
+            hello
+        )
+        .unwrap());
+    }
+
+    #[test]
+    fn verifies_legacy_signatures() {
+        // deterministic (RFC 6979) signature by the compressed key
+        // L4rK1yDtCWekvXuE6oXD9jCYfFNV2cWRpVuPLBcCU2z8TrisoyY1, as `bitcoin-cli signmessage` produces
+        let signature = "HwxMsQJ+s7mMjDBiCFyRfHk5SOdPnfIQbszlBLNIBE6efexHARz5is8619IZ1XLlfXDlTzlYxaY/XaLRv/oKlxQ=";
+        let legacy = "1F3sAm6ZtwLAUnj7d38pGFxtP3RVEvtsbV";
+        assert!(verify_message(legacy, "vires is numeris", signature).unwrap());
+        assert!(!verify_message(legacy, "vires in numeris", signature).unwrap());
+        assert!(!verify_message(BIP322_ADDRESS, "vires is numeris", signature).unwrap());
+
+        // the same key controls the P2WPKH address of its hash
+        let (_, hash) = decode_address(legacy).unwrap();
+        assert!(verify_message(&p2wpkh_address(&hash), "vires is numeris", signature).unwrap());
+    }
+
+    #[test]
+    fn encodes_addresses() {
+        let hash: [u8; 20] = hex::decode("751e76e8199196d454941c45d1b3a323f1433bd6")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let segwit = p2wpkh_address(&hash);
+        let legacy = p2pkh_address(&hash);
+
+        assert_eq!(segwit, "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4");
+        assert_eq!(legacy, "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH");
+        assert_eq!(
+            decode_address(&segwit).unwrap(),
+            (BtcAddressType::P2wpkh, hash)
+        );
+        assert_eq!(
+            decode_address(&legacy).unwrap(),
+            (BtcAddressType::P2pkh, hash)
+        );
+    }
+}
@@ -0,0 +1,41 @@
+//! Minimal CBOR writer for the definite length encodings signed by Filecoin
+//! and the Internet Computer.
+
+pub const CBOR_UINT: u8 = 0;
+pub const CBOR_BYTES: u8 = 2;
+pub const CBOR_TEXT: u8 = 3;
+pub const CBOR_ARRAY: u8 = 4;
+pub const CBOR_MAP: u8 = 5;
+pub const CBOR_TAG: u8 = 6;
+
+/// Writes the head of a data item of major type `major` and argument `value`,
+/// using the shortest form.
+pub fn write_header(buf: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+    match value {
+        0..=23 => buf.push(major | value as u8),
+        24..=0xff => buf.extend_from_slice(&[major | 24, value as u8]),
+        0x100..=0xffff => {
+            buf.push(major | 25);
+            buf.extend_from_slice(&(value as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            buf.push(major | 26);
+            buf.extend_from_slice(&(value as u32).to_be_bytes());
+        }
+        _ => {
+            buf.push(major | 27);
+            buf.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+}
+
+pub fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_header(buf, CBOR_BYTES, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+pub fn write_text(buf: &mut Vec<u8>, text: &str) {
+    write_header(buf, CBOR_TEXT, text.len() as u64);
+    buf.extend_from_slice(text.as_bytes());
+}
@@ -0,0 +1,334 @@
+use bech32::{ToBase32, Variant};
+use candid::{CandidType, Deserialize};
+use ethers_core::types::Address;
+
+use crate::error::{Error, Result};
+use crate::state::ecdsa::eth::{keccak256, public_key_to_address};
+use crate::state::ecdsa::hash::{hash160, sha256};
+use crate::state::ecdsa::protobuf::ProtoWriter;
+use crate::state::ecdsa::signature::{normalized_signature, recoverable_signature};
+use crate::state::ecdsa::Signer;
+
+pub const SECP256K1_PUBKEY_TYPE: &str = "/cosmos.crypto.secp256k1.PubKey";
+pub const ETHSECP256K1_PUBKEY_TYPE: &str = "/ethermint.crypto.v1.ethsecp256k1.PubKey";
+pub const MSG_SEND_TYPE: &str = "/cosmos.bank.v1beta1.MsgSend";
+
+const SIGN_MODE_DIRECT: u64 = 1;
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct CosmosCoin {
+    pub denom: String,
+    /// Integer amount in the smallest denomination.
+    pub amount: String,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub enum CosmosMsg {
+    /// `cosmos.bank.v1beta1.MsgSend` from the caller's address.
+    BankSend {
+        to_address: String,
+        amount: Vec<CosmosCoin>,
+    },
+    /// Any other message, with `value` being its protobuf encoding.
+    Any { type_url: String, value: Vec<u8> },
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct CosmosFee {
+    pub amount: Vec<CosmosCoin>,
+    pub gas_limit: u64,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct CosmosTransaction {
+    pub messages: Vec<CosmosMsg>,
+    pub memo: String,
+    pub timeout_height: u64,
+    pub fee: CosmosFee,
+    pub account_number: u64,
+    pub sequence: u64,
+}
+
+/// Flavour of the account key, deciding the address, the public key type and
+/// the hash of the sign doc.
+pub enum CosmosKeyType {
+    /// Standard Cosmos SDK key: RIPEMD160(SHA256) address, SHA-256 sign doc hash.
+    Secp256k1,
+    /// Ethermint `ethsecp256k1` key: EVM address, Keccak-256 sign doc hash.
+    /// Holds the public key type url, which differs between chains.
+    EthSecp256k1(String),
+}
+
+pub struct CosmosWallet {
+    pub signer: Signer,
+    pub key_type: CosmosKeyType,
+    pub address: String,
+    pub chain_id: String,
+}
+
+impl CosmosWallet {
+    pub fn new(signer: Signer, hrp: &str, chain_id: String) -> Result<Self> {
+        let address = bech32_address(hrp, &hash160(signer.public_key()))?;
+        Ok(Self {
+            signer,
+            key_type: CosmosKeyType::Secp256k1,
+            address,
+            chain_id,
+        })
+    }
+
+    /// Wallet of an Ethermint based chain (Evmos, Injective, Cronos...), whose account
+    /// is the bech32 form of the same address as `EthWallet::address`.
+    ///
+    /// `pub_key_type` defaults to the Ethermint `ethsecp256k1` type url.
+    pub fn new_ethermint(
+        signer: Signer,
+        hrp: &str,
+        chain_id: String,
+        pub_key_type: Option<String>,
+    ) -> Result<Self> {
+        let address = ethermint_address(hrp, public_key_to_address(signer.public_key())?)?;
+        Ok(Self {
+            signer,
+            key_type: CosmosKeyType::EthSecp256k1(
+                pub_key_type.unwrap_or_else(|| ETHSECP256K1_PUBKEY_TYPE.to_string()),
+            ),
+            address,
+            chain_id,
+        })
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Signs the transaction in `SIGN_MODE_DIRECT` and returns the encoded `TxRaw`.
+    pub async fn sign_transaction(&self, tx: &CosmosTransaction) -> Result<Vec<u8>> {
+        let pub_key_type = match &self.key_type {
+            CosmosKeyType::Secp256k1 => SECP256K1_PUBKEY_TYPE,
+            CosmosKeyType::EthSecp256k1(type_url) => type_url,
+        };
+        let body = tx.body_bytes(&self.address)?;
+        let auth_info = tx.auth_info_bytes(pub_key_type, self.signer.public_key());
+        let sign_doc = sign_doc_bytes(&body, &auth_info, &self.chain_id, tx.account_number);
+
+        let signature = match self.key_type {
+            CosmosKeyType::Secp256k1 => {
+                let sign = self.signer.sign_hash(sha256(sign_doc)).await?;
+                normalized_signature(&sign)?.to_bytes().to_vec()
+            }
+            CosmosKeyType::EthSecp256k1(_) => {
+                // `ethsecp256k1` signatures are 65 bytes `r || s || v` with v being the recovery id
+                let hash = keccak256(sign_doc);
+                let sign = self.signer.sign_hash(hash).await?;
+                let (sig, recovery_id) =
+                    recoverable_signature(self.signer.public_key(), &hash, &sign)?;
+                let mut signature = sig.to_bytes().to_vec();
+                signature.push(recovery_id.to_byte());
+                signature
+            }
+        };
+
+        Ok(tx_raw_bytes(&body, &auth_info, &signature))
+    }
+}
+
+/// Bech32 form of an EVM address, as used by the Cosmos side of Ethermint chains.
+pub fn ethermint_address(hrp: &str, address: Address) -> Result<String> {
+    bech32_address(hrp, &address.0)
+}
+
+/// Bech32 encoding of a 20 bytes account address.
+pub fn bech32_address(hrp: &str, hash: &[u8; 20]) -> Result<String> {
+    bech32::encode(hrp, hash.to_base32(), Variant::Bech32)
+        .map_err(|e| Error::InvalidAddress(format!("{hrp}: {e}")))
+}
+
+fn check_address(address: &str) -> Result<()> {
+    match bech32::decode(address) {
+        Ok((_, _, Variant::Bech32)) => Ok(()),
+        _ => Err(Error::InvalidAddress(address.to_string())),
+    }
+}
+
+impl CosmosTransaction {
+    /// `cosmos.tx.v1beta1.TxBody`
+    pub fn body_bytes(&self, from_address: &str) -> Result<Vec<u8>> {
+        let mut body = ProtoWriter::new();
+        for msg in &self.messages {
+            body.message(1, &msg.to_any(from_address)?);
+        }
+        body.string(2, &self.memo).uint64(3, self.timeout_height);
+        Ok(body.into_bytes())
+    }
+
+    /// `cosmos.tx.v1beta1.AuthInfo` with a single `SIGN_MODE_DIRECT` signer.
+    pub fn auth_info_bytes(&self, pub_key_type: &str, public_key: &[u8]) -> Vec<u8> {
+        let mut pub_key = ProtoWriter::new();
+        pub_key.bytes(1, public_key);
+        let pub_key = any(pub_key_type, &pub_key.into_bytes());
+
+        let mut single = ProtoWriter::new();
+        single.uint64(1, SIGN_MODE_DIRECT);
+        let mut mode_info = ProtoWriter::new();
+        mode_info.message(1, &single.into_bytes());
+
+        let mut signer_info = ProtoWriter::new();
+        signer_info
+            .message(1, &pub_key)
+            .message(2, &mode_info.into_bytes())
+            .uint64(3, self.sequence);
+
+        let mut fee = ProtoWriter::new();
+        for coin in &self.fee.amount {
+            fee.message(1, &coin.to_bytes());
+        }
+        fee.uint64(2, self.fee.gas_limit);
+
+        let mut auth_info = ProtoWriter::new();
+        auth_info
+            .message(1, &signer_info.into_bytes())
+            .message(2, &fee.into_bytes());
+        auth_info.into_bytes()
+    }
+}
+
+impl CosmosMsg {
+    fn to_any(&self, from_address: &str) -> Result<Vec<u8>> {
+        match self {
+            Self::BankSend { to_address, amount } => {
+                check_address(to_address)?;
+                let mut msg = ProtoWriter::new();
+                msg.string(1, from_address).string(2, to_address);
+                for coin in amount {
+                    msg.message(3, &coin.to_bytes());
+                }
+                Ok(any(MSG_SEND_TYPE, &msg.into_bytes()))
+            }
+            Self::Any { type_url, value } => Ok(any(type_url, value)),
+        }
+    }
+}
+
+impl CosmosCoin {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut coin = ProtoWriter::new();
+        coin.string(1, &self.denom).string(2, &self.amount);
+        coin.into_bytes()
+    }
+}
+
+/// `google.protobuf.Any`
+fn any(type_url: &str, value: &[u8]) -> Vec<u8> {
+    let mut any = ProtoWriter::new();
+    any.string(1, type_url).bytes(2, value);
+    any.into_bytes()
+}
+
+/// `cosmos.tx.v1beta1.SignDoc`
+pub fn sign_doc_bytes(
+    body: &[u8],
+    auth_info: &[u8],
+    chain_id: &str,
+    account_number: u64,
+) -> Vec<u8> {
+    let mut sign_doc = ProtoWriter::new();
+    sign_doc
+        .bytes(1, body)
+        .bytes(2, auth_info)
+        .string(3, chain_id)
+        .uint64(4, account_number);
+    sign_doc.into_bytes()
+}
+
+/// `cosmos.tx.v1beta1.TxRaw`
+pub fn tx_raw_bytes(body: &[u8], auth_info: &[u8], signature: &[u8]) -> Vec<u8> {
+    let mut tx_raw = ProtoWriter::new();
+    tx_raw
+        .bytes(1, body)
+        .bytes(2, auth_info)
+        .repeated_bytes(3, signature);
+    tx_raw.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address_from_public_key() {
+        // https://github.com/cosmos/cosmjs/blob/main/packages/amino/src/addresses.spec.ts
+        let public_key =
+            hex::decode("02d41a0aa167b21699429eab224bc03f2cd386f0af5d20cefbd0336f1544aea24f")
+                .unwrap();
+        assert_eq!(
+            bech32_address("cosmos", &hash160(public_key)).unwrap(),
+            "cosmos1h806c7khnvmjlywdrkdgk2vrayy2mmvf9rxk2r"
+        );
+    }
+
+    #[test]
+    fn encodes_bank_send() {
+        let tx = CosmosTransaction {
+            messages: vec![CosmosMsg::BankSend {
+                to_address: "cosmos1h806c7khnvmjlywdrkdgk2vrayy2mmvf9rxk2r".to_string(),
+                amount: vec![CosmosCoin {
+                    denom: "uatom".to_string(),
+                    amount: "1234".to_string(),
+                }],
+            }],
+            memo: String::new(),
+            timeout_height: 0,
+            fee: CosmosFee {
+                amount: vec![],
+                gas_limit: 200000,
+            },
+            account_number: 0,
+            sequence: 0,
+        };
+
+        let from = "cosmos1h806c7khnvmjlywdrkdgk2vrayy2mmvf9rxk2r";
+        let body = tx.body_bytes(from).unwrap();
+        let coin = "0a057561746f6d120431323334";
+        let msg = format!(
+            "0a2d{}122d{}1a0d{coin}",
+            hex::encode(from),
+            hex::encode(from)
+        );
+        let any = format!("0a1c{}126d{msg}", hex::encode(MSG_SEND_TYPE));
+        assert_eq!(hex::encode(body), format!("0a8d01{any}"));
+
+        // the empty fee amount and the zero sequence are omitted
+        let auth_info = tx.auth_info_bytes(SECP256K1_PUBKEY_TYPE, &[2; 33]);
+        let pub_key = format!("0a2102{}", "02".repeat(32));
+        let pub_key_any = format!("0a1f{}1223{pub_key}", hex::encode(SECP256K1_PUBKEY_TYPE));
+        let signer_info = format!("0a46{pub_key_any}12040a020801");
+        assert_eq!(
+            hex::encode(auth_info),
+            format!("0a4e{signer_info}120410c09a0c")
+        );
+    }
+
+    #[test]
+    fn ethermint_address_from_evm_address() {
+        // https://docs.evmos.org/protocol/concepts/accounts#address-conversion
+        let address = "0x7cB61D4117AE31a12E393a1Cfa3BaC666481D02E"
+            .parse::<Address>()
+            .unwrap();
+        assert_eq!(
+            ethermint_address("evmos", address).unwrap(),
+            "evmos10jmp6sgh4cc6zt3e8gw05wavvejgr5pwjnpcky"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_recipient() {
+        let msg = CosmosMsg::BankSend {
+            to_address: "cosmos1h806c7khnvmjlywdrkdgk2vrayy2mmvf9rxk2s".to_string(),
+            amount: vec![],
+        };
+        assert!(msg
+            .to_any("cosmos1h806c7khnvmjlywdrkdgk2vrayy2mmvf9rxk2r")
+            .is_err());
+    }
+}
@@ -0,0 +1,374 @@
+use blake2::digest::consts::{U20, U4};
+use blake2::{Blake2b, Digest};
+use candid::{CandidType, Deserialize};
+use ethers_core::k256::elliptic_curve::sec1::ToEncodedPoint;
+use ethers_core::k256::PublicKey;
+use ethers_core::types::Address;
+
+use crate::error::{Error, Result};
+use crate::state::ecdsa::cbor::{write_bytes, write_header, CBOR_ARRAY, CBOR_UINT};
+use crate::state::ecdsa::eth::public_key_to_address;
+use crate::state::ecdsa::hash::blake2b_256;
+use crate::state::ecdsa::signature::recoverable_signature;
+use crate::state::ecdsa::Signer;
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+const PROTOCOL_ID: u8 = 0;
+const PROTOCOL_SECP256K1: u8 = 1;
+const PROTOCOL_ACTOR: u8 = 2;
+const PROTOCOL_BLS: u8 = 3;
+const PROTOCOL_DELEGATED: u8 = 4;
+/// Actor id of the Ethereum address manager, namespace of f410 addresses.
+pub const EAM_ACTOR_ID: u64 = 10;
+
+const SIGNATURE_SECP256K1: u8 = 1;
+/// `InvokeContract` method number of EVM actors.
+pub const INVOKE_EVM_METHOD: u64 = 3_844_450_837;
+/// CIDv1, DAG-CBOR codec and BLAKE2b-256 multihash of 32 bytes.
+const CID_PREFIX: [u8; 6] = [0x01, 0x71, 0xa0, 0xe4, 0x02, 0x20];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum FilecoinNetwork {
+    Mainnet,
+    Testnet,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub enum FilecoinMethod {
+    /// Plain FIL transfer.
+    Send,
+    /// Call of an EVM actor with ABI encoded `calldata`, as sent by FEVM wallets.
+    InvokeEvm { calldata: Vec<u8> },
+    /// Any method with its CBOR encoded params.
+    Raw { method: u64, params: Vec<u8> },
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct FilecoinMessage {
+    /// f0, f1, f2, f3 or f4 address, or a `0x` address converted to its f410 address.
+    pub to: String,
+    pub nonce: u64,
+    /// Value in attoFIL.
+    pub value: u128,
+    pub gas_limit: u64,
+    pub gas_fee_cap: u128,
+    pub gas_premium: u128,
+    pub method: FilecoinMethod,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct FilecoinSignedMessage {
+    /// CID of the signed message.
+    pub cid: String,
+    /// Hex encoded CBOR of the `SignedMessage`.
+    pub signed_message: String,
+    /// Hex encoded 65 bytes `r || s || recovery id` signature.
+    pub signature: String,
+}
+
+pub struct FilecoinWallet {
+    pub signer: Signer,
+    /// Protocol byte followed by the BLAKE2b-160 hash of the uncompressed public key.
+    pub address: [u8; 21],
+}
+
+impl FilecoinWallet {
+    pub fn new(signer: Signer) -> Result<Self> {
+        let public_key = PublicKey::from_sec1_bytes(signer.public_key())
+            .map_err(|_| Error::InvalidPublicKey(hex::encode(signer.public_key())))?;
+        let hash: [u8; 20] = Blake2b::<U20>::digest(public_key.to_encoded_point(false)).into();
+
+        let mut address = [PROTOCOL_SECP256K1; 21];
+        address[1..].copy_from_slice(&hash);
+        Ok(Self { signer, address })
+    }
+
+    /// `f1...` or `t1...` address.
+    pub fn address(&self, network: FilecoinNetwork) -> String {
+        encode_address(network, &self.address)
+    }
+
+    /// The Ethereum address of the same key, usable on FEVM.
+    pub fn eth_address(&self) -> Result<Address> {
+        public_key_to_address(self.signer.public_key())
+    }
+
+    /// The `f410...` address of the Ethereum address of the same key.
+    pub fn delegated_address(&self, network: FilecoinNetwork) -> Result<String> {
+        Ok(encode_address(
+            network,
+            &delegated_address_bytes(&self.eth_address()?),
+        ))
+    }
+
+    pub async fn sign_message(&self, msg: &FilecoinMessage) -> Result<FilecoinSignedMessage> {
+        let message = msg.to_cbor(&self.address)?;
+        let hash = blake2b_256(cid_bytes(&message));
+
+        let sign = self.signer.sign_hash(hash).await?;
+        let (sig, recovery_id) = recoverable_signature(self.signer.public_key(), &hash, &sign)?;
+        let mut signature = sig.to_bytes().to_vec();
+        signature.push(recovery_id.to_byte());
+
+        let mut signed = vec![];
+        write_header(&mut signed, CBOR_ARRAY, 2);
+        signed.extend(message);
+        write_bytes(
+            &mut signed,
+            &[&[SIGNATURE_SECP256K1], signature.as_slice()].concat(),
+        );
+
+        Ok(FilecoinSignedMessage {
+            cid: format!("b{}", base32_encode(&cid_bytes(&signed))),
+            signed_message: hex::encode(signed),
+            signature: hex::encode(signature),
+        })
+    }
+}
+
+impl FilecoinMessage {
+    /// DAG-CBOR encoding of the `Message` sent from `from`.
+    pub fn to_cbor(&self, from: &[u8]) -> Result<Vec<u8>> {
+        let (method, params) = match &self.method {
+            FilecoinMethod::Send => (0, vec![]),
+            FilecoinMethod::InvokeEvm { calldata } => {
+                // EVM actors take their calldata as a CBOR byte string
+                let mut params = vec![];
+                write_bytes(&mut params, calldata);
+                (INVOKE_EVM_METHOD, params)
+            }
+            FilecoinMethod::Raw { method, params } => (*method, params.clone()),
+        };
+
+        let mut buf = vec![];
+        write_header(&mut buf, CBOR_ARRAY, 10);
+        // version
+        write_header(&mut buf, CBOR_UINT, 0);
+        write_bytes(&mut buf, &decode_address(&self.to)?);
+        write_bytes(&mut buf, from);
+        write_header(&mut buf, CBOR_UINT, self.nonce);
+        write_bytes(&mut buf, &big_int_bytes(self.value));
+        write_header(&mut buf, CBOR_UINT, self.gas_limit);
+        write_bytes(&mut buf, &big_int_bytes(self.gas_fee_cap));
+        write_bytes(&mut buf, &big_int_bytes(self.gas_premium));
+        write_header(&mut buf, CBOR_UINT, method);
+        write_bytes(&mut buf, &params);
+        Ok(buf)
+    }
+}
+
+/// CID bytes of a DAG-CBOR block.
+fn cid_bytes(block: &[u8]) -> Vec<u8> {
+    [CID_PREFIX.as_slice(), &blake2b_256(block)].concat()
+}
+
+/// Filecoin big integers: empty for zero, else a sign byte and the big endian magnitude.
+fn big_int_bytes(value: u128) -> Vec<u8> {
+    if value == 0 {
+        return vec![];
+    }
+    let bytes = value.to_be_bytes();
+    let start = (value.leading_zeros() / 8) as usize;
+    [&[0], &bytes[start..]].concat()
+}
+
+fn write_leb128(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn read_leb128(bytes: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, &byte) in bytes.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+fn checksum(address: &[u8]) -> [u8; 4] {
+    Blake2b::<U4>::digest(address).into()
+}
+
+/// Address bytes of the f410 address of an Ethereum address.
+pub fn delegated_address_bytes(address: &Address) -> Vec<u8> {
+    let mut bytes = vec![PROTOCOL_DELEGATED];
+    write_leb128(&mut bytes, EAM_ACTOR_ID);
+    bytes.extend_from_slice(address.as_bytes());
+    bytes
+}
+
+/// String form of address bytes.
+pub fn encode_address(network: FilecoinNetwork, address: &[u8]) -> String {
+    let network = match network {
+        FilecoinNetwork::Mainnet => 'f',
+        FilecoinNetwork::Testnet => 't',
+    };
+    let protocol = address[0];
+    let payload = &address[1..];
+    match protocol {
+        PROTOCOL_ID => {
+            let (id, _) = read_leb128(payload).unwrap_or_default();
+            format!("{network}0{id}")
+        }
+        PROTOCOL_DELEGATED => {
+            let (namespace, len) = read_leb128(payload).unwrap_or_default();
+            let data = [&payload[len..], checksum(address).as_slice()].concat();
+            format!("{network}4{namespace}f{}", base32_encode(&data))
+        }
+        _ => {
+            let data = [payload, checksum(address).as_slice()].concat();
+            format!("{network}{protocol}{}", base32_encode(&data))
+        }
+    }
+}
+
+/// Address bytes of a `f`/`t` address, or of the f410 address of a `0x` address.
+pub fn decode_address(address: &str) -> Result<Vec<u8>> {
+    let invalid = || Error::InvalidAddress(address.to_string());
+
+    if address.starts_with("0x") {
+        let eth_address = address.parse::<Address>().map_err(|_| invalid())?;
+        return Ok(delegated_address_bytes(&eth_address));
+    }
+    if !address.starts_with(['f', 't']) || !address.is_ascii() || address.len() < 3 {
+        return Err(invalid());
+    }
+    let protocol = address[1..2].parse::<u8>().map_err(|_| invalid())?;
+    let rest = &address[2..];
+
+    let mut bytes = vec![protocol];
+    let (payload, payload_len) = match protocol {
+        PROTOCOL_ID => {
+            let id = rest.parse::<u64>().map_err(|_| invalid())?;
+            write_leb128(&mut bytes, id);
+            return Ok(bytes);
+        }
+        PROTOCOL_SECP256K1 | PROTOCOL_ACTOR => (rest, Some(20)),
+        PROTOCOL_BLS => (rest, Some(48)),
+        PROTOCOL_DELEGATED => {
+            let (namespace, sub_address) = rest.split_once('f').ok_or_else(invalid)?;
+            let namespace = namespace.parse::<u64>().map_err(|_| invalid())?;
+            write_leb128(&mut bytes, namespace);
+            (sub_address, None)
+        }
+        _ => return Err(invalid()),
+    };
+
+    // non canonical encodings differ in their padding bits
+    let data = base32_decode(payload)
+        .filter(|data| base32_encode(data) == payload)
+        .ok_or_else(invalid)?;
+    if data.len() < 4 || payload_len.is_some_and(|len| data.len() != len + 4) {
+        return Err(invalid());
+    }
+    let (payload, sum) = data.split_at(data.len() - 4);
+    bytes.extend_from_slice(payload);
+    if checksum(&bytes) != sum {
+        return Err(invalid());
+    }
+    Ok(bytes)
+}
+
+/// Unpadded lower case RFC 4648 base32.
+fn base32_encode(data: &[u8]) -> String {
+    let mut result = String::new();
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for &byte in data {
+        buffer = (buffer << 8) | byte as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        result.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    result
+}
+
+fn base32_decode(data: &str) -> Option<Vec<u8>> {
+    let mut result = vec![];
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for c in data.bytes() {
+        let value = BASE32_ALPHABET.iter().position(|&a| a == c)? as u16;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((buffer >> bits) as u8);
+        }
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_addresses() {
+        for address in [
+            "f01729",
+            "f17uoq6tp427uzv7fztkbsnn64iwotfrristwpryy",
+            "f410f2oekwcmo2pueydmaq53eic2i62crtbeyuzx2gmy",
+        ] {
+            let bytes = decode_address(address).unwrap();
+            assert_eq!(encode_address(FilecoinNetwork::Mainnet, &bytes), address);
+        }
+        assert!(decode_address("f17uoq6tp427uzv7fztkbsnn64iwotfrristwprya").is_err());
+        assert!(decode_address("f17uoq6tp427uzv7fztkbsnn64iwotfrristwpryz").is_err());
+
+        let eth_address = "0xd388ab098ed3e84c0d808776440b48f685198498";
+        assert_eq!(
+            encode_address(
+                FilecoinNetwork::Testnet,
+                &decode_address(eth_address).unwrap()
+            ),
+            "t410f2oekwcmo2pueydmaq53eic2i62crtbeyuzx2gmy"
+        );
+    }
+
+    #[test]
+    fn encodes_big_ints() {
+        assert_eq!(big_int_bytes(0), Vec::<u8>::new());
+        assert_eq!(big_int_bytes(1), vec![0, 1]);
+        assert_eq!(big_int_bytes(0x0100), vec![0, 1, 0]);
+    }
+
+    #[test]
+    fn encodes_messages() {
+        let from = decode_address("f17uoq6tp427uzv7fztkbsnn64iwotfrristwpryy").unwrap();
+        let msg = FilecoinMessage {
+            to: "f01729".to_string(),
+            nonce: 1,
+            value: 1000,
+            gas_limit: 25_000,
+            gas_fee_cap: 0,
+            gas_premium: 256,
+            method: FilecoinMethod::InvokeEvm {
+                calldata: vec![0xaa, 0xbb],
+            },
+        };
+        // f01729 is the id 1729 in LEB128, the value and gas premium big integers
+        // are sign prefixed, the calldata is a byte string within the params byte string
+        let expected = format!(
+            "8a004300c10d55{}01430003e81961a840430001001ae525aa154342aabb",
+            hex::encode(&from)
+        );
+        assert_eq!(hex::encode(msg.to_cbor(&from).unwrap()), expected);
+    }
+}
@@ -0,0 +1,29 @@
+use blake2::digest::consts::U32;
+use blake2::{Blake2b, Blake2b512};
+use ripemd::Ripemd160;
+use sha2::{Digest, Sha256};
+
+/// Compute the SHA-256 hash of input bytes.
+pub fn sha256<T: AsRef<[u8]>>(bytes: T) -> [u8; 32] {
+    Sha256::digest(bytes.as_ref()).into()
+}
+
+/// Compute the double SHA-256 hash of input bytes, as used by Bitcoin and its forks.
+pub fn sha256d<T: AsRef<[u8]>>(bytes: T) -> [u8; 32] {
+    sha256(sha256(bytes))
+}
+
+/// Compute RIPEMD160(SHA256(bytes)), the Bitcoin style public key hash.
+pub fn hash160<T: AsRef<[u8]>>(bytes: T) -> [u8; 20] {
+    Ripemd160::digest(sha256(bytes)).into()
+}
+
+/// Compute the 256 bits BLAKE2b hash of input bytes, `blake2_256` in Substrate.
+pub fn blake2b_256<T: AsRef<[u8]>>(bytes: T) -> [u8; 32] {
+    Blake2b::<U32>::digest(bytes.as_ref()).into()
+}
+
+/// Compute the 512 bits BLAKE2b hash of input bytes.
+pub fn blake2b_512<T: AsRef<[u8]>>(bytes: T) -> [u8; 64] {
+    Blake2b512::digest(bytes.as_ref()).into()
+}
@@ -0,0 +1,264 @@
+use candid::{CandidType, Deserialize, Principal};
+use ethers_core::k256::elliptic_curve::sec1::ToEncodedPoint;
+use ethers_core::k256::PublicKey;
+
+use crate::error::{Error, Result};
+use crate::state::ecdsa::cbor::{
+    write_bytes, write_header, write_text, CBOR_ARRAY, CBOR_MAP, CBOR_TAG, CBOR_UINT,
+};
+use crate::state::ecdsa::hash::sha256;
+use crate::state::ecdsa::protobuf::write_varint;
+use crate::state::ecdsa::signature::normalized_signature;
+use crate::state::ecdsa::Signer;
+
+/// DER header of a `SubjectPublicKeyInfo` holding an uncompressed secp256k1 point:
+/// `id-ecPublicKey` with the `secp256k1` curve, then the bit string of the point.
+const SECP256K1_SPKI_PREFIX: [u8; 23] = [
+    0x30, 0x56, 0x30, 0x10, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x05, 0x2b,
+    0x81, 0x04, 0x00, 0x0a, 0x03, 0x42, 0x00,
+];
+/// Domain separator prepended to the request id before signing.
+const IC_REQUEST_DOMAIN: &[u8] = b"\x0Aic-request";
+/// Self-describe tag opening the CBOR envelope.
+const SELF_DESCRIBE_TAG: u64 = 55799;
+
+/// Update or query call of a canister method.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct IcCall {
+    pub canister_id: Principal,
+    pub method_name: String,
+    /// Candid encoded arguments.
+    pub arg: Vec<u8>,
+    /// Expiry in nanoseconds since the epoch, at most 5 minutes after the request
+    /// reaches the replica.
+    pub ingress_expiry: u64,
+    pub nonce: Option<Vec<u8>>,
+}
+
+/// Content of an ingress message sent by the user's self-authenticating principal.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum IcRequest {
+    Call(IcCall),
+    Query(IcCall),
+    /// Reads the state tree, e.g. `["request_status", <request id>]` to poll a call.
+    ReadState {
+        paths: Vec<Vec<Vec<u8>>>,
+        ingress_expiry: u64,
+    },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct IcSignedRequest {
+    /// Hex encoded request id.
+    pub request_id: String,
+    /// CBOR envelope to post to `/api/v2/canister/<canister id>/<request type>`.
+    pub envelope: Vec<u8>,
+}
+
+pub struct IcWallet {
+    pub signer: Signer,
+    pub der_public_key: Vec<u8>,
+}
+
+impl IcWallet {
+    pub fn new(signer: Signer) -> Result<Self> {
+        let der_public_key = der_public_key(signer.public_key())?;
+        Ok(Self {
+            signer,
+            der_public_key,
+        })
+    }
+
+    /// Self-authenticating principal of the DER encoded public key.
+    pub fn principal(&self) -> Principal {
+        Principal::self_authenticating(&self.der_public_key)
+    }
+
+    /// Signs the request id of `req` sent by the wallet principal and wraps it in
+    /// an authenticated envelope.
+    pub async fn sign_request(&self, req: &IcRequest) -> Result<IcSignedRequest> {
+        if let IcRequest::Call(call) | IcRequest::Query(call) = req {
+            if call.method_name.is_empty() {
+                return Err(Error::InvalidTransaction("empty method name".to_string()));
+            }
+        }
+
+        let content = req.content(self.principal());
+        let request_id = hash_of_map(&content);
+        let sign = self
+            .signer
+            .sign_hash(sha256([IC_REQUEST_DOMAIN, &request_id].concat()))
+            .await?;
+        // the replica only accepts low-s signatures
+        let sig = normalized_signature(&sign)?;
+
+        let mut envelope = vec![];
+        write_header(&mut envelope, CBOR_TAG, SELF_DESCRIBE_TAG);
+        write_header(&mut envelope, CBOR_MAP, 3);
+        write_text(&mut envelope, "content");
+        write_map(&mut envelope, &content);
+        write_text(&mut envelope, "sender_pubkey");
+        write_bytes(&mut envelope, &self.der_public_key);
+        write_text(&mut envelope, "sender_sig");
+        write_bytes(&mut envelope, &sig.to_bytes());
+
+        Ok(IcSignedRequest {
+            request_id: hex::encode(request_id),
+            envelope,
+        })
+    }
+}
+
+impl IcRequest {
+    fn content(&self, sender: Principal) -> Vec<(&'static str, Value)> {
+        let (request_type, mut fields, ingress_expiry) = match self {
+            IcRequest::Call(call) | IcRequest::Query(call) => {
+                let mut fields = vec![
+                    (
+                        "canister_id",
+                        Value::Bytes(call.canister_id.as_slice().to_vec()),
+                    ),
+                    ("method_name", Value::Text(call.method_name.clone())),
+                    ("arg", Value::Bytes(call.arg.clone())),
+                ];
+                if let Some(nonce) = &call.nonce {
+                    fields.push(("nonce", Value::Bytes(nonce.clone())));
+                }
+                let request_type = match self {
+                    IcRequest::Call(_) => "call",
+                    _ => "query",
+                };
+                (request_type, fields, call.ingress_expiry)
+            }
+            IcRequest::ReadState {
+                paths,
+                ingress_expiry,
+            } => {
+                let paths = paths
+                    .iter()
+                    .map(|path| {
+                        Value::Array(path.iter().map(|l| Value::Bytes(l.clone())).collect())
+                    })
+                    .collect();
+                (
+                    "read_state",
+                    vec![("paths", Value::Array(paths))],
+                    *ingress_expiry,
+                )
+            }
+        };
+        fields.push(("request_type", Value::Text(request_type.to_string())));
+        fields.push(("sender", Value::Bytes(sender.as_slice().to_vec())));
+        fields.push(("ingress_expiry", Value::Nat(ingress_expiry)));
+        fields
+    }
+}
+
+/// Values of the request content.
+enum Value {
+    Bytes(Vec<u8>),
+    Text(String),
+    Nat(u64),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// Representation-independent hash of the value.
+    fn hash(&self) -> [u8; 32] {
+        match self {
+            Value::Bytes(bytes) => sha256(bytes),
+            Value::Text(text) => sha256(text),
+            Value::Nat(n) => {
+                // protobuf varints are unsigned LEB128
+                let mut buf = vec![];
+                write_varint(&mut buf, *n);
+                sha256(buf)
+            }
+            Value::Array(values) => {
+                sha256(values.iter().flat_map(|v| v.hash()).collect::<Vec<_>>())
+            }
+        }
+    }
+
+    fn write_cbor(&self, buf: &mut Vec<u8>) {
+        match self {
+            Value::Bytes(bytes) => write_bytes(buf, bytes),
+            Value::Text(text) => write_text(buf, text),
+            Value::Nat(n) => write_header(buf, CBOR_UINT, *n),
+            Value::Array(values) => {
+                write_header(buf, CBOR_ARRAY, values.len() as u64);
+                values.iter().for_each(|v| v.write_cbor(buf));
+            }
+        }
+    }
+}
+
+/// Request id: hash of the sorted concatenation of the field hashes.
+fn hash_of_map(fields: &[(&str, Value)]) -> [u8; 32] {
+    let mut hashes = fields
+        .iter()
+        .map(|(key, value)| [sha256(key), value.hash()].concat())
+        .collect::<Vec<_>>();
+    hashes.sort();
+    sha256(hashes.concat())
+}
+
+fn write_map(buf: &mut Vec<u8>, fields: &[(&str, Value)]) {
+    write_header(buf, CBOR_MAP, fields.len() as u64);
+    for (key, value) in fields {
+        write_text(buf, key);
+        value.write_cbor(buf);
+    }
+}
+
+/// DER encoded `SubjectPublicKeyInfo` of a SEC1 encoded secp256k1 public key.
+pub fn der_public_key(public_key: &[u8]) -> Result<Vec<u8>> {
+    let key = PublicKey::from_sec1_bytes(public_key)
+        .map_err(|_| Error::InvalidPublicKey(hex::encode(public_key)))?;
+    Ok([
+        SECP256K1_SPKI_PREFIX.as_slice(),
+        key.to_encoded_point(false).as_bytes(),
+    ]
+    .concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_request_ids() {
+        // example of the interface specification
+        let fields = [
+            ("request_type", Value::Text("call".to_string())),
+            (
+                "canister_id",
+                Value::Bytes(hex::decode("00000000000004D2").unwrap()),
+            ),
+            ("method_name", Value::Text("hello".to_string())),
+            ("arg", Value::Bytes(b"DIDL\x00\xFD*".to_vec())),
+        ];
+        assert_eq!(
+            hex::encode(hash_of_map(&fields)),
+            "8781291c347db32a9d8c10eb62b710fce5a93be676474c42babc74c51858f94b"
+        );
+    }
+
+    #[test]
+    fn encodes_der_public_keys() {
+        let public_key =
+            hex::decode("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+                .unwrap();
+        let der = der_public_key(&public_key).unwrap();
+        assert_eq!(der.len(), 88);
+        assert_eq!(
+            hex::encode(&der[..24]),
+            "3056301006072a8648ce3d020106052b8104000a03420004"
+        );
+        assert_eq!(
+            hex::encode(&der[24..56]),
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+        );
+        assert!(der_public_key(&public_key[1..]).is_err());
+    }
+}
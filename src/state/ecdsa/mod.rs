@@ -11,13 +11,31 @@ use ic_stable_structures::storable::Bound;
 use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
 
 use crate::error::Result;
-use crate::state::{
-    decode, encode, StorablePrincipal, MEMORY_MANAGER, NONCES_MEMORY_ID, SIGNERS_MEMORY_ID,
+use crate::state::codec::{
+    read_padded, read_principal, read_u64, write_padded, write_principal, MAX_PRINCIPAL_LEN,
+    PRINCIPAL_SIZE,
 };
+use crate::state::ecdsa::filecoin::FilecoinNetwork;
+use crate::state::ecdsa::stacks::StacksNetwork;
+use crate::state::schnorr::polkadot::PolkadotKeyType;
+use crate::state::{StorablePrincipal, MEMORY_MANAGER, NONCES_MEMORY_ID, SIGNERS_MEMORY_ID};
 
+pub mod bch;
+pub mod btc;
+pub mod cbor;
+pub mod cosmos;
 pub mod eth;
+pub mod filecoin;
+pub mod hash;
+pub mod ic;
+pub mod protobuf;
+pub mod signature;
+pub mod stacks;
+pub mod sui;
+pub mod tron;
+pub mod xrp;
 
-#[derive(Copy, Clone, Deserialize, CandidType)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, CandidType)]
 pub enum EcdsaKeyIds {
     TestKeyLocalDevelopment,
     TestKey1,
@@ -33,12 +51,34 @@ impl EcdsaKeyIds {
     fn to_key_id(self) -> EcdsaKeyId {
         EcdsaKeyId {
             curve: EcdsaCurve::Secp256k1,
-            name: match self {
-                Self::TestKeyLocalDevelopment => "dfx_test_key",
-                Self::TestKey1 => "test_key_1",
-                Self::ProductionKey1 => "key_1",
-            }
-            .to_string(),
+            name: self.key_name().to_string(),
+        }
+    }
+
+    /// Byte of the key in the binary layouts.
+    pub(crate) fn to_byte(self) -> u8 {
+        match self {
+            Self::TestKeyLocalDevelopment => 0,
+            Self::TestKey1 => 1,
+            Self::ProductionKey1 => 2,
+        }
+    }
+
+    pub(crate) fn from_byte(byte: u8) -> Self {
+        match byte {
+            0 => Self::TestKeyLocalDevelopment,
+            1 => Self::TestKey1,
+            2 => Self::ProductionKey1,
+            _ => panic!("unknown ecdsa key byte {byte}"),
+        }
+    }
+
+    /// Name of the master key, threshold Schnorr keys are deployed under the same names.
+    pub(crate) fn key_name(self) -> &'static str {
+        match self {
+            Self::TestKeyLocalDevelopment => "dfx_test_key",
+            Self::TestKey1 => "test_key_1",
+            Self::ProductionKey1 => "key_1",
         }
     }
 }
@@ -69,6 +109,10 @@ impl Signer {
         })
     }
 
+    pub fn key_id(&self) -> EcdsaKeyIds {
+        self.key_id
+    }
+
     pub fn public_key(&self) -> &[u8] {
         &self.public_key
     }
@@ -88,18 +132,46 @@ impl Signer {
     }
 }
 
+#[cfg(test)]
+impl Signer {
+    /// Signer of an existing public key, for tests which don't sign.
+    pub fn test(key_id: EcdsaKeyIds, public_key: Vec<u8>) -> Self {
+        Self {
+            key_id,
+            path: vec![],
+            public_key,
+            chain_code: vec![0; 32],
+        }
+    }
+}
+
+/// Key id byte, derivation path, 33 bytes compressed public key and 32 bytes chain code.
+pub const SIGNER_SIZE: usize = 1 + PRINCIPAL_SIZE + 33 + 32;
+
 impl Storable for Signer {
     fn to_bytes(&self) -> Cow<[u8]> {
-        encode(&self).into()
+        let mut bytes = Vec::with_capacity(SIGNER_SIZE);
+        bytes.push(self.key_id.to_byte());
+        write_padded(&mut bytes, &self.path, MAX_PRINCIPAL_LEN);
+        bytes.extend_from_slice(&self.public_key);
+        bytes.extend_from_slice(&self.chain_code);
+        assert_eq!(bytes.len(), SIGNER_SIZE, "invalid signer key lengths");
+        bytes.into()
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        decode(bytes.as_ref())
+        let (public_key, chain_code) = bytes[1 + PRINCIPAL_SIZE..].split_at(33);
+        Self {
+            key_id: EcdsaKeyIds::from_byte(bytes[0]),
+            path: read_padded(&bytes[1..]).to_vec(),
+            public_key: public_key.to_vec(),
+            chain_code: chain_code.to_vec(),
+        }
     }
 
     const BOUND: Bound = Bound::Bounded {
-        max_size: 153,
-        is_fixed_size: false,
+        max_size: SIGNER_SIZE as u32,
+        is_fixed_size: true,
     };
 }
 
@@ -109,6 +181,28 @@ impl Storable for Signer {
 pub enum CoinType {
     Evm(u64),
     Btc,
+    Bch,
+    Cosmos {
+        hrp: String,
+        chain_id: String,
+    },
+    Ethermint {
+        hrp: String,
+        chain_id: String,
+    },
+    Tron,
+    Xrp,
+    Solana,
+    Polkadot {
+        prefix: u16,
+        key_type: PolkadotKeyType,
+    },
+    Sui,
+    Stacks(StacksNetwork),
+    Filecoin(FilecoinNetwork),
+    Ton,
+    InternetComputer,
+    Nostr,
 }
 
 #[derive(Default, Clone, Copy)]
@@ -134,6 +228,10 @@ impl Signers {
                 .insert(StorablePrincipal(principal), signer)
         });
     }
+
+    pub fn count(&self) -> u64 {
+        SIGNERS.with(|signers| signers.borrow().len())
+    }
 }
 
 #[derive(Clone, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
@@ -145,18 +243,23 @@ impl Default for PrincipalChainIdKey {
     }
 }
 
+/// Principal followed by the big endian chain id, so that the keys of a user are
+/// contiguous and sorted by chain id.
 impl Storable for PrincipalChainIdKey {
     fn to_bytes(&self) -> Cow<[u8]> {
-        encode(&self).into()
+        let mut bytes = Vec::with_capacity(PRINCIPAL_SIZE + 8);
+        write_principal(&mut bytes, &self.0);
+        bytes.extend_from_slice(&self.1.to_be_bytes());
+        bytes.into()
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        decode(bytes.as_ref())
+        Self(read_principal(&bytes), read_u64(&bytes[PRINCIPAL_SIZE..]))
     }
 
     const BOUND: Bound = Bound::Bounded {
-        max_size: 29,
-        is_fixed_size: false,
+        max_size: PRINCIPAL_SIZE as u32 + 8,
+        is_fixed_size: true,
     };
 }
 
@@ -0,0 +1,79 @@
+//! Minimal proto3 writer, enough to build the canonical transaction encodings
+//! of protobuf based chains without generated code.
+//!
+//! Fields must be written in field number order and default values are skipped,
+//! which makes the output byte for byte identical to the reference implementations.
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_LEN: u64 = 2;
+
+#[derive(Default)]
+pub struct ProtoWriter {
+    buf: Vec<u8>,
+}
+
+impl ProtoWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn uint64(&mut self, field: u32, value: u64) -> &mut Self {
+        if value != 0 {
+            self.key(field, WIRE_VARINT);
+            write_varint(&mut self.buf, value);
+        }
+        self
+    }
+
+    pub fn int64(&mut self, field: u32, value: i64) -> &mut Self {
+        self.uint64(field, value as u64)
+    }
+
+    pub fn bool(&mut self, field: u32, value: bool) -> &mut Self {
+        self.uint64(field, value as u64)
+    }
+
+    pub fn bytes(&mut self, field: u32, value: &[u8]) -> &mut Self {
+        if !value.is_empty() {
+            self.raw_bytes(field, value);
+        }
+        self
+    }
+
+    pub fn string(&mut self, field: u32, value: &str) -> &mut Self {
+        self.bytes(field, value.as_bytes())
+    }
+
+    /// Embedded message, written even when empty as presence matters for messages.
+    pub fn message(&mut self, field: u32, value: &[u8]) -> &mut Self {
+        self.raw_bytes(field, value)
+    }
+
+    /// Element of a repeated bytes field, written even when empty.
+    pub fn repeated_bytes(&mut self, field: u32, value: &[u8]) -> &mut Self {
+        self.raw_bytes(field, value)
+    }
+
+    fn raw_bytes(&mut self, field: u32, value: &[u8]) -> &mut Self {
+        self.key(field, WIRE_LEN);
+        write_varint(&mut self.buf, value.len() as u64);
+        self.buf.extend_from_slice(value);
+        self
+    }
+
+    fn key(&mut self, field: u32, wire_type: u64) {
+        write_varint(&mut self.buf, ((field as u64) << 3) | wire_type);
+    }
+}
+
+pub fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
@@ -0,0 +1,32 @@
+use ethers_core::k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+
+use crate::error::{Error, Result};
+
+/// Parses a 64 bytes `r || s` signature returned by the management canister and
+/// normalizes `s` to the lower half of the curve order, as most chains require.
+pub fn normalized_signature(sig: &[u8]) -> Result<Signature> {
+    let sig = Signature::from_slice(sig).map_err(|_| Error::InvalidSignature(hex::encode(sig)))?;
+    Ok(sig.normalize_s().unwrap_or(sig))
+}
+
+/// DER encoding of a low-s signature.
+pub fn to_der(sig: &[u8]) -> Result<Vec<u8>> {
+    Ok(normalized_signature(sig)?.to_der().as_bytes().to_vec())
+}
+
+/// Low-s signature together with the recovery id which recovers `public_key` from `hash`.
+pub fn recoverable_signature(
+    public_key: &[u8],
+    hash: &[u8; 32],
+    sig: &[u8],
+) -> Result<(Signature, RecoveryId)> {
+    let sig = normalized_signature(sig)?;
+    let key = VerifyingKey::from_sec1_bytes(public_key)
+        .map_err(|_| Error::InvalidPublicKey(hex::encode(public_key)))?;
+
+    (0..2)
+        .filter_map(RecoveryId::from_byte)
+        .find(|id| VerifyingKey::recover_from_prehash(hash, &sig, *id).ok() == Some(key))
+        .map(|id| (sig, id))
+        .ok_or_else(|| Error::InvalidSignature(hex::encode(sig.to_bytes())))
+}
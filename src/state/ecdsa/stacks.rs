@@ -0,0 +1,557 @@
+use candid::{CandidType, Deserialize};
+use sha2::{Digest, Sha512_256};
+
+use crate::error::{Error, Result};
+use crate::state::ecdsa::hash::{hash160, sha256d};
+use crate::state::ecdsa::signature::recoverable_signature;
+use crate::state::ecdsa::Signer;
+
+const C32_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// Address versions of single signature accounts.
+pub const MAINNET_P2PKH_VERSION: u8 = 22;
+pub const TESTNET_P2PKH_VERSION: u8 = 26;
+/// Address versions of multisig accounts.
+pub const MAINNET_P2SH_VERSION: u8 = 20;
+pub const TESTNET_P2SH_VERSION: u8 = 21;
+
+const MAINNET_CHAIN_ID: u32 = 0x0000_0001;
+const TESTNET_CHAIN_ID: u32 = 0x8000_0000;
+const AUTH_STANDARD: u8 = 0x04;
+const HASH_MODE_P2PKH: u8 = 0x00;
+const PUBKEY_COMPRESSED: u8 = 0x00;
+const ANCHOR_MODE_ANY: u8 = 0x03;
+const PAYLOAD_TOKEN_TRANSFER: u8 = 0x00;
+const PAYLOAD_CONTRACT_CALL: u8 = 0x02;
+const PRINCIPAL_STANDARD: u8 = 0x05;
+const PRINCIPAL_CONTRACT: u8 = 0x06;
+const MEMO_LEN: usize = 34;
+const SIGNATURE_LEN: usize = 65;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum StacksNetwork {
+    Mainnet,
+    Testnet,
+}
+
+#[derive(Clone, Copy, CandidType, Deserialize)]
+pub enum StacksPostConditionMode {
+    Allow,
+    Deny,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub enum StacksPayload {
+    /// STX transfer, `amount` in micro-STX, `memo` up to 34 bytes.
+    TokenTransfer {
+        recipient: String,
+        amount: u64,
+        memo: String,
+    },
+    ContractCall {
+        contract_address: String,
+        contract_name: String,
+        function_name: String,
+        /// Hex encoded serialized Clarity values.
+        function_args: Vec<String>,
+    },
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct StacksTransaction {
+    pub network: StacksNetwork,
+    pub payload: StacksPayload,
+    pub nonce: u64,
+    /// Fee in micro-STX.
+    pub fee: u64,
+    /// Contract calls moving assets without post conditions need `Allow`.
+    pub post_condition_mode: StacksPostConditionMode,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct StacksSignedTransaction {
+    /// Hex encoded SHA-512/256 of the signed transaction.
+    pub tx_id: String,
+    /// Hex encoded signed transaction, as expected by `/v2/transactions`.
+    pub tx_hex: String,
+}
+
+pub struct StacksWallet {
+    pub signer: Signer,
+    pub pubkey_hash: [u8; 20],
+}
+
+impl StacksWallet {
+    pub fn new(signer: Signer) -> Self {
+        let pubkey_hash = hash160(signer.public_key());
+        Self {
+            signer,
+            pubkey_hash,
+        }
+    }
+
+    /// c32check encoded `SP...` or `ST...` address.
+    pub fn address(&self, network: StacksNetwork) -> String {
+        encode_address(network.address_version(), &self.pubkey_hash)
+    }
+
+    pub async fn sign_transaction(
+        &self,
+        tx: &StacksTransaction,
+    ) -> Result<StacksSignedTransaction> {
+        let sighash = tx.presign_sighash(&self.pubkey_hash)?;
+        let sign = self.signer.sign_hash(sighash).await?;
+        self.signed_transaction(tx, &sighash, &sign)
+    }
+
+    /// Transaction with the 64 bytes signature `sign` of its presign `sighash`.
+    fn signed_transaction(
+        &self,
+        tx: &StacksTransaction,
+        sighash: &[u8; 32],
+        sign: &[u8],
+    ) -> Result<StacksSignedTransaction> {
+        let (sig, recovery_id) = recoverable_signature(self.signer.public_key(), sighash, sign)?;
+        let mut signature = [0; SIGNATURE_LEN];
+        signature[0] = recovery_id.to_byte();
+        signature[1..].copy_from_slice(&sig.to_bytes());
+
+        let signed = tx.serialize(&self.pubkey_hash, tx.nonce, tx.fee, &signature)?;
+        Ok(StacksSignedTransaction {
+            tx_id: hex::encode(sha512_256(&signed)),
+            tx_hex: hex::encode(signed),
+        })
+    }
+}
+
+impl StacksNetwork {
+    pub const ALL: [Self; 2] = [Self::Mainnet, Self::Testnet];
+
+    /// Chain id of the network in the pause scopes.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Mainnet => "Mainnet",
+            Self::Testnet => "Testnet",
+        }
+    }
+
+    fn address_version(self) -> u8 {
+        match self {
+            Self::Mainnet => MAINNET_P2PKH_VERSION,
+            Self::Testnet => TESTNET_P2PKH_VERSION,
+        }
+    }
+
+    /// Decodes an address of an account of the network.
+    fn decode_address(self, address: &str) -> Result<(u8, [u8; 20])> {
+        let (version, hash) = decode_address(address)?;
+        let versions = match self {
+            Self::Mainnet => [MAINNET_P2PKH_VERSION, MAINNET_P2SH_VERSION],
+            Self::Testnet => [TESTNET_P2PKH_VERSION, TESTNET_P2SH_VERSION],
+        };
+        if !versions.contains(&version) {
+            return Err(Error::InvalidAddress(format!(
+                "{address} is not a {} address",
+                self.as_str()
+            )));
+        }
+        Ok((version, hash))
+    }
+}
+
+impl StacksTransaction {
+    /// SIP-005 sighash signed by `signer`, of the initial sighash, which commits to the
+    /// transaction with a cleared spending condition, the auth type, the fee and the nonce.
+    fn presign_sighash(&self, signer: &[u8; 20]) -> Result<[u8; 32]> {
+        let unsigned = self.serialize(signer, 0, 0, &[0; SIGNATURE_LEN])?;
+        let mut presign = Sha512_256::new();
+        presign.update(sha512_256(&unsigned));
+        presign.update([AUTH_STANDARD]);
+        presign.update(self.fee.to_be_bytes());
+        presign.update(self.nonce.to_be_bytes());
+        Ok(presign.finalize().into())
+    }
+
+    /// Transaction wire format with a single signature P2PKH authorization.
+    fn serialize(
+        &self,
+        signer: &[u8; 20],
+        nonce: u64,
+        fee: u64,
+        signature: &[u8; SIGNATURE_LEN],
+    ) -> Result<Vec<u8>> {
+        let (version, chain_id) = match self.network {
+            StacksNetwork::Mainnet => (0x00, MAINNET_CHAIN_ID),
+            StacksNetwork::Testnet => (0x80, TESTNET_CHAIN_ID),
+        };
+
+        let mut buf = vec![version];
+        buf.extend_from_slice(&chain_id.to_be_bytes());
+        buf.extend_from_slice(&[AUTH_STANDARD, HASH_MODE_P2PKH]);
+        buf.extend_from_slice(signer);
+        buf.extend_from_slice(&nonce.to_be_bytes());
+        buf.extend_from_slice(&fee.to_be_bytes());
+        buf.push(PUBKEY_COMPRESSED);
+        buf.extend_from_slice(signature);
+        buf.push(ANCHOR_MODE_ANY);
+        buf.push(match self.post_condition_mode {
+            StacksPostConditionMode::Allow => 0x01,
+            StacksPostConditionMode::Deny => 0x02,
+        });
+        // no post conditions
+        buf.extend_from_slice(&0u32.to_be_bytes());
+        self.payload.write(&mut buf, self.network)?;
+        Ok(buf)
+    }
+}
+
+impl StacksPayload {
+    fn write(&self, buf: &mut Vec<u8>, network: StacksNetwork) -> Result<()> {
+        match self {
+            Self::TokenTransfer {
+                recipient,
+                amount,
+                memo,
+            } => {
+                if memo.len() > MEMO_LEN {
+                    return Err(Error::InvalidTransaction(format!("memo {memo}")));
+                }
+                buf.push(PAYLOAD_TOKEN_TRANSFER);
+                write_principal(buf, recipient, network)?;
+                buf.extend_from_slice(&amount.to_be_bytes());
+                let mut memo_bytes = [0; MEMO_LEN];
+                memo_bytes[..memo.len()].copy_from_slice(memo.as_bytes());
+                buf.extend_from_slice(&memo_bytes);
+            }
+            Self::ContractCall {
+                contract_address,
+                contract_name,
+                function_name,
+                function_args,
+            } => {
+                let (version, hash) = network.decode_address(contract_address)?;
+                buf.push(PAYLOAD_CONTRACT_CALL);
+                buf.push(version);
+                buf.extend_from_slice(&hash);
+                write_name(buf, contract_name)?;
+                write_name(buf, function_name)?;
+                buf.extend_from_slice(&(function_args.len() as u32).to_be_bytes());
+                for arg in function_args {
+                    let value = hex::decode(arg.trim_start_matches("0x"))
+                        .map_err(|_| Error::InvalidTransaction(format!("function arg {arg}")))?;
+                    buf.extend(value);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Standard `SP...` or contract `SP....name` principal of `network`.
+fn write_principal(buf: &mut Vec<u8>, principal: &str, network: StacksNetwork) -> Result<()> {
+    match principal.split_once('.') {
+        None => {
+            let (version, hash) = network.decode_address(principal)?;
+            buf.push(PRINCIPAL_STANDARD);
+            buf.push(version);
+            buf.extend_from_slice(&hash);
+        }
+        Some((address, name)) => {
+            let (version, hash) = network.decode_address(address)?;
+            buf.push(PRINCIPAL_CONTRACT);
+            buf.push(version);
+            buf.extend_from_slice(&hash);
+            write_name(buf, name)?;
+        }
+    }
+    Ok(())
+}
+
+/// Length prefixed contract or function name.
+fn write_name(buf: &mut Vec<u8>, name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > 128 {
+        return Err(Error::InvalidTransaction(format!("name {name}")));
+    }
+    buf.push(name.len() as u8);
+    buf.extend_from_slice(name.as_bytes());
+    Ok(())
+}
+
+fn sha512_256(bytes: &[u8]) -> [u8; 32] {
+    Sha512_256::digest(bytes).into()
+}
+
+/// Crockford base32 encoding of bytes, keeping their leading zeros.
+pub fn c32_encode(input: &[u8]) -> String {
+    let mut result = vec![];
+    let mut carry = 0;
+    let mut carry_bits = 0;
+    for &byte in input.iter().rev() {
+        let low_bits = byte & ((1 << (5 - carry_bits)) - 1);
+        result.push(C32_ALPHABET[((low_bits << carry_bits) + carry) as usize]);
+        carry_bits += 3;
+        carry = byte >> (8 - carry_bits);
+        if carry_bits >= 5 {
+            result.push(C32_ALPHABET[(carry & 0x1f) as usize]);
+            carry_bits -= 5;
+            carry >>= 5;
+        }
+    }
+    if carry_bits > 0 {
+        result.push(C32_ALPHABET[carry as usize]);
+    }
+
+    while result.last() == Some(&C32_ALPHABET[0]) {
+        result.pop();
+    }
+    result.extend(
+        input
+            .iter()
+            .take_while(|&&b| b == 0)
+            .map(|_| C32_ALPHABET[0]),
+    );
+    result.reverse();
+    String::from_utf8(result).expect("c32 alphabet is ascii")
+}
+
+/// Value of a c32 character, accepting the Crockford substitutions.
+fn c32_value(c: u8) -> Option<u8> {
+    match c.to_ascii_uppercase() {
+        b'O' => Some(0),
+        b'L' | b'I' => Some(1),
+        c => C32_ALPHABET.iter().position(|&a| a == c).map(|v| v as u8),
+    }
+}
+
+pub fn c32_decode(input: &str) -> Option<Vec<u8>> {
+    let digits = input
+        .bytes()
+        .map(|c| c32_value(c).map(u16::from))
+        .collect::<Option<Vec<_>>>()?;
+
+    let mut result = vec![];
+    let mut carry = 0u16;
+    let mut carry_bits = 0;
+    for &digit in digits.iter().rev() {
+        carry += digit << carry_bits;
+        carry_bits += 5;
+        if carry_bits >= 8 {
+            result.push((carry & 0xff) as u8);
+            carry_bits -= 8;
+            carry >>= 8;
+        }
+    }
+    if carry_bits > 0 {
+        result.push(carry as u8);
+    }
+
+    while result.last() == Some(&0) {
+        result.pop();
+    }
+    result.extend(digits.iter().take_while(|&&d| d == 0).map(|_| 0));
+    result.reverse();
+    Some(result)
+}
+
+fn c32_checksum(version: u8, hash: &[u8; 20]) -> [u8; 4] {
+    let digest = sha256d([&[version], hash.as_slice()].concat());
+    [digest[0], digest[1], digest[2], digest[3]]
+}
+
+/// c32check address of a hash160 with the given version.
+pub fn encode_address(version: u8, hash: &[u8; 20]) -> String {
+    let data = [hash.as_slice(), &c32_checksum(version, hash)].concat();
+    format!(
+        "S{}{}",
+        C32_ALPHABET[version as usize] as char,
+        c32_encode(&data)
+    )
+}
+
+/// Decodes a c32check address into its version and hash160.
+pub fn decode_address(address: &str) -> Result<(u8, [u8; 20])> {
+    let invalid = || Error::InvalidAddress(address.to_string());
+    let rest = address.strip_prefix('S').ok_or_else(invalid)?;
+    let version = rest
+        .bytes()
+        .next()
+        .and_then(c32_value)
+        .ok_or_else(invalid)?;
+
+    let data = c32_decode(&rest[1..]).ok_or_else(invalid)?;
+    if data.len() != 24 {
+        return Err(invalid());
+    }
+    let hash: [u8; 20] = data[..20].try_into().map_err(|_| invalid())?;
+    if c32_checksum(version, &hash) != data[20..] {
+        return Err(invalid());
+    }
+    Ok((version, hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::ecdsa::EcdsaKeyIds;
+
+    const HASH: &str = "a46ff88886c2ef9762d970b4d2c63678835bd39d";
+
+    #[test]
+    fn encodes_c32check_addresses() {
+        let hash: [u8; 20] = hex::decode(HASH).unwrap().try_into().unwrap();
+        assert_eq!(
+            encode_address(MAINNET_P2PKH_VERSION, &hash),
+            "SP2J6ZY48GV1EZ5V2V5RB9MP66SW86PYKKNRV9EJ7"
+        );
+        assert_eq!(
+            encode_address(TESTNET_P2PKH_VERSION, &hash),
+            "ST2J6ZY48GV1EZ5V2V5RB9MP66SW86PYKKQYAC0RQ"
+        );
+        assert_eq!(
+            encode_address(MAINNET_P2PKH_VERSION, &[0; 20]),
+            "SP000000000000000000002Q6VF78"
+        );
+
+        assert_eq!(
+            decode_address("SP2J6ZY48GV1EZ5V2V5RB9MP66SW86PYKKNRV9EJ7").unwrap(),
+            (MAINNET_P2PKH_VERSION, hash)
+        );
+        assert_eq!(
+            decode_address("SP000000000000000000002Q6VF78").unwrap(),
+            (MAINNET_P2PKH_VERSION, [0; 20])
+        );
+        assert!(decode_address("SP2J6ZY48GV1EZ5V2V5RB9MP66SW86PYKKNRV9EJ8").is_err());
+    }
+
+    #[test]
+    fn c32_roundtrip() {
+        for bytes in [
+            vec![],
+            vec![0],
+            vec![0, 0, 1],
+            vec![0xff; 7],
+            hex::decode(HASH).unwrap(),
+        ] {
+            assert_eq!(c32_decode(&c32_encode(&bytes)).unwrap(), bytes);
+        }
+        assert_eq!(c32_encode(&[0x01, 0x02]), "82");
+    }
+
+    #[test]
+    fn signs_token_transfer() {
+        use ethers_core::k256::ecdsa::SigningKey;
+
+        // `makeSTXTokenTransfer` test of the stacks.js transactions builder, whose serialized
+        // transaction carries the signature of the presign sighash
+        let key = SigningKey::from_slice(
+            &hex::decode("edf9aee84d9b7abc145504dde6726c64f369d37ee34ded868fabd876c26570bc")
+                .unwrap(),
+        )
+        .unwrap();
+        let public_key = key
+            .verifying_key()
+            .to_encoded_point(true)
+            .as_bytes()
+            .to_vec();
+        let wallet = StacksWallet::new(Signer::test(EcdsaKeyIds::TestKey1, public_key));
+        assert_eq!(
+            wallet.address(StacksNetwork::Mainnet),
+            "SPAW66WC3G8WA5F28JVNG1NTRJ6H76E7EN5H6QQD"
+        );
+        let tx = StacksTransaction {
+            network: StacksNetwork::Mainnet,
+            payload: StacksPayload::TokenTransfer {
+                recipient: "SP3FGQ8Z7JY9BWYZ5WM53E0M9NK7WHJF0691NZ159".to_string(),
+                amount: 12345,
+                memo: "test memo".to_string(),
+            },
+            nonce: 0,
+            fee: 0,
+            post_condition_mode: StacksPostConditionMode::Deny,
+        };
+
+        let sighash = tx.presign_sighash(&wallet.pubkey_hash).unwrap();
+        assert_eq!(
+            hex::encode(sighash),
+            "9aef893c106ea08489676c708797909f7319643bf724cec4ae3c0cd336750253"
+        );
+        // deterministic signature, as stacks.js signs
+        let (sig, _) = key.sign_prehash_recoverable(&sighash).unwrap();
+        let signed = wallet
+            .signed_transaction(&tx, &sighash, &sig.to_bytes())
+            .unwrap();
+        assert_eq!(
+            signed.tx_hex,
+            concat!(
+                "0000000001040015c31b8c1c11c515e244b75806bac48d1399c77500000000000000000000000000000000",
+                "00008b316d56e35b3b8d03ab3b9dbe05eb44d64c53e7ba3c468f9a78c82a13f2174c32facb0f29faeb2107",
+                "5ec933db935ebc28a8793cc60e14b8ee4ef05f52c94016030200000000000516df0ba3e79792be7be5e50a",
+                "370289accfc8c9e032000000000000303974657374206d656d6f00000000000000000000000000000000",
+                "000000000000000000",
+            )
+        );
+        assert_eq!(
+            signed.tx_id,
+            "84cccb05f4bd0e1b08905ef1f1350ad635a6474448310548bdccfa04e0121bab"
+        );
+    }
+
+    #[test]
+    fn rejects_addresses_of_other_networks() {
+        let transfer = |network, recipient: &str| StacksTransaction {
+            network,
+            payload: StacksPayload::TokenTransfer {
+                recipient: recipient.to_string(),
+                amount: 1,
+                memo: String::new(),
+            },
+            nonce: 0,
+            fee: 0,
+            post_condition_mode: StacksPostConditionMode::Deny,
+        };
+        let mainnet = "SP2J6ZY48GV1EZ5V2V5RB9MP66SW86PYKKNRV9EJ7";
+        let testnet = "ST2J6ZY48GV1EZ5V2V5RB9MP66SW86PYKKQYAC0RQ";
+        assert!(transfer(StacksNetwork::Mainnet, mainnet)
+            .presign_sighash(&[1; 20])
+            .is_ok());
+        assert!(transfer(StacksNetwork::Testnet, testnet)
+            .presign_sighash(&[1; 20])
+            .is_ok());
+        assert!(matches!(
+            transfer(StacksNetwork::Testnet, mainnet).presign_sighash(&[1; 20]),
+            Err(Error::InvalidAddress(_))
+        ));
+        assert!(matches!(
+            transfer(StacksNetwork::Mainnet, &format!("{testnet}.contract"))
+                .presign_sighash(&[1; 20]),
+            Err(Error::InvalidAddress(_))
+        ));
+
+        let call = StacksPayload::ContractCall {
+            contract_address: "SP000000000000000000002Q6VF78".to_string(),
+            contract_name: "pox".to_string(),
+            function_name: "get-info".to_string(),
+            function_args: vec![],
+        };
+        assert!(call.write(&mut vec![], StacksNetwork::Testnet).is_err());
+    }
+
+    #[test]
+    fn serializes_contract_call() {
+        let payload = StacksPayload::ContractCall {
+            contract_address: "SP000000000000000000002Q6VF78".to_string(),
+            contract_name: "pox".to_string(),
+            function_name: "get-info".to_string(),
+            function_args: vec!["0x0100000000000000000000000000000001".to_string()],
+        };
+        let mut buf = vec![];
+        payload.write(&mut buf, StacksNetwork::Mainnet).unwrap();
+        assert_eq!(
+            hex::encode(buf),
+            format!(
+                "0216{}03706f78086765742d696e666f000000010100000000000000000000000000000001",
+                "00".repeat(20)
+            )
+        );
+    }
+}
@@ -0,0 +1,76 @@
+use crate::error::{Error, Result};
+use crate::state::ecdsa::hash::{blake2b_256, sha256};
+use crate::state::ecdsa::signature::normalized_signature;
+use crate::state::ecdsa::Signer;
+
+/// Signature scheme flag of secp256k1 keys.
+pub const SECP256K1_FLAG: u8 = 0x01;
+
+/// Intent of a `TransactionData`: scope `TransactionData`, version `V0`, app `Sui`.
+const TRANSACTION_INTENT: [u8; 3] = [0, 0, 0];
+
+pub struct SuiWallet {
+    pub signer: Signer,
+    pub address: [u8; 32],
+}
+
+impl SuiWallet {
+    pub fn new(signer: Signer) -> Self {
+        let address = blake2b_256([&[SECP256K1_FLAG], signer.public_key()].concat());
+        Self { signer, address }
+    }
+
+    /// `0x` prefixed hex encoded address.
+    pub fn address(&self) -> String {
+        format!("0x{}", hex::encode(self.address))
+    }
+
+    /// Signs BCS encoded `TransactionData` bytes.
+    ///
+    /// Returns the serialized `flag || signature || public key` signature.
+    pub async fn sign_transaction(&self, tx_bytes: &[u8]) -> Result<Vec<u8>> {
+        if tx_bytes.is_empty() {
+            return Err(Error::InvalidTransaction(
+                "empty transaction data".to_string(),
+            ));
+        }
+        let digest = intent_digest(tx_bytes);
+
+        // secp256k1 signatures are verified against the SHA-256 hash of the signed digest
+        let sign = self.signer.sign_hash(sha256(digest)).await?;
+        let sig = normalized_signature(&sign)?;
+
+        let mut signature = vec![SECP256K1_FLAG];
+        signature.extend_from_slice(&sig.to_bytes());
+        signature.extend_from_slice(self.signer.public_key());
+        Ok(signature)
+    }
+}
+
+/// BLAKE2b-256 digest of the intent message of transaction data.
+pub fn intent_digest(tx_bytes: &[u8]) -> [u8; 32] {
+    blake2b_256([&TRANSACTION_INTENT, tx_bytes].concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_intent_messages() {
+        assert_eq!(intent_digest(&[1, 2, 3]), blake2b_256([0, 0, 0, 1, 2, 3]));
+    }
+
+    #[test]
+    fn derives_addresses() {
+        // the generator point, public key of the private key 1
+        let public_key =
+            hex::decode("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+                .unwrap();
+        let address = blake2b_256([&[SECP256K1_FLAG], public_key.as_slice()].concat());
+        assert_eq!(
+            hex::encode(address),
+            "d4c3524e6642b2e54945c02378024f822ac3f80b0870a5f95f06e68a61890a6c"
+        );
+    }
+}
@@ -0,0 +1,263 @@
+use candid::{CandidType, Deserialize};
+use ethers_core::abi::{self, Token};
+use ethers_core::types::{Address, U256};
+
+use crate::error::{Error, Result};
+use crate::state::ecdsa::eth::public_key_to_address;
+use crate::state::ecdsa::hash::sha256;
+use crate::state::ecdsa::protobuf::ProtoWriter;
+use crate::state::ecdsa::signature::recoverable_signature;
+use crate::state::ecdsa::Signer;
+
+/// Prefix byte of Tron mainnet addresses.
+pub const ADDRESS_PREFIX: u8 = 0x41;
+
+const TRANSFER_CONTRACT: u64 = 1;
+const TRIGGER_SMART_CONTRACT: u64 = 31;
+const TRANSFER_CONTRACT_TYPE: &str = "type.googleapis.com/protocol.TransferContract";
+const TRIGGER_SMART_CONTRACT_TYPE: &str = "type.googleapis.com/protocol.TriggerSmartContract";
+/// `transfer(address,uint256)`
+const TRC20_TRANSFER_SELECTOR: [u8; 4] = [0xa9, 0x05, 0x9c, 0xbb];
+
+#[derive(Clone, CandidType, Deserialize)]
+pub enum TronContract {
+    /// TRX transfer, `amount` in sun.
+    Transfer { to: String, amount: u64 },
+    /// TRC-20 `transfer` call, `amount` is a decimal integer in token units.
+    Trc20Transfer {
+        contract: String,
+        to: String,
+        amount: String,
+    },
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct TronTransaction {
+    pub contract: TronContract,
+    /// Hex encoded bytes 6..8 of the reference block number.
+    pub ref_block_bytes: String,
+    /// Hex encoded bytes 8..16 of the reference block id.
+    pub ref_block_hash: String,
+    /// Expiration time in milliseconds.
+    pub expiration: u64,
+    /// Creation time in milliseconds.
+    pub timestamp: u64,
+    /// Maximum energy fee in sun, required by smart contract calls.
+    pub fee_limit: u64,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct TronSignedTransaction {
+    /// Hex encoded SHA-256 of `raw_data`.
+    pub tx_id: String,
+    /// Hex encoded `protocol.Transaction.raw` protobuf.
+    pub raw_data_hex: String,
+    /// Hex encoded 65 bytes `r || s || v` signature, with v = 27 + recovery id.
+    pub signature: String,
+}
+
+pub struct TronWallet {
+    pub signer: Signer,
+    pub address: [u8; 21],
+}
+
+impl TronWallet {
+    pub fn new(signer: Signer) -> Result<Self> {
+        let address = to_tron_address(public_key_to_address(signer.public_key())?);
+        Ok(Self { signer, address })
+    }
+
+    /// Base58check encoded `T...` address.
+    pub fn address(&self) -> String {
+        bs58::encode(self.address).with_check().into_string()
+    }
+
+    pub async fn sign_transaction(&self, tx: &TronTransaction) -> Result<TronSignedTransaction> {
+        let raw_data = tx.raw_data(&self.address)?;
+        let tx_id = sha256(&raw_data);
+
+        let sign = self.signer.sign_hash(tx_id).await?;
+        let (sig, recovery_id) = recoverable_signature(self.signer.public_key(), &tx_id, &sign)?;
+        let mut signature = sig.to_bytes().to_vec();
+        signature.push(27 + recovery_id.to_byte());
+
+        Ok(TronSignedTransaction {
+            tx_id: hex::encode(tx_id),
+            raw_data_hex: hex::encode(raw_data),
+            signature: hex::encode(signature),
+        })
+    }
+}
+
+fn to_tron_address(address: Address) -> [u8; 21] {
+    let mut bytes = [ADDRESS_PREFIX; 21];
+    bytes[1..].copy_from_slice(address.as_bytes());
+    bytes
+}
+
+/// Decodes a base58check `T...` address into its 21 bytes form.
+pub fn decode_address(address: &str) -> Result<[u8; 21]> {
+    bs58::decode(address)
+        .with_check(Some(ADDRESS_PREFIX))
+        .into_vec()
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Error::InvalidAddress(address.to_string()))
+}
+
+fn decode_hex<const N: usize>(name: &str, value: &str) -> Result<[u8; N]> {
+    hex::decode(value)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Error::InvalidTransaction(format!("{name} {value}")))
+}
+
+impl TronTransaction {
+    /// `protocol.Transaction.raw` protobuf with the transaction contract owned by `owner`.
+    pub fn raw_data(&self, owner: &[u8; 21]) -> Result<Vec<u8>> {
+        let ref_block_bytes = decode_hex::<2>("ref_block_bytes", &self.ref_block_bytes)?;
+        let ref_block_hash = decode_hex::<8>("ref_block_hash", &self.ref_block_hash)?;
+
+        let mut raw = ProtoWriter::new();
+        raw.bytes(1, &ref_block_bytes)
+            .bytes(4, &ref_block_hash)
+            .int64(8, self.expiration as i64)
+            .message(11, &self.contract.to_bytes(owner)?)
+            .int64(14, self.timestamp as i64)
+            .int64(18, self.fee_limit as i64);
+        Ok(raw.into_bytes())
+    }
+}
+
+impl TronContract {
+    /// `protocol.Transaction.Contract`
+    fn to_bytes(&self, owner: &[u8; 21]) -> Result<Vec<u8>> {
+        let (contract_type, type_url, value) = match self {
+            Self::Transfer { to, amount } => {
+                let mut transfer = ProtoWriter::new();
+                transfer
+                    .bytes(1, owner)
+                    .bytes(2, &decode_address(to)?)
+                    .int64(3, *amount as i64);
+                (
+                    TRANSFER_CONTRACT,
+                    TRANSFER_CONTRACT_TYPE,
+                    transfer.into_bytes(),
+                )
+            }
+            Self::Trc20Transfer {
+                contract,
+                to,
+                amount,
+            } => {
+                let to = decode_address(to)?;
+                let amount = U256::from_dec_str(amount)
+                    .map_err(|_| Error::InvalidTransaction(format!("amount {amount}")))?;
+                let mut data = TRC20_TRANSFER_SELECTOR.to_vec();
+                data.extend(abi::encode(&[
+                    Token::Address(Address::from_slice(&to[1..])),
+                    Token::Uint(amount),
+                ]));
+
+                let mut trigger = ProtoWriter::new();
+                trigger
+                    .bytes(1, owner)
+                    .bytes(2, &decode_address(contract)?)
+                    .bytes(4, &data);
+                (
+                    TRIGGER_SMART_CONTRACT,
+                    TRIGGER_SMART_CONTRACT_TYPE,
+                    trigger.into_bytes(),
+                )
+            }
+        };
+
+        let mut parameter = ProtoWriter::new();
+        parameter.string(1, type_url).bytes(2, &value);
+
+        let mut contract = ProtoWriter::new();
+        contract
+            .uint64(1, contract_type)
+            .message(2, &parameter.into_bytes());
+        Ok(contract.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_addresses() {
+        let address = to_tron_address(Address::zero());
+        assert_eq!(
+            bs58::encode(address).with_check().into_string(),
+            "T9yD14Nj9j7xAB4dbGeiX9h8unkKHxuWwb"
+        );
+
+        let address = decode_address("TPL66VK2gCXNCD7EJg9pgJRfqcRazjhUZY").unwrap();
+        assert_eq!(
+            hex::encode(address),
+            "41928c9af0651632157ef27a2cf17ca72c575a4d21"
+        );
+        assert!(decode_address("TPL66VK2gCXNCD7EJg9pgJRfqcRazjhUZZ").is_err());
+    }
+
+    #[test]
+    fn encodes_trx_transfer() {
+        let owner = decode_address("TPL66VK2gCXNCD7EJg9pgJRfqcRazjhUZY").unwrap();
+        let tx = TronTransaction {
+            contract: TronContract::Transfer {
+                to: "T9yD14Nj9j7xAB4dbGeiX9h8unkKHxuWwb".to_string(),
+                amount: 1_000_000,
+            },
+            ref_block_bytes: "1a2b".to_string(),
+            ref_block_hash: "0102030405060708".to_string(),
+            expiration: 1_700_000_060_000,
+            timestamp: 1_700_000_000_000,
+            fee_limit: 0,
+        };
+
+        let transfer = format!(
+            "0a15{}1215{}18c0843d",
+            hex::encode(owner),
+            hex::encode(to_tron_address(Address::zero()))
+        );
+        let parameter = format!("0a2d{}1232{transfer}", hex::encode(TRANSFER_CONTRACT_TYPE));
+        let contract = format!("08011263{parameter}");
+        // the zero fee limit is omitted
+        assert_eq!(
+            hex::encode(tx.raw_data(&owner).unwrap()),
+            format!("0a021a2b2208010203040506070840e0a499ffbc315a67{contract}7080d095ffbc31")
+        );
+    }
+
+    #[test]
+    fn encodes_trc20_transfer_call() {
+        // 1 USDT to the owner itself
+        let contract = TronContract::Trc20Transfer {
+            contract: "TR7NHqjeKQxGTCi8q8ZY4pL8otSzgjLj6t".to_string(),
+            to: "TPL66VK2gCXNCD7EJg9pgJRfqcRazjhUZY".to_string(),
+            amount: "1000000".to_string(),
+        };
+        let owner = decode_address("TPL66VK2gCXNCD7EJg9pgJRfqcRazjhUZY").unwrap();
+
+        // transfer(address,uint256) with the 20 bytes address and the amount as 32 bytes words
+        let data = concat!(
+            "a9059cbb",
+            "000000000000000000000000928c9af0651632157ef27a2cf17ca72c575a4d21",
+            "00000000000000000000000000000000000000000000000000000000000f4240",
+        );
+        let trigger = format!(
+            "0a1541928c9af0651632157ef27a2cf17ca72c575a4d21121541a614f803b6fd780986a42c78ec9c7f77e6ded13c2244{data}"
+        );
+        let parameter = format!(
+            "0a31{}1274{trigger}",
+            hex::encode(TRIGGER_SMART_CONTRACT_TYPE)
+        );
+        assert_eq!(
+            hex::encode(contract.to_bytes(&owner).unwrap()),
+            format!("081f12a901{parameter}")
+        );
+    }
+}
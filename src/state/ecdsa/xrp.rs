@@ -0,0 +1,524 @@
+use candid::{CandidType, Deserialize};
+use sha2::{Digest, Sha512};
+
+use crate::error::{Error, Result};
+use crate::state::ecdsa::hash::hash160;
+use crate::state::ecdsa::signature::to_der;
+use crate::state::ecdsa::Signer;
+
+const ACCOUNT_ID_PREFIX: u8 = 0x00;
+/// `STX\0`, prefix of the data signed by single signatures.
+const SIGNING_PREFIX: [u8; 4] = [0x53, 0x54, 0x58, 0x00];
+/// `TXN\0`, prefix of the data hashed into the transaction id.
+const TRANSACTION_ID_PREFIX: [u8; 4] = [0x54, 0x58, 0x4e, 0x00];
+
+const PAYMENT: u16 = 0;
+const TRUST_SET: u16 = 20;
+
+// (type code, field code) of the serialized fields, declared in canonical order
+const TRANSACTION_TYPE: (u8, u8) = (1, 2);
+const FLAGS: (u8, u8) = (2, 2);
+const SEQUENCE: (u8, u8) = (2, 4);
+const DESTINATION_TAG: (u8, u8) = (2, 14);
+const LAST_LEDGER_SEQUENCE: (u8, u8) = (2, 27);
+const AMOUNT: (u8, u8) = (6, 1);
+const LIMIT_AMOUNT: (u8, u8) = (6, 3);
+const FEE: (u8, u8) = (6, 8);
+const SIGNING_PUB_KEY: (u8, u8) = (7, 3);
+const TXN_SIGNATURE: (u8, u8) = (7, 4);
+const ACCOUNT: (u8, u8) = (8, 1);
+const DESTINATION: (u8, u8) = (8, 3);
+
+#[derive(Clone, CandidType, Deserialize)]
+pub enum XrpAmount {
+    /// Amount of XRP in drops.
+    Xrp(u64),
+    /// Issued currency amount.
+    Issued {
+        /// Decimal value, e.g. `"12.5"` or `"1e-3"`.
+        value: String,
+        /// Three letter ISO code or 40 hex characters for non standard codes.
+        currency: String,
+        issuer: String,
+    },
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub enum XrpTransactionKind {
+    Payment {
+        destination: String,
+        amount: XrpAmount,
+        destination_tag: Option<u32>,
+    },
+    TrustSet {
+        limit_amount: XrpAmount,
+    },
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct XrpTransaction {
+    pub kind: XrpTransactionKind,
+    /// Fee in drops.
+    pub fee: u64,
+    pub sequence: u32,
+    pub last_ledger_sequence: Option<u32>,
+    pub flags: u32,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct XrpSignedTransaction {
+    /// Upper case hex encoded signed transaction, as expected by the `submit` method.
+    pub tx_blob: String,
+    /// Transaction id.
+    pub hash: String,
+}
+
+pub struct XrpWallet {
+    pub signer: Signer,
+    pub account_id: [u8; 20],
+}
+
+impl XrpWallet {
+    pub fn new(signer: Signer) -> Self {
+        let account_id = hash160(signer.public_key());
+        Self { signer, account_id }
+    }
+
+    /// Classic `r...` address.
+    pub fn address(&self) -> String {
+        encode_address(&self.account_id)
+    }
+
+    pub async fn sign_transaction(&self, tx: &XrpTransaction) -> Result<XrpSignedTransaction> {
+        let public_key = self.signer.public_key();
+        let unsigned = tx.serialize(&self.account_id, public_key, None)?;
+        let sign = self
+            .signer
+            .sign_hash(sha512_half(&SIGNING_PREFIX, &unsigned))
+            .await?;
+
+        let signature = to_der(&sign)?;
+        let signed = tx.serialize(&self.account_id, public_key, Some(&signature))?;
+
+        Ok(XrpSignedTransaction {
+            hash: hex::encode_upper(sha512_half(&TRANSACTION_ID_PREFIX, &signed)),
+            tx_blob: hex::encode_upper(signed),
+        })
+    }
+}
+
+/// First half of the SHA-512 of the prefixed data.
+pub fn sha512_half(prefix: &[u8], data: &[u8]) -> [u8; 32] {
+    let hash = Sha512::new()
+        .chain_update(prefix)
+        .chain_update(data)
+        .finalize();
+    let mut half = [0u8; 32];
+    half.copy_from_slice(&hash[..32]);
+    half
+}
+
+pub fn encode_address(account_id: &[u8; 20]) -> String {
+    let mut payload = vec![ACCOUNT_ID_PREFIX];
+    payload.extend_from_slice(account_id);
+    bs58::encode(payload)
+        .with_alphabet(bs58::Alphabet::RIPPLE)
+        .with_check()
+        .into_string()
+}
+
+pub fn decode_address(address: &str) -> Result<[u8; 20]> {
+    bs58::decode(address)
+        .with_alphabet(bs58::Alphabet::RIPPLE)
+        .with_check(Some(ACCOUNT_ID_PREFIX))
+        .into_vec()
+        .ok()
+        .and_then(|payload| payload[1..].try_into().ok())
+        .ok_or_else(|| Error::InvalidAddress(address.to_string()))
+}
+
+impl XrpTransaction {
+    /// Canonical binary encoding, with the signature field only for the signed form.
+    pub fn serialize(
+        &self,
+        account: &[u8; 20],
+        public_key: &[u8],
+        signature: Option<&[u8]>,
+    ) -> Result<Vec<u8>> {
+        let mut s = Serializer::default();
+
+        match &self.kind {
+            XrpTransactionKind::Payment {
+                destination,
+                amount,
+                destination_tag,
+            } => {
+                s.uint16(TRANSACTION_TYPE, PAYMENT);
+                s.uint32(FLAGS, self.flags);
+                s.uint32(SEQUENCE, self.sequence);
+                if let Some(tag) = destination_tag {
+                    s.uint32(DESTINATION_TAG, *tag);
+                }
+                if let Some(last_ledger_sequence) = self.last_ledger_sequence {
+                    s.uint32(LAST_LEDGER_SEQUENCE, last_ledger_sequence);
+                }
+                s.amount(AMOUNT, amount)?;
+                s.amount(FEE, &XrpAmount::Xrp(self.fee))?;
+                s.blob(SIGNING_PUB_KEY, public_key);
+                if let Some(signature) = signature {
+                    s.blob(TXN_SIGNATURE, signature);
+                }
+                s.account(ACCOUNT, account);
+                s.account(DESTINATION, &decode_address(destination)?);
+            }
+            XrpTransactionKind::TrustSet { limit_amount } => {
+                if matches!(limit_amount, XrpAmount::Xrp(_)) {
+                    return Err(Error::InvalidTransaction(
+                        "trust line limit must be an issued currency".to_string(),
+                    ));
+                }
+                s.uint16(TRANSACTION_TYPE, TRUST_SET);
+                s.uint32(FLAGS, self.flags);
+                s.uint32(SEQUENCE, self.sequence);
+                if let Some(last_ledger_sequence) = self.last_ledger_sequence {
+                    s.uint32(LAST_LEDGER_SEQUENCE, last_ledger_sequence);
+                }
+                s.amount(LIMIT_AMOUNT, limit_amount)?;
+                s.amount(FEE, &XrpAmount::Xrp(self.fee))?;
+                s.blob(SIGNING_PUB_KEY, public_key);
+                if let Some(signature) = signature {
+                    s.blob(TXN_SIGNATURE, signature);
+                }
+                s.account(ACCOUNT, account);
+            }
+        }
+
+        Ok(s.buf)
+    }
+}
+
+#[derive(Default)]
+struct Serializer {
+    buf: Vec<u8>,
+}
+
+impl Serializer {
+    fn field_id(&mut self, (type_code, field_code): (u8, u8)) {
+        match (type_code < 16, field_code < 16) {
+            (true, true) => self.buf.push(type_code << 4 | field_code),
+            (true, false) => self.buf.extend_from_slice(&[type_code << 4, field_code]),
+            (false, true) => self.buf.extend_from_slice(&[field_code, type_code]),
+            (false, false) => self.buf.extend_from_slice(&[0, type_code, field_code]),
+        }
+    }
+
+    fn uint16(&mut self, field: (u8, u8), value: u16) {
+        self.field_id(field);
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn uint32(&mut self, field: (u8, u8), value: u32) {
+        self.field_id(field);
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn amount(&mut self, field: (u8, u8), amount: &XrpAmount) -> Result<()> {
+        self.field_id(field);
+        self.buf.extend_from_slice(&encode_amount(amount)?);
+        Ok(())
+    }
+
+    fn blob(&mut self, field: (u8, u8), value: &[u8]) {
+        self.field_id(field);
+        self.length_prefix(value.len());
+        self.buf.extend_from_slice(value);
+    }
+
+    fn account(&mut self, field: (u8, u8), account_id: &[u8; 20]) {
+        self.blob(field, account_id)
+    }
+
+    fn length_prefix(&mut self, len: usize) {
+        // public keys, signatures and account ids always fit the single byte form
+        debug_assert!(len <= 192);
+        self.buf.push(len as u8);
+    }
+}
+
+const MIN_MANTISSA: u64 = 1_000_000_000_000_000;
+const MAX_MANTISSA: u64 = 9_999_999_999_999_999;
+const MIN_EXPONENT: i32 = -96;
+const MAX_EXPONENT: i32 = 80;
+
+fn encode_amount(amount: &XrpAmount) -> Result<Vec<u8>> {
+    match amount {
+        XrpAmount::Xrp(drops) => {
+            if *drops > 100_000_000_000_000_000 {
+                return Err(Error::InvalidTransaction(format!("XRP amount {drops}")));
+            }
+            // not an issued currency, positive
+            Ok((drops | 0x4000_0000_0000_0000).to_be_bytes().to_vec())
+        }
+        XrpAmount::Issued {
+            value,
+            currency,
+            issuer,
+        } => {
+            let mut bytes = encode_issued_value(value)?.to_be_bytes().to_vec();
+            bytes.extend_from_slice(&encode_currency(currency)?);
+            bytes.extend_from_slice(&decode_address(issuer)?);
+            Ok(bytes)
+        }
+    }
+}
+
+fn encode_issued_value(value: &str) -> Result<u64> {
+    let invalid = || Error::InvalidTransaction(format!("issued amount {value}"));
+
+    let (negative, unsigned) = match value.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, value),
+    };
+    let (number, mut exponent) = match unsigned.split_once(['e', 'E']) {
+        Some((number, exponent)) => (number, exponent.parse::<i32>().map_err(|_| invalid())?),
+        None => (unsigned, 0),
+    };
+    let (integer, fraction) = number.split_once('.').unwrap_or((number, ""));
+    if integer.is_empty() && fraction.is_empty()
+        || !integer
+            .chars()
+            .chain(fraction.chars())
+            .all(|c| c.is_ascii_digit())
+    {
+        return Err(invalid());
+    }
+
+    let digits = format!("{integer}{fraction}");
+    let digits = digits.trim_start_matches('0');
+    exponent -= fraction.len() as i32;
+
+    // keep at most 16 significant digits, the precision of issued amounts
+    let (significant, dropped) = digits.split_at(digits.len().min(16));
+    if dropped.chars().any(|c| c != '0') {
+        return Err(invalid());
+    }
+    exponent += dropped.len() as i32;
+
+    let mut mantissa: u64 = if significant.is_empty() {
+        0
+    } else {
+        significant.parse().map_err(|_| invalid())?
+    };
+    if mantissa == 0 {
+        // canonical zero
+        return Ok(0x8000_0000_0000_0000);
+    }
+
+    while mantissa < MIN_MANTISSA {
+        mantissa *= 10;
+        exponent -= 1;
+    }
+    debug_assert!(mantissa <= MAX_MANTISSA);
+    if !(MIN_EXPONENT..=MAX_EXPONENT).contains(&exponent) {
+        return Err(invalid());
+    }
+
+    let mut encoded = 0x8000_0000_0000_0000 | mantissa | (((exponent + 97) as u64) << 54);
+    if !negative {
+        encoded |= 0x4000_0000_0000_0000;
+    }
+    Ok(encoded)
+}
+
+fn encode_currency(currency: &str) -> Result<[u8; 20]> {
+    let invalid = || Error::InvalidTransaction(format!("currency {currency}"));
+
+    if currency.len() == 40 {
+        return hex::decode(currency)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(invalid);
+    }
+    if currency.len() != 3 || currency == "XRP" || !currency.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut bytes = [0u8; 20];
+    bytes[12..15].copy_from_slice(currency.as_bytes());
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GENESIS: &str = "rHb9CJAWyB4rj91VRWn96DkukG4bwdtyTh";
+
+    #[test]
+    fn encodes_addresses() {
+        // genesis account of the `masterpassphrase` seed
+        let public_key =
+            hex::decode("0330E7FC9D56BB25D6893BA3F317AE5BCF33B3291BD63DB32654A313222F7FD020")
+                .unwrap();
+        let account_id = hash160(public_key);
+        assert_eq!(encode_address(&account_id), GENESIS);
+        assert_eq!(decode_address(GENESIS).unwrap(), account_id);
+
+        assert_eq!(encode_address(&[0; 20]), "rrrrrrrrrrrrrrrrrrrrrhoLvTp");
+        assert!(decode_address("rHb9CJAWyB4rj91VRWn96DkukG4bwdtyTi").is_err());
+    }
+
+    #[test]
+    fn encodes_amounts() {
+        assert_eq!(
+            hex::encode_upper(encode_amount(&XrpAmount::Xrp(1)).unwrap()),
+            "4000000000000001"
+        );
+        assert_eq!(encode_issued_value("1").unwrap(), 0xD4838D7EA4C68000);
+        assert_eq!(encode_issued_value("1.0").unwrap(), 0xD4838D7EA4C68000);
+        assert_eq!(encode_issued_value("0.1e1").unwrap(), 0xD4838D7EA4C68000);
+        assert_eq!(encode_issued_value("-1").unwrap(), 0x94838D7EA4C68000);
+        assert_eq!(encode_issued_value("0").unwrap(), 0x8000000000000000);
+        assert!(encode_issued_value("1.2.3").is_err());
+        assert!(encode_issued_value("12345678901234567").is_err());
+
+        assert_eq!(
+            hex::encode_upper(encode_currency("USD").unwrap()),
+            "0000000000000000000000005553440000000000"
+        );
+        assert!(encode_currency("XRP").is_err());
+    }
+
+    // OfferCreate example of https://xrpl.org/serialization.html, whose signature verifies
+    const EXAMPLE_ACCOUNT: &str = "rMBzp8CgpE441cp5PVyA9rpVV7oT8hP3ys";
+    const EXAMPLE_ISSUER: &str = "rvYAfWj5gh67oV6fW32ZzP3Aw4Eubs59B";
+    const EXAMPLE_PUBLIC_KEY: &str =
+        "03EE83BB432547885C219634A1BC407A9DB0474145D69737D09CCDC63E1DEE7FE3";
+    const EXAMPLE_SIGNATURE: &str = "30440220143759437C04F7B61F012563AFE90D8DAFC46E86035E1D965A9CED282C97D4CE02204CFD241E86F17E011298FC1A39B63386C74306A5DE047E213B0F29EFA4571C2C";
+    const EXAMPLE_BLOB: &str = "120007220008000024001ABED82A2380BF2C2019001ABED764D55920AC9391400000000000000000000000000055534400000000000A20B3C85F482532A9578DBB3950B85CA06594D165400000037E11D60068400000000000000A732103EE83BB432547885C219634A1BC407A9DB0474145D69737D09CCDC63E1DEE7FE3744630440220143759437C04F7B61F012563AFE90D8DAFC46E86035E1D965A9CED282C97D4CE02204CFD241E86F17E011298FC1A39B63386C74306A5DE047E213B0F29EFA4571C2C8114DD76483FACDEE26E60D8A586BB58D09F27045C46";
+    // fields of the example blob
+    const EXAMPLE_USD_AMOUNT: &str =
+        "D55920AC9391400000000000000000000000000055534400000000000A20B3C85F482532A9578DBB3950B85CA06594D1";
+    const EXAMPLE_XRP_AMOUNT: &str = "400000037E11D600";
+    const EXAMPLE_ACCOUNT_ID: &str = "DD76483FACDEE26E60D8A586BB58D09F27045C46";
+
+    fn usd_7072_8() -> XrpAmount {
+        XrpAmount::Issued {
+            value: "7072.8".to_string(),
+            currency: "USD".to_string(),
+            issuer: EXAMPLE_ISSUER.to_string(),
+        }
+    }
+
+    #[test]
+    fn serializes_published_example() {
+        use ethers_core::k256::ecdsa::signature::hazmat::PrehashVerifier;
+        use ethers_core::k256::ecdsa::{Signature, VerifyingKey};
+
+        let public_key = hex::decode(EXAMPLE_PUBLIC_KEY).unwrap();
+        let signature = hex::decode(EXAMPLE_SIGNATURE).unwrap();
+        let account = decode_address(EXAMPLE_ACCOUNT).unwrap();
+        assert_eq!(account, hash160(&public_key));
+        for field in [EXAMPLE_USD_AMOUNT, EXAMPLE_XRP_AMOUNT, EXAMPLE_ACCOUNT_ID] {
+            assert!(EXAMPLE_BLOB.contains(field));
+        }
+
+        let serialize = |signature: Option<&[u8]>| {
+            let mut s = Serializer::default();
+            s.uint16(TRANSACTION_TYPE, 7);
+            s.uint32(FLAGS, 524288);
+            s.uint32(SEQUENCE, 1752792);
+            s.uint32((2, 10), 595640108);
+            s.uint32((2, 25), 1752791);
+            s.amount((6, 4), &usd_7072_8()).unwrap();
+            s.amount((6, 5), &XrpAmount::Xrp(15_000_000_000)).unwrap();
+            s.amount(FEE, &XrpAmount::Xrp(10)).unwrap();
+            s.blob(SIGNING_PUB_KEY, &public_key);
+            if let Some(signature) = signature {
+                s.blob(TXN_SIGNATURE, signature);
+            }
+            s.account(ACCOUNT, &account);
+            s.buf
+        };
+        assert_eq!(hex::encode_upper(serialize(Some(&signature))), EXAMPLE_BLOB);
+
+        let hash = sha512_half(&SIGNING_PREFIX, &serialize(None));
+        assert!(VerifyingKey::from_sec1_bytes(&public_key)
+            .unwrap()
+            .verify_prehash(&hash, &Signature::from_der(&signature).unwrap())
+            .is_ok());
+    }
+
+    #[test]
+    fn serializes_payment() {
+        let tx = XrpTransaction {
+            kind: XrpTransactionKind::Payment {
+                destination: EXAMPLE_ISSUER.to_string(),
+                amount: XrpAmount::Xrp(15_000_000_000),
+                destination_tag: Some(7),
+            },
+            fee: 10,
+            sequence: 1752792,
+            last_ledger_sequence: None,
+            flags: 0x8000_0000,
+        };
+        let account = decode_address(EXAMPLE_ACCOUNT).unwrap();
+        let public_key = hex::decode(EXAMPLE_PUBLIC_KEY).unwrap();
+        let signature = hex::decode(EXAMPLE_SIGNATURE).unwrap();
+        let bytes = tx
+            .serialize(&account, &public_key, Some(&signature))
+            .unwrap();
+
+        let expected = [
+            "120000",
+            "2280000000",
+            "24001ABED8",
+            "2E00000007",
+            &format!("61{EXAMPLE_XRP_AMOUNT}"),
+            "68400000000000000A",
+            &format!("7321{EXAMPLE_PUBLIC_KEY}"),
+            &format!("7446{EXAMPLE_SIGNATURE}"),
+            &format!("8114{EXAMPLE_ACCOUNT_ID}"),
+            "83140A20B3C85F482532A9578DBB3950B85CA06594D1",
+        ]
+        .concat();
+        assert_eq!(hex::encode_upper(bytes), expected);
+    }
+
+    #[test]
+    fn serializes_trust_set() {
+        let tx = XrpTransaction {
+            kind: XrpTransactionKind::TrustSet {
+                limit_amount: usd_7072_8(),
+            },
+            fee: 10,
+            sequence: 1752792,
+            last_ledger_sequence: Some(300),
+            flags: 0,
+        };
+        let account = decode_address(EXAMPLE_ACCOUNT).unwrap();
+        let public_key = hex::decode(EXAMPLE_PUBLIC_KEY).unwrap();
+        let bytes = tx.serialize(&account, &public_key, None).unwrap();
+
+        let expected = [
+            "120014",
+            "2200000000",
+            "24001ABED8",
+            "201B0000012C",
+            &format!("63{EXAMPLE_USD_AMOUNT}"),
+            "68400000000000000A",
+            &format!("7321{EXAMPLE_PUBLIC_KEY}"),
+            &format!("8114{EXAMPLE_ACCOUNT_ID}"),
+        ]
+        .concat();
+        assert_eq!(hex::encode_upper(bytes), expected);
+
+        let tx = XrpTransaction {
+            kind: XrpTransactionKind::TrustSet {
+                limit_amount: XrpAmount::Xrp(1),
+            },
+            ..tx
+        };
+        assert!(tx.serialize(&account, &public_key, None).is_err());
+    }
+}
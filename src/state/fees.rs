@@ -0,0 +1,502 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_stable_structures::memory_manager::VirtualMemory;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+
+use crate::error::{Error, Result};
+use crate::state::codec::read_u64;
+use crate::state::{
+    decode, encode, PrincipalIdKey, StorablePrincipal, CHARGES_MEMORY_ID, FEE_ACCOUNTS_MEMORY_ID,
+    FEE_SCHEDULE_MEMORY_ID, MEMORY_MANAGER,
+};
+
+/// Operation charged to the users.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum Operation {
+    /// Creation of a threshold key by `init_user` and the like.
+    CreateKey,
+    EcdsaSignature,
+    /// Ed25519 and BIP-340 signatures.
+    SchnorrSignature,
+}
+
+/// Fee of each operation.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub struct OperationFees {
+    pub create_key: u128,
+    pub ecdsa_signature: u128,
+    pub schnorr_signature: u128,
+}
+
+impl OperationFees {
+    pub fn get(&self, operation: Operation) -> u128 {
+        match operation {
+            Operation::CreateKey => self.create_key,
+            Operation::EcdsaSignature => self.ecdsa_signature,
+            Operation::SchnorrSignature => self.schnorr_signature,
+        }
+    }
+}
+
+/// Fees in the tokens of a supported ledger.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct IcrcFees {
+    pub ledger: Principal,
+    pub fees: OperationFees,
+}
+
+/// Fees charged to the users, set by the fee managers.
+///
+/// An operation is paid from the prepaid cycles balance of the user, or with an ICRC-2
+/// `transfer_from` when the balance is short. Operations without a fee in cycles are
+/// paid in tokens only, operations without any fee are free.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub struct FeeSchedule {
+    pub cycles: OperationFees,
+    pub icrc: Option<IcrcFees>,
+}
+
+impl Storable for FeeSchedule {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        encode(&self).into()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(bytes.as_ref())
+    }
+
+    // the cell stores the value whatever its size
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum Payment {
+    Cycles(u128),
+    Icrc {
+        ledger: Principal,
+        amount: Nat,
+        /// Index of the `transfer_from` block.
+        block: Nat,
+    },
+}
+
+/// Operation paid by a user.
+// if change the struct, need to update the BOUND in Storable impl
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Charge {
+    pub id: u64,
+    /// Nanoseconds since the epoch.
+    pub timestamp: u64,
+    pub operation: Operation,
+    pub payment: Payment,
+    /// The operation failed and its fee was given back.
+    pub refunded: bool,
+}
+
+impl Storable for Charge {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        encode(&self).into()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 256,
+        is_fixed_size: false,
+    };
+}
+
+/// What a user owes for an operation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Due {
+    Nothing,
+    /// Paid with prepaid cycles, the id of the charge.
+    Charged(u64),
+    /// To pay with an ICRC-2 `transfer_from`, then to `record`.
+    Tokens {
+        ledger: Principal,
+        amount: u128,
+    },
+}
+
+/// Prepaid cycles of a user and the number of its charges.
+#[derive(Clone, Copy, Default)]
+struct FeeAccount {
+    balance: u128,
+    charges: u64,
+}
+
+const FEE_ACCOUNT_SIZE: usize = 16 + 8;
+
+/// Big endian balance followed by the big endian number of charges.
+impl Storable for FeeAccount {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(FEE_ACCOUNT_SIZE);
+        bytes.extend_from_slice(&self.balance.to_be_bytes());
+        bytes.extend_from_slice(&self.charges.to_be_bytes());
+        bytes.into()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            balance: u128::from_be_bytes(bytes[..16].try_into().expect("16 bytes")),
+            charges: read_u64(&bytes[16..]),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: FEE_ACCOUNT_SIZE as u32,
+        is_fixed_size: true,
+    };
+}
+
+/// Prepaid balance and charges of a user, from its charge `start`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct UsageStatement {
+    /// Cycles left to pay the operations.
+    pub balance: u128,
+    /// Number of charges of the user.
+    pub total_charges: u64,
+    pub charges: Vec<Charge>,
+}
+
+/// Fee schedule, prepaid balances and charges of the users.
+#[derive(Default, Clone, Copy)]
+pub struct Fees {}
+
+impl Fees {
+    pub fn reset(&mut self) {
+        FEE_SCHEDULE.with(|cell| {
+            cell.borrow_mut()
+                .set(FeeSchedule::default())
+                .expect("failed to update fee schedule stable memory data")
+        });
+        FEE_ACCOUNTS.with(|accounts| {
+            accounts.replace(StableBTreeMap::new(
+                MEMORY_MANAGER.with(|m| m.borrow().get(FEE_ACCOUNTS_MEMORY_ID)),
+            ))
+        });
+        CHARGES.with(|charges| {
+            charges.replace(StableBTreeMap::new(
+                MEMORY_MANAGER.with(|m| m.borrow().get(CHARGES_MEMORY_ID)),
+            ))
+        });
+    }
+
+    pub fn get_schedule(&self) -> FeeSchedule {
+        FEE_SCHEDULE.with(|cell| *cell.borrow().get())
+    }
+
+    pub fn set_schedule(&mut self, schedule: FeeSchedule) -> Result<()> {
+        FEE_SCHEDULE
+            .with(|cell| cell.borrow_mut().set(schedule))
+            .map_err(|e| Error::StableError(format!("fee schedule update error is {:?}", e)))?;
+        Ok(())
+    }
+
+    pub fn balance(&self, user: Principal) -> u128 {
+        self.account(user).balance
+    }
+
+    /// Adds `amount` cycles to the prepaid balance of `user`, returns the new balance.
+    pub fn top_up(&mut self, user: Principal, amount: u128) -> u128 {
+        let mut account = self.account(user);
+        account.balance = account.balance.saturating_add(amount);
+        self.set_account(user, account);
+        account.balance
+    }
+
+    /// Takes `times` the fee of `operation` from the prepaid balance of `user` when the
+    /// schedule has one, else returns the tokens it owes.
+    pub fn charge(
+        &mut self,
+        user: Principal,
+        operation: Operation,
+        times: u64,
+        timestamp: u64,
+    ) -> Result<Due> {
+        let schedule = self.get_schedule();
+        let fee = schedule.cycles.get(operation).saturating_mul(times.into());
+        let icrc = schedule.icrc.and_then(|icrc| {
+            let amount = icrc.fees.get(operation).saturating_mul(times.into());
+            (amount > 0).then_some((icrc.ledger, amount))
+        });
+        if fee > 0 {
+            match self.debit(user, operation, fee, timestamp) {
+                Ok(id) => return Ok(Due::Charged(id)),
+                Err(Error::InsufficientCycles(_)) if icrc.is_some() => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(match icrc {
+            Some((ledger, amount)) => Due::Tokens { ledger, amount },
+            None => Due::Nothing,
+        })
+    }
+
+    /// Takes `fee` cycles from the prepaid balance of `user` for `operation`, returns
+    /// the id of the charge.
+    pub fn debit(
+        &mut self,
+        user: Principal,
+        operation: Operation,
+        fee: u128,
+        timestamp: u64,
+    ) -> Result<u64> {
+        let account = self.account(user);
+        if account.balance < fee {
+            return Err(Error::InsufficientCycles(fee));
+        }
+        self.set_account(
+            user,
+            FeeAccount {
+                balance: account.balance - fee,
+                ..account
+            },
+        );
+        Ok(self.record(user, operation, Payment::Cycles(fee), timestamp))
+    }
+
+    /// Adds a charge paid by `user`, returns its id.
+    pub fn record(
+        &mut self,
+        user: Principal,
+        operation: Operation,
+        payment: Payment,
+        timestamp: u64,
+    ) -> u64 {
+        let mut account = self.account(user);
+        let id = account.charges;
+        account.charges += 1;
+        self.set_account(user, account);
+        CHARGES.with(|charges| {
+            charges.borrow_mut().insert(
+                PrincipalIdKey(user, id),
+                Charge {
+                    id,
+                    timestamp,
+                    operation,
+                    payment,
+                    refunded: false,
+                },
+            )
+        });
+        id
+    }
+
+    /// Marks the charge `id` of `user` refunded and gives back its cycles, once. Returns
+    /// the payment to give back, tokens being transferred by the caller.
+    pub fn refund(&mut self, user: Principal, id: u64) -> Option<Payment> {
+        let key = PrincipalIdKey(user, id);
+        let mut charge = CHARGES.with(|charges| charges.borrow().get(&key))?;
+        if charge.refunded {
+            return None;
+        }
+        charge.refunded = true;
+        CHARGES.with(|charges| charges.borrow_mut().insert(key, charge.clone()));
+        if let Payment::Cycles(fee) = charge.payment {
+            self.top_up(user, fee);
+        }
+        Some(charge.payment)
+    }
+
+    /// Marks the charge `id` of `user` not refunded again, when its tokens could not be
+    /// transferred back.
+    pub fn cancel_refund(&mut self, user: Principal, id: u64) {
+        let key = PrincipalIdKey(user, id);
+        if let Some(mut charge) = CHARGES.with(|charges| charges.borrow().get(&key)) {
+            charge.refunded = false;
+            CHARGES.with(|charges| charges.borrow_mut().insert(key, charge));
+        }
+    }
+
+    /// Returns the balance of `user` and at most `limit` of its charges, from the charge `start`.
+    pub fn statement(&self, user: Principal, start: u64, limit: usize) -> UsageStatement {
+        let account = self.account(user);
+        let charges = CHARGES.with(|charges| {
+            charges
+                .borrow()
+                .range(PrincipalIdKey(user, start)..)
+                .take_while(|(key, _)| key.0 == user)
+                .take(limit)
+                .map(|(_, charge)| charge)
+                .collect()
+        });
+        UsageStatement {
+            balance: account.balance,
+            total_charges: account.charges,
+            charges,
+        }
+    }
+
+    fn account(&self, user: Principal) -> FeeAccount {
+        FEE_ACCOUNTS
+            .with(|accounts| accounts.borrow().get(&StorablePrincipal(user)))
+            .unwrap_or_default()
+    }
+
+    fn set_account(&mut self, user: Principal, account: FeeAccount) {
+        FEE_ACCOUNTS.with(|accounts| {
+            accounts
+                .borrow_mut()
+                .insert(StorablePrincipal(user), account)
+        });
+    }
+}
+
+thread_local! {
+    static FEE_SCHEDULE: RefCell<StableCell<FeeSchedule, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(FEE_SCHEDULE_MEMORY_ID)), FeeSchedule::default()).expect("fee schedule initialization failed"));
+    static FEE_ACCOUNTS: RefCell<StableBTreeMap<StorablePrincipal, FeeAccount, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(FEE_ACCOUNTS_MEMORY_ID))));
+    static CHARGES: RefCell<StableBTreeMap<PrincipalIdKey, Charge, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(CHARGES_MEMORY_ID))));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(byte: u8) -> Principal {
+        Principal::from_slice(&[byte; 29])
+    }
+
+    #[test]
+    fn charges_prepaid_cycles() {
+        let mut fees = Fees::default();
+        fees.reset();
+        assert_eq!(
+            fees.debit(user(1), Operation::EcdsaSignature, 10, 0),
+            Err(Error::InsufficientCycles(10))
+        );
+
+        assert_eq!(fees.top_up(user(1), 25), 25);
+        assert_eq!(fees.debit(user(1), Operation::EcdsaSignature, 10, 1), Ok(0));
+        assert_eq!(fees.debit(user(1), Operation::CreateKey, 10, 2), Ok(1));
+        assert_eq!(fees.balance(user(1)), 5);
+        assert_eq!(fees.balance(user(2)), 0);
+
+        assert_eq!(fees.refund(user(1), 1), Some(Payment::Cycles(10)));
+        assert_eq!(fees.refund(user(1), 1), None);
+        assert_eq!(fees.balance(user(1)), 15);
+
+        let id = fees.record(
+            user(1),
+            Operation::SchnorrSignature,
+            Payment::Icrc {
+                ledger: user(9),
+                amount: 3u64.into(),
+                block: 7u64.into(),
+            },
+            3,
+        );
+        assert_eq!(id, 2);
+        // tokens are given back by the caller, not as cycles
+        let payment = fees.refund(user(1), 2).unwrap();
+        assert!(matches!(payment, Payment::Icrc { .. }));
+        assert_eq!(fees.balance(user(1)), 15);
+        fees.cancel_refund(user(1), 2);
+        assert_eq!(fees.refund(user(1), 2), Some(payment));
+        fees.cancel_refund(user(1), 2);
+        fees.top_up(user(2), 1);
+
+        let statement = fees.statement(user(1), 1, 10);
+        assert_eq!(statement.balance, 15);
+        assert_eq!(statement.total_charges, 3);
+        assert_eq!(
+            statement.charges.iter().map(|c| c.id).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert!(statement.charges[0].refunded);
+        assert_eq!(statement.charges[0].payment, Payment::Cycles(10));
+        assert!(fees.statement(user(2), 0, 10).charges.is_empty());
+    }
+
+    #[test]
+    fn charges_cycles_then_tokens() {
+        let mut fees = Fees::default();
+        fees.reset();
+        assert_eq!(
+            fees.charge(user(1), Operation::EcdsaSignature, 1, 0),
+            Ok(Due::Nothing)
+        );
+
+        // cycles only, the operation isn't free once they run out
+        fees.set_schedule(FeeSchedule {
+            cycles: OperationFees {
+                ecdsa_signature: 10,
+                ..OperationFees::default()
+            },
+            icrc: Some(IcrcFees {
+                ledger: user(9),
+                fees: OperationFees {
+                    schnorr_signature: 3,
+                    ..OperationFees::default()
+                },
+            }),
+        })
+        .unwrap();
+        fees.top_up(user(1), 25);
+        assert_eq!(
+            fees.charge(user(1), Operation::EcdsaSignature, 2, 1),
+            Ok(Due::Charged(0))
+        );
+        assert_eq!(
+            fees.charge(user(1), Operation::EcdsaSignature, 1, 2),
+            Err(Error::InsufficientCycles(10))
+        );
+        assert_eq!(fees.balance(user(1)), 5);
+        // tokens only, whatever the prepaid balance
+        assert_eq!(
+            fees.charge(user(1), Operation::SchnorrSignature, 2, 3),
+            Ok(Due::Tokens {
+                ledger: user(9),
+                amount: 6
+            })
+        );
+        assert_eq!(
+            fees.charge(user(1), Operation::CreateKey, 1, 4),
+            Ok(Due::Nothing)
+        );
+
+        // tokens once the cycles run out
+        fees.set_schedule(FeeSchedule {
+            cycles: OperationFees {
+                ecdsa_signature: 10,
+                ..OperationFees::default()
+            },
+            icrc: Some(IcrcFees {
+                ledger: user(9),
+                fees: OperationFees {
+                    ecdsa_signature: 3,
+                    ..OperationFees::default()
+                },
+            }),
+        })
+        .unwrap();
+        assert_eq!(
+            fees.charge(user(1), Operation::EcdsaSignature, 1, 5),
+            Ok(Due::Tokens {
+                ledger: user(9),
+                amount: 3
+            })
+        );
+        assert_eq!(fees.balance(user(1)), 5);
+        assert_eq!(fees.statement(user(1), 0, 10).total_charges, 1);
+    }
+
+    #[test]
+    fn encodes_fee_accounts() {
+        let account = FeeAccount {
+            balance: u128::MAX - 1,
+            charges: 42,
+        };
+        let bytes = account.to_bytes();
+        assert_eq!(bytes.len(), FEE_ACCOUNT_SIZE);
+        let decoded = FeeAccount::from_bytes(bytes);
+        assert_eq!(decoded.balance, account.balance);
+        assert_eq!(decoded.charges, account.charges);
+    }
+}
@@ -0,0 +1,352 @@
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::memory_manager::VirtualMemory;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+
+use crate::error::{Error, Result};
+use crate::state::codec::{
+    read_padded, read_principal, write_padded, write_principal, PRINCIPAL_SIZE,
+};
+use crate::state::ecdsa::hash::sha256;
+use crate::state::{
+    decode, encode, IDEMPOTENCY_MEMORY_ID, IDEMPOTENCY_TTL_MEMORY_ID, MEMORY_MANAGER,
+};
+
+/// Maximum length of an idempotency key.
+pub const MAX_IDEMPOTENCY_KEY_LEN: usize = 64;
+/// Time during which a key returns the result of its first call, 24 hours.
+pub const DEFAULT_IDEMPOTENCY_TTL: u64 = 24 * 60 * 60 * 1_000_000_000;
+/// Records looked at by each call for expired ones.
+const GC_BATCH: usize = 8;
+
+/// Hash of the endpoint and of its arguments, telling a retry from a reuse of its key.
+pub fn fingerprint(endpoint: &str, args: &impl CandidType) -> [u8; 32] {
+    let mut bytes = endpoint.as_bytes().to_vec();
+    bytes.push(0);
+    bytes.extend_from_slice(&encode(args));
+    sha256(bytes)
+}
+
+/// Idempotency key of a user.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IdempotencyKey(pub Principal, pub String);
+
+const IDEMPOTENCY_KEY_SIZE: usize = PRINCIPAL_SIZE + 1 + MAX_IDEMPOTENCY_KEY_LEN;
+
+/// Principal followed by the key, both zero padded.
+impl Storable for IdempotencyKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(IDEMPOTENCY_KEY_SIZE);
+        write_principal(&mut bytes, &self.0);
+        write_padded(&mut bytes, self.1.as_bytes(), MAX_IDEMPOTENCY_KEY_LEN);
+        bytes.into()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let key = read_padded(&bytes[PRINCIPAL_SIZE..]);
+        Self(
+            read_principal(&bytes),
+            String::from_utf8(key.to_vec()).expect("idempotency key is utf-8"),
+        )
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: IDEMPOTENCY_KEY_SIZE as u32,
+        is_fixed_size: true,
+    };
+}
+
+/// Outcome of `Idempotency::begin`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Call {
+    /// Candid encoded response of the first call made with the key.
+    Replay(Vec<u8>),
+    /// The call must run, as the attempt finishing it.
+    Run(u64),
+}
+
+/// Call made with an idempotency key.
+// the responses are not bounded
+#[derive(Clone, CandidType, Deserialize)]
+struct IdempotencyRecord {
+    fingerprint: [u8; 32],
+    /// Nanoseconds since the epoch.
+    created_at: u64,
+    /// Attempt running the call, only it can finish the call.
+    attempt: u64,
+    /// Candid encoded response, `None` while the call runs.
+    response: Option<Vec<u8>>,
+}
+
+impl Storable for IdempotencyRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        encode(&self).into()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Results of the signing calls made with an idempotency key, kept for a TTL so that
+/// retries don't sign again.
+///
+/// Failed calls are forgotten, so that they can be retried with the same key. So are
+/// the calls which trap, their futures are dropped by the cleanup of the trap; a call
+/// in progress otherwise blocks its key until the TTL.
+#[derive(Default, Clone, Copy)]
+pub struct Idempotency {}
+
+impl Idempotency {
+    pub fn reset(&mut self) {
+        IDEMPOTENCY_TTL.with(|cell| {
+            cell.borrow_mut()
+                .set(DEFAULT_IDEMPOTENCY_TTL)
+                .expect("failed to update idempotency TTL stable memory data")
+        });
+        RECORDS.with(|records| {
+            records.replace(StableBTreeMap::new(
+                MEMORY_MANAGER.with(|m| m.borrow().get(IDEMPOTENCY_MEMORY_ID)),
+            ))
+        });
+        GC_CURSOR.with(|cursor| cursor.take());
+        LAST_ATTEMPT.with(|attempt| attempt.set(0));
+    }
+
+    pub fn get_ttl(&self) -> u64 {
+        IDEMPOTENCY_TTL.with(|cell| *cell.borrow().get())
+    }
+
+    pub fn set_ttl(&mut self, ttl: u64) -> Result<()> {
+        IDEMPOTENCY_TTL
+            .with(|cell| cell.borrow_mut().set(ttl))
+            .map_err(|e| Error::StableError(format!("idempotency TTL update error is {:?}", e)))?;
+        Ok(())
+    }
+
+    /// Starts a call of `caller` with `key`. Returns the response of the first call
+    /// made with the key and the same `fingerprint`, or the attempt to finish the call with.
+    pub fn begin(
+        &mut self,
+        caller: Principal,
+        key: &str,
+        fingerprint: [u8; 32],
+        timestamp: u64,
+    ) -> Result<Call> {
+        if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+            return Err(Error::Internal(format!("invalid idempotency key: {key}")));
+        }
+        self.collect_garbage(timestamp);
+
+        let key = IdempotencyKey(caller, key.to_string());
+        let ttl = self.get_ttl();
+        if let Some(record) = RECORDS.with(|records| records.borrow().get(&key)) {
+            if !is_expired(&record, ttl, timestamp) {
+                if record.fingerprint != fingerprint {
+                    return Err(Error::IdempotencyConflict(key.1));
+                }
+                return match record.response {
+                    Some(response) => Ok(Call::Replay(response)),
+                    None => Err(Error::RequestInProgress(key.1)),
+                };
+            }
+        }
+
+        // increasing across upgrades, the attempts of expired records never match again
+        let attempt = LAST_ATTEMPT.with(|last| {
+            let attempt = timestamp.max(last.get() + 1);
+            last.set(attempt);
+            attempt
+        });
+        let record = IdempotencyRecord {
+            fingerprint,
+            created_at: timestamp,
+            attempt,
+            response: None,
+        };
+        RECORDS.with(|records| records.borrow_mut().insert(key, record));
+        Ok(Call::Run(attempt))
+    }
+
+    /// Stores the `response` of a call started by `begin`, or forgets the call if it failed.
+    /// Does nothing if the record of the key belongs to another attempt.
+    pub fn finish(
+        &mut self,
+        caller: Principal,
+        key: &str,
+        attempt: u64,
+        response: Option<Vec<u8>>,
+    ) {
+        let key = IdempotencyKey(caller, key.to_string());
+        RECORDS.with(|records| {
+            let mut records = records.borrow_mut();
+            let Some(mut record) = records.get(&key).filter(|record| record.attempt == attempt)
+            else {
+                return;
+            };
+            match response {
+                Some(response) => {
+                    record.response = Some(response);
+                    records.insert(key, record);
+                }
+                None => {
+                    records.remove(&key);
+                }
+            }
+        });
+    }
+
+    /// Removes the expired records among the next `GC_BATCH` ones, going through all
+    /// the records over the calls.
+    fn collect_garbage(&mut self, timestamp: u64) {
+        let ttl = self.get_ttl();
+        RECORDS.with(|records| {
+            let mut records = records.borrow_mut();
+            let batch: Vec<(IdempotencyKey, IdempotencyRecord)> =
+                match GC_CURSOR.with(|cursor| cursor.take()) {
+                    Some(cursor) => records.range(cursor..).take(GC_BATCH).collect(),
+                    None => records.iter().take(GC_BATCH).collect(),
+                };
+            // start over once the end is reached
+            let next = match batch.len() {
+                GC_BATCH => batch.last().map(|(key, _)| key.clone()),
+                _ => None,
+            };
+            for (key, record) in batch {
+                if is_expired(&record, ttl, timestamp) {
+                    records.remove(&key);
+                }
+            }
+            GC_CURSOR.with(|cursor| cursor.replace(next));
+        });
+    }
+}
+
+fn is_expired(record: &IdempotencyRecord, ttl: u64, timestamp: u64) -> bool {
+    record.created_at.saturating_add(ttl) <= timestamp
+}
+
+thread_local! {
+    static IDEMPOTENCY_TTL: RefCell<StableCell<u64, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(IDEMPOTENCY_TTL_MEMORY_ID)), DEFAULT_IDEMPOTENCY_TTL).expect("idempotency TTL initialization failed"));
+    static RECORDS: RefCell<StableBTreeMap<IdempotencyKey, IdempotencyRecord, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(IDEMPOTENCY_MEMORY_ID))));
+    static GC_CURSOR: RefCell<Option<IdempotencyKey>> = RefCell::new(None);
+    static LAST_ATTEMPT: Cell<u64> = Cell::new(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(byte: u8) -> Principal {
+        Principal::from_slice(&[byte; 29])
+    }
+
+    fn records() -> u64 {
+        RECORDS.with(|records| records.borrow().len())
+    }
+
+    fn run(call: Result<Call>) -> u64 {
+        match call {
+            Ok(Call::Run(attempt)) => attempt,
+            _ => panic!("the call doesn't run"),
+        }
+    }
+
+    #[test]
+    fn replays_identical_retries() {
+        let mut idempotency = Idempotency::default();
+        idempotency.reset();
+        let tx = fingerprint("sign_xrp_transaction", &"tx");
+
+        let attempt = run(idempotency.begin(user(1), "key", tx, 0));
+        assert_eq!(
+            idempotency.begin(user(1), "key", tx, 1),
+            Err(Error::RequestInProgress("key".to_string()))
+        );
+        idempotency.finish(user(1), "key", attempt, Some(vec![1, 2]));
+        assert_eq!(
+            idempotency.begin(user(1), "key", tx, 2),
+            Ok(Call::Replay(vec![1, 2]))
+        );
+
+        // other arguments or endpoints can't reuse the key
+        let other_tx = fingerprint("sign_xrp_transaction", &"other tx");
+        assert_eq!(
+            idempotency.begin(user(1), "key", other_tx, 3),
+            Err(Error::IdempotencyConflict("key".to_string()))
+        );
+        assert_ne!(fingerprint("sign_tron_transaction", &"tx"), tx);
+        // keys are per user
+        let attempt = run(idempotency.begin(user(2), "key", other_tx, 3));
+
+        // failed calls run again
+        idempotency.finish(user(2), "key", attempt, None);
+        run(idempotency.begin(user(2), "key", other_tx, 4));
+
+        assert!(idempotency.begin(user(1), "", tx, 5).is_err());
+        assert!(idempotency
+            .begin(user(1), &"k".repeat(MAX_IDEMPOTENCY_KEY_LEN + 1), tx, 5)
+            .is_err());
+    }
+
+    #[test]
+    fn expires_records() {
+        let mut idempotency = Idempotency::default();
+        idempotency.reset();
+        idempotency.set_ttl(100).unwrap();
+        let tx = fingerprint("sign_nostr_event", &"event");
+
+        for i in 0..GC_BATCH as u8 * 2 {
+            let attempt = run(idempotency.begin(user(i), "key", tx, 0));
+            idempotency.finish(user(i), "key", attempt, Some(vec![i]));
+        }
+        assert_eq!(records(), GC_BATCH as u64 * 2);
+
+        // an expired key runs the call again
+        run(idempotency.begin(user(0), "key", tx, 100));
+
+        // the calls remove the expired records, a batch at a time
+        idempotency.begin(user(100), "key", tx, 100).unwrap();
+        idempotency.begin(user(101), "key", tx, 100).unwrap();
+        assert_eq!(records(), 3);
+    }
+
+    #[test]
+    fn ignores_other_attempts() {
+        let mut idempotency = Idempotency::default();
+        idempotency.reset();
+        idempotency.set_ttl(100).unwrap();
+        let tx = fingerprint("sign_sui_transaction", &"tx");
+
+        // a call still in progress when its record expires
+        let late = run(idempotency.begin(user(1), "key", tx, 0));
+        let retry = run(idempotency.begin(user(1), "key", tx, 100));
+        assert_ne!(late, retry);
+
+        // the late call can neither overwrite nor forget the retry
+        idempotency.finish(user(1), "key", late, Some(vec![1]));
+        idempotency.finish(user(1), "key", late, None);
+        assert_eq!(
+            idempotency.begin(user(1), "key", tx, 101),
+            Err(Error::RequestInProgress("key".to_string()))
+        );
+        idempotency.finish(user(1), "key", retry, Some(vec![2]));
+        assert_eq!(
+            idempotency.begin(user(1), "key", tx, 102),
+            Ok(Call::Replay(vec![2]))
+        );
+    }
+
+    #[test]
+    fn encodes_keys() {
+        let key = IdempotencyKey(user(1), "retry-42".to_string());
+        let bytes = key.to_bytes();
+        assert_eq!(bytes.len(), IDEMPOTENCY_KEY_SIZE);
+        assert_eq!(IdempotencyKey::from_bytes(bytes), key);
+    }
+}
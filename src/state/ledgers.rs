@@ -0,0 +1,75 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::memory_manager::VirtualMemory;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+
+use crate::state::{decode, encode, StorablePrincipal, LEDGERS_MEMORY_ID, MEMORY_MANAGER};
+
+/// Maximum length of a token symbol.
+pub const MAX_SYMBOL_LEN: usize = 32;
+
+/// Token of a supported ICRC-1 ledger.
+// if change the struct, need to update the BOUND in Storable impl
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct LedgerInfo {
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+impl Storable for LedgerInfo {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        encode(&self).into()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 128,
+        is_fixed_size: false,
+    };
+}
+
+/// Registry of the ICRC-1 ledgers whose tokens the canister holds for its users.
+#[derive(Default, Clone, Copy)]
+pub struct Ledgers {}
+
+impl Ledgers {
+    pub fn reset(&mut self) {
+        LEDGERS.with(|ledgers| {
+            ledgers.replace(StableBTreeMap::new(
+                MEMORY_MANAGER.with(|m| m.borrow().get(LEDGERS_MEMORY_ID)),
+            ))
+        });
+    }
+
+    pub fn get(&self, ledger: Principal) -> Option<LedgerInfo> {
+        LEDGERS.with(|ledgers| ledgers.borrow().get(&StorablePrincipal(ledger)))
+    }
+
+    pub fn set(&mut self, ledger: Principal, info: LedgerInfo) {
+        LEDGERS.with(|ledgers| ledgers.borrow_mut().insert(StorablePrincipal(ledger), info));
+    }
+
+    pub fn remove(&mut self, ledger: Principal) -> Option<LedgerInfo> {
+        LEDGERS.with(|ledgers| ledgers.borrow_mut().remove(&StorablePrincipal(ledger)))
+    }
+
+    pub fn list(&self) -> Vec<(Principal, LedgerInfo)> {
+        LEDGERS.with(|ledgers| {
+            ledgers
+                .borrow()
+                .iter()
+                .map(|(ledger, info)| (ledger.0, info))
+                .collect()
+        })
+    }
+}
+
+thread_local! {
+    static LEDGERS: RefCell<StableBTreeMap<StorablePrincipal, LedgerInfo, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(LEDGERS_MEMORY_ID))));
+}
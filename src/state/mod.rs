@@ -1,5 +1,6 @@
 use std::borrow::Cow;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeSet;
 
 use candid::Principal;
 use candid::{CandidType, Decode, Deserialize, Encode};
@@ -7,15 +8,55 @@ use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
 use ic_stable_structures::storable::Bound;
 use ic_stable_structures::{DefaultMemoryImpl, Storable};
 
+use crate::error::{Error, Result};
+use crate::state::access::Access;
+use crate::state::codec::{read_principal, read_u64, write_principal, PRINCIPAL_SIZE};
 use crate::state::config::Config;
 use crate::state::ecdsa::{EcdsaKeyIds, Nonces, Signers};
+use crate::state::fees::Fees;
+use crate::state::idempotency::Idempotency;
+use crate::state::ledgers::Ledgers;
+use crate::state::pauses::Pauses;
+use crate::state::proposals::{Action, Proposal, ProposalStatus, Proposals};
+use crate::state::queue::SigningQueue;
+use crate::state::roles::{Role, Roles};
+use crate::state::schema::Schema;
+use crate::state::schnorr::{Bip340Signers, Ed25519Signers};
 
+pub mod access;
+pub mod codec;
 mod config;
 pub mod ecdsa;
+pub mod fees;
+pub mod idempotency;
+pub mod ledgers;
+pub mod pauses;
+pub mod proposals;
+pub mod queue;
+pub mod roles;
+pub mod schema;
+pub mod schnorr;
 
+const SCHEMA_MEMORY_ID: MemoryId = MemoryId::new(0);
 const CONFIG_MEMORY_ID: MemoryId = MemoryId::new(1);
 const SIGNERS_MEMORY_ID: MemoryId = MemoryId::new(2);
 const NONCES_MEMORY_ID: MemoryId = MemoryId::new(3);
+const ED25519_SIGNERS_MEMORY_ID: MemoryId = MemoryId::new(4);
+const LEDGERS_MEMORY_ID: MemoryId = MemoryId::new(5);
+const BIP340_SIGNERS_MEMORY_ID: MemoryId = MemoryId::new(6);
+const ROLES_MEMORY_ID: MemoryId = MemoryId::new(7);
+const ROLE_AUDIT_MEMORY_ID: MemoryId = MemoryId::new(8);
+const PROPOSALS_MEMORY_ID: MemoryId = MemoryId::new(9);
+const PAUSES_MEMORY_ID: MemoryId = MemoryId::new(10);
+const ACCESS_POLICY_MEMORY_ID: MemoryId = MemoryId::new(11);
+const ALLOWLIST_MEMORY_ID: MemoryId = MemoryId::new(12);
+const FEE_SCHEDULE_MEMORY_ID: MemoryId = MemoryId::new(13);
+const FEE_ACCOUNTS_MEMORY_ID: MemoryId = MemoryId::new(14);
+const CHARGES_MEMORY_ID: MemoryId = MemoryId::new(15);
+const JOBS_MEMORY_ID: MemoryId = MemoryId::new(16);
+const JOB_QUEUE_MEMORY_ID: MemoryId = MemoryId::new(17);
+const IDEMPOTENCY_MEMORY_ID: MemoryId = MemoryId::new(18);
+const IDEMPOTENCY_TTL_MEMORY_ID: MemoryId = MemoryId::new(19);
 
 /// State of a minter canister.
 #[derive(Default)]
@@ -24,6 +65,17 @@ pub struct State {
     pub config: Config,
     pub signers: Signers,
     pub nonces: Nonces,
+    pub ed25519_signers: Ed25519Signers,
+    pub ledgers: Ledgers,
+    pub bip340_signers: Bip340Signers,
+    pub roles: Roles,
+    pub proposals: Proposals,
+    pub pauses: Pauses,
+    pub access: Access,
+    pub fees: Fees,
+    pub queue: SigningQueue,
+    pub idempotency: Idempotency,
+    pub schema: Schema,
 }
 
 impl State {
@@ -32,19 +84,227 @@ impl State {
         self.config.reset(settings);
         self.signers.reset();
         self.nonces.reset();
+        self.ed25519_signers.reset();
+        self.ledgers.reset();
+        self.bip340_signers.reset();
+        self.roles.reset();
+        self.proposals.reset();
+        self.pauses.reset();
+        self.access.reset();
+        self.fees.reset();
+        self.queue.reset();
+        self.idempotency.reset();
+        self.schema.reset();
+        USER_COUNT.with(|count| count.set(None));
+        PENDING_USERS.with(|pending| pending.borrow_mut().clear());
+        self.grant_admins(settings.installer, settings.owner, settings.timestamp);
+    }
+
+    /// Migrates the stable memory written by the previous version of the canister and
+    /// applies the changed settings. Unlike `reset`, it keeps all the user data.
+    ///
+    /// The signing jobs interrupted by the upgrade fail, waiting ones stay queued.
+    ///
+    /// A new owner is granted the admin role. When no admin is left, as after the
+    /// upgrade of a canister without roles, the installer and the owner become admins.
+    pub fn upgrade(&mut self, settings: UpgradeSettings) -> Result<()> {
+        self.schema.migrate()?;
+        self.queue.restart(settings.timestamp);
+        if let Some(owner) = settings.owner {
+            self.config.set_owner(owner)?;
+        }
+        if let Some(ecdsa_env) = settings.ecdsa_env {
+            self.config.set_ecdsa_env(ecdsa_env)?;
+        }
+        if let Some(owner) = settings.owner {
+            self.roles
+                .grant(owner, Role::Admin, settings.installer, settings.timestamp)?;
+        }
+        if !self.roles.has_admin() {
+            let owner = self.config.get_owner();
+            self.grant_admins(settings.installer, owner, settings.timestamp);
+        }
+        Ok(())
+    }
+
+    /// Whether `user` has a key of any type.
+    pub fn is_user(&self, user: Principal) -> bool {
+        self.signers.get(user).is_some()
+            || self.ed25519_signers.get(user).is_some()
+            || self.bip340_signers.get(user).is_some()
+    }
+
+    /// Number of the principals with a key of any type. Counted once from the signers,
+    /// then kept on the heap.
+    pub fn user_count(&self) -> u64 {
+        if let Some(count) = USER_COUNT.with(Cell::get) {
+            return count;
+        }
+        let ed25519_only = self
+            .ed25519_signers
+            .principals()
+            .into_iter()
+            .filter(|user| self.signers.get(*user).is_none())
+            .count();
+        let bip340_only = self
+            .bip340_signers
+            .principals()
+            .into_iter()
+            .filter(|user| {
+                self.signers.get(*user).is_none() && self.ed25519_signers.get(*user).is_none()
+            })
+            .count();
+        let count = self.signers.count() + ed25519_only as u64 + bip340_only as u64;
+        USER_COUNT.with(|cell| cell.set(Some(count)));
+        count
+    }
+
+    /// Reserves the creation of a key of `user` until the returned reservation is dropped,
+    /// before the key derivation awaits. Fails if another key creation of `user` is in
+    /// progress, or if `user` has no key yet and the maximum of users, counting the ones
+    /// whose first key is in progress, is reached.
+    pub fn reserve_user(&self, user: Principal) -> Result<UserReservation> {
+        PENDING_USERS.with(|pending| {
+            let mut pending = pending.borrow_mut();
+            if pending.contains(&user) {
+                return Err(Error::KeyCreationInProgress);
+            }
+            if !self.is_user(user) {
+                let new_users = pending.iter().filter(|user| !self.is_user(**user)).count();
+                self.access
+                    .check_new_user(self.user_count() + new_users as u64)?;
+            }
+            pending.insert(user);
+            Ok(UserReservation(user))
+        })
+    }
+
+    /// Counts `user` among the users, to be called before storing its first key.
+    pub fn add_user(&mut self, user: Principal) {
+        if !self.is_user(user) {
+            let count = self.user_count();
+            USER_COUNT.with(|cell| cell.set(Some(count + 1)));
+        }
+    }
+
+    /// Executes the action of an open proposal once approved by the threshold of the
+    /// current admins, the approval of `by` being the last one. Returns the proposal
+    /// with its new status.
+    pub fn execute_approved(
+        &mut self,
+        mut proposal: Proposal,
+        by: Principal,
+        timestamp: u64,
+    ) -> Proposal {
+        let threshold = self.config.get_approval_policy().threshold as usize;
+        let approvals = proposal
+            .approvals
+            .iter()
+            .filter(|admin| self.roles.has(**admin, Role::Admin))
+            .count();
+        if proposal.status != ProposalStatus::Open || approvals < threshold.min(self.roles.admins())
+        {
+            return proposal;
+        }
+
+        proposal.status = match self.execute(&proposal.action, by, timestamp) {
+            Ok(()) => ProposalStatus::Executed,
+            Err(e) => ProposalStatus::Failed(e.to_string()),
+        };
+        self.proposals
+            .set_status(proposal.id, proposal.status.clone());
+        proposal
+    }
+
+    fn execute(&mut self, action: &Action, by: Principal, timestamp: u64) -> Result<()> {
+        match action.clone() {
+            Action::SetOwner(owner) => {
+                self.roles.grant(owner, Role::Admin, by, timestamp)?;
+                self.config.set_owner(owner)
+            }
+            Action::GrantRole { principal, role } => {
+                self.roles.grant(principal, role, by, timestamp).map(|_| ())
+            }
+            Action::RevokeRole { principal, role } => self
+                .roles
+                .revoke(principal, role, by, timestamp)
+                .map(|_| ()),
+            Action::SetEcdsaEnv(ecdsa_env) => self.config.set_ecdsa_env(ecdsa_env),
+            Action::AddLedger { ledger, info } => {
+                self.ledgers.set(ledger, info);
+                Ok(())
+            }
+            Action::RemoveLedger(ledger) => self
+                .ledgers
+                .remove(ledger)
+                .map(|_| ())
+                .ok_or(Error::LedgerNotSupported(ledger)),
+            Action::SetApprovalPolicy(policy) => self.config.set_approval_policy(policy),
+            Action::SetAccessPolicy(policy) => self.access.set_policy(policy),
+            Action::AllowPrincipals(principals) => {
+                principals
+                    .into_iter()
+                    .for_each(|principal| self.access.allow(principal));
+                Ok(())
+            }
+            Action::DisallowPrincipals(principals) => {
+                principals
+                    .into_iter()
+                    .for_each(|principal| self.access.disallow(principal));
+                Ok(())
+            }
+            Action::SetIdempotencyTtl(ttl) => self.idempotency.set_ttl(ttl),
+        }
+    }
+
+    /// Grants the admin role to the installer and to the owner, unless anonymous.
+    fn grant_admins(&mut self, installer: Principal, owner: Principal, timestamp: u64) {
+        for admin in [installer, owner] {
+            if admin != Principal::anonymous() {
+                self.roles
+                    .grant(admin, Role::Admin, installer, timestamp)
+                    .expect("admin is not anonymous");
+            }
+        }
     }
 }
 
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    static USER_COUNT: Cell<Option<u64>> = Cell::new(None);
+    static PENDING_USERS: RefCell<BTreeSet<Principal>> = RefCell::new(BTreeSet::new());
+}
+
+/// Key creation of a user in progress, see `State::reserve_user`.
+pub struct UserReservation(Principal);
+
+impl Drop for UserReservation {
+    fn drop(&mut self) {
+        PENDING_USERS.with(|pending| pending.borrow_mut().remove(&self.0));
+    }
 }
 
 /// State settings.
 #[derive(Clone, Copy)]
 pub struct Settings {
+    /// Principal installing the canister, its first admin.
+    pub installer: Principal,
     pub owner: Principal,
     pub ecdsa_env: EcdsaKeyIds,
+    /// Time of the installation, in nanoseconds since the epoch.
+    pub timestamp: u64,
+}
+
+/// Settings changed on upgrade, `None` keeps the current value.
+#[derive(Clone, Copy)]
+pub struct UpgradeSettings {
+    /// Principal upgrading the canister.
+    pub installer: Principal,
+    pub owner: Option<Principal>,
+    pub ecdsa_env: Option<EcdsaKeyIds>,
+    /// Time of the upgrade, in nanoseconds since the epoch.
+    pub timestamp: u64,
 }
 
 #[derive(Clone, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
@@ -71,6 +331,30 @@ impl Storable for StorablePrincipal {
     };
 }
 
+/// Record `id` of a user, as the charges and the signing jobs.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PrincipalIdKey(pub Principal, pub u64);
+
+/// Principal followed by the big endian id, so that the records of a user are
+/// contiguous and sorted by id.
+impl Storable for PrincipalIdKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(PRINCIPAL_SIZE + 8);
+        write_principal(&mut bytes, &self.0);
+        bytes.extend_from_slice(&self.1.to_be_bytes());
+        bytes.into()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(read_principal(&bytes), read_u64(&bytes[PRINCIPAL_SIZE..]))
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: PRINCIPAL_SIZE as u32 + 8,
+        is_fixed_size: true,
+    };
+}
+
 pub fn encode(item: &impl CandidType) -> Vec<u8> {
     Encode!(item).expect("failed to encode item to candid")
 }
@@ -78,3 +362,232 @@ pub fn encode(item: &impl CandidType) -> Vec<u8> {
 pub fn decode<'a, T: CandidType + Deserialize<'a>>(bytes: &'a [u8]) -> T {
     Decode!(bytes, T).expect("failed to decode item from candid")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::access::AccessPolicy;
+    use crate::state::ecdsa::eth::EthWallet;
+    use crate::state::ecdsa::Signer;
+    use crate::state::proposals::ApprovalPolicy;
+    use crate::state::schnorr::{SchnorrAlgorithm, SchnorrSigner};
+
+    fn settings() -> Settings {
+        Settings {
+            installer: Principal::from_slice(&[9; 29]),
+            owner: Principal::from_slice(&[1; 29]),
+            ecdsa_env: EcdsaKeyIds::TestKey1,
+            timestamp: 1,
+        }
+    }
+
+    fn upgrade_settings() -> UpgradeSettings {
+        UpgradeSettings {
+            installer: Principal::from_slice(&[9; 29]),
+            owner: None,
+            ecdsa_env: None,
+            timestamp: 2,
+        }
+    }
+
+    #[test]
+    fn reset_keeps_settings() {
+        let mut state = State::default();
+        state.reset(settings());
+        assert_eq!(state.config.get_owner(), settings().owner);
+        assert!(matches!(
+            state.config.get_ecdsa_env(),
+            EcdsaKeyIds::TestKey1
+        ));
+    }
+
+    #[test]
+    fn upgrade_keeps_users() {
+        let mut state = State::default();
+        state.reset(settings());
+        let user = Principal::from_slice(&[2; 29]);
+        let public_key =
+            hex::decode("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+                .unwrap();
+        state
+            .signers
+            .set(user, Signer::test(EcdsaKeyIds::TestKey1, public_key));
+        state.nonces.set(user, 1, 4);
+        let address = EthWallet::new(state.signers.get(user).unwrap(), 1)
+            .unwrap()
+            .address();
+
+        let owner = Principal::from_slice(&[3; 29]);
+        state
+            .upgrade(UpgradeSettings {
+                owner: Some(owner),
+                ecdsa_env: Some(EcdsaKeyIds::ProductionKey1),
+                ..upgrade_settings()
+            })
+            .unwrap();
+        assert_eq!(state.config.get_owner(), owner);
+        assert!(matches!(
+            state.config.get_ecdsa_env(),
+            EcdsaKeyIds::ProductionKey1
+        ));
+        let signer = state.signers.get(user).unwrap();
+        // existing users keep their key
+        assert!(matches!(signer.key_id(), EcdsaKeyIds::TestKey1));
+        assert_eq!(EthWallet::new(signer, 1).unwrap().address(), address);
+        assert_eq!(state.nonces.get(user, 1), Some(4));
+
+        state.upgrade(upgrade_settings()).unwrap();
+        assert_eq!(state.config.get_owner(), owner);
+        assert!(state.signers.get(user).is_some());
+    }
+
+    #[test]
+    fn caps_distinct_users() {
+        let mut state = State::default();
+        state.reset(settings());
+        state
+            .access
+            .set_policy(AccessPolicy {
+                max_users: Some(2),
+                ..AccessPolicy::default()
+            })
+            .unwrap();
+        let (first, second, third) = (
+            Principal::from_slice(&[2; 29]),
+            Principal::from_slice(&[3; 29]),
+            Principal::from_slice(&[4; 29]),
+        );
+
+        let reservation = state.reserve_user(first).unwrap();
+        // concurrent key creations of a user are rejected
+        assert_eq!(
+            state.reserve_user(first).err(),
+            Some(Error::KeyCreationInProgress)
+        );
+        state.add_user(first);
+        state
+            .signers
+            .set(first, Signer::test(EcdsaKeyIds::TestKey1, vec![2; 33]));
+        drop(reservation);
+        // more keys of a user don't count as more users
+        state.add_user(first);
+        state.ed25519_signers.set(
+            first,
+            SchnorrSigner::test(SchnorrAlgorithm::Ed25519, vec![2; 32]),
+        );
+        assert_eq!(state.user_count(), 1);
+
+        // the first key of a user in progress takes the last slot
+        let reservation = state.reserve_user(second).unwrap();
+        assert_eq!(
+            state.reserve_user(third).err(),
+            Some(Error::MaxUsersReached)
+        );
+        // released if the creation fails
+        drop(reservation);
+        let reservation = state.reserve_user(second).unwrap();
+        state.add_user(second);
+        state.bip340_signers.set(
+            second,
+            SchnorrSigner::test(SchnorrAlgorithm::Bip340Secp256k1, vec![3; 33]),
+        );
+        drop(reservation);
+        assert_eq!(state.user_count(), 2);
+        assert_eq!(
+            state.reserve_user(third).err(),
+            Some(Error::MaxUsersReached)
+        );
+        // existing users still create keys of the other types
+        assert!(state.reserve_user(first).is_ok());
+        assert!(state.reserve_user(second).is_ok());
+
+        // the count is rebuilt from the signers after an upgrade
+        USER_COUNT.with(|count| count.set(None));
+        assert_eq!(state.user_count(), 2);
+    }
+
+    #[test]
+    fn bootstraps_admins() {
+        let mut state = State::default();
+        state.reset(Settings {
+            owner: Principal::anonymous(),
+            ..settings()
+        });
+        // the anonymous owner holds no role, the installer does
+        assert_eq!(
+            state.roles.list(),
+            vec![(settings().installer, vec![Role::Admin])]
+        );
+
+        state.reset(settings());
+        assert!(state.roles.has(settings().owner, Role::Admin));
+        assert!(state.roles.has(settings().installer, Role::Admin));
+
+        // canisters upgraded from versions without roles get their admins
+        state.roles.reset();
+        state.upgrade(upgrade_settings()).unwrap();
+        assert!(state.roles.has(settings().owner, Role::Admin));
+        assert!(state.roles.has(settings().installer, Role::Admin));
+    }
+
+    #[test]
+    fn executes_approved_proposals() {
+        let mut state = State::default();
+        state.reset(settings());
+        let (installer, owner) = (settings().installer, settings().owner);
+        let admin = Principal::from_slice(&[4; 29]);
+        let policy = state.config.get_approval_policy();
+
+        // a single approval executes while the threshold is 1
+        let proposal = state.proposals.create(
+            Action::GrantRole {
+                principal: admin,
+                role: Role::Admin,
+            },
+            owner,
+            10,
+            policy,
+        );
+        let proposal = state.execute_approved(proposal, owner, 10);
+        assert_eq!(proposal.status, ProposalStatus::Executed);
+        assert!(state.roles.has(admin, Role::Admin));
+
+        let proposal = state.proposals.create(
+            Action::SetApprovalPolicy(ApprovalPolicy {
+                threshold: 2,
+                expiry: 100,
+            }),
+            owner,
+            20,
+            policy,
+        );
+        state.execute_approved(proposal, owner, 20);
+        let policy = state.config.get_approval_policy();
+        assert_eq!(policy.threshold, 2);
+
+        let proposal = state
+            .proposals
+            .create(Action::SetOwner(admin), owner, 30, policy);
+        let proposal = state.execute_approved(proposal, owner, 30);
+        assert_eq!(proposal.status, ProposalStatus::Open);
+        assert_eq!(state.config.get_owner(), owner);
+
+        let proposal = state.proposals.approve(proposal.id, installer, 40).unwrap();
+        let proposal = state.execute_approved(proposal, installer, 40);
+        assert_eq!(proposal.status, ProposalStatus::Executed);
+        assert_eq!(state.proposals.get(proposal.id), Some(proposal));
+        assert_eq!(state.config.get_owner(), admin);
+
+        // failed actions close the proposal
+        let ledger = Principal::from_slice(&[5; 29]);
+        let proposal = state
+            .proposals
+            .create(Action::RemoveLedger(ledger), owner, 50, policy);
+        let proposal = state.proposals.approve(proposal.id, admin, 50).unwrap();
+        let proposal = state.execute_approved(proposal, admin, 50);
+        assert_eq!(
+            proposal.status,
+            ProposalStatus::Failed(Error::LedgerNotSupported(ledger).to_string())
+        );
+    }
+}
@@ -0,0 +1,414 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::memory_manager::VirtualMemory;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+
+use crate::error::{Error, Result};
+use crate::state::ecdsa::stacks::StacksNetwork;
+use crate::state::{decode, encode, MEMORY_MANAGER, PAUSES_MEMORY_ID};
+
+/// Maximum length of the names and chain ids of a scope.
+pub const MAX_SCOPE_NAME_LEN: usize = 64;
+/// Maximum length of the reason of a pause.
+pub const MAX_REASON_LEN: usize = 256;
+
+/// Endpoints which can be paused on their own.
+pub const SIGNING_ENDPOINTS: [&str; 16] = [
+    "sign_bch_transaction",
+    "sign_btc_message",
+    "sign_cosmos_transaction",
+    "sign_ethermint_transaction",
+    "sign_filecoin_message",
+    "sign_ic_request",
+    "sign_nostr_event",
+    "sign_polkadot_transaction",
+    "sign_solana_message",
+    "sign_solana_transaction",
+    "sign_stacks_transaction",
+    "sign_sui_transaction",
+    "sign_ton_transaction",
+    "sign_tron_transaction",
+    "sign_xrp_transaction",
+    "test_transfer_eth",
+];
+
+/// Variant names of `CoinType`.
+pub const COIN_TYPES: [&str; 15] = [
+    "Bch",
+    "Btc",
+    "Cosmos",
+    "Ethermint",
+    "Evm",
+    "Filecoin",
+    "InternetComputer",
+    "Nostr",
+    "Polkadot",
+    "Solana",
+    "Stacks",
+    "Sui",
+    "Ton",
+    "Tron",
+    "Xrp",
+];
+
+/// Signing requests stopped by a pause switch.
+// if change the enum, need to update the BOUND in Storable impl
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, CandidType, Deserialize)]
+pub enum PauseScope {
+    /// Every signing endpoint.
+    Global,
+    /// A signing endpoint, by method name, e.g. `sign_xrp_transaction`.
+    Endpoint(String),
+    /// Every chain of a `CoinType`, by variant name, e.g. `Evm`.
+    CoinType(String),
+    /// A chain of a `CoinType`, e.g. `Evm` and `1`, or `Cosmos` and `cosmoshub-4`.
+    Chain { coin_type: String, chain_id: String },
+}
+
+impl PauseScope {
+    /// Checks that the scope names a known endpoint, coin type or chain, and returns it
+    /// with the chain id in the form `Pauses::check` looks up.
+    fn normalize(self) -> Result<Self> {
+        let known = match &self {
+            PauseScope::Global => true,
+            PauseScope::Endpoint(name) => SIGNING_ENDPOINTS.contains(&name.as_str()),
+            PauseScope::CoinType(name) => COIN_TYPES.contains(&name.as_str()),
+            PauseScope::Chain { coin_type, .. } => COIN_TYPES.contains(&coin_type.as_str()),
+        };
+        let invalid = || Error::Internal(format!("invalid pause scope: {self:?}"));
+        if !known {
+            return Err(invalid());
+        }
+        if let PauseScope::Chain {
+            coin_type,
+            chain_id,
+        } = &self
+        {
+            return Ok(PauseScope::Chain {
+                coin_type: coin_type.clone(),
+                chain_id: normalize_chain_id(coin_type, chain_id).map_err(|_| invalid())?,
+            });
+        }
+        Ok(self)
+    }
+}
+
+/// Canonical form of the chain ids of a coin type, so that any spelling accepted by the
+/// signing endpoints hits the pauses of the chain: decimal EVM chain ids, lower case
+/// Polkadot genesis hashes without `0x` and `StacksNetwork::as_str` names.
+pub fn normalize_chain_id(coin_type: &str, chain_id: &str) -> Result<String> {
+    let invalid = || Error::InvalidTransaction(format!("{coin_type} chain id {chain_id}"));
+    let normalized = match coin_type {
+        "Evm" => chain_id.parse::<u64>().map_err(|_| invalid())?.to_string(),
+        "Cosmos" | "Ethermint" => chain_id.to_string(),
+        "Polkadot" => {
+            let hash = hex::decode(chain_id.trim_start_matches("0x")).map_err(|_| invalid())?;
+            if hash.len() != 32 {
+                return Err(invalid());
+            }
+            hex::encode(hash)
+        }
+        "Stacks" => StacksNetwork::ALL
+            .into_iter()
+            .find(|network| network.as_str().eq_ignore_ascii_case(chain_id))
+            .ok_or_else(invalid)?
+            .as_str()
+            .to_string(),
+        // no chains
+        _ => return Err(invalid()),
+    };
+    if normalized.is_empty() || normalized.len() > MAX_SCOPE_NAME_LEN {
+        return Err(invalid());
+    }
+    Ok(normalized)
+}
+
+impl Storable for PauseScope {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        encode(&self).into()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 256,
+        is_fixed_size: false,
+    };
+}
+
+// if change the struct, need to update the BOUND in Storable impl
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Pause {
+    pub reason: String,
+    pub paused_by: Principal,
+    /// Nanoseconds since the epoch.
+    pub paused_at: u64,
+    /// Time from which signing resumes on its own, if any.
+    pub expires_at: Option<u64>,
+}
+
+impl Pause {
+    fn is_active(&self, timestamp: u64) -> bool {
+        match self.expires_at {
+            Some(expires_at) => timestamp < expires_at,
+            None => true,
+        }
+    }
+}
+
+impl Storable for Pause {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        encode(&self).into()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 512,
+        is_fixed_size: false,
+    };
+}
+
+/// Emergency switches stopping signing without an upgrade.
+#[derive(Default, Clone, Copy)]
+pub struct Pauses {}
+
+impl Pauses {
+    pub fn reset(&mut self) {
+        PAUSES.with(|pauses| {
+            pauses.replace(StableBTreeMap::new(
+                MEMORY_MANAGER.with(|m| m.borrow().get(PAUSES_MEMORY_ID)),
+            ))
+        });
+    }
+
+    /// Pauses the scope, replacing its previous pause.
+    pub fn pause(&mut self, scope: PauseScope, pause: Pause) -> Result<()> {
+        let scope = scope.normalize()?;
+        if pause.reason.len() > MAX_REASON_LEN {
+            return Err(Error::Internal(format!(
+                "reason longer than {MAX_REASON_LEN} bytes"
+            )));
+        }
+        PAUSES.with(|pauses| pauses.borrow_mut().insert(scope, pause));
+        Ok(())
+    }
+
+    pub fn resume(&mut self, scope: PauseScope) -> Result<Option<Pause>> {
+        let scope = scope.normalize()?;
+        Ok(PAUSES.with(|pauses| pauses.borrow_mut().remove(&scope)))
+    }
+
+    /// Returns the pauses active at `timestamp`.
+    pub fn list(&self, timestamp: u64) -> Vec<(PauseScope, Pause)> {
+        PAUSES.with(|pauses| {
+            pauses
+                .borrow()
+                .iter()
+                .filter(|(_, pause)| pause.is_active(timestamp))
+                .collect()
+        })
+    }
+
+    /// Fails with `Error::Paused` if the endpoint, the coin type or the chain is paused
+    /// at `timestamp`, globally or on their own, and with `Error::InvalidTransaction`
+    /// if the chain id is malformed.
+    pub fn check(
+        &self,
+        endpoint: &str,
+        coin_type: &str,
+        chain_id: Option<&str>,
+        timestamp: u64,
+    ) -> Result<()> {
+        let mut scopes = vec![
+            PauseScope::Global,
+            PauseScope::Endpoint(endpoint.to_string()),
+            PauseScope::CoinType(coin_type.to_string()),
+        ];
+        if let Some(chain_id) = chain_id {
+            scopes.push(PauseScope::Chain {
+                coin_type: coin_type.to_string(),
+                chain_id: normalize_chain_id(coin_type, chain_id)?,
+            });
+        }
+
+        PAUSES.with(|pauses| {
+            let pauses = pauses.borrow();
+            for scope in scopes {
+                if let Some(pause) = pauses.get(&scope) {
+                    if pause.is_active(timestamp) {
+                        return Err(Error::Paused(format!("{scope:?}: {}", pause.reason)));
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+thread_local! {
+    static PAUSES: RefCell<StableBTreeMap<PauseScope, Pause, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(PAUSES_MEMORY_ID))));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pause(reason: &str, expires_at: Option<u64>) -> Pause {
+        Pause {
+            reason: reason.to_string(),
+            paused_by: Principal::from_slice(&[1; 29]),
+            paused_at: 10,
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn checks_pauses() {
+        let mut pauses = Pauses::default();
+        pauses.reset();
+        let evm_1 = PauseScope::Chain {
+            coin_type: "Evm".to_string(),
+            chain_id: "1".to_string(),
+        };
+        pauses.pause(evm_1.clone(), pause("reorg", None)).unwrap();
+        pauses
+            .pause(
+                PauseScope::CoinType("Xrp".to_string()),
+                pause("bug", Some(20)),
+            )
+            .unwrap();
+
+        assert_eq!(
+            pauses.check("test_transfer_eth", "Evm", Some("1"), 15),
+            Err(Error::Paused(format!("{evm_1:?}: reorg")))
+        );
+        assert!(pauses
+            .check("test_transfer_eth", "Evm", Some("10"), 15)
+            .is_ok());
+        assert!(pauses
+            .check("sign_xrp_transaction", "Xrp", None, 15)
+            .is_err());
+        // expired pauses resume signing
+        assert!(pauses
+            .check("sign_xrp_transaction", "Xrp", None, 20)
+            .is_ok());
+        assert_eq!(pauses.list(20), vec![(evm_1.clone(), pause("reorg", None))]);
+
+        assert!(pauses.resume(evm_1).unwrap().is_some());
+        assert!(pauses
+            .check("test_transfer_eth", "Evm", Some("1"), 15)
+            .is_ok());
+
+        pauses
+            .pause(
+                PauseScope::Endpoint("sign_nostr_event".to_string()),
+                pause("maintenance", None),
+            )
+            .unwrap();
+        assert!(pauses.check("sign_nostr_event", "Nostr", None, 15).is_err());
+        pauses
+            .pause(PauseScope::Global, pause("incident", None))
+            .unwrap();
+        assert!(pauses
+            .check("sign_ton_transaction", "Ton", None, 15)
+            .is_err());
+        assert!(pauses
+            .pause(PauseScope::Endpoint(String::new()), pause("", None))
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_scopes() {
+        let mut pauses = Pauses::default();
+        pauses.reset();
+        for scope in [
+            PauseScope::Endpoint("sign_xrp_transactoin".to_string()),
+            PauseScope::Endpoint("get_address".to_string()),
+            PauseScope::CoinType("EVM".to_string()),
+            PauseScope::Chain {
+                coin_type: "Evm".to_string(),
+                chain_id: "mainnet".to_string(),
+            },
+            PauseScope::Chain {
+                coin_type: "Xrp".to_string(),
+                chain_id: "1".to_string(),
+            },
+            PauseScope::Chain {
+                coin_type: "Polkadot".to_string(),
+                chain_id: "0x91b1".to_string(),
+            },
+        ] {
+            assert!(
+                pauses.pause(scope.clone(), pause("", None)).is_err(),
+                "{scope:?}"
+            );
+        }
+        assert!(pauses.list(0).is_empty());
+    }
+
+    #[test]
+    fn normalizes_chain_ids() {
+        let mut pauses = Pauses::default();
+        pauses.reset();
+        let genesis = "91b171bb158e2d3848fa23a9f1c25182fb8e20313b2c1eb49219da7a70ce90c3";
+        pauses
+            .pause(
+                PauseScope::Chain {
+                    coin_type: "Polkadot".to_string(),
+                    chain_id: format!("0x{genesis}"),
+                },
+                pause("upgrade", None),
+            )
+            .unwrap();
+        pauses
+            .pause(
+                PauseScope::Chain {
+                    coin_type: "Stacks".to_string(),
+                    chain_id: "mainnet".to_string(),
+                },
+                pause("upgrade", None),
+            )
+            .unwrap();
+
+        let upper = genesis.to_ascii_uppercase();
+        for chain_id in [
+            genesis.to_string(),
+            format!("0x{genesis}"),
+            upper.clone(),
+            format!("0x{upper}"),
+        ] {
+            assert!(pauses
+                .check("sign_polkadot_transaction", "Polkadot", Some(&chain_id), 0)
+                .is_err());
+        }
+        assert!(pauses
+            .check("sign_stacks_transaction", "Stacks", Some("Mainnet"), 0)
+            .is_err());
+        assert!(pauses
+            .check("sign_stacks_transaction", "Stacks", Some("Testnet"), 0)
+            .is_ok());
+        assert!(matches!(
+            pauses.check("sign_polkadot_transaction", "Polkadot", Some("0x91"), 0),
+            Err(Error::InvalidTransaction(_))
+        ));
+
+        assert!(pauses
+            .resume(PauseScope::Chain {
+                coin_type: "Polkadot".to_string(),
+                chain_id: upper,
+            })
+            .unwrap()
+            .is_some());
+        assert!(pauses
+            .check("sign_polkadot_transaction", "Polkadot", Some(genesis), 0)
+            .is_ok());
+    }
+}
@@ -0,0 +1,295 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::memory_manager::VirtualMemory;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+
+use crate::error::{Error, Result};
+use crate::state::access::AccessPolicy;
+use crate::state::ecdsa::EcdsaKeyIds;
+use crate::state::ledgers::{LedgerInfo, MAX_SYMBOL_LEN};
+use crate::state::roles::Role;
+use crate::state::{decode, encode, MEMORY_MANAGER, PROPOSALS_MEMORY_ID};
+
+/// Approvals needed by the proposals of canisters installed before proposals.
+pub const DEFAULT_APPROVAL_THRESHOLD: u32 = 1;
+/// Time to approve the proposals of canisters installed before proposals, 7 days.
+pub const DEFAULT_PROPOSAL_EXPIRY: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+/// Number of admin approvals executing a proposal, and time to collect them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ApprovalPolicy {
+    /// Capped by the number of admins, so revoking admins never locks the canister.
+    pub threshold: u32,
+    /// Nanoseconds from the creation of a proposal.
+    pub expiry: u64,
+}
+
+impl Default for ApprovalPolicy {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_APPROVAL_THRESHOLD,
+            expiry: DEFAULT_PROPOSAL_EXPIRY,
+        }
+    }
+}
+
+/// Sensitive operation executed once enough admins approve it.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum Action {
+    SetOwner(Principal),
+    GrantRole {
+        principal: Principal,
+        role: Role,
+    },
+    RevokeRole {
+        principal: Principal,
+        role: Role,
+    },
+    /// Key of the users created from now on.
+    SetEcdsaEnv(EcdsaKeyIds),
+    AddLedger {
+        ledger: Principal,
+        info: LedgerInfo,
+    },
+    RemoveLedger(Principal),
+    SetApprovalPolicy(ApprovalPolicy),
+    SetAccessPolicy(AccessPolicy),
+    /// Principals added to the allowlist, used while the access policy enables it.
+    AllowPrincipals(Vec<Principal>),
+    DisallowPrincipals(Vec<Principal>),
+    /// Nanoseconds during which an idempotency key returns the result of its first call.
+    SetIdempotencyTtl(u64),
+}
+
+impl Action {
+    /// Checks the arguments of the action before it's proposed.
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Action::SetOwner(principal)
+            | Action::GrantRole { principal, .. }
+            | Action::RevokeRole { principal, .. }
+                if *principal == Principal::anonymous() =>
+            {
+                Err(Error::AnonymousNotAllowed)
+            }
+            Action::AddLedger { info, .. }
+                if info.symbol.is_empty() || info.symbol.len() > MAX_SYMBOL_LEN =>
+            {
+                Err(Error::Internal(format!("invalid symbol: {}", info.symbol)))
+            }
+            Action::SetApprovalPolicy(policy) if policy.threshold == 0 || policy.expiry == 0 => {
+                Err(Error::Internal(format!(
+                    "invalid approval policy: {policy:?}"
+                )))
+            }
+            Action::SetAccessPolicy(policy) => policy.validate(),
+            Action::AllowPrincipals(principals) if principals.contains(&Principal::anonymous()) => {
+                Err(Error::AnonymousNotAllowed)
+            }
+            Action::SetIdempotencyTtl(0) => {
+                Err(Error::Internal("invalid idempotency TTL: 0".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ProposalStatus {
+    Open,
+    Executed,
+    /// The action returned an error when executed.
+    Failed(String),
+    Expired,
+}
+
+// the number of approvals is not bounded
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Proposal {
+    pub id: u64,
+    pub action: Action,
+    pub proposer: Principal,
+    /// Nanoseconds since the epoch.
+    pub created_at: u64,
+    pub expires_at: u64,
+    /// Admins that approved the proposal, its proposer first.
+    pub approvals: Vec<Principal>,
+    pub status: ProposalStatus,
+}
+
+impl Proposal {
+    /// The proposal with the status it has at `timestamp`: an open proposal past its
+    /// expiry is stored as expired only once an admin tries to approve it.
+    pub fn at(mut self, timestamp: u64) -> Self {
+        if self.status == ProposalStatus::Open && timestamp > self.expires_at {
+            self.status = ProposalStatus::Expired;
+        }
+        self
+    }
+}
+
+impl Storable for Proposal {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        encode(&self).into()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Proposals of the admins with their approvals.
+#[derive(Default, Clone, Copy)]
+pub struct Proposals {}
+
+impl Proposals {
+    pub fn reset(&mut self) {
+        PROPOSALS.with(|proposals| {
+            proposals.replace(StableBTreeMap::new(
+                MEMORY_MANAGER.with(|m| m.borrow().get(PROPOSALS_MEMORY_ID)),
+            ))
+        });
+    }
+
+    pub fn get(&self, id: u64) -> Option<Proposal> {
+        PROPOSALS.with(|proposals| proposals.borrow().get(&id))
+    }
+
+    /// Creates an open proposal approved by its proposer.
+    pub fn create(
+        &mut self,
+        action: Action,
+        proposer: Principal,
+        timestamp: u64,
+        policy: ApprovalPolicy,
+    ) -> Proposal {
+        PROPOSALS.with(|proposals| {
+            let mut proposals = proposals.borrow_mut();
+            let id = proposals.last_key_value().map_or(0, |(id, _)| id + 1);
+            let proposal = Proposal {
+                id,
+                action,
+                proposer,
+                created_at: timestamp,
+                expires_at: timestamp.saturating_add(policy.expiry),
+                approvals: vec![proposer],
+                status: ProposalStatus::Open,
+            };
+            proposals.insert(id, proposal.clone());
+            proposal
+        })
+    }
+
+    /// Adds the approval of `admin` to an open proposal, approving twice is a no-op.
+    /// A proposal approved after its expiry is closed as expired.
+    pub fn approve(&mut self, id: u64, admin: Principal, timestamp: u64) -> Result<Proposal> {
+        let mut proposal = self.get(id).ok_or(Error::ProposalNotFound(id))?;
+        if proposal.status != ProposalStatus::Open {
+            return Err(Error::ProposalClosed(id));
+        }
+        if timestamp > proposal.expires_at {
+            self.set_status(id, ProposalStatus::Expired);
+            return Err(Error::ProposalExpired(id));
+        }
+        if !proposal.approvals.contains(&admin) {
+            proposal.approvals.push(admin);
+            PROPOSALS.with(|proposals| proposals.borrow_mut().insert(id, proposal.clone()));
+        }
+        Ok(proposal)
+    }
+
+    pub fn set_status(&mut self, id: u64, status: ProposalStatus) {
+        if let Some(mut proposal) = self.get(id) {
+            proposal.status = status;
+            PROPOSALS.with(|proposals| proposals.borrow_mut().insert(id, proposal));
+        }
+    }
+
+    /// Returns at most `limit` proposals, starting from the proposal `start`, with their
+    /// status at `timestamp`.
+    pub fn list(&self, start: u64, limit: usize, timestamp: u64) -> Vec<Proposal> {
+        PROPOSALS.with(|proposals| {
+            proposals
+                .borrow()
+                .range(start..)
+                .take(limit)
+                .map(|(_, proposal)| proposal.at(timestamp))
+                .collect()
+        })
+    }
+}
+
+thread_local! {
+    static PROPOSALS: RefCell<StableBTreeMap<u64, Proposal, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(PROPOSALS_MEMORY_ID))));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admin(byte: u8) -> Principal {
+        Principal::from_slice(&[byte; 29])
+    }
+
+    #[test]
+    fn collects_approvals() {
+        let mut proposals = Proposals::default();
+        proposals.reset();
+        let policy = ApprovalPolicy {
+            threshold: 2,
+            expiry: 10,
+        };
+        let action = Action::SetOwner(admin(3));
+        let proposal = proposals.create(action.clone(), admin(1), 100, policy);
+        assert_eq!(proposal.id, 0);
+        assert_eq!(proposal.expires_at, 110);
+        assert_eq!(proposal.approvals, vec![admin(1)]);
+
+        let proposal = proposals.approve(0, admin(1), 105).unwrap();
+        assert_eq!(proposal.approvals, vec![admin(1)]);
+        let proposal = proposals.approve(0, admin(2), 110).unwrap();
+        assert_eq!(proposal.approvals, vec![admin(1), admin(2)]);
+        assert_eq!(proposals.get(0), Some(proposal));
+
+        proposals.set_status(0, ProposalStatus::Executed);
+        assert_eq!(
+            proposals.approve(0, admin(3), 110),
+            Err(Error::ProposalClosed(0))
+        );
+        assert_eq!(
+            proposals.approve(1, admin(3), 110),
+            Err(Error::ProposalNotFound(1))
+        );
+    }
+
+    #[test]
+    fn expires_proposals() {
+        let mut proposals = Proposals::default();
+        proposals.reset();
+        let policy = ApprovalPolicy {
+            threshold: 2,
+            expiry: 10,
+        };
+        proposals.create(Action::RemoveLedger(admin(9)), admin(1), 100, policy);
+        let proposal = proposals.create(Action::RemoveLedger(admin(8)), admin(1), 200, policy);
+        assert_eq!(proposal.id, 1);
+
+        assert_eq!(
+            proposals.approve(0, admin(2), 111),
+            Err(Error::ProposalExpired(0))
+        );
+        assert_eq!(proposals.get(0).unwrap().status, ProposalStatus::Expired);
+        assert_eq!(proposals.list(1, 10, 210), vec![proposal.clone()]);
+        assert_eq!(proposals.list(0, 10, 210).len(), 2);
+
+        // listed as expired before any approval
+        let listed = proposals.list(1, 10, 211);
+        assert_eq!(listed[0].status, ProposalStatus::Expired);
+        assert_eq!(proposals.get(1), Some(proposal));
+    }
+}
@@ -0,0 +1,581 @@
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::memory_manager::VirtualMemory;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+
+use crate::error::{Error, Result};
+use crate::state::ecdsa::bch::BchTransaction;
+use crate::state::ecdsa::btc::{BtcAddressType, BtcSignedMessage};
+use crate::state::ecdsa::cosmos::CosmosTransaction;
+use crate::state::ecdsa::filecoin::{FilecoinMessage, FilecoinSignedMessage};
+use crate::state::ecdsa::ic::{IcRequest, IcSignedRequest};
+use crate::state::ecdsa::stacks::{StacksSignedTransaction, StacksTransaction};
+use crate::state::ecdsa::tron::{TronSignedTransaction, TronTransaction};
+use crate::state::ecdsa::xrp::{XrpSignedTransaction, XrpTransaction};
+use crate::state::schnorr::nostr::NostrEvent;
+use crate::state::schnorr::polkadot::{
+    PolkadotKeyType, PolkadotSignedTransaction, PolkadotTransaction,
+};
+use crate::state::schnorr::solana::{SolanaSignedMessage, SolanaTransaction};
+use crate::state::schnorr::ton::{TonSignedMessage, TonTransaction};
+use crate::state::{
+    decode, encode, PrincipalIdKey, JOBS_MEMORY_ID, JOB_QUEUE_MEMORY_ID, MEMORY_MANAGER,
+};
+
+/// Jobs signing at the same time, so that batches don't hit the subnet signature limits.
+pub const MAX_RUNNING_JOBS: usize = 8;
+/// Jobs waiting or signing, new ones are rejected beyond it.
+pub const MAX_QUEUED_JOBS: u64 = 10_000;
+/// Jobs waiting or signing of a user, so that one user can't fill the queue.
+pub const MAX_QUEUED_JOBS_PER_USER: usize = 100;
+/// Maximum length of the method name of a callback.
+pub const MAX_METHOD_LEN: usize = 64;
+/// Time during which a completed job stays available with its result, 7 days.
+pub const COMPLETED_JOB_TTL: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+/// Jobs looked at by each submission for expired ones.
+const GC_BATCH: usize = 8;
+
+/// Arguments of a signing endpoint, by endpoint.
+#[derive(Clone, CandidType, Deserialize)]
+pub enum SigningRequest {
+    BchTransaction(BchTransaction),
+    BtcMessage {
+        address_type: BtcAddressType,
+        message: String,
+    },
+    CosmosTransaction {
+        hrp: String,
+        chain_id: String,
+        tx: CosmosTransaction,
+    },
+    EthermintTransaction {
+        hrp: String,
+        chain_id: String,
+        pub_key_type: Option<String>,
+        tx: CosmosTransaction,
+    },
+    TronTransaction(TronTransaction),
+    XrpTransaction(XrpTransaction),
+    SolanaMessage(Vec<u8>),
+    SolanaTransaction(SolanaTransaction),
+    PolkadotTransaction {
+        key_type: PolkadotKeyType,
+        tx: PolkadotTransaction,
+    },
+    SuiTransaction(String),
+    StacksTransaction(StacksTransaction),
+    FilecoinMessage(FilecoinMessage),
+    TonTransaction(TonTransaction),
+    NostrEvent(NostrEvent),
+    IcRequest(IcRequest),
+}
+
+impl SigningRequest {
+    /// Endpoint, `CoinType` variant name and chain id of the request, as checked by
+    /// the pause switches.
+    pub fn scope(&self) -> (&'static str, &'static str, Option<String>) {
+        match self {
+            Self::BchTransaction(_) => ("sign_bch_transaction", "Bch", None),
+            Self::BtcMessage { .. } => ("sign_btc_message", "Btc", None),
+            Self::CosmosTransaction { chain_id, .. } => {
+                ("sign_cosmos_transaction", "Cosmos", Some(chain_id.clone()))
+            }
+            Self::EthermintTransaction { chain_id, .. } => (
+                "sign_ethermint_transaction",
+                "Ethermint",
+                Some(chain_id.clone()),
+            ),
+            Self::TronTransaction(_) => ("sign_tron_transaction", "Tron", None),
+            Self::XrpTransaction(_) => ("sign_xrp_transaction", "Xrp", None),
+            Self::SolanaMessage(_) => ("sign_solana_message", "Solana", None),
+            Self::SolanaTransaction(_) => ("sign_solana_transaction", "Solana", None),
+            Self::PolkadotTransaction { tx, .. } => (
+                "sign_polkadot_transaction",
+                "Polkadot",
+                Some(tx.genesis_hash.clone()),
+            ),
+            Self::SuiTransaction(_) => ("sign_sui_transaction", "Sui", None),
+            Self::StacksTransaction(tx) => (
+                "sign_stacks_transaction",
+                "Stacks",
+                Some(tx.network.as_str().to_string()),
+            ),
+            Self::FilecoinMessage(_) => ("sign_filecoin_message", "Filecoin", None),
+            Self::TonTransaction(_) => ("sign_ton_transaction", "Ton", None),
+            Self::NostrEvent(_) => ("sign_nostr_event", "Nostr", None),
+            Self::IcRequest(_) => ("sign_ic_request", "InternetComputer", None),
+        }
+    }
+}
+
+/// Result of the signing endpoint of a `SigningRequest`, by endpoint.
+#[derive(Clone, CandidType, Deserialize)]
+pub enum SigningResponse {
+    BchTransaction(String),
+    BtcMessage(BtcSignedMessage),
+    CosmosTransaction(String),
+    EthermintTransaction(String),
+    TronTransaction(TronSignedTransaction),
+    XrpTransaction(XrpSignedTransaction),
+    SolanaMessage(SolanaSignedMessage),
+    SolanaTransaction(SolanaSignedMessage),
+    PolkadotTransaction(PolkadotSignedTransaction),
+    SuiTransaction(String),
+    StacksTransaction(StacksSignedTransaction),
+    FilecoinMessage(FilecoinSignedMessage),
+    TonTransaction(TonSignedMessage),
+    NostrEvent(String),
+    IcRequest(IcSignedRequest),
+}
+
+/// Canister method notified with the id and the status of a job once it completes.
+/// The canister must be the caller, the notification can't reach other canisters
+/// under the identity of this one.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Callback {
+    pub canister: Principal,
+    pub method: String,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Done(SigningResponse),
+    Failed(Error),
+}
+
+// the requests are not bounded
+#[derive(Clone, CandidType, Deserialize)]
+pub struct SigningJob {
+    pub id: u64,
+    /// User whose keys sign the request.
+    pub caller: Principal,
+    pub request: SigningRequest,
+    pub callback: Option<Callback>,
+    /// Nanoseconds since the epoch.
+    pub submitted_at: u64,
+    pub completed_at: Option<u64>,
+    pub status: JobStatus,
+}
+
+impl Storable for SigningJob {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        encode(&self).into()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Signing jobs run by timers, with their results kept for `COMPLETED_JOB_TTL`.
+///
+/// The queue holds the jobs waiting or signing by user and job id, flagged once started.
+/// Jobs start in turns of users, the oldest job of each user first.
+#[derive(Default, Clone, Copy)]
+pub struct SigningQueue {}
+
+impl SigningQueue {
+    pub fn reset(&mut self) {
+        JOBS.with(|jobs| {
+            jobs.replace(StableBTreeMap::new(
+                MEMORY_MANAGER.with(|m| m.borrow().get(JOBS_MEMORY_ID)),
+            ))
+        });
+        JOB_QUEUE.with(|queue| {
+            queue.replace(StableBTreeMap::new(
+                MEMORY_MANAGER.with(|m| m.borrow().get(JOB_QUEUE_MEMORY_ID)),
+            ))
+        });
+        RUNNING.with(|running| running.set(0));
+        LAST_USER.with(|user| user.set(None));
+        GC_CURSOR.with(|cursor| cursor.set(None));
+    }
+
+    /// Queues a job of `caller`, returns its id. A callback must be a method of `caller`.
+    pub fn submit(
+        &mut self,
+        caller: Principal,
+        request: SigningRequest,
+        callback: Option<Callback>,
+        timestamp: u64,
+    ) -> Result<u64> {
+        if let Some(callback) = &callback {
+            if callback.canister != caller {
+                return Err(Error::NotAuthorized);
+            }
+            if callback.method.is_empty() || callback.method.len() > MAX_METHOD_LEN {
+                return Err(Error::Internal(format!(
+                    "invalid callback method: {}",
+                    callback.method
+                )));
+            }
+        }
+        if JOB_QUEUE.with(|queue| queue.borrow().len()) >= MAX_QUEUED_JOBS
+            || self.user_count(caller) >= MAX_QUEUED_JOBS_PER_USER
+        {
+            return Err(Error::QueueFull);
+        }
+        self.collect_garbage(timestamp);
+
+        let id = JOBS.with(|jobs| jobs.borrow().last_key_value().map_or(0, |(id, _)| id + 1));
+        let job = SigningJob {
+            id,
+            caller,
+            request,
+            callback,
+            submitted_at: timestamp,
+            completed_at: None,
+            status: JobStatus::Pending,
+        };
+        JOBS.with(|jobs| jobs.borrow_mut().insert(id, job));
+        JOB_QUEUE.with(|queue| queue.borrow_mut().insert(PrincipalIdKey(caller, id), false));
+        Ok(id)
+    }
+
+    pub fn get(&self, id: u64) -> Option<SigningJob> {
+        JOBS.with(|jobs| jobs.borrow().get(&id))
+    }
+
+    /// Starts the oldest waiting job accepted by `ready` of the user after the last
+    /// served one, unless `MAX_RUNNING_JOBS` jobs are running.
+    pub fn start_next(&mut self, ready: impl Fn(&SigningJob) -> bool) -> Option<SigningJob> {
+        if RUNNING.with(|running| running.get()) >= MAX_RUNNING_JOBS {
+            return None;
+        }
+        let job = JOB_QUEUE.with(|queue| {
+            let queue = queue.borrow();
+            let last_user = LAST_USER.with(|user| user.get());
+            let next_users = last_user.into_iter().flat_map(|user| {
+                queue
+                    .range(PrincipalIdKey(user, u64::MAX)..)
+                    .filter(move |(key, _)| key.0 != user)
+            });
+            next_users
+                .chain(queue.iter())
+                .filter(|(_, started)| !started)
+                .filter_map(|(key, _)| self.get(key.1))
+                .find(ready)
+        })?;
+
+        let key = PrincipalIdKey(job.caller, job.id);
+        JOB_QUEUE.with(|queue| queue.borrow_mut().insert(key, true));
+        RUNNING.with(|running| running.set(running.get() + 1));
+        LAST_USER.with(|user| user.set(Some(job.caller)));
+        self.update(job.id, |job| job.status = JobStatus::Running)
+    }
+
+    /// Puts back in the queue a started job which can't run yet.
+    pub fn defer(&mut self, id: u64) {
+        let Some(job) = self.update(id, |job| job.status = JobStatus::Pending) else {
+            return;
+        };
+        JOB_QUEUE.with(|queue| {
+            queue
+                .borrow_mut()
+                .insert(PrincipalIdKey(job.caller, id), false)
+        });
+        RUNNING.with(|running| running.set(running.get().saturating_sub(1)));
+    }
+
+    /// Whether some jobs wait to start.
+    pub fn has_waiting(&self) -> bool {
+        JOB_QUEUE.with(|queue| queue.borrow().iter().any(|(_, started)| !started))
+    }
+
+    /// Stores the result of a running job, returns the completed job.
+    pub fn complete(
+        &mut self,
+        id: u64,
+        result: Result<SigningResponse>,
+        timestamp: u64,
+    ) -> Option<SigningJob> {
+        let job = self.update(id, |job| {
+            job.status = match result {
+                Ok(response) => JobStatus::Done(response),
+                Err(e) => JobStatus::Failed(e),
+            };
+            job.completed_at = Some(timestamp);
+        })?;
+        JOB_QUEUE.with(|queue| queue.borrow_mut().remove(&PrincipalIdKey(job.caller, id)));
+        RUNNING.with(|running| running.set(running.get().saturating_sub(1)));
+        Some(job)
+    }
+
+    /// Number of jobs waiting or signing.
+    pub fn count(&self) -> u64 {
+        JOB_QUEUE.with(|queue| queue.borrow().len())
+    }
+
+    /// Number of jobs of `user` waiting or signing, up to `MAX_QUEUED_JOBS_PER_USER`.
+    fn user_count(&self, user: Principal) -> usize {
+        JOB_QUEUE.with(|queue| {
+            queue
+                .borrow()
+                .range(PrincipalIdKey(user, 0)..=PrincipalIdKey(user, u64::MAX))
+                .take(MAX_QUEUED_JOBS_PER_USER)
+                .count()
+        })
+    }
+
+    /// Fails the jobs started before an upgrade, whose calls are lost. They may have
+    /// signed and charged the user already, running them again could do it twice.
+    /// Their callbacks are not notified, the results stay available to the users.
+    pub fn restart(&mut self, timestamp: u64) {
+        let started: Vec<PrincipalIdKey> = JOB_QUEUE.with(|queue| {
+            queue
+                .borrow()
+                .iter()
+                .filter(|(_, started)| *started)
+                .map(|(key, _)| key)
+                .collect()
+        });
+        for key in started {
+            self.update(key.1, |job| {
+                job.status = JobStatus::Failed(Error::JobInterrupted);
+                job.completed_at = Some(timestamp);
+            });
+            JOB_QUEUE.with(|queue| queue.borrow_mut().remove(&key));
+        }
+        RUNNING.with(|running| running.set(0));
+    }
+
+    /// Removes the jobs completed for `COMPLETED_JOB_TTL` among the next `GC_BATCH` ones,
+    /// going through all the jobs over the submissions.
+    fn collect_garbage(&mut self, timestamp: u64) {
+        JOBS.with(|jobs| {
+            let mut jobs = jobs.borrow_mut();
+            let last = jobs.last_key_value().map(|(id, _)| id);
+            let batch: Vec<(u64, SigningJob)> = match GC_CURSOR.with(|cursor| cursor.take()) {
+                Some(cursor) => jobs.range(cursor..).take(GC_BATCH).collect(),
+                None => jobs.iter().take(GC_BATCH).collect(),
+            };
+            // start over once the end is reached
+            let next = match batch.len() {
+                GC_BATCH => batch.last().map(|(id, _)| *id),
+                _ => None,
+            };
+            for (id, job) in batch {
+                let expired = job.completed_at.is_some_and(|completed_at| {
+                    completed_at.saturating_add(COMPLETED_JOB_TTL) <= timestamp
+                });
+                // the last job is kept, the ids of the next ones follow it
+                if expired && Some(id) != last {
+                    jobs.remove(&id);
+                }
+            }
+            GC_CURSOR.with(|cursor| cursor.set(next));
+        });
+    }
+
+    fn update(&mut self, id: u64, f: impl FnOnce(&mut SigningJob)) -> Option<SigningJob> {
+        JOBS.with(|jobs| {
+            let mut jobs = jobs.borrow_mut();
+            let mut job = jobs.get(&id)?;
+            f(&mut job);
+            jobs.insert(id, job.clone());
+            Some(job)
+        })
+    }
+}
+
+thread_local! {
+    static JOBS: RefCell<StableBTreeMap<u64, SigningJob, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(JOBS_MEMORY_ID))));
+    static JOB_QUEUE: RefCell<StableBTreeMap<PrincipalIdKey, bool, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(JOB_QUEUE_MEMORY_ID))));
+    static RUNNING: Cell<usize> = Cell::new(0);
+    static LAST_USER: Cell<Option<Principal>> = Cell::new(None);
+    static GC_CURSOR: Cell<Option<u64>> = Cell::new(None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(byte: u8) -> Principal {
+        Principal::from_slice(&[byte; 29])
+    }
+
+    fn submit(queue: &mut SigningQueue, caller: Principal) -> u64 {
+        queue
+            .submit(
+                caller,
+                SigningRequest::SuiTransaction(String::new()),
+                None,
+                0,
+            )
+            .unwrap()
+    }
+
+    fn start_all(queue: &mut SigningQueue) -> Vec<u64> {
+        std::iter::from_fn(|| queue.start_next(|_| true))
+            .map(|job| job.id)
+            .collect()
+    }
+
+    #[test]
+    fn starts_jobs_in_turns_of_users() {
+        let mut queue = SigningQueue::default();
+        queue.reset();
+        // a batch of user 1 submitted before the jobs of users 2 and 3
+        for _ in 0..4 {
+            submit(&mut queue, user(1));
+        }
+        submit(&mut queue, user(2));
+        submit(&mut queue, user(3));
+        submit(&mut queue, user(2));
+
+        assert_eq!(start_all(&mut queue), vec![0, 4, 5, 1, 6, 2, 3]);
+        assert!(matches!(queue.get(0).unwrap().status, JobStatus::Running));
+    }
+
+    #[test]
+    fn bounds_running_jobs() {
+        let mut queue = SigningQueue::default();
+        queue.reset();
+        for id in 0..MAX_RUNNING_JOBS as u8 + 2 {
+            submit(&mut queue, user(id));
+        }
+        assert_eq!(start_all(&mut queue).len(), MAX_RUNNING_JOBS);
+
+        let job = queue
+            .complete(
+                0,
+                Ok(SigningResponse::SuiTransaction("signature".to_string())),
+                10,
+            )
+            .unwrap();
+        assert!(matches!(job.status, JobStatus::Done(_)));
+        assert_eq!(job.completed_at, Some(10));
+        queue.complete(1, Err(Error::UserNotInitialized), 10);
+        assert!(matches!(
+            queue.get(1).unwrap().status,
+            JobStatus::Failed(Error::UserNotInitialized)
+        ));
+        assert_eq!(queue.count(), 2 + MAX_RUNNING_JOBS as u64 - 2);
+        assert_eq!(start_all(&mut queue).len(), 2);
+        let waiting = submit(&mut queue, user(1));
+
+        // started jobs fail on upgrade rather than sign twice, waiting ones still start
+        queue.restart(20);
+        let job = queue.get(2).unwrap();
+        assert!(matches!(
+            job.status,
+            JobStatus::Failed(Error::JobInterrupted)
+        ));
+        assert_eq!(job.completed_at, Some(20));
+        assert_eq!(queue.count(), 1);
+        assert_eq!(start_all(&mut queue), vec![waiting]);
+    }
+
+    #[test]
+    fn expires_completed_jobs() {
+        let mut queue = SigningQueue::default();
+        queue.reset();
+        for _ in 0..GC_BATCH * 2 {
+            submit(&mut queue, user(1));
+        }
+        while let Some(job) = queue.start_next(|_| true) {
+            queue.complete(job.id, Err(Error::UserNotInitialized), 10);
+        }
+        let waiting = submit(&mut queue, user(2));
+        GC_CURSOR.with(|cursor| cursor.set(None));
+
+        // submissions remove the expired jobs, a batch at a time
+        let expiry = 10 + COMPLETED_JOB_TTL;
+        let mut submit_at = |timestamp| {
+            queue
+                .submit(
+                    user(3),
+                    SigningRequest::SuiTransaction(String::new()),
+                    None,
+                    timestamp,
+                )
+                .unwrap()
+        };
+        submit_at(expiry - 1);
+        submit_at(expiry);
+        submit_at(expiry);
+        let last = submit_at(expiry);
+        assert_eq!(last, waiting + 4);
+        assert!(queue.get(0).is_none());
+        // jobs not completed are kept
+        assert!(queue.get(waiting).is_some());
+        assert_eq!(JOBS.with(|jobs| jobs.borrow().len()), 5);
+    }
+
+    #[test]
+    fn bounds_queued_jobs_per_user() {
+        let mut queue = SigningQueue::default();
+        queue.reset();
+        for _ in 0..MAX_QUEUED_JOBS_PER_USER {
+            submit(&mut queue, user(1));
+        }
+        assert_eq!(
+            queue.submit(
+                user(1),
+                SigningRequest::SuiTransaction(String::new()),
+                None,
+                0
+            ),
+            Err(Error::QueueFull)
+        );
+        // other users still queue jobs
+        submit(&mut queue, user(2));
+
+        let job = queue.start_next(|_| true).unwrap();
+        queue.complete(job.id, Err(Error::UserNotInitialized), 0);
+        submit(&mut queue, user(1));
+    }
+
+    #[test]
+    fn defers_jobs_not_ready() {
+        let mut queue = SigningQueue::default();
+        queue.reset();
+        submit(&mut queue, user(1));
+        submit(&mut queue, user(2));
+        submit(&mut queue, user(1));
+
+        // the jobs of user 1 are paused
+        let ready = |job: &SigningJob| job.caller != user(1);
+        assert_eq!(queue.start_next(ready).unwrap().id, 1);
+        assert!(queue.start_next(ready).is_none());
+        assert!(queue.has_waiting());
+
+        let job = queue.start_next(|_| true).unwrap();
+        assert_eq!(job.id, 0);
+        queue.defer(job.id);
+        assert!(matches!(queue.get(0).unwrap().status, JobStatus::Pending));
+        assert_eq!(queue.count(), 3);
+        assert_eq!(start_all(&mut queue), vec![0, 2]);
+        assert!(!queue.has_waiting());
+    }
+
+    #[test]
+    fn rejects_invalid_callbacks() {
+        let mut queue = SigningQueue::default();
+        queue.reset();
+        let mut submit_with = |canister, method: &str| {
+            let callback = Callback {
+                canister,
+                method: method.to_string(),
+            };
+            queue.submit(
+                user(1),
+                SigningRequest::SolanaMessage(vec![]),
+                Some(callback),
+                0,
+            )
+        };
+        assert!(submit_with(user(1), "").is_err());
+        // other canisters can't be called on behalf of this one
+        assert_eq!(submit_with(user(2), "on_signed"), Err(Error::NotAuthorized));
+        assert_eq!(submit_with(user(1), "on_signed"), Ok(0));
+        assert_eq!(queue.count(), 1);
+    }
+}
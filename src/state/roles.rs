@@ -0,0 +1,283 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::memory_manager::VirtualMemory;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+
+use crate::error::{Error, Result};
+use crate::state::{
+    decode, encode, StorablePrincipal, MEMORY_MANAGER, ROLES_MEMORY_ID, ROLE_AUDIT_MEMORY_ID,
+};
+
+/// Permission held by a principal. Admins hold every permission.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum Role {
+    /// Grants and revokes roles.
+    Admin,
+    /// Runs the day to day operations of the canister.
+    Operator,
+    /// Reads the roles and their audit log.
+    Auditor,
+    /// Manages the fees charged to the users.
+    FeeManager,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [Role::Admin, Role::Operator, Role::Auditor, Role::FeeManager];
+
+    /// Bit of the role in the stored role set, part of the stored layout.
+    fn bit(self) -> u8 {
+        match self {
+            Self::Admin => 1,
+            Self::Operator => 1 << 1,
+            Self::Auditor => 1 << 2,
+            Self::FeeManager => 1 << 3,
+        }
+    }
+}
+
+/// Grant or revocation of a role, kept in the audit log.
+// if change the struct, need to update the BOUND in Storable impl
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct RoleChange {
+    pub id: u64,
+    /// Nanoseconds since the epoch.
+    pub timestamp: u64,
+    /// Principal that made the change.
+    pub by: Principal,
+    pub principal: Principal,
+    pub role: Role,
+    pub granted: bool,
+}
+
+impl Storable for RoleChange {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        encode(&self).into()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 256,
+        is_fixed_size: false,
+    };
+}
+
+/// Roles of the principals, with the audit log of their changes.
+///
+/// The anonymous principal never holds a role.
+#[derive(Default, Clone, Copy)]
+pub struct Roles {}
+
+impl Roles {
+    pub fn reset(&mut self) {
+        ROLES.with(|roles| {
+            roles.replace(StableBTreeMap::new(
+                MEMORY_MANAGER.with(|m| m.borrow().get(ROLES_MEMORY_ID)),
+            ))
+        });
+        ROLE_AUDIT.with(|audit| {
+            audit.replace(StableBTreeMap::new(
+                MEMORY_MANAGER.with(|m| m.borrow().get(ROLE_AUDIT_MEMORY_ID)),
+            ))
+        });
+    }
+
+    pub fn get(&self, principal: Principal) -> Vec<Role> {
+        let bits = self.bits(principal);
+        Role::ALL
+            .into_iter()
+            .filter(|role| bits & role.bit() != 0)
+            .collect()
+    }
+
+    /// Whether `principal` holds `role`, directly or as an admin.
+    pub fn has(&self, principal: Principal, role: Role) -> bool {
+        self.bits(principal) & (role.bit() | Role::Admin.bit()) != 0
+    }
+
+    pub fn has_admin(&self) -> bool {
+        ROLES.with(|roles| {
+            roles
+                .borrow()
+                .iter()
+                .any(|(_, bits)| bits & Role::Admin.bit() != 0)
+        })
+    }
+
+    /// Grants `role` to `principal` and logs the change made `by` a principal at
+    /// `timestamp`. Returns false when the principal already holds the role.
+    pub fn grant(
+        &mut self,
+        principal: Principal,
+        role: Role,
+        by: Principal,
+        timestamp: u64,
+    ) -> Result<bool> {
+        if principal == Principal::anonymous() {
+            return Err(Error::AnonymousNotAllowed);
+        }
+        let bits = self.bits(principal);
+        if bits & role.bit() != 0 {
+            return Ok(false);
+        }
+        ROLES.with(|roles| {
+            roles
+                .borrow_mut()
+                .insert(StorablePrincipal(principal), bits | role.bit())
+        });
+        self.log(principal, role, true, by, timestamp);
+        Ok(true)
+    }
+
+    /// Revokes `role` from `principal` and logs the change. Returns false when the
+    /// principal doesn't hold the role. The last admin can't be revoked, so the
+    /// canister always has one.
+    pub fn revoke(
+        &mut self,
+        principal: Principal,
+        role: Role,
+        by: Principal,
+        timestamp: u64,
+    ) -> Result<bool> {
+        let bits = self.bits(principal);
+        if bits & role.bit() == 0 {
+            return Ok(false);
+        }
+        if role == Role::Admin && self.admins() == 1 {
+            return Err(Error::LastAdmin);
+        }
+        ROLES.with(|roles| {
+            let mut roles = roles.borrow_mut();
+            match bits & !role.bit() {
+                0 => roles.remove(&StorablePrincipal(principal)),
+                bits => roles.insert(StorablePrincipal(principal), bits),
+            }
+        });
+        self.log(principal, role, false, by, timestamp);
+        Ok(true)
+    }
+
+    pub fn list(&self) -> Vec<(Principal, Vec<Role>)> {
+        let principals =
+            ROLES.with(|roles| roles.borrow().iter().map(|(p, _)| p.0).collect::<Vec<_>>());
+        principals
+            .into_iter()
+            .map(|principal| (principal, self.get(principal)))
+            .collect()
+    }
+
+    /// Returns at most `limit` changes of the audit log, starting from the change `start`.
+    pub fn audit_log(&self, start: u64, limit: usize) -> Vec<RoleChange> {
+        ROLE_AUDIT.with(|audit| {
+            audit
+                .borrow()
+                .range(start..)
+                .take(limit)
+                .map(|(_, change)| change)
+                .collect()
+        })
+    }
+
+    fn bits(&self, principal: Principal) -> u8 {
+        ROLES
+            .with(|roles| roles.borrow().get(&StorablePrincipal(principal)))
+            .unwrap_or_default()
+    }
+
+    pub fn admins(&self) -> usize {
+        ROLES.with(|roles| {
+            roles
+                .borrow()
+                .iter()
+                .filter(|(_, bits)| bits & Role::Admin.bit() != 0)
+                .count()
+        })
+    }
+
+    fn log(
+        &mut self,
+        principal: Principal,
+        role: Role,
+        granted: bool,
+        by: Principal,
+        timestamp: u64,
+    ) {
+        ROLE_AUDIT.with(|audit| {
+            let mut audit = audit.borrow_mut();
+            let id = audit.last_key_value().map_or(0, |(id, _)| id + 1);
+            audit.insert(
+                id,
+                RoleChange {
+                    id,
+                    timestamp,
+                    by,
+                    principal,
+                    role,
+                    granted,
+                },
+            );
+        });
+    }
+}
+
+thread_local! {
+    static ROLES: RefCell<StableBTreeMap<StorablePrincipal, u8, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(ROLES_MEMORY_ID))));
+    static ROLE_AUDIT: RefCell<StableBTreeMap<u64, RoleChange, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(ROLE_AUDIT_MEMORY_ID))));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(byte: u8) -> Principal {
+        Principal::from_slice(&[byte; 29])
+    }
+
+    #[test]
+    fn grants_and_revokes_roles() {
+        let mut roles = Roles::default();
+        roles.reset();
+        let (admin, user) = (principal(1), principal(2));
+        assert!(roles.grant(admin, Role::Admin, admin, 1).unwrap());
+        assert!(roles.grant(user, Role::Operator, admin, 2).unwrap());
+        assert!(!roles.grant(user, Role::Operator, admin, 3).unwrap());
+
+        // admins hold every permission
+        assert!(roles.has(admin, Role::FeeManager));
+        assert!(roles.has(user, Role::Operator));
+        assert!(!roles.has(user, Role::Auditor));
+        assert_eq!(
+            roles.grant(Principal::anonymous(), Role::Auditor, admin, 4),
+            Err(Error::AnonymousNotAllowed)
+        );
+        assert!(!roles.has(Principal::anonymous(), Role::Auditor));
+
+        assert!(roles.revoke(user, Role::Operator, admin, 5).unwrap());
+        assert!(roles.get(user).is_empty());
+        assert_eq!(
+            roles.revoke(admin, Role::Admin, admin, 6),
+            Err(Error::LastAdmin)
+        );
+        assert_eq!(roles.list(), vec![(admin, vec![Role::Admin])]);
+
+        let log = roles.audit_log(0, 10);
+        assert_eq!(log.len(), 3);
+        assert_eq!(
+            log[2],
+            RoleChange {
+                id: 2,
+                timestamp: 5,
+                by: admin,
+                principal: user,
+                role: Role::Operator,
+                granted: false,
+            }
+        );
+        assert_eq!(roles.audit_log(1, 1), log[1..2]);
+    }
+}
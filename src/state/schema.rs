@@ -0,0 +1,569 @@
+//! Schema versions of the stable memory regions.
+//!
+//! Every region records the version of the layout its records are written with, and
+//! `Schema::migrate` brings them to their current version on upgrade, before the typed
+//! structures of the state decode any record. Regions without a recorded version hold
+//! the layouts of the first release, version 0.
+//!
+//! Migrations read and write records through the frozen types of their versions, never
+//! through the live ones, so they keep working once the live types move on.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, Memory, StableBTreeMap, StableCell, Storable};
+
+use crate::error::{Error, Result};
+use crate::state::codec::{write_padded, write_principal, MAX_PRINCIPAL_LEN};
+use crate::state::ecdsa::EcdsaKeyIds;
+use crate::state::proposals::{DEFAULT_APPROVAL_THRESHOLD, DEFAULT_PROPOSAL_EXPIRY};
+use crate::state::{
+    decode, encode, StorablePrincipal, CONFIG_MEMORY_ID, MEMORY_MANAGER, NONCES_MEMORY_ID,
+    SCHEMA_MEMORY_ID, SIGNERS_MEMORY_ID,
+};
+
+/// Version of the regions written before versions were recorded.
+const LEGACY_VERSION: u32 = 0;
+
+/// Current layout version of the regions, by memory id.
+const VERSIONS: [(u8, u32); 19] = [
+    // config
+    (1, 2),
+    // signers
+    (2, 1),
+    // nonces
+    (3, 2),
+    // ed25519 signers
+    (4, 0),
+    // ledgers
+    (5, 0),
+    // bip340 signers
+    (6, 0),
+    // roles
+    (7, 0),
+    // role audit log
+    (8, 0),
+    // proposals
+    (9, 0),
+    // pauses
+    (10, 0),
+    // access policy
+    (11, 0),
+    // allowlist
+    (12, 0),
+    // fee schedule
+    (13, 0),
+    // fee accounts
+    (14, 0),
+    // charges
+    (15, 0),
+    // signing jobs
+    (16, 0),
+    // signing job queue
+    (17, 0),
+    // idempotency records
+    (18, 0),
+    // idempotency TTL
+    (19, 0),
+];
+
+/// Migration of the records of a region from version `from` to `from + 1`.
+struct Migration {
+    memory_id: u8,
+    from: u32,
+    run: fn(),
+}
+
+const MIGRATIONS: [Migration; 5] = [
+    Migration {
+        memory_id: 1,
+        from: 0,
+        run: migrate_config_v0,
+    },
+    Migration {
+        memory_id: 1,
+        from: 1,
+        run: migrate_config_v1,
+    },
+    Migration {
+        memory_id: 2,
+        from: 0,
+        run: migrate_signers_v0,
+    },
+    Migration {
+        memory_id: 3,
+        from: 0,
+        run: migrate_nonces_v0,
+    },
+    Migration {
+        memory_id: 3,
+        from: 1,
+        run: migrate_nonces_v1,
+    },
+];
+
+/// Layout versions of the stable memory regions.
+#[derive(Default, Clone, Copy)]
+pub struct Schema {}
+
+impl Schema {
+    /// Marks all regions as written with their current layout.
+    pub fn reset(&mut self) {
+        SCHEMA_VERSIONS.with(|versions| {
+            versions.replace(StableBTreeMap::new(region_memory(SCHEMA_MEMORY_ID)));
+            for (memory_id, version) in VERSIONS {
+                versions.borrow_mut().insert(memory_id, version);
+            }
+        });
+    }
+
+    pub fn version(&self, memory_id: u8) -> Option<u32> {
+        SCHEMA_VERSIONS.with(|versions| versions.borrow().get(&memory_id))
+    }
+
+    /// Runs the migrations of every region from its recorded version to the current one.
+    ///
+    /// Must be called before the state is accessed after an upgrade. An error leaves
+    /// the regions partially migrated, so the caller traps to roll the upgrade back.
+    pub fn migrate(&mut self) -> Result<()> {
+        for (memory_id, current) in VERSIONS {
+            let mut version = match self.version(memory_id) {
+                Some(version) => version,
+                None if region_memory(MemoryId::new(memory_id)).size() == 0 => current,
+                None => LEGACY_VERSION,
+            };
+            if version > current {
+                return Err(Error::Internal(format!(
+                    "memory {memory_id} has version {version}, newer than {current}"
+                )));
+            }
+
+            while version < current {
+                let migration = MIGRATIONS
+                    .iter()
+                    .find(|m| m.memory_id == memory_id && m.from == version)
+                    .ok_or_else(|| {
+                        Error::Internal(format!(
+                            "no migration of memory {memory_id} from version {version}"
+                        ))
+                    })?;
+                (migration.run)();
+                version += 1;
+            }
+            SCHEMA_VERSIONS.with(|versions| versions.borrow_mut().insert(memory_id, version));
+        }
+        Ok(())
+    }
+}
+
+fn region_memory(memory_id: MemoryId) -> VirtualMemory<DefaultMemoryImpl> {
+    MEMORY_MANAGER.with(|m| m.borrow().get(memory_id))
+}
+
+/// Candid encoded record of a frozen layout, bounded to `MAX` bytes.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Candid<T, const MAX: u32>(T);
+
+impl<T, const MAX: u32> Storable for Candid<T, MAX>
+where
+    T: CandidType + for<'de> Deserialize<'de>,
+{
+    fn to_bytes(&self) -> Cow<[u8]> {
+        encode(&self.0).into()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(decode(bytes.as_ref()))
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX,
+        is_fixed_size: false,
+    };
+}
+
+/// Binary record of a frozen layout, `SIZE` bytes long.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Fixed<const SIZE: u32>(Vec<u8>);
+
+impl<const SIZE: u32> Storable for Fixed<SIZE> {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(bytes.into_owned())
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: SIZE,
+        is_fixed_size: true,
+    };
+}
+
+/// Configuration, up to version 0.
+#[derive(Clone, CandidType, Deserialize)]
+struct ConfigDataV0 {
+    owner: Principal,
+    ecdsa_env: EcdsaKeyIds,
+}
+
+/// Signer of a user, up to version 0.
+#[derive(Clone, CandidType, Deserialize)]
+struct SignerV0 {
+    key_id: EcdsaKeyIds,
+    path: Vec<u8>,
+    public_key: Vec<u8>,
+    chain_code: Vec<u8>,
+}
+
+/// Key of the nonces, up to version 1.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, CandidType, Deserialize)]
+struct NonceKeyV0(Principal, u64);
+
+/// Version 1 replaces the candid record with the owner and the ECDSA key byte.
+fn migrate_config_v0() {
+    let config = StableCell::<Candid<ConfigDataV0, 29>, _>::init(
+        region_memory(CONFIG_MEMORY_ID),
+        Candid(ConfigDataV0 {
+            owner: Principal::anonymous(),
+            ecdsa_env: EcdsaKeyIds::TestKeyLocalDevelopment,
+        }),
+    )
+    .expect("failed to load config")
+    .get()
+    .0
+    .clone();
+
+    let mut bytes = vec![];
+    write_principal(&mut bytes, &config.owner);
+    bytes.push(config.ecdsa_env.to_byte());
+    StableCell::<Fixed<31>, _>::init(region_memory(CONFIG_MEMORY_ID), Fixed(vec![]))
+        .expect("failed to load config")
+        .set(Fixed(bytes))
+        .expect("failed to write config");
+}
+
+/// Version 2 appends the approval policy of the proposals.
+fn migrate_config_v1() {
+    let mut bytes =
+        StableCell::<Fixed<31>, _>::init(region_memory(CONFIG_MEMORY_ID), Fixed(vec![]))
+            .expect("failed to load config")
+            .get()
+            .0
+            .clone();
+
+    bytes.extend_from_slice(&DEFAULT_APPROVAL_THRESHOLD.to_be_bytes());
+    bytes.extend_from_slice(&DEFAULT_PROPOSAL_EXPIRY.to_be_bytes());
+    StableCell::<Fixed<43>, _>::init(region_memory(CONFIG_MEMORY_ID), Fixed(vec![]))
+        .expect("failed to load config")
+        .set(Fixed(bytes))
+        .expect("failed to write config");
+}
+
+/// Version 1 replaces the candid records with fixed 96 bytes ones.
+fn migrate_signers_v0() {
+    let signers = StableBTreeMap::<StorablePrincipal, Candid<SignerV0, 153>, _>::init(
+        region_memory(SIGNERS_MEMORY_ID),
+    )
+    .iter()
+    .collect::<Vec<_>>();
+
+    let mut map =
+        StableBTreeMap::<StorablePrincipal, Fixed<96>, _>::new(region_memory(SIGNERS_MEMORY_ID));
+    for (user, Candid(signer)) in signers {
+        let mut bytes = vec![signer.key_id.to_byte()];
+        write_padded(&mut bytes, &signer.path, MAX_PRINCIPAL_LEN);
+        bytes.extend_from_slice(&signer.public_key);
+        bytes.extend_from_slice(&signer.chain_code);
+        map.insert(user, Fixed(bytes));
+    }
+}
+
+/// Version 0 declared the nonce keys 29 bytes long, which only fits the anonymous
+/// principal. The map is rebuilt with the 52 bytes their candid encoding can take.
+fn migrate_nonces_v0() {
+    let nonces =
+        StableBTreeMap::<Candid<NonceKeyV0, 29>, u64, _>::init(region_memory(NONCES_MEMORY_ID))
+            .iter()
+            .collect::<Vec<_>>();
+
+    let mut map =
+        StableBTreeMap::<Candid<NonceKeyV0, 52>, u64, _>::new(region_memory(NONCES_MEMORY_ID));
+    for (Candid(key), nonce) in nonces {
+        map.insert(Candid(key), nonce);
+    }
+}
+
+/// Version 2 replaces the candid keys with the principal and the big endian chain id.
+fn migrate_nonces_v1() {
+    let nonces =
+        StableBTreeMap::<Candid<NonceKeyV0, 52>, u64, _>::init(region_memory(NONCES_MEMORY_ID))
+            .iter()
+            .collect::<Vec<_>>();
+
+    let mut map = StableBTreeMap::<Fixed<38>, u64, _>::new(region_memory(NONCES_MEMORY_ID));
+    for (Candid(NonceKeyV0(user, chain_id)), nonce) in nonces {
+        let mut key = vec![];
+        write_principal(&mut key, &user);
+        key.extend_from_slice(&chain_id.to_be_bytes());
+        map.insert(Fixed(key), nonce);
+    }
+}
+
+/// Instructions spent by `count` inserts and then `count` lookups of signers, in the
+/// candid layout of version 0 and the binary one of version 1.
+#[cfg(feature = "bench")]
+#[derive(CandidType, Deserialize)]
+pub struct SignersBench {
+    pub candid_set: u64,
+    pub candid_get: u64,
+    pub binary_set: u64,
+    pub binary_get: u64,
+}
+
+/// Runs the signers benchmark on heap memory, which leaves the state untouched and
+/// charges both layouts the same memory accesses.
+#[cfg(feature = "bench")]
+pub fn bench_signers(count: u8) -> SignersBench {
+    use ic_exports::ic_cdk::api::performance_counter;
+    use ic_stable_structures::VectorMemory;
+
+    use crate::state::ecdsa::Signer;
+
+    fn instructions(f: impl FnOnce()) -> u64 {
+        let start = performance_counter(0);
+        f();
+        performance_counter(0) - start
+    }
+
+    let signers = (0..count)
+        .map(|id| {
+            let user = Principal::from_slice(&[id; 29]);
+            let signer = SignerV0 {
+                key_id: EcdsaKeyIds::ProductionKey1,
+                path: user.as_slice().to_vec(),
+                public_key: [&[2], [id; 32].as_slice()].concat(),
+                chain_code: vec![id; 32],
+            };
+            (StorablePrincipal(user), signer)
+        })
+        .collect::<Vec<_>>();
+
+    let mut candid =
+        StableBTreeMap::<StorablePrincipal, Candid<SignerV0, 153>, _>::new(VectorMemory::default());
+    let candid_set = instructions(|| {
+        for (user, signer) in &signers {
+            candid.insert(user.clone(), Candid(signer.clone()));
+        }
+    });
+    let candid_get = instructions(|| {
+        for (user, _) in &signers {
+            candid.get(user);
+        }
+    });
+
+    let mut binary = StableBTreeMap::<StorablePrincipal, Signer, _>::new(VectorMemory::default());
+    let signers = signers
+        .into_iter()
+        .map(|(user, signer)| {
+            let mut bytes = vec![signer.key_id.to_byte()];
+            write_padded(&mut bytes, &signer.path, MAX_PRINCIPAL_LEN);
+            bytes.extend_from_slice(&signer.public_key);
+            bytes.extend_from_slice(&signer.chain_code);
+            (user, Signer::from_bytes(bytes.into()))
+        })
+        .collect::<Vec<_>>();
+    let binary_set = instructions(|| {
+        for (user, signer) in &signers {
+            binary.insert(user.clone(), signer.clone());
+        }
+    });
+    let binary_get = instructions(|| {
+        for (user, _) in &signers {
+            binary.get(user);
+        }
+    });
+
+    SignersBench {
+        candid_set,
+        candid_get,
+        binary_set,
+        binary_get,
+    }
+}
+
+thread_local! {
+    static SCHEMA_VERSIONS: RefCell<StableBTreeMap<u8, u32, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(StableBTreeMap::init(region_memory(SCHEMA_MEMORY_ID)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::ecdsa::{Nonces, PrincipalChainIdKey, Signer, Signers};
+    use crate::state::proposals::ApprovalPolicy;
+    use crate::state::State;
+    use crate::state::{
+        ACCESS_POLICY_MEMORY_ID, ALLOWLIST_MEMORY_ID, BIP340_SIGNERS_MEMORY_ID, CHARGES_MEMORY_ID,
+        ED25519_SIGNERS_MEMORY_ID, FEE_ACCOUNTS_MEMORY_ID, FEE_SCHEDULE_MEMORY_ID,
+        IDEMPOTENCY_MEMORY_ID, IDEMPOTENCY_TTL_MEMORY_ID, JOBS_MEMORY_ID, JOB_QUEUE_MEMORY_ID,
+        LEDGERS_MEMORY_ID, PAUSES_MEMORY_ID, PROPOSALS_MEMORY_ID, ROLES_MEMORY_ID,
+        ROLE_AUDIT_MEMORY_ID,
+    };
+
+    const PUBLIC_KEY: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+    fn current_version(memory_id: u8) -> u32 {
+        VERSIONS.iter().find(|(id, _)| *id == memory_id).unwrap().1
+    }
+
+    fn user(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    #[test]
+    fn versions_cover_all_regions() {
+        let regions = [
+            CONFIG_MEMORY_ID,
+            SIGNERS_MEMORY_ID,
+            NONCES_MEMORY_ID,
+            ED25519_SIGNERS_MEMORY_ID,
+            LEDGERS_MEMORY_ID,
+            BIP340_SIGNERS_MEMORY_ID,
+            ROLES_MEMORY_ID,
+            ROLE_AUDIT_MEMORY_ID,
+            PROPOSALS_MEMORY_ID,
+            PAUSES_MEMORY_ID,
+            ACCESS_POLICY_MEMORY_ID,
+            ALLOWLIST_MEMORY_ID,
+            FEE_SCHEDULE_MEMORY_ID,
+            FEE_ACCOUNTS_MEMORY_ID,
+            CHARGES_MEMORY_ID,
+            JOBS_MEMORY_ID,
+            JOB_QUEUE_MEMORY_ID,
+            IDEMPOTENCY_MEMORY_ID,
+            IDEMPOTENCY_TTL_MEMORY_ID,
+        ];
+        assert_eq!(regions.len(), VERSIONS.len());
+        for (memory_id, _) in VERSIONS {
+            assert!(regions.contains(&MemoryId::new(memory_id)));
+        }
+    }
+
+    #[test]
+    fn stamps_empty_regions_with_current_versions() {
+        Schema::default().migrate().unwrap();
+        for (memory_id, version) in VERSIONS {
+            assert_eq!(Schema::default().version(memory_id), Some(version));
+        }
+    }
+
+    #[test]
+    fn migrates_legacy_config() {
+        // fixture of a first release canister
+        StableCell::<Candid<ConfigDataV0, 29>, _>::init(
+            region_memory(CONFIG_MEMORY_ID),
+            Candid(ConfigDataV0 {
+                owner: user(1),
+                ecdsa_env: EcdsaKeyIds::ProductionKey1,
+            }),
+        )
+        .unwrap();
+
+        Schema::default().migrate().unwrap();
+        let state = State::default();
+        assert_eq!(state.config.get_owner(), user(1));
+        assert!(matches!(
+            state.config.get_ecdsa_env(),
+            EcdsaKeyIds::ProductionKey1
+        ));
+        assert_eq!(
+            state.config.get_approval_policy(),
+            ApprovalPolicy::default()
+        );
+    }
+
+    #[test]
+    fn migrates_legacy_signers() {
+        let mut legacy = StableBTreeMap::<StorablePrincipal, Candid<SignerV0, 153>, _>::new(
+            region_memory(SIGNERS_MEMORY_ID),
+        );
+        for id in 1..=3 {
+            let signer = SignerV0 {
+                key_id: EcdsaKeyIds::TestKey1,
+                path: user(id).as_slice().to_vec(),
+                public_key: hex::decode(PUBLIC_KEY).unwrap(),
+                chain_code: vec![id; 32],
+            };
+            legacy.insert(StorablePrincipal(user(id)), Candid(signer));
+        }
+        drop(legacy);
+
+        Schema::default().migrate().unwrap();
+        assert_eq!(Schema::default().version(2), Some(current_version(2)));
+        let signers = Signers::default();
+        for id in 1..=3 {
+            let signer = signers.get(user(id)).unwrap();
+            assert!(matches!(signer.key_id(), EcdsaKeyIds::TestKey1));
+            assert_eq!(hex::encode(signer.public_key()), PUBLIC_KEY);
+            assert_eq!(signer.chain_code(), &[id; 32]);
+        }
+        assert!(signers.get(user(4)).is_none());
+    }
+
+    #[test]
+    fn shrinks_records() {
+        let signer = SignerV0 {
+            key_id: EcdsaKeyIds::ProductionKey1,
+            path: user(1).as_slice().to_vec(),
+            public_key: hex::decode(PUBLIC_KEY).unwrap(),
+            chain_code: vec![1; 32],
+        };
+        let candid = Candid::<SignerV0, 153>(signer.clone()).to_bytes();
+        let mut binary = vec![signer.key_id.to_byte()];
+        write_padded(&mut binary, &signer.path, MAX_PRINCIPAL_LEN);
+        binary.extend_from_slice(&signer.public_key);
+        binary.extend_from_slice(&signer.chain_code);
+        let binary = Signer::from_bytes(binary.into()).to_bytes();
+        assert_eq!((candid.len(), binary.len()), (153, 96));
+
+        let candid = Candid::<NonceKeyV0, 52>(NonceKeyV0(user(1), 1)).to_bytes();
+        let binary = PrincipalChainIdKey(user(1), 1).to_bytes();
+        assert_eq!((candid.len(), binary.len()), (52, 38));
+    }
+
+    #[test]
+    fn migrates_legacy_nonces() {
+        let mut legacy =
+            StableBTreeMap::<Candid<NonceKeyV0, 29>, u64, _>::new(region_memory(NONCES_MEMORY_ID));
+        legacy.insert(Candid(NonceKeyV0(Principal::anonymous(), 1)), 5);
+        legacy.insert(Candid(NonceKeyV0(Principal::anonymous(), 56)), 7);
+        drop(legacy);
+
+        let mut schema = Schema::default();
+        schema.migrate().unwrap();
+        assert_eq!(schema.version(3), Some(current_version(3)));
+
+        let mut nonces = Nonces::default();
+        assert_eq!(nonces.get(Principal::anonymous(), 1), Some(5));
+        assert_eq!(nonces.get(Principal::anonymous(), 56), Some(7));
+        // longer principals fit the new layout
+        nonces.set(user(7), 1, 3);
+        assert_eq!(nonces.get(user(7), 1), Some(3));
+
+        // migrations run once
+        schema.migrate().unwrap();
+        assert_eq!(nonces.get(user(7), 1), Some(3));
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let mut schema = Schema::default();
+        schema.reset();
+        SCHEMA_VERSIONS.with(|versions| versions.borrow_mut().insert(3, current_version(3) + 1));
+        assert!(schema.migrate().is_err());
+    }
+}
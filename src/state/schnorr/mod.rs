@@ -0,0 +1,241 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_exports::ic_cdk::api::call::{call, call_with_payment128};
+use ic_stable_structures::memory_manager::VirtualMemory;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+
+use crate::error::Result;
+use crate::state::ecdsa::EcdsaKeyIds;
+use crate::state::{
+    decode, encode, StorablePrincipal, BIP340_SIGNERS_MEMORY_ID, ED25519_SIGNERS_MEMORY_ID,
+    MEMORY_MANAGER,
+};
+
+pub mod nostr;
+pub mod polkadot;
+pub mod solana;
+pub mod ton;
+
+/// Cycles attached to `sign_with_schnorr`, the same amount ic-cdk attaches to `sign_with_ecdsa`.
+const SIGN_WITH_SCHNORR_FEE: u128 = 26_153_846_153;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum SchnorrAlgorithm {
+    #[serde(rename = "bip340secp256k1")]
+    Bip340Secp256k1,
+    #[serde(rename = "ed25519")]
+    Ed25519,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct SchnorrKeyId {
+    algorithm: SchnorrAlgorithm,
+    name: String,
+}
+
+#[derive(CandidType)]
+struct SchnorrPublicKeyArgument {
+    canister_id: Option<Principal>,
+    derivation_path: Vec<Vec<u8>>,
+    key_id: SchnorrKeyId,
+}
+
+#[derive(Deserialize, CandidType)]
+struct SchnorrPublicKeyResponse {
+    public_key: Vec<u8>,
+    chain_code: Vec<u8>,
+}
+
+#[derive(CandidType)]
+struct SignWithSchnorrArgument {
+    message: Vec<u8>,
+    derivation_path: Vec<Vec<u8>>,
+    key_id: SchnorrKeyId,
+}
+
+#[derive(Deserialize, CandidType)]
+struct SignWithSchnorrResponse {
+    signature: Vec<u8>,
+}
+
+/// Threshold Schnorr key of a user, the counterpart of the ECDSA `Signer`
+/// for chains that need Ed25519 or BIP-340 signatures.
+// if change the struct, need to update the BOUND in Storable impl
+#[derive(Clone, CandidType, Deserialize)]
+pub struct SchnorrSigner {
+    key_id: EcdsaKeyIds,
+    algorithm: SchnorrAlgorithm,
+    path: Vec<u8>,
+    public_key: Vec<u8>,
+    chain_code: Vec<u8>,
+}
+
+impl SchnorrSigner {
+    pub async fn new(
+        key_id: EcdsaKeyIds,
+        algorithm: SchnorrAlgorithm,
+        path: Vec<u8>,
+    ) -> Result<Self> {
+        let arg = SchnorrPublicKeyArgument {
+            canister_id: None,
+            derivation_path: vec![path.clone()],
+            key_id: schnorr_key_id(key_id, algorithm),
+        };
+        let (res,): (SchnorrPublicKeyResponse,) = call(
+            Principal::management_canister(),
+            "schnorr_public_key",
+            (arg,),
+        )
+        .await?;
+
+        Ok(Self {
+            key_id,
+            algorithm,
+            path,
+            public_key: res.public_key,
+            chain_code: res.chain_code,
+        })
+    }
+
+    /// 32 bytes key for Ed25519, 33 bytes compressed SEC1 key for BIP-340.
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    pub fn chain_code(&self) -> &[u8] {
+        &self.chain_code
+    }
+
+    pub fn algorithm(&self) -> SchnorrAlgorithm {
+        self.algorithm
+    }
+
+    /// Signs the message itself, Ed25519 and BIP-340 hash it as part of signing.
+    pub async fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let arg = SignWithSchnorrArgument {
+            message: message.to_vec(),
+            derivation_path: vec![self.path.clone()],
+            key_id: schnorr_key_id(self.key_id, self.algorithm),
+        };
+        let (res,): (SignWithSchnorrResponse,) = call_with_payment128(
+            Principal::management_canister(),
+            "sign_with_schnorr",
+            (arg,),
+            SIGN_WITH_SCHNORR_FEE,
+        )
+        .await?;
+        Ok(res.signature)
+    }
+}
+
+#[cfg(test)]
+impl SchnorrSigner {
+    /// Signer of an existing public key, for tests which don't sign.
+    pub fn test(algorithm: SchnorrAlgorithm, public_key: Vec<u8>) -> Self {
+        Self {
+            key_id: EcdsaKeyIds::TestKey1,
+            algorithm,
+            path: vec![],
+            public_key,
+            chain_code: vec![0; 32],
+        }
+    }
+}
+
+fn schnorr_key_id(key_id: EcdsaKeyIds, algorithm: SchnorrAlgorithm) -> SchnorrKeyId {
+    SchnorrKeyId {
+        algorithm,
+        name: key_id.key_name().to_string(),
+    }
+}
+
+impl Storable for SchnorrSigner {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        encode(&self).into()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 256,
+        is_fixed_size: false,
+    };
+}
+
+/// Ed25519 signers of the users, stored next to their secp256k1 `Signers`.
+#[derive(Default, Clone, Copy)]
+pub struct Ed25519Signers {}
+
+impl Ed25519Signers {
+    pub fn reset(&mut self) {
+        ED25519_SIGNERS.with(|signers| {
+            signers.replace(StableBTreeMap::new(
+                MEMORY_MANAGER.with(|m| m.borrow().get(ED25519_SIGNERS_MEMORY_ID)),
+            ))
+        });
+    }
+
+    pub fn get(&self, principal: Principal) -> Option<SchnorrSigner> {
+        ED25519_SIGNERS.with(|signers| signers.borrow().get(&StorablePrincipal(principal)))
+    }
+
+    pub fn set(&mut self, principal: Principal, signer: SchnorrSigner) {
+        ED25519_SIGNERS.with(|signers| {
+            signers
+                .borrow_mut()
+                .insert(StorablePrincipal(principal), signer)
+        });
+    }
+
+    pub fn count(&self) -> u64 {
+        ED25519_SIGNERS.with(|signers| signers.borrow().len())
+    }
+
+    pub fn principals(&self) -> Vec<Principal> {
+        ED25519_SIGNERS.with(|signers| signers.borrow().iter().map(|(p, _)| p.0).collect())
+    }
+}
+
+/// BIP-340 signers of the users.
+#[derive(Default, Clone, Copy)]
+pub struct Bip340Signers {}
+
+impl Bip340Signers {
+    pub fn reset(&mut self) {
+        BIP340_SIGNERS.with(|signers| {
+            signers.replace(StableBTreeMap::new(
+                MEMORY_MANAGER.with(|m| m.borrow().get(BIP340_SIGNERS_MEMORY_ID)),
+            ))
+        });
+    }
+
+    pub fn get(&self, principal: Principal) -> Option<SchnorrSigner> {
+        BIP340_SIGNERS.with(|signers| signers.borrow().get(&StorablePrincipal(principal)))
+    }
+
+    pub fn set(&mut self, principal: Principal, signer: SchnorrSigner) {
+        BIP340_SIGNERS.with(|signers| {
+            signers
+                .borrow_mut()
+                .insert(StorablePrincipal(principal), signer)
+        });
+    }
+
+    pub fn count(&self) -> u64 {
+        BIP340_SIGNERS.with(|signers| signers.borrow().len())
+    }
+
+    pub fn principals(&self) -> Vec<Principal> {
+        BIP340_SIGNERS.with(|signers| signers.borrow().iter().map(|(p, _)| p.0).collect())
+    }
+}
+
+thread_local! {
+    static ED25519_SIGNERS: RefCell<StableBTreeMap<StorablePrincipal, SchnorrSigner, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(ED25519_SIGNERS_MEMORY_ID))));
+    static BIP340_SIGNERS: RefCell<StableBTreeMap<StorablePrincipal, SchnorrSigner, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(BIP340_SIGNERS_MEMORY_ID))));
+}
@@ -0,0 +1,149 @@
+use bech32::{ToBase32, Variant};
+use candid::{CandidType, Deserialize};
+
+use crate::error::{Error, Result};
+use crate::state::ecdsa::hash::sha256;
+use crate::state::schnorr::SchnorrSigner;
+
+pub const NPUB_HRP: &str = "npub";
+
+/// Event of NIP-01 without its author, id and signature, filled in by the canister.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct NostrEvent {
+    /// Unix timestamp in seconds.
+    pub created_at: u64,
+    pub kind: u32,
+    pub tags: Vec<Vec<String>>,
+    pub content: String,
+}
+
+pub struct NostrWallet {
+    pub signer: SchnorrSigner,
+    /// x-only BIP-340 public key.
+    pub pubkey: [u8; 32],
+}
+
+impl NostrWallet {
+    pub fn new(signer: SchnorrSigner) -> Result<Self> {
+        let public_key = signer.public_key();
+        if public_key.len() != 33 || !matches!(public_key[0], 0x02 | 0x03) {
+            return Err(Error::InvalidPublicKey(hex::encode(public_key)));
+        }
+        let mut pubkey = [0; 32];
+        pubkey.copy_from_slice(&public_key[1..]);
+        Ok(Self { signer, pubkey })
+    }
+
+    pub fn npub(&self) -> Result<String> {
+        encode_npub(&self.pubkey)
+    }
+
+    /// Signs the event and returns it with its `id`, `pubkey` and `sig` as JSON.
+    pub async fn sign_event(&self, event: &NostrEvent) -> Result<String> {
+        let pubkey = hex::encode(self.pubkey);
+        let id = event_id(&pubkey, event);
+        let sig = self.signer.sign(&id).await?;
+        if sig.len() != 64 {
+            return Err(Error::InvalidSignature(hex::encode(sig)));
+        }
+
+        let mut json = format!(
+            r#"{{"id":"{}","pubkey":"{}","created_at":{},"kind":{},"tags":"#,
+            hex::encode(id),
+            pubkey,
+            event.created_at,
+            event.kind
+        );
+        write_tags(&mut json, &event.tags);
+        json.push_str(r#","content":"#);
+        write_string(&mut json, &event.content);
+        json.push_str(&format!(r#","sig":"{}"}}"#, hex::encode(sig)));
+        Ok(json)
+    }
+}
+
+/// NIP-19 bech32 encoding of an x-only public key.
+pub fn encode_npub(pubkey: &[u8; 32]) -> Result<String> {
+    bech32::encode(NPUB_HRP, pubkey.to_base32(), Variant::Bech32)
+        .map_err(|e| Error::InvalidPublicKey(e.to_string()))
+}
+
+/// SHA-256 of the canonical serialization `[0,pubkey,created_at,kind,tags,content]`.
+pub fn event_id(pubkey: &str, event: &NostrEvent) -> [u8; 32] {
+    let mut json = format!(r#"[0,"{}",{},{},"#, pubkey, event.created_at, event.kind);
+    write_tags(&mut json, &event.tags);
+    json.push(',');
+    write_string(&mut json, &event.content);
+    json.push(']');
+    sha256(json)
+}
+
+fn write_tags(json: &mut String, tags: &[Vec<String>]) {
+    json.push('[');
+    for (i, tag) in tags.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        json.push('[');
+        for (j, value) in tag.iter().enumerate() {
+            if j > 0 {
+                json.push(',');
+            }
+            write_string(json, value);
+        }
+        json.push(']');
+    }
+    json.push(']');
+}
+
+/// JSON string with the escapes of NIP-01, other characters written as is.
+fn write_string(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            '\u{08}' => json.push_str("\\b"),
+            '\u{0c}' => json.push_str("\\f"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_npub() {
+        // NIP-19 example
+        let pubkey =
+            hex::decode("7e7e9c42a91bfef19fa929e5fda1b72e0ebc1a4c1141673e2794234d86addf4e")
+                .unwrap();
+        assert_eq!(
+            encode_npub(&pubkey.try_into().unwrap()).unwrap(),
+            "npub10elfcs4fr0l0r8af98jlmgdh9c8tcxjvz9qkw038js35mp4dma8qzvjptg"
+        );
+    }
+
+    #[test]
+    fn serializes_events() {
+        let event = NostrEvent {
+            created_at: 1_700_000_000,
+            kind: 1,
+            tags: vec![
+                vec!["e".to_string(), "ab".to_string()],
+                vec!["t".to_string()],
+            ],
+            content: "hi \"nostr\"\n\\ é".to_string(),
+        };
+        let expected =
+            "[0,\"00\",1700000000,1,[[\"e\",\"ab\"],[\"t\"]],\"hi \\\"nostr\\\"\\n\\\\ é\"]";
+        assert_eq!(event_id("00", &event), sha256(expected));
+    }
+}
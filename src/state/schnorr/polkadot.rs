@@ -0,0 +1,389 @@
+use candid::{CandidType, Deserialize};
+
+use crate::error::{Error, Result};
+use crate::state::ecdsa::hash::{blake2b_256, blake2b_512};
+use crate::state::ecdsa::signature::recoverable_signature;
+use crate::state::ecdsa::Signer;
+use crate::state::schnorr::SchnorrSigner;
+
+/// Generic Substrate SS58 prefix.
+pub const SUBSTRATE_PREFIX: u16 = 42;
+/// Index of the `Balances` pallet on Polkadot, Kusama and Westend.
+pub const BALANCES_PALLET_INDEX: u8 = 5;
+/// Index of `Balances.transfer_keep_alive`.
+pub const TRANSFER_KEEP_ALIVE_CALL_INDEX: u8 = 3;
+
+const SS58_PRE: &[u8] = b"SS58PRE";
+/// Signed extrinsic of the version 4 format.
+const SIGNED_EXTRINSIC_V4: u8 = 0x84;
+const MULTI_ADDRESS_ID: u8 = 0;
+const MULTI_SIGNATURE_ED25519: u8 = 0;
+const MULTI_SIGNATURE_ECDSA: u8 = 2;
+/// Payloads longer than this are signed through their BLAKE2b-256 hash.
+const MAX_PAYLOAD_LEN: usize = 256;
+
+pub type AccountId = [u8; 32];
+
+#[derive(Clone, Copy, CandidType, Deserialize)]
+pub enum PolkadotKeyType {
+    Ed25519,
+    /// The secp256k1 key of the user, its account id is the BLAKE2b-256 hash
+    /// of the compressed public key.
+    Ecdsa,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub enum PolkadotCall {
+    /// `Balances.transfer_keep_alive`, with the pallet and call indices of the runtime.
+    TransferKeepAlive {
+        dest: String,
+        value: u128,
+        pallet_index: u8,
+        call_index: u8,
+    },
+    /// SCALE encoded call, including its pallet and call indices.
+    Raw(Vec<u8>),
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub enum PolkadotEra {
+    Immortal,
+    /// Valid for `period` blocks from `current_block`, `block_hash` must then be
+    /// the hash of `current_block`.
+    Mortal {
+        period: u64,
+        current_block: u64,
+    },
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct PolkadotTransaction {
+    pub call: PolkadotCall,
+    pub era: PolkadotEra,
+    pub nonce: u64,
+    pub tip: u128,
+    pub spec_version: u32,
+    pub transaction_version: u32,
+    /// Hex encoded genesis hash of the chain.
+    pub genesis_hash: String,
+    /// Hex encoded hash of the era checkpoint block, the genesis hash for immortal transactions.
+    pub block_hash: String,
+    /// Adds the `CheckMetadataHash` signed extension, disabled, required by recent runtimes.
+    pub check_metadata_hash: bool,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct PolkadotSignedTransaction {
+    /// Hex encoded signed extrinsic, as expected by `author_submitExtrinsic`.
+    pub extrinsic: String,
+    /// Hex encoded BLAKE2b-256 hash of the extrinsic.
+    pub hash: String,
+}
+
+pub enum PolkadotSigner {
+    Ed25519(SchnorrSigner),
+    Ecdsa(Signer),
+}
+
+pub struct PolkadotWallet {
+    pub signer: PolkadotSigner,
+    pub account_id: AccountId,
+}
+
+impl PolkadotWallet {
+    pub fn new(signer: PolkadotSigner) -> Result<Self> {
+        let account_id = match &signer {
+            PolkadotSigner::Ed25519(signer) => signer
+                .public_key()
+                .try_into()
+                .map_err(|_| Error::InvalidPublicKey(hex::encode(signer.public_key())))?,
+            PolkadotSigner::Ecdsa(signer) => blake2b_256(signer.public_key()),
+        };
+        Ok(Self { signer, account_id })
+    }
+
+    /// SS58 encoded address for the network `prefix`.
+    pub fn address(&self, prefix: u16) -> Result<String> {
+        encode_address(prefix, &self.account_id)
+    }
+
+    pub async fn sign_transaction(
+        &self,
+        tx: &PolkadotTransaction,
+    ) -> Result<PolkadotSignedTransaction> {
+        let call = tx.call.to_bytes()?;
+        let extra = tx.extra();
+        let mut payload = [call.as_slice(), &extra, &tx.additional_signed()?].concat();
+        if payload.len() > MAX_PAYLOAD_LEN {
+            payload = blake2b_256(&payload).to_vec();
+        }
+
+        let mut extrinsic = vec![SIGNED_EXTRINSIC_V4, MULTI_ADDRESS_ID];
+        extrinsic.extend_from_slice(&self.account_id);
+        match &self.signer {
+            PolkadotSigner::Ed25519(signer) => {
+                extrinsic.push(MULTI_SIGNATURE_ED25519);
+                extrinsic.extend(signer.sign(&payload).await?);
+            }
+            PolkadotSigner::Ecdsa(signer) => {
+                // Substrate ECDSA signatures are always made over the BLAKE2b-256 hash
+                let hash = blake2b_256(&payload);
+                let sign = signer.sign_hash(hash).await?;
+                let (sig, recovery_id) = recoverable_signature(signer.public_key(), &hash, &sign)?;
+                extrinsic.push(MULTI_SIGNATURE_ECDSA);
+                extrinsic.extend_from_slice(&sig.to_bytes());
+                extrinsic.push(recovery_id.to_byte());
+            }
+        }
+        extrinsic.extend(extra);
+        extrinsic.extend(call);
+
+        let mut bytes = vec![];
+        write_compact(&mut bytes, extrinsic.len() as u128);
+        bytes.extend(extrinsic);
+        Ok(PolkadotSignedTransaction {
+            hash: hex::encode(blake2b_256(&bytes)),
+            extrinsic: hex::encode(bytes),
+        })
+    }
+}
+
+impl PolkadotTransaction {
+    /// Signed extensions data included in the extrinsic.
+    fn extra(&self) -> Vec<u8> {
+        let mut extra = self.era.to_bytes();
+        write_compact(&mut extra, self.nonce as u128);
+        write_compact(&mut extra, self.tip);
+        if self.check_metadata_hash {
+            // `Mode::Disabled`
+            extra.push(0);
+        }
+        extra
+    }
+
+    /// Signed extensions data only included in the signed payload.
+    fn additional_signed(&self) -> Result<Vec<u8>> {
+        let mut additional = self.spec_version.to_le_bytes().to_vec();
+        additional.extend_from_slice(&self.transaction_version.to_le_bytes());
+        additional.extend_from_slice(&decode_hash("genesis_hash", &self.genesis_hash)?);
+        additional.extend_from_slice(&decode_hash("block_hash", &self.block_hash)?);
+        if self.check_metadata_hash {
+            // no metadata hash
+            additional.push(0);
+        }
+        Ok(additional)
+    }
+}
+
+impl PolkadotCall {
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        match self {
+            Self::TransferKeepAlive {
+                dest,
+                value,
+                pallet_index,
+                call_index,
+            } => {
+                let (_, dest) = decode_address(dest)?;
+                let mut call = vec![*pallet_index, *call_index, MULTI_ADDRESS_ID];
+                call.extend_from_slice(&dest);
+                write_compact(&mut call, *value);
+                Ok(call)
+            }
+            Self::Raw(call) => Ok(call.clone()),
+        }
+    }
+}
+
+impl PolkadotEra {
+    fn to_bytes(&self) -> Vec<u8> {
+        match *self {
+            Self::Immortal => vec![0],
+            Self::Mortal {
+                period,
+                current_block,
+            } => {
+                let period = period
+                    .checked_next_power_of_two()
+                    .unwrap_or(1 << 16)
+                    .clamp(4, 1 << 16);
+                let phase = current_block % period;
+                let quantize_factor = (period >> 12).max(1);
+                let quantized_phase = phase / quantize_factor * quantize_factor;
+
+                let low = (period.trailing_zeros() - 1).clamp(1, 15) as u16;
+                let high = ((quantized_phase / quantize_factor) << 4) as u16;
+                (low | high).to_le_bytes().to_vec()
+            }
+        }
+    }
+}
+
+fn decode_hash(name: &str, value: &str) -> Result<[u8; 32]> {
+    hex::decode(value.trim_start_matches("0x"))
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Error::InvalidTransaction(format!("{name} {value}")))
+}
+
+/// SCALE compact encoding of an unsigned integer.
+pub fn write_compact(buf: &mut Vec<u8>, value: u128) {
+    match value {
+        0..=0x3f => buf.push((value as u8) << 2),
+        0x40..=0x3fff => buf.extend_from_slice(&((value as u16) << 2 | 0b01).to_le_bytes()),
+        0x4000..=0x3fff_ffff => buf.extend_from_slice(&((value as u32) << 2 | 0b10).to_le_bytes()),
+        _ => {
+            let bytes = value.to_le_bytes();
+            let len = 16 - value.leading_zeros() as usize / 8;
+            buf.push(((len - 4) as u8) << 2 | 0b11);
+            buf.extend_from_slice(&bytes[..len]);
+        }
+    }
+}
+
+fn ss58_checksum(data: &[u8]) -> [u8; 2] {
+    let hash = blake2b_512([SS58_PRE, data].concat());
+    [hash[0], hash[1]]
+}
+
+/// SS58 encoding of an account id, with a one or two bytes network prefix.
+pub fn encode_address(prefix: u16, account_id: &AccountId) -> Result<String> {
+    let mut data = match prefix {
+        0..=63 => vec![prefix as u8],
+        64..=16383 => vec![
+            ((prefix & 0b1111_1100) >> 2) as u8 | 0b0100_0000,
+            (prefix >> 8) as u8 | ((prefix & 0b11) << 6) as u8,
+        ],
+        _ => return Err(Error::InvalidAddress(format!("SS58 prefix {prefix}"))),
+    };
+    data.extend_from_slice(account_id);
+    let checksum = ss58_checksum(&data);
+    data.extend_from_slice(&checksum);
+    Ok(bs58::encode(data).into_string())
+}
+
+/// Decodes a SS58 address into its network prefix and account id.
+pub fn decode_address(address: &str) -> Result<(u16, AccountId)> {
+    let invalid = || Error::InvalidAddress(address.to_string());
+    let data = bs58::decode(address).into_vec().map_err(|_| invalid())?;
+
+    let (prefix, prefix_len) = match data.first() {
+        Some(&first @ 0..=63) => (first as u16, 1),
+        Some(&first @ 64..=127) => {
+            let second = *data.get(1).ok_or_else(invalid)? as u16;
+            let lower = ((first as u16) << 2) | (second >> 6);
+            let upper = second & 0b0011_1111;
+            ((lower & 0b1111_1111) | (upper << 8), 2)
+        }
+        _ => return Err(invalid()),
+    };
+    if data.len() != prefix_len + 32 + 2 {
+        return Err(invalid());
+    }
+    let (body, checksum) = data.split_at(prefix_len + 32);
+    if ss58_checksum(body) != checksum {
+        return Err(invalid());
+    }
+    let account_id = body[prefix_len..].try_into().map_err(|_| invalid())?;
+    Ok((prefix, account_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: &str = "d43593c715fdd31c61141abd04a99fd6822c8558854ccde39a5684e7a56da27d";
+
+    #[test]
+    fn encodes_ss58_addresses() {
+        let alice: AccountId = hex::decode(ALICE).unwrap().try_into().unwrap();
+        assert_eq!(
+            encode_address(SUBSTRATE_PREFIX, &alice).unwrap(),
+            "5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY"
+        );
+        assert_eq!(
+            encode_address(0, &alice).unwrap(),
+            "15oF4uVJwmo4TdGW7VfQxNLavjCXviqxT9S1MgbjMNHr6Sp5"
+        );
+        assert_eq!(
+            decode_address("5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY").unwrap(),
+            (SUBSTRATE_PREFIX, alice)
+        );
+        assert!(decode_address("5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQZ").is_err());
+
+        for prefix in [64, 1284, 16383] {
+            let address = encode_address(prefix, &alice).unwrap();
+            assert_eq!(decode_address(&address).unwrap(), (prefix, alice));
+        }
+        assert!(encode_address(16384, &alice).is_err());
+    }
+
+    #[test]
+    fn encodes_compact_integers() {
+        for (value, encoded) in [
+            (0, "00"),
+            (1, "04"),
+            (63, "fc"),
+            (64, "0101"),
+            (16383, "fdff"),
+            (16384, "02000100"),
+            (1_073_741_823, "feffffff"),
+            (1_073_741_824, "0300000040"),
+            (u64::MAX as u128, "13ffffffffffffffff"),
+        ] {
+            let mut buf = vec![];
+            write_compact(&mut buf, value);
+            assert_eq!(hex::encode(buf), encoded, "{value}");
+        }
+    }
+
+    #[test]
+    fn encodes_eras() {
+        assert_eq!(PolkadotEra::Immortal.to_bytes(), [0]);
+        let era = PolkadotEra::Mortal {
+            period: 64,
+            current_block: 42,
+        };
+        assert_eq!(era.to_bytes(), [5 + 42 % 16 * 16, 42 / 16]);
+        let era = PolkadotEra::Mortal {
+            period: 32768,
+            current_block: 20000,
+        };
+        // quantized by a factor of 8
+        assert_eq!(era.to_bytes(), ((20000 / 8) << 4 | 14u16).to_le_bytes());
+    }
+
+    #[test]
+    fn encodes_transfer_keep_alive() {
+        let call = PolkadotCall::TransferKeepAlive {
+            dest: "5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY".to_string(),
+            value: 12345,
+            pallet_index: BALANCES_PALLET_INDEX,
+            call_index: TRANSFER_KEEP_ALIVE_CALL_INDEX,
+        };
+        assert_eq!(
+            hex::encode(call.to_bytes().unwrap()),
+            format!("050300{ALICE}e5c0")
+        );
+    }
+
+    #[test]
+    fn builds_signing_payload() {
+        let tx = PolkadotTransaction {
+            call: PolkadotCall::Raw(vec![0, 0]),
+            era: PolkadotEra::Immortal,
+            nonce: 1,
+            tip: 0,
+            spec_version: 1_002_000,
+            transaction_version: 26,
+            genesis_hash: format!("0x{}", "11".repeat(32)),
+            block_hash: "11".repeat(32),
+            check_metadata_hash: true,
+        };
+        assert_eq!(hex::encode(tx.extra()), "00040000");
+        assert_eq!(
+            hex::encode(tx.additional_signed().unwrap()),
+            format!("104a0f001a000000{}00", "11".repeat(64))
+        );
+    }
+}
@@ -0,0 +1,517 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use candid::{CandidType, Deserialize};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+
+use crate::error::{Error, Result};
+use crate::state::ecdsa::hash::sha256;
+use crate::state::schnorr::SchnorrSigner;
+
+pub type Pubkey = [u8; 32];
+
+pub const SYSTEM_PROGRAM_ID: Pubkey = [0; 32];
+pub const TOKEN_PROGRAM_ID: &str = "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA";
+pub const ASSOCIATED_TOKEN_PROGRAM_ID: &str = "ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL";
+
+const PDA_MARKER: &[u8] = b"ProgramDerivedAddress";
+const VERSION_PREFIX: u8 = 0x80;
+const SYSTEM_TRANSFER: u32 = 2;
+const TOKEN_TRANSFER_CHECKED: u8 = 12;
+const ATA_CREATE_IDEMPOTENT: u8 = 1;
+
+#[derive(Clone, Copy, CandidType, Deserialize)]
+pub enum SolanaMessageVersion {
+    Legacy,
+    V0,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub enum SolanaInstruction {
+    /// System program SOL transfer from the caller.
+    Transfer { to: String, lamports: u64 },
+    /// SPL token `TransferChecked` between the associated token accounts of the
+    /// caller and the `to` wallet.
+    TokenTransfer {
+        mint: String,
+        to: String,
+        amount: u64,
+        decimals: u8,
+        /// Adds an idempotent creation of the recipient's associated token account.
+        create_recipient_account: bool,
+        /// Token program owning the mint, defaults to the SPL token program.
+        token_program: Option<String>,
+    },
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct SolanaTransaction {
+    pub instructions: Vec<SolanaInstruction>,
+    /// Base58 encoded recent blockhash.
+    pub recent_blockhash: String,
+    pub version: SolanaMessageVersion,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct SolanaSignedMessage {
+    /// Base58 encoded signature, also the transaction id when the caller pays the fees.
+    pub signature: String,
+    /// Base64 encoded transaction, ready for `sendTransaction`, when the caller is the only
+    /// required signer.
+    pub transaction: Option<String>,
+}
+
+pub struct AccountMeta {
+    pub pubkey: Pubkey,
+    pub is_signer: bool,
+    pub is_writable: bool,
+}
+
+pub struct Instruction {
+    pub program_id: Pubkey,
+    pub accounts: Vec<AccountMeta>,
+    pub data: Vec<u8>,
+}
+
+pub struct SolanaWallet {
+    pub signer: SchnorrSigner,
+    pub pubkey: Pubkey,
+}
+
+impl SolanaWallet {
+    pub fn new(signer: SchnorrSigner) -> Result<Self> {
+        let pubkey = signer
+            .public_key()
+            .try_into()
+            .map_err(|_| Error::InvalidPublicKey(hex::encode(signer.public_key())))?;
+        Ok(Self { signer, pubkey })
+    }
+
+    /// Base58 encoded account address.
+    pub fn address(&self) -> String {
+        bs58::encode(self.pubkey).into_string()
+    }
+
+    /// Builds the message of the transaction, paid by the caller.
+    pub fn build_message(&self, tx: &SolanaTransaction) -> Result<Vec<u8>> {
+        let mut instructions = vec![];
+        for instruction in &tx.instructions {
+            instructions.extend(instruction.compile(&self.pubkey)?);
+        }
+        let recent_blockhash = decode_pubkey(&tx.recent_blockhash)?;
+        Ok(compile_message(
+            &self.pubkey,
+            &instructions,
+            &recent_blockhash,
+            tx.version,
+        ))
+    }
+
+    /// Signs a serialized legacy or v0 message in which the caller is a required signer.
+    pub async fn sign_message(&self, message: &[u8]) -> Result<SolanaSignedMessage> {
+        let signers = required_signers(message)?;
+        if !signers.contains(&self.pubkey) {
+            return Err(Error::InvalidTransaction(
+                "caller is not a signer of the message".to_string(),
+            ));
+        }
+
+        let signature = self.signer.sign(message).await?;
+
+        // only complete transactions can be returned, other signers add theirs off chain
+        let transaction = (signers.len() == 1).then(|| {
+            let mut tx = vec![1];
+            tx.extend_from_slice(&signature);
+            tx.extend_from_slice(message);
+            BASE64.encode(tx)
+        });
+
+        Ok(SolanaSignedMessage {
+            signature: bs58::encode(signature).into_string(),
+            transaction,
+        })
+    }
+}
+
+pub fn decode_pubkey(value: &str) -> Result<Pubkey> {
+    bs58::decode(value)
+        .into_vec()
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Error::InvalidAddress(value.to_string()))
+}
+
+/// Program derived address of the seeds, with the highest bump seed leading off the curve.
+pub fn find_program_address(seeds: &[&[u8]], program_id: &Pubkey) -> Option<(Pubkey, u8)> {
+    (0..=u8::MAX).rev().find_map(|bump| {
+        let mut buf = seeds.concat();
+        buf.push(bump);
+        buf.extend_from_slice(program_id);
+        buf.extend_from_slice(PDA_MARKER);
+        let address = sha256(buf);
+
+        let on_curve = CompressedEdwardsY(address).decompress().is_some();
+        (!on_curve).then_some((address, bump))
+    })
+}
+
+/// Associated token account of `owner` for `mint`.
+pub fn associated_token_address(
+    owner: &Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+) -> Result<Pubkey> {
+    let program_id = decode_pubkey(ASSOCIATED_TOKEN_PROGRAM_ID)?;
+    find_program_address(&[owner, token_program, mint], &program_id)
+        .map(|(address, _)| address)
+        .ok_or_else(|| Error::Internal("no associated token address".to_string()))
+}
+
+impl SolanaInstruction {
+    fn compile(&self, owner: &Pubkey) -> Result<Vec<Instruction>> {
+        match self {
+            Self::Transfer { to, lamports } => {
+                let mut data = SYSTEM_TRANSFER.to_le_bytes().to_vec();
+                data.extend_from_slice(&lamports.to_le_bytes());
+                Ok(vec![Instruction {
+                    program_id: SYSTEM_PROGRAM_ID,
+                    accounts: vec![
+                        AccountMeta::writable(*owner, true),
+                        AccountMeta::writable(decode_pubkey(to)?, false),
+                    ],
+                    data,
+                }])
+            }
+            Self::TokenTransfer {
+                mint,
+                to,
+                amount,
+                decimals,
+                create_recipient_account,
+                token_program,
+            } => {
+                let mint = decode_pubkey(mint)?;
+                let to = decode_pubkey(to)?;
+                let token_program =
+                    decode_pubkey(token_program.as_deref().unwrap_or(TOKEN_PROGRAM_ID))?;
+                let source = associated_token_address(owner, &mint, &token_program)?;
+                let destination = associated_token_address(&to, &mint, &token_program)?;
+
+                let mut instructions = vec![];
+                if *create_recipient_account {
+                    instructions.push(Instruction {
+                        program_id: decode_pubkey(ASSOCIATED_TOKEN_PROGRAM_ID)?,
+                        accounts: vec![
+                            AccountMeta::writable(*owner, true),
+                            AccountMeta::writable(destination, false),
+                            AccountMeta::readonly(to, false),
+                            AccountMeta::readonly(mint, false),
+                            AccountMeta::readonly(SYSTEM_PROGRAM_ID, false),
+                            AccountMeta::readonly(token_program, false),
+                        ],
+                        data: vec![ATA_CREATE_IDEMPOTENT],
+                    });
+                }
+
+                let mut data = vec![TOKEN_TRANSFER_CHECKED];
+                data.extend_from_slice(&amount.to_le_bytes());
+                data.push(*decimals);
+                instructions.push(Instruction {
+                    program_id: token_program,
+                    accounts: vec![
+                        AccountMeta::writable(source, false),
+                        AccountMeta::readonly(mint, false),
+                        AccountMeta::writable(destination, false),
+                        AccountMeta::readonly(*owner, true),
+                    ],
+                    data,
+                });
+                Ok(instructions)
+            }
+        }
+    }
+}
+
+impl AccountMeta {
+    pub fn writable(pubkey: Pubkey, is_signer: bool) -> Self {
+        Self {
+            pubkey,
+            is_signer,
+            is_writable: true,
+        }
+    }
+
+    pub fn readonly(pubkey: Pubkey, is_signer: bool) -> Self {
+        Self {
+            pubkey,
+            is_signer,
+            is_writable: false,
+        }
+    }
+}
+
+/// Serializes a message paid by `payer`, without address lookup tables for v0 messages.
+pub fn compile_message(
+    payer: &Pubkey,
+    instructions: &[Instruction],
+    recent_blockhash: &Pubkey,
+    version: SolanaMessageVersion,
+) -> Vec<u8> {
+    // merge the flags of every account, the payer always being the first writable signer
+    let mut keys: Vec<AccountMeta> = vec![AccountMeta::writable(*payer, true)];
+    let mut add_key = |meta: AccountMeta| match keys.iter_mut().find(|k| k.pubkey == meta.pubkey) {
+        Some(key) => {
+            key.is_signer |= meta.is_signer;
+            key.is_writable |= meta.is_writable;
+        }
+        None => keys.push(meta),
+    };
+    for instruction in instructions {
+        for account in &instruction.accounts {
+            add_key(AccountMeta {
+                pubkey: account.pubkey,
+                is_signer: account.is_signer,
+                is_writable: account.is_writable,
+            });
+        }
+        add_key(AccountMeta::readonly(instruction.program_id, false));
+    }
+    // stable sort keeps the payer first
+    keys.sort_by_key(|k| (!k.is_signer, !k.is_writable));
+
+    let num_required_signatures = keys.iter().filter(|k| k.is_signer).count() as u8;
+    let num_readonly_signed = keys
+        .iter()
+        .filter(|k| k.is_signer && !k.is_writable)
+        .count() as u8;
+    let num_readonly_unsigned = keys
+        .iter()
+        .filter(|k| !k.is_signer && !k.is_writable)
+        .count() as u8;
+    let index_of = |pubkey: &Pubkey| keys.iter().position(|k| k.pubkey == *pubkey).unwrap() as u8;
+
+    let mut message = vec![];
+    if let SolanaMessageVersion::V0 = version {
+        message.push(VERSION_PREFIX);
+    }
+    message.extend_from_slice(&[
+        num_required_signatures,
+        num_readonly_signed,
+        num_readonly_unsigned,
+    ]);
+    write_compact_u16(&mut message, keys.len());
+    for key in &keys {
+        message.extend_from_slice(&key.pubkey);
+    }
+    message.extend_from_slice(recent_blockhash);
+
+    write_compact_u16(&mut message, instructions.len());
+    for instruction in instructions {
+        message.push(index_of(&instruction.program_id));
+        write_compact_u16(&mut message, instruction.accounts.len());
+        for account in &instruction.accounts {
+            message.push(index_of(&account.pubkey));
+        }
+        write_compact_u16(&mut message, instruction.data.len());
+        message.extend_from_slice(&instruction.data);
+    }
+
+    if let SolanaMessageVersion::V0 = version {
+        // no address table lookups
+        write_compact_u16(&mut message, 0);
+    }
+    message
+}
+
+/// Public keys of the required signers of a legacy or v0 message.
+pub fn required_signers(message: &[u8]) -> Result<Vec<Pubkey>> {
+    let invalid = || Error::InvalidTransaction("malformed Solana message".to_string());
+
+    let mut pos = 0;
+    if message.first().ok_or_else(invalid)? & VERSION_PREFIX != 0 {
+        if message[0] != VERSION_PREFIX {
+            return Err(Error::InvalidTransaction(format!(
+                "unsupported message version {}",
+                message[0] & !VERSION_PREFIX
+            )));
+        }
+        pos += 1;
+    }
+
+    let num_required_signatures = *message.get(pos).ok_or_else(invalid)? as usize;
+    pos += 3;
+    let num_keys = read_compact_u16(message, &mut pos).ok_or_else(invalid)?;
+    if num_required_signatures > num_keys {
+        return Err(invalid());
+    }
+
+    (0..num_required_signatures)
+        .map(|i| {
+            let start = pos + i * 32;
+            message
+                .get(start..start + 32)
+                .and_then(|key| key.try_into().ok())
+                .ok_or_else(invalid)
+        })
+        .collect()
+}
+
+fn write_compact_u16(buf: &mut Vec<u8>, len: usize) {
+    let mut value = len as u16;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn read_compact_u16(buf: &[u8], pos: &mut usize) -> Option<usize> {
+    let mut value = 0usize;
+    for i in 0..3 {
+        let byte = *buf.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_program_ids() {
+        assert_eq!(
+            bs58::encode(SYSTEM_PROGRAM_ID).into_string(),
+            "11111111111111111111111111111111"
+        );
+        assert!(decode_pubkey(TOKEN_PROGRAM_ID).is_ok());
+        assert!(decode_pubkey("11111111111111111111111111111111111").is_err());
+    }
+
+    #[test]
+    fn compact_u16() {
+        for (value, encoded) in [
+            (0, vec![0x00]),
+            (0x7f, vec![0x7f]),
+            (0x80, vec![0x80, 0x01]),
+            (0x3fff, vec![0xff, 0x7f]),
+            (0x4000, vec![0x80, 0x80, 0x01]),
+        ] {
+            let mut buf = vec![];
+            write_compact_u16(&mut buf, value);
+            assert_eq!(buf, encoded);
+            assert_eq!(read_compact_u16(&buf, &mut 0), Some(value));
+        }
+    }
+
+    #[test]
+    fn compiles_sol_transfer() {
+        let payer = [1; 32];
+        let to = [2; 32];
+        let blockhash = [3; 32];
+        let instructions = SolanaInstruction::Transfer {
+            to: bs58::encode(to).into_string(),
+            lamports: 1_000_000,
+        }
+        .compile(&payer)
+        .unwrap();
+
+        let legacy = compile_message(
+            &payer,
+            &instructions,
+            &blockhash,
+            SolanaMessageVersion::Legacy,
+        );
+        let expected = [
+            vec![1, 0, 1, 3],
+            payer.to_vec(),
+            to.to_vec(),
+            SYSTEM_PROGRAM_ID.to_vec(),
+            blockhash.to_vec(),
+            vec![
+                1, 2, 2, 0, 1, 12, 2, 0, 0, 0, 0x40, 0x42, 0x0f, 0, 0, 0, 0, 0,
+            ],
+        ]
+        .concat();
+        assert_eq!(legacy, expected);
+        assert_eq!(required_signers(&legacy).unwrap(), vec![payer]);
+
+        let v0 = compile_message(&payer, &instructions, &blockhash, SolanaMessageVersion::V0);
+        assert_eq!(v0, [vec![0x80], expected, vec![0]].concat());
+        assert_eq!(required_signers(&v0).unwrap(), vec![payer]);
+    }
+
+    #[test]
+    fn compiles_token_transfer() {
+        let payer = [1; 32];
+        let mint = [4; 32];
+        let instructions = SolanaInstruction::TokenTransfer {
+            mint: bs58::encode(mint).into_string(),
+            to: bs58::encode([2; 32]).into_string(),
+            amount: 5,
+            decimals: 6,
+            create_recipient_account: true,
+            token_program: None,
+        }
+        .compile(&payer)
+        .unwrap();
+        assert_eq!(instructions.len(), 2);
+
+        let message = compile_message(
+            &payer,
+            &instructions,
+            &[3; 32],
+            SolanaMessageVersion::Legacy,
+        );
+        // the payer signs, both token accounts are writable, the recipient wallet, the mint
+        // and the system, associated token and token programs are read only
+        assert_eq!(message[..4], [1, 0, 5, 8]);
+
+        let transfer = &instructions[1];
+        assert_eq!(transfer.data, [12, 5, 0, 0, 0, 0, 0, 0, 0, 6]);
+        assert_eq!(transfer.accounts[3].pubkey, payer);
+        assert_eq!(
+            bs58::encode(transfer.accounts[0].pubkey).into_string(),
+            "8ZfjnwxdKftw8Kk9xcDmsR2kdyDJhHSaTo2uWypq4m8g"
+        );
+        for account in [&transfer.accounts[0], &transfer.accounts[2]] {
+            assert!(CompressedEdwardsY(account.pubkey).decompress().is_none());
+        }
+    }
+
+    #[test]
+    fn derives_associated_token_addresses() {
+        // USDC account of a wallet, derived off chain like `getAssociatedTokenAddressSync`
+        let owner = decode_pubkey("9WzDXwBbmkg8ZTbNMqUxvQRAyrZzDsGYdLVL9zYtAWWM").unwrap();
+        let mint = decode_pubkey("EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v").unwrap();
+        let token_program = decode_pubkey(TOKEN_PROGRAM_ID).unwrap();
+        let program_id = decode_pubkey(ASSOCIATED_TOKEN_PROGRAM_ID).unwrap();
+
+        let (address, bump) =
+            find_program_address(&[&owner, &token_program, &mint], &program_id).unwrap();
+        assert_eq!(
+            bs58::encode(address).into_string(),
+            "FGETo8T8wMcN2wCjav8VK6eh3dLk63evNDPxzLSJra8B"
+        );
+        // the first bump leads on the curve
+        assert_eq!(bump, 254);
+        assert_eq!(
+            associated_token_address(&owner, &mint, &token_program).unwrap(),
+            address
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        assert!(required_signers(&[0x81, 1, 0, 0, 0]).is_err());
+        assert!(required_signers(&[1, 0, 0, 1]).is_err());
+        assert!(required_signers(&[]).is_err());
+    }
+}
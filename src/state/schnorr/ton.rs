@@ -0,0 +1,599 @@
+use std::rc::Rc;
+
+use base64::engine::general_purpose::{STANDARD as BASE64, URL_SAFE as BASE64_URL};
+use base64::Engine;
+use candid::{CandidType, Deserialize};
+use crc::{Crc, CRC_16_XMODEM, CRC_32_ISCSI};
+
+use crate::error::{Error, Result};
+use crate::state::ecdsa::hash::sha256;
+use crate::state::schnorr::SchnorrSigner;
+
+/// Bag of cells of the Wallet V4R2 contract code.
+const WALLET_V4R2_CODE: &str = concat!(
+    "B5EE9C72410214010002D4000114FF00F4A413F4BCF2C80B010201200203020148040504F8F28308",
+    "D71820D31FD31FD31F02F823BBF264ED44D0D31FD31FD3FFF404D15143BAF2A15151BAF2A205F901",
+    "541064F910F2A3F80024A4C8CB1F5240CB1F5230CBFF5210F400C9ED54F80F01D30721C0009F6C51",
+    "9320D74A96D307D402FB00E830E021C001E30021C002E30001C0039130E30D03A4C8CB1F12CB1FCB",
+    "FF1011121302E6D001D0D3032171B0925F04E022D749C120925F04E002D31F218210706C7567BD22",
+    "821064737472BDB0925F05E003FA403020FA4401C8CA07CBFFC9D0ED44D0810140D721F404305C81",
+    "0108F40A6FA131B3925F07E005D33FC8258210706C7567BA923830E30D03821064737472BA925F06",
+    "E30D06070201200809007801FA00F40430F8276F2230500AA121BEF2E0508210706C7567831EB170",
+    "80185004CB0526CF1658FA0219F400CB6917CB1F5260CB3F20C98040FB0006008A5004810108F459",
+    "30ED44D0810140D720C801CF16F400C9ED540172B08E23821064737472831EB17080185005CB0550",
+    "03CF1623FA0213CB6ACB1FCB3FC98040FB00925F03E20201200A0B0059BD242B6F6A2684080A06B9",
+    "0FA0218470D4080847A4937D29910CE6903E9FF9837812801B7810148987159F31840201580C0D00",
+    "11B8C97ED44D0D70B1F8003DB29DFB513420405035C87D010C00B23281F2FFF274006040423D029B",
+    "E84C600201200E0F0019ADCE76A26840206B90EB85FFC00019AF1DF6A26840106B90EB858FC0006E",
+    "D207FA00D4D422F90005C8CA0715CBFFC9D077748018C8CB05CB0222CF165005FA0214CB6B12CCCC",
+    "C973FB00C84014810108F451F2A7020070810108D718FA00D33FC8542047810108F451F2A782106E",
+    "6F746570748018C8CB05CB025006CF165004FA0214CB6A12CB1FCB3FC973FB0002006C810108D718",
+    "FA00D33F305224810108F459F2A782106473747270748018C8CB05CB025005CF165003FA0213CB6A",
+    "CB1F12CB3FC973FB00000AF400C9ED54696225E5",
+);
+/// Default subwallet id of workchain 0 wallets.
+pub const DEFAULT_SUBWALLET_ID: u32 = 698_983_191;
+/// Sends the value separately from fees, ignoring errors, the mode of most wallet transfers.
+pub const DEFAULT_SEND_MODE: u8 = 3;
+/// Maximum number of messages of a V4 wallet transfer.
+const MAX_MESSAGES: usize = 4;
+
+const MAX_CELL_BITS: usize = 1023;
+const MAX_CELL_REFS: usize = 4;
+const BOC_MAGIC: [u8; 4] = [0xb5, 0xee, 0x9c, 0x72];
+const BOUNCEABLE_TAG: u8 = 0x11;
+const NON_BOUNCEABLE_TAG: u8 = 0x51;
+const TESTNET_FLAG: u8 = 0x80;
+
+/// An ordinary cell of up to 1023 bits and 4 references.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Cell {
+    data: Vec<u8>,
+    bits: usize,
+    refs: Vec<Rc<Cell>>,
+}
+
+#[derive(Default)]
+pub struct CellBuilder {
+    cell: Cell,
+}
+
+impl CellBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn store_bit(&mut self, bit: bool) -> &mut Self {
+        if self.cell.bits.is_multiple_of(8) {
+            self.cell.data.push(0);
+        }
+        if bit {
+            let last = self.cell.data.len() - 1;
+            self.cell.data[last] |= 0x80 >> (self.cell.bits % 8);
+        }
+        self.cell.bits += 1;
+        self
+    }
+
+    /// Stores the `bits` lowest bits of `value`, most significant first.
+    pub fn store_uint(&mut self, value: u64, bits: usize) -> &mut Self {
+        for i in (0..bits).rev() {
+            self.store_bit(value >> i & 1 == 1);
+        }
+        self
+    }
+
+    pub fn store_bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        for &byte in bytes {
+            self.store_uint(byte as u64, 8);
+        }
+        self
+    }
+
+    /// `Grams`: the byte length on 4 bits, then the big endian value.
+    pub fn store_coins(&mut self, value: u128) -> &mut Self {
+        let len = 16 - value.leading_zeros() as usize / 8;
+        self.store_uint(len as u64, 4)
+            .store_bytes(&value.to_be_bytes()[16 - len..])
+    }
+
+    /// `addr_std` without anycast.
+    pub fn store_address(&mut self, address: &TonAddress) -> &mut Self {
+        self.store_uint(0b100, 3)
+            .store_uint(address.workchain as u8 as u64, 8)
+            .store_bytes(&address.hash)
+    }
+
+    /// Stores the bits and references of `cell`.
+    pub fn store_cell(&mut self, cell: &Cell) -> &mut Self {
+        for i in 0..cell.bits {
+            self.store_bit(cell.data[i / 8] & (0x80 >> (i % 8)) != 0);
+        }
+        self.cell.refs.extend(cell.refs.iter().cloned());
+        self
+    }
+
+    pub fn store_ref(&mut self, cell: Cell) -> &mut Self {
+        self.cell.refs.push(Rc::new(cell));
+        self
+    }
+
+    pub fn build(&mut self) -> Result<Cell> {
+        if self.cell.bits > MAX_CELL_BITS || self.cell.refs.len() > MAX_CELL_REFS {
+            return Err(Error::InvalidTransaction("cell overflow".to_string()));
+        }
+        Ok(std::mem::take(&mut self.cell))
+    }
+}
+
+impl Cell {
+    /// Representation hash of the cell.
+    pub fn hash(&self) -> [u8; 32] {
+        let mut repr = self.descriptors().to_vec();
+        repr.extend(self.padded_data());
+        for cell in &self.refs {
+            repr.extend_from_slice(&cell.depth().to_be_bytes());
+        }
+        for cell in &self.refs {
+            repr.extend_from_slice(&cell.hash());
+        }
+        sha256(repr)
+    }
+
+    pub fn depth(&self) -> u16 {
+        self.refs
+            .iter()
+            .map(|cell| cell.depth() + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn descriptors(&self) -> [u8; 2] {
+        [
+            self.refs.len() as u8,
+            (self.bits / 8 + self.bits.div_ceil(8)) as u8,
+        ]
+    }
+
+    /// Data with a completion tag, a one bit followed by zeros, when not byte aligned.
+    fn padded_data(&self) -> Vec<u8> {
+        let mut data = self.data.clone();
+        if !self.bits.is_multiple_of(8) {
+            let last = data.len() - 1;
+            data[last] |= 0x80 >> (self.bits % 8);
+        }
+        data
+    }
+
+    /// Single root bag of cells with a CRC32-C checksum and no index.
+    pub fn to_boc(&self) -> Vec<u8> {
+        // reversed post order: parents come before their children, identical cells are
+        // stored once
+        fn visit<'a>(cell: &'a Cell, cells: &mut Vec<(&'a Cell, [u8; 32])>) {
+            let hash = cell.hash();
+            if cells.iter().any(|(_, h)| *h == hash) {
+                return;
+            }
+            for child in cell.refs.iter().rev() {
+                visit(child, cells);
+            }
+            cells.push((cell, hash));
+        }
+        let mut cells = vec![];
+        visit(self, &mut cells);
+        cells.reverse();
+
+        let ref_size = byte_len(cells.len() as u64);
+        let index_of = |hash: &[u8; 32]| cells.iter().position(|(_, h)| h == hash).unwrap();
+        let mut data = vec![];
+        for (cell, _) in &cells {
+            data.extend(cell.descriptors());
+            data.extend(cell.padded_data());
+            for child in &cell.refs {
+                let index = index_of(&child.hash()) as u64;
+                data.extend_from_slice(&index.to_be_bytes()[8 - ref_size..]);
+            }
+        }
+        let offset_size = byte_len(data.len() as u64);
+
+        let mut boc = BOC_MAGIC.to_vec();
+        // has_crc32c flag and the size of cell references
+        boc.push(0x40 | ref_size as u8);
+        boc.push(offset_size as u8);
+        for value in [cells.len(), 1, 0] {
+            boc.extend_from_slice(&(value as u64).to_be_bytes()[8 - ref_size..]);
+        }
+        boc.extend_from_slice(&(data.len() as u64).to_be_bytes()[8 - offset_size..]);
+        // the root index
+        boc.extend_from_slice(&0u64.to_be_bytes()[8 - ref_size..]);
+        boc.extend(data);
+        let crc = Crc::<u32>::new(&CRC_32_ISCSI).checksum(&boc);
+        boc.extend_from_slice(&crc.to_le_bytes());
+        boc
+    }
+
+    /// Root cell of a single root bag of cells.
+    pub fn from_boc(boc: &[u8]) -> Result<Self> {
+        let invalid = || Error::Internal("invalid bag of cells".to_string());
+        let mut reader = Reader { boc, pos: 0 };
+        if reader.bytes(4).ok_or_else(invalid)? != BOC_MAGIC {
+            return Err(invalid());
+        }
+        let flags = reader.uint(1).ok_or_else(invalid)?;
+        let has_index = flags & 0x80 != 0;
+        let ref_size = (flags & 0x07) as usize;
+        let offset_size = reader.uint(1).ok_or_else(invalid)? as usize;
+        let cells_count = reader.uint(ref_size).ok_or_else(invalid)? as usize;
+        let roots = reader.uint(ref_size).ok_or_else(invalid)?;
+        reader.uint(ref_size).ok_or_else(invalid)?;
+        reader.uint(offset_size).ok_or_else(invalid)?;
+        if roots != 1 || reader.uint(ref_size) != Some(0) {
+            return Err(invalid());
+        }
+        if has_index {
+            reader
+                .bytes(cells_count * offset_size)
+                .ok_or_else(invalid)?;
+        }
+
+        let mut raw_cells = vec![];
+        for _ in 0..cells_count {
+            let d1 = reader.uint(1).ok_or_else(invalid)? as usize;
+            let d2 = reader.uint(1).ok_or_else(invalid)? as usize;
+            if d1 & 0xf8 != 0 {
+                // exotic cells and cells with a level never appear in wallet code
+                return Err(invalid());
+            }
+            let mut data = reader.bytes(d2.div_ceil(2)).ok_or_else(invalid)?.to_vec();
+            let mut bits = data.len() * 8;
+            if d2 % 2 == 1 {
+                // remove the completion tag
+                let last = data.last_mut().ok_or_else(invalid)?;
+                let trailing = last.trailing_zeros() as usize;
+                if trailing >= 8 {
+                    return Err(invalid());
+                }
+                *last &= !(1 << trailing);
+                bits -= trailing + 1;
+            }
+            let refs = (0..d1)
+                .map(|_| reader.uint(ref_size).map(|i| i as usize))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(invalid)?;
+            raw_cells.push((data, bits, refs));
+        }
+
+        // children have higher indices than their parents
+        let mut cells: Vec<Option<Rc<Cell>>> = vec![None; cells_count];
+        for (i, (data, bits, refs)) in raw_cells.into_iter().enumerate().rev() {
+            let refs = refs
+                .into_iter()
+                .map(|r| cells.get(r).cloned().flatten().filter(|_| r > i))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(invalid)?;
+            cells[i] = Some(Rc::new(Cell { data, bits, refs }));
+        }
+        let root = cells.first().cloned().flatten().ok_or_else(invalid)?;
+        Ok(root.as_ref().clone())
+    }
+}
+
+struct Reader<'a> {
+    boc: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.boc.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn uint(&mut self, len: usize) -> Option<u64> {
+        let bytes = self.bytes(len)?;
+        Some(bytes.iter().fold(0, |acc, &b| acc << 8 | b as u64))
+    }
+}
+
+fn byte_len(value: u64) -> usize {
+    (64 - value.leading_zeros() as usize).div_ceil(8).max(1)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TonAddress {
+    pub workchain: i8,
+    pub hash: [u8; 32],
+    pub bounceable: bool,
+}
+
+impl TonAddress {
+    /// User friendly base64url address.
+    pub fn to_friendly(&self, testnet: bool) -> String {
+        let mut tag = if self.bounceable {
+            BOUNCEABLE_TAG
+        } else {
+            NON_BOUNCEABLE_TAG
+        };
+        if testnet {
+            tag |= TESTNET_FLAG;
+        }
+        let mut bytes = vec![tag, self.workchain as u8];
+        bytes.extend_from_slice(&self.hash);
+        let crc = Crc::<u16>::new(&CRC_16_XMODEM).checksum(&bytes);
+        bytes.extend_from_slice(&crc.to_be_bytes());
+        BASE64_URL.encode(bytes)
+    }
+
+    /// Parses a user friendly address, or a raw `workchain:hex` address which is bounceable.
+    pub fn parse(address: &str) -> Result<Self> {
+        let invalid = || Error::InvalidAddress(address.to_string());
+
+        if let Some((workchain, hash)) = address.split_once(':') {
+            return Ok(Self {
+                workchain: workchain.parse().map_err(|_| invalid())?,
+                hash: hex::decode(hash)
+                    .ok()
+                    .and_then(|h| h.try_into().ok())
+                    .ok_or_else(invalid)?,
+                bounceable: true,
+            });
+        }
+
+        let bytes = BASE64
+            .decode(address.replace('-', "+").replace('_', "/"))
+            .map_err(|_| invalid())?;
+        if bytes.len() != 36 {
+            return Err(invalid());
+        }
+        let crc = Crc::<u16>::new(&CRC_16_XMODEM).checksum(&bytes[..34]);
+        if crc.to_be_bytes() != bytes[34..] {
+            return Err(invalid());
+        }
+        let bounceable = match bytes[0] & !TESTNET_FLAG {
+            BOUNCEABLE_TAG => true,
+            NON_BOUNCEABLE_TAG => false,
+            _ => return Err(invalid()),
+        };
+        Ok(Self {
+            workchain: bytes[1] as i8,
+            hash: bytes[2..34].try_into().map_err(|_| invalid())?,
+            bounceable,
+        })
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct TonTransfer {
+    /// User friendly or raw destination address, its bounce flag is used for the message.
+    pub to: String,
+    /// Amount in nanotons.
+    pub amount: u64,
+    pub comment: Option<String>,
+    /// Send mode, `DEFAULT_SEND_MODE` when missing.
+    pub mode: Option<u8>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct TonTransaction {
+    /// Up to 4 transfers.
+    pub transfers: Vec<TonTransfer>,
+    /// Current seqno of the wallet, 0 deploys it with the first transfer.
+    pub seqno: u32,
+    /// Unix time in seconds after which the message is rejected.
+    pub valid_until: u32,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct TonSignedMessage {
+    /// Base64 encoded external message, as expected by `sendBoc`.
+    pub boc: String,
+    /// Hex encoded hash of the external message.
+    pub hash: String,
+}
+
+/// Wallet V4R2 contract of an Ed25519 key.
+pub struct TonWallet {
+    pub signer: SchnorrSigner,
+    pub state_init: Cell,
+    pub address: TonAddress,
+}
+
+impl TonWallet {
+    pub fn new(signer: SchnorrSigner) -> Result<Self> {
+        let public_key: [u8; 32] = signer
+            .public_key()
+            .try_into()
+            .map_err(|_| Error::InvalidPublicKey(hex::encode(signer.public_key())))?;
+        let state_init = wallet_state_init(&public_key)?;
+        let address = TonAddress {
+            workchain: 0,
+            hash: state_init.hash(),
+            bounceable: false,
+        };
+        Ok(Self {
+            signer,
+            state_init,
+            address,
+        })
+    }
+
+    /// Non bounceable user friendly address, the form used for wallets.
+    pub fn address(&self, testnet: bool) -> String {
+        self.address.to_friendly(testnet)
+    }
+
+    pub async fn sign_transaction(&self, tx: &TonTransaction) -> Result<TonSignedMessage> {
+        let signing_message = signing_message(tx)?;
+        let signature = self.signer.sign(&signing_message.hash()).await?;
+
+        let body = CellBuilder::new()
+            .store_bytes(&signature)
+            .store_cell(&signing_message)
+            .build()?;
+
+        let mut message = CellBuilder::new();
+        // ext_in_msg_info$10, no source address, no import fee
+        message
+            .store_uint(0b10, 2)
+            .store_uint(0b00, 2)
+            .store_address(&self.address)
+            .store_coins(0);
+        if tx.seqno == 0 {
+            message
+                .store_bit(true)
+                .store_bit(true)
+                .store_ref(self.state_init.clone());
+        } else {
+            message.store_bit(false);
+        }
+        message.store_bit(true).store_ref(body);
+        let message = message.build()?;
+
+        Ok(TonSignedMessage {
+            boc: BASE64.encode(message.to_boc()),
+            hash: hex::encode(message.hash()),
+        })
+    }
+}
+
+/// Parsed Wallet V4R2 code cell.
+pub fn wallet_code() -> Cell {
+    let boc = hex::decode(WALLET_V4R2_CODE).expect("wallet code is valid hex");
+    Cell::from_boc(&boc).expect("wallet code is a valid bag of cells")
+}
+
+fn wallet_state_init(public_key: &[u8; 32]) -> Result<Cell> {
+    let data = CellBuilder::new()
+        .store_uint(0, 32)
+        .store_uint(DEFAULT_SUBWALLET_ID as u64, 32)
+        .store_bytes(public_key)
+        // no plugins
+        .store_bit(false)
+        .build()?;
+    // no split depth nor special, code and data, no library
+    CellBuilder::new()
+        .store_uint(0b00110, 5)
+        .store_ref(wallet_code())
+        .store_ref(data)
+        .build()
+}
+
+/// Unsigned body of a V4 wallet external message.
+fn signing_message(tx: &TonTransaction) -> Result<Cell> {
+    if tx.transfers.is_empty() || tx.transfers.len() > MAX_MESSAGES {
+        return Err(Error::InvalidTransaction(format!(
+            "{} transfers",
+            tx.transfers.len()
+        )));
+    }
+
+    let mut builder = CellBuilder::new();
+    builder
+        .store_uint(DEFAULT_SUBWALLET_ID as u64, 32)
+        .store_uint(tx.valid_until as u64, 32)
+        .store_uint(tx.seqno as u64, 32)
+        // simple send
+        .store_uint(0, 8);
+    for transfer in &tx.transfers {
+        builder
+            .store_uint(transfer.mode.unwrap_or(DEFAULT_SEND_MODE) as u64, 8)
+            .store_ref(transfer.to_cell()?);
+    }
+    builder.build()
+}
+
+impl TonTransfer {
+    /// Internal message cell of the transfer.
+    fn to_cell(&self) -> Result<Cell> {
+        let to = TonAddress::parse(&self.to)?;
+        let mut builder = CellBuilder::new();
+        // int_msg_info$0, ihr disabled, bounce, not bounced, no source address
+        builder
+            .store_bit(false)
+            .store_bit(true)
+            .store_bit(to.bounceable)
+            .store_bit(false)
+            .store_uint(0b00, 2)
+            .store_address(&to)
+            .store_coins(self.amount as u128)
+            // no extra currencies, zero ihr and forward fees, lt and time set by validators
+            .store_bit(false)
+            .store_coins(0)
+            .store_coins(0)
+            .store_uint(0, 64)
+            .store_uint(0, 32)
+            // no state init
+            .store_bit(false);
+
+        match &self.comment {
+            Some(comment) => {
+                let body = CellBuilder::new()
+                    .store_uint(0, 32)
+                    .store_bytes(comment.as_bytes())
+                    .build()
+                    .map_err(|_| Error::InvalidTransaction(format!("comment {comment}")))?;
+                builder.store_bit(true).store_ref(body);
+            }
+            None => {
+                builder.store_bit(false);
+            }
+        }
+        builder.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_cells() {
+        assert_eq!(
+            hex::encode(Cell::default().hash()),
+            "96a296d224f285c67bee93c30f8a309157f0daa35dc5b87e410b78630a09cfc7"
+        );
+    }
+
+    #[test]
+    fn parses_wallet_code() {
+        let code = wallet_code();
+        assert_eq!(
+            hex::encode(code.hash()),
+            "feb5ff6820e2ff0d9483e7e0d62c817d846789fb4ae580c878866d959dabd5c0"
+        );
+        assert_eq!(Cell::from_boc(&code.to_boc()).unwrap(), code);
+    }
+
+    #[test]
+    fn parses_addresses() {
+        let address =
+            TonAddress::parse("EQCD39VS5jcptHL8vMjEXrzGaRcCVYto7HUn4bpAOg8xqB2N").unwrap();
+        assert_eq!(address.workchain, 0);
+        assert!(address.bounceable);
+        assert_eq!(
+            address.to_friendly(false),
+            "EQCD39VS5jcptHL8vMjEXrzGaRcCVYto7HUn4bpAOg8xqB2N"
+        );
+        let raw = format!("0:{}", hex::encode(address.hash));
+        assert_eq!(TonAddress::parse(&raw).unwrap(), address);
+
+        let non_bounceable = TonAddress {
+            bounceable: false,
+            ..address
+        };
+        assert_eq!(
+            TonAddress::parse(&non_bounceable.to_friendly(true)).unwrap(),
+            non_bounceable
+        );
+        assert!(TonAddress::parse("EQCD39VS5jcptHL8vMjEXrzGaRcCVYto7HUn4bpAOg8xqB2M").is_err());
+    }
+
+    #[test]
+    fn stores_coins() {
+        let cell = CellBuilder::new().store_coins(0).build().unwrap();
+        assert_eq!((cell.bits, cell.data), (4, vec![0]));
+        let cell = CellBuilder::new().store_coins(1_000).build().unwrap();
+        assert_eq!((cell.bits, cell.data), (20, vec![0x20, 0x3e, 0x80]));
+    }
+}